@@ -1,5 +1,4 @@
 use criterion::{black_box, criterion_group, criterion_main, Criterion};
-use std::sync::Arc;
 use tinyproxy_rust::config::Config;
 use tinyproxy_rust::utils::{format_bytes, is_valid_hostname};
 
@@ -50,8 +49,7 @@ ConnectPort 563
 
     c.bench_function("config_parsing", |b| {
         b.iter(|| {
-            // Note: parse_config is a private method, so we'll benchmark a public operation instead
-            black_box(Config::default());
+            black_box(Config::parse_config(black_box(config_content)).unwrap());
         });
     });
 }
@@ -0,0 +1,588 @@
+use crate::config::Config;
+use crate::connection::request_host;
+use crate::utils::{find_end_of_headers, HttpRequest};
+use chrono::{DateTime, Local, SecondsFormat};
+use log::warn;
+use serde_json::json;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::net::IpAddr;
+use std::pin::Pin;
+use std::sync::Mutex;
+use std::task::{ready, Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, ReadBuf};
+
+/// Response heads longer than this are not searched for a status.
+const MAX_HEAD: usize = 65536;
+
+/// Measurements of one request, logged when it completes: the bytes sent
+/// each way, how long connecting upstream and the first response byte
+/// took, and the total time.
+#[derive(Debug)]
+pub struct Exchange {
+    started: Instant,
+    pub status: Option<u16>,
+    /// Bytes of the request sent upstream, head included.
+    pub request_bytes: u64,
+    /// Bytes of the response sent to the client, head included.
+    pub response_bytes: u64,
+    pub connect_time: Option<Duration>,
+    pub first_byte: Option<Duration>,
+}
+
+impl Exchange {
+    pub fn new() -> Self {
+        Self {
+            started: Instant::now(),
+            status: None,
+            request_bytes: 0,
+            response_bytes: 0,
+            connect_time: None,
+            first_byte: None,
+        }
+    }
+
+    pub fn started(&self) -> Instant {
+        self.started
+    }
+
+    /// Access log line for `client`'s request, e.g.
+    ///
+    /// ```text
+    /// 192.0.2.7 "GET http://example.com/ HTTP/1.1" 200 412 5120 connect=3ms ttfb=41ms time=45ms
+    /// ```
+    ///
+    /// Unknown values are written as `-`.
+    pub fn format_line(&self, client: IpAddr, request_line: &str, duration: Duration) -> String {
+        let millis = |time: Option<Duration>| match time {
+            Some(time) => format!("{}ms", time.as_millis()),
+            None => "-".to_string(),
+        };
+        format!(
+            "{} \"{}\" {} {} {} connect={} ttfb={} time={}",
+            client,
+            request_line.replace('"', "%22"),
+            self.status
+                .map_or("-".to_string(), |status| status.to_string()),
+            self.request_bytes,
+            self.response_bytes,
+            millis(self.connect_time),
+            millis(self.first_byte),
+            millis(Some(duration))
+        )
+    }
+}
+
+impl Default for Exchange {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Format of the Common Log Format, `LogFormat common`.
+const COMMON: &str = r#"%h %l %u %t "%r" %>s %b"#;
+
+/// Format of the Combined Log Format, `LogFormat combined`.
+const COMBINED: &str = r#"%h %l %u %t "%r" %>s %b "%{Referer}i" "%{User-Agent}i""#;
+
+/// One part of a log line format.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Field {
+    Text(String),
+    /// `%h`
+    Client,
+    /// `%l`
+    Ident,
+    /// `%u`
+    User,
+    /// `%t`
+    Time,
+    /// `%r`
+    RequestLine,
+    /// `%m`
+    Method,
+    /// `%U`
+    Uri,
+    /// `%H`
+    Protocol,
+    /// `%s` or `%>s`
+    Status,
+    /// `%b`, `-` for nothing
+    Bytes,
+    /// `%B`
+    BytesZero,
+    /// `%I`
+    RequestBytes,
+    /// `%D`
+    Micros,
+    /// `%T`
+    Seconds,
+    /// `%{Name}i`
+    Header(String),
+}
+
+/// A LogFormat: `common`, `combined`, `json` or a format string of
+/// Apache's `%` directives.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LogFormat {
+    layout: Layout,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Layout {
+    Fields(Vec<Field>),
+    /// One JSON object per entry, with fixed field names
+    Json,
+}
+
+impl LogFormat {
+    pub fn parse(format: &str) -> Result<Self, String> {
+        let format = match format {
+            "common" => COMMON,
+            "combined" => COMBINED,
+            "json" => {
+                return Ok(Self {
+                    layout: Layout::Json,
+                })
+            }
+            format => format,
+        };
+
+        let mut fields = Vec::new();
+        let mut text = String::new();
+        let mut chars = format.chars();
+        while let Some(c) = chars.next() {
+            if c != '%' {
+                text.push(c);
+                continue;
+            }
+            let field = match chars.next() {
+                Some('%') => {
+                    text.push('%');
+                    continue;
+                }
+                Some('h') => Field::Client,
+                Some('l') => Field::Ident,
+                Some('u') => Field::User,
+                Some('t') => Field::Time,
+                Some('r') => Field::RequestLine,
+                Some('m') => Field::Method,
+                Some('U') => Field::Uri,
+                Some('H') => Field::Protocol,
+                Some('s') => Field::Status,
+                Some('>') if chars.next() == Some('s') => Field::Status,
+                Some('b') => Field::Bytes,
+                Some('B') => Field::BytesZero,
+                Some('I') => Field::RequestBytes,
+                Some('D') => Field::Micros,
+                Some('T') => Field::Seconds,
+                Some('{') => {
+                    let name: String = chars.by_ref().take_while(|&c| c != '}').collect();
+                    if chars.next() != Some('i') || name.is_empty() {
+                        return Err(format!("unsupported directive %{{{}}}", name));
+                    }
+                    Field::Header(name)
+                }
+                Some(c) => return Err(format!("unsupported directive %{}", c)),
+                None => return Err("format ends with %".to_string()),
+            };
+            if !text.is_empty() {
+                fields.push(Field::Text(std::mem::take(&mut text)));
+            }
+            fields.push(field);
+        }
+        if !text.is_empty() {
+            fields.push(Field::Text(text));
+        }
+        Ok(Self {
+            layout: Layout::Fields(fields),
+        })
+    }
+
+    /// The log line for `entry`, without a line break.
+    fn format(&self, entry: &AccessEntry) -> String {
+        let fields = match &self.layout {
+            Layout::Fields(fields) => fields,
+            Layout::Json => return format_json(entry),
+        };
+        let dash = |value: Option<&str>| match value {
+            Some(value) if !value.is_empty() => escape(value),
+            _ => "-".to_string(),
+        };
+        let request = entry.request;
+        let exchange = entry.exchange;
+
+        let mut line = String::new();
+        for field in fields {
+            match field {
+                Field::Text(text) => line.push_str(text),
+                Field::Client => line.push_str(&entry.client.to_string()),
+                Field::Ident => line.push_str(&dash(entry.ident)),
+                Field::User => line.push_str(&dash(entry.user)),
+                Field::Time => {
+                    line.push_str(&entry.time.format("[%d/%b/%Y:%H:%M:%S %z]").to_string())
+                }
+                Field::RequestLine => line.push_str(&escape(&format!(
+                    "{} {} HTTP/{}",
+                    request.method, request.uri, request.version
+                ))),
+                Field::Method => line.push_str(&escape(&request.method)),
+                Field::Uri => line.push_str(&escape(&request.uri)),
+                Field::Protocol => line.push_str(&escape(&format!("HTTP/{}", request.version))),
+                Field::Status => line.push_str(
+                    &exchange
+                        .status
+                        .map_or("-".to_string(), |status| status.to_string()),
+                ),
+                Field::Bytes if exchange.response_bytes == 0 => line.push('-'),
+                Field::Bytes | Field::BytesZero => {
+                    line.push_str(&exchange.response_bytes.to_string())
+                }
+                Field::RequestBytes => line.push_str(&exchange.request_bytes.to_string()),
+                Field::Micros => line.push_str(&entry.duration.as_micros().to_string()),
+                Field::Seconds => line.push_str(&entry.duration.as_secs().to_string()),
+                Field::Header(name) => {
+                    line.push_str(&dash(request.headers.get_combined(name).as_deref()))
+                }
+            }
+        }
+        line
+    }
+}
+
+/// An access log entry as a JSON object. Missing values are null.
+fn format_json(entry: &AccessEntry) -> String {
+    let request = entry.request;
+    let exchange = entry.exchange;
+    json!({
+        "timestamp": entry.time.to_rfc3339_opts(SecondsFormat::Millis, true),
+        "level": "info",
+        "client_ip": entry.client.to_string(),
+        "ident": entry.ident,
+        "user": entry.user,
+        "method": request.method,
+        "uri": request.uri,
+        "host": request_host(request),
+        "protocol": format!("HTTP/{}", request.version),
+        "status": exchange.status,
+        "bytes": exchange.response_bytes,
+        "request_bytes": exchange.request_bytes,
+        "duration_ms": entry.duration.as_millis() as u64,
+        "referer": request.headers.get_combined("referer"),
+        "user_agent": request.headers.get_combined("user-agent"),
+    })
+    .to_string()
+}
+
+/// What the access log knows of a completed request.
+pub struct AccessEntry<'a> {
+    pub client: IpAddr,
+    /// User from the client's ident server.
+    pub ident: Option<&'a str>,
+    /// User the client authenticated as.
+    pub user: Option<&'a str>,
+    /// When the request arrived.
+    pub time: DateTime<Local>,
+    /// The request as the client sent it.
+    pub request: &'a HttpRequest,
+    pub exchange: &'a Exchange,
+    pub duration: Duration,
+}
+
+/// Log of every request to the AccessLog file, one line each in the
+/// LogFormat: Apache's common or combined format, or a format string of
+/// its `%` directives. Bytes are those sent to the client, head included.
+pub struct AccessLog {
+    file: Option<Mutex<File>>,
+    format: LogFormat,
+}
+
+impl AccessLog {
+    pub fn new(config: &Config) -> Self {
+        let format = LogFormat::parse(&config.log_format).unwrap_or_else(|e| {
+            warn!(
+                "Invalid LogFormat {:?} ({}), using common",
+                config.log_format, e
+            );
+            LogFormat::parse("common").unwrap()
+        });
+        let file = config.access_log.as_ref().and_then(|path| {
+            match OpenOptions::new().create(true).append(true).open(path) {
+                Ok(file) => Some(Mutex::new(file)),
+                Err(e) => {
+                    warn!("Failed to open access log {}: {}", path, e);
+                    None
+                }
+            }
+        });
+        Self { file, format }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.file.is_some()
+    }
+
+    pub fn record(&self, entry: &AccessEntry) {
+        let file = match &self.file {
+            Some(file) => file,
+            None => return,
+        };
+
+        let mut line = self.format.format(entry);
+        line.push('\n');
+        if let Err(e) = file.lock().unwrap().write_all(line.as_bytes()) {
+            warn!("Failed to write access log: {}", e);
+        }
+    }
+}
+
+/// Escape a value from the client the way Apache does, so that it cannot
+/// break out of quotes or forge lines.
+fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if c.is_control() => escaped.push_str(&format!("\\x{:02x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Reader over the response relayed to a client that notes in an
+/// [`Exchange`] when its first byte arrived, its final status and its size
+/// as it goes. Interim 1xx responses are skipped when looking for the
+/// status.
+pub struct ResponseMeter<'a, R> {
+    inner: R,
+    exchange: &'a mut Exchange,
+    /// Start of the response, until its status is known.
+    head: Option<Vec<u8>>,
+}
+
+impl<'a, R> ResponseMeter<'a, R> {
+    pub fn new(inner: R, exchange: &'a mut Exchange) -> Self {
+        Self {
+            inner,
+            exchange,
+            head: Some(Vec::new()),
+        }
+    }
+
+    fn scan(&mut self, data: &[u8]) {
+        let head = match &mut self.head {
+            Some(head) => head,
+            None => return,
+        };
+        head.extend_from_slice(data);
+
+        // Enough for "HTTP/1.1 200"
+        while head.len() >= 12 {
+            let status = head
+                .starts_with(b"HTTP/")
+                .then(|| std::str::from_utf8(&head[9..12]).ok()?.parse::<u16>().ok())
+                .flatten();
+            match status {
+                Some(100..=199) if status != Some(101) => match find_end_of_headers(head) {
+                    Some(end) => {
+                        head.drain(..end + 4);
+                    }
+                    None if head.len() > MAX_HEAD => break,
+                    None => return,
+                },
+                _ => {
+                    self.exchange.status = status.or(self.exchange.status);
+                    break;
+                }
+            }
+        }
+        if head.len() >= 12 {
+            self.head = None;
+        }
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for ResponseMeter<'_, R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        ready!(Pin::new(&mut self.inner).poll_read(cx, buf))?;
+
+        let data = &buf.filled()[before..];
+        if !data.is_empty() {
+            let exchange = &mut *self.exchange;
+            if exchange.first_byte.is_none() {
+                exchange.first_byte = Some(exchange.started.elapsed());
+            }
+            exchange.response_bytes += data.len() as u64;
+            self.scan(data);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncReadExt;
+
+    #[test]
+    fn test_format_line() {
+        let exchange = Exchange {
+            status: Some(200),
+            request_bytes: 412,
+            response_bytes: 5120,
+            connect_time: Some(Duration::from_millis(3)),
+            ..Exchange::new()
+        };
+        assert_eq!(
+            exchange.format_line(
+                "192.0.2.7".parse().unwrap(),
+                "GET http://example.com/\"x HTTP/1.1",
+                Duration::from_millis(45)
+            ),
+            "192.0.2.7 \"GET http://example.com/%22x HTTP/1.1\" 200 412 5120 \
+             connect=3ms ttfb=- time=45ms"
+        );
+    }
+
+    #[test]
+    fn test_log_format() {
+        let request = crate::utils::parse_http_request(
+            b"GET http://example.com/a\"b HTTP/1.1\r\n\
+              Referer: http://example.org/\r\nUser-Agent: curl/8.5.0\r\n\r\n",
+        )
+        .unwrap();
+        let exchange = Exchange {
+            status: Some(200),
+            request_bytes: 120,
+            response_bytes: 5120,
+            ..Exchange::new()
+        };
+        let time = DateTime::parse_from_rfc3339("2024-05-01T12:00:00+02:00").unwrap();
+        let entry = AccessEntry {
+            client: "192.0.2.7".parse().unwrap(),
+            ident: None,
+            user: Some("bob"),
+            time: time.with_timezone(&Local),
+            request: &request,
+            exchange: &exchange,
+            duration: Duration::from_millis(1500),
+        };
+        let time = time
+            .with_timezone(&Local)
+            .format("[%d/%b/%Y:%H:%M:%S %z]")
+            .to_string();
+
+        let format = |format| LogFormat::parse(format).unwrap().format(&entry);
+        assert_eq!(
+            format("common"),
+            format!(
+                "192.0.2.7 - bob {} \"GET http://example.com/a\\\"b HTTP/1.1\" 200 5120",
+                time
+            )
+        );
+        assert!(format("combined").ends_with(" 200 5120 \"http://example.org/\" \"curl/8.5.0\""));
+        assert_eq!(
+            format("%m %H %D %T %I %B 100%% %{X-Missing}i"),
+            "GET HTTP/1.1 1500000 1 120 5120 100% -"
+        );
+
+        let line: serde_json::Value = serde_json::from_str(&format("json")).unwrap();
+        assert_eq!(line["client_ip"], "192.0.2.7");
+        assert_eq!(line["method"], "GET");
+        assert_eq!(line["host"], "example.com");
+        assert_eq!(line["status"], 200);
+        assert_eq!(line["bytes"], 5120);
+        assert_eq!(line["duration_ms"], 1500);
+        assert_eq!(line["user"], "bob");
+        assert!(line["ident"].is_null());
+        let logged = DateTime::parse_from_rfc3339(line["timestamp"].as_str().unwrap()).unwrap();
+        assert_eq!(logged, entry.time);
+
+        assert!(LogFormat::parse("%h %q").is_err());
+        assert!(LogFormat::parse("%{Referer}").is_err());
+        assert!(LogFormat::parse("%h %").is_err());
+    }
+
+    #[test]
+    fn test_access_log() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("access.log");
+        let config = Config::parse_config(&format!(
+            "AccessLog \"{}\"\nLogFormat \"\\\"%h\\\" %>s %u\"",
+            path.display()
+        ))
+        .unwrap();
+        let log = AccessLog::new(&config);
+        assert!(log.is_enabled());
+
+        let request = crate::utils::parse_http_request(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+        let exchange = Exchange::new();
+        log.record(&AccessEntry {
+            client: "::1".parse().unwrap(),
+            ident: None,
+            user: None,
+            time: Local::now(),
+            request: &request,
+            exchange: &exchange,
+            duration: Duration::ZERO,
+        });
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "\"::1\" - -\n");
+        assert!(!AccessLog::new(&Config::default()).is_enabled());
+    }
+
+    /// Reader returning at most `chunk` bytes per read.
+    struct Chunked<'a> {
+        data: &'a [u8],
+        chunk: usize,
+    }
+
+    impl AsyncRead for Chunked<'_> {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<io::Result<()>> {
+            let n = self.chunk.min(self.data.len()).min(buf.remaining());
+            buf.put_slice(&self.data[..n]);
+            self.data = &self.data[n..];
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_response_meter() {
+        let response = b"HTTP/1.1 100 Continue\r\n\r\nHTTP/1.1 404 Not Found\r\n\r\nmissing";
+        // Small reads split the status lines
+        for chunk in [1, 5, response.len()] {
+            let reads = Chunked {
+                data: response,
+                chunk,
+            };
+            let mut exchange = Exchange::new();
+            let mut relayed = Vec::new();
+            ResponseMeter::new(reads, &mut exchange)
+                .read_to_end(&mut relayed)
+                .await
+                .unwrap();
+            assert_eq!(relayed, response);
+            assert_eq!(exchange.status, Some(404));
+            assert_eq!(exchange.response_bytes, response.len() as u64);
+            assert!(exchange.first_byte.is_some());
+        }
+
+        let mut exchange = Exchange::new();
+        ResponseMeter::new(&b"garbage that is no response"[..], &mut exchange)
+            .read_to_end(&mut Vec::new())
+            .await
+            .unwrap();
+        assert_eq!(exchange.status, None);
+    }
+}
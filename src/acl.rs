@@ -4,8 +4,31 @@ use std::net::{IpAddr, SocketAddr};
 use std::str::FromStr;
 
 pub struct AccessControl {
-    allow_rules: Vec<IpRule>,
-    deny_rules: Vec<IpRule>,
+    allow_rules: Vec<AclEntry>,
+    deny_rules: Vec<AclEntry>,
+}
+
+/// An Allow/Deny rule together with the text it was parsed from.
+#[derive(Debug, Clone)]
+struct AclEntry {
+    text: String,
+    rule: IpRule,
+}
+
+/// Which list an access rule belongs to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AclList {
+    Allow,
+    Deny,
+}
+
+/// Outcome of checking an address against the access rules.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AclDecision {
+    pub allowed: bool,
+    /// The rule that decided, if any (`None` when no rules are configured
+    /// or no allow rule matched).
+    pub rule: Option<String>,
 }
 
 #[derive(Debug, Clone)]
@@ -17,97 +40,243 @@ enum IpRule {
 
 impl AccessControl {
     pub fn new(config: &Config) -> Self {
-        let mut allow_rules = Vec::new();
-        let mut deny_rules = Vec::new();
+        Self::from_rules(&config.allow, &config.deny)
+    }
+
+    /// Access control with the given allow and deny rules, skipping
+    /// invalid ones with a warning.
+    pub fn from_rules(allow: &[String], deny: &[String]) -> Self {
+        let mut acl = Self {
+            allow_rules: Vec::new(),
+            deny_rules: Vec::new(),
+        };
 
         // Parse allow rules
-        for rule in &config.allow {
-            if let Ok(ip_rule) = parse_ip_rule(rule) {
-                allow_rules.push(ip_rule);
-            } else {
+        for rule in allow {
+            if acl.add_rule(AclList::Allow, rule).is_err() {
                 warn!("Invalid allow rule: {}", rule);
             }
         }
 
         // Parse deny rules
-        for rule in &config.deny {
-            if let Ok(ip_rule) = parse_ip_rule(rule) {
-                deny_rules.push(ip_rule);
-            } else {
+        for rule in deny {
+            if acl.add_rule(AclList::Deny, rule).is_err() {
                 warn!("Invalid deny rule: {}", rule);
             }
         }
 
-        // If no allow rules are specified, allow all by default
-        if allow_rules.is_empty() && deny_rules.is_empty() {
-            allow_rules.push(IpRule::All);
-        }
-
-        Self {
-            allow_rules,
-            deny_rules,
-        }
+        acl
     }
 
     pub fn is_allowed(&self, addr: &SocketAddr) -> bool {
-        let ip = addr.ip();
+        self.check(&addr.ip()).allowed
+    }
+
+    /// Decide whether `ip` may use the proxy, and by which rule.
+    pub fn check(&self, ip: &IpAddr) -> AclDecision {
+        // If no rules are specified, allow all by default
+        if self.allow_rules.is_empty() && self.deny_rules.is_empty() {
+            return AclDecision {
+                allowed: true,
+                rule: None,
+            };
+        }
 
         // First check deny rules - if any deny rule matches, deny access
-        for rule in &self.deny_rules {
-            if self.matches_rule(rule, &ip) {
-                debug!("IP {} denied by rule: {:?}", ip, rule);
-                return false;
+        for entry in &self.deny_rules {
+            if entry.rule.matches(ip) {
+                debug!("IP {} denied by rule: {:?}", ip, entry.rule);
+                return AclDecision {
+                    allowed: false,
+                    rule: Some(entry.text.clone()),
+                };
             }
         }
 
         // Then check allow rules - if any allow rule matches, allow access
-        for rule in &self.allow_rules {
-            if self.matches_rule(rule, &ip) {
-                debug!("IP {} allowed by rule: {:?}", ip, rule);
-                return true;
+        for entry in &self.allow_rules {
+            if entry.rule.matches(ip) {
+                debug!("IP {} allowed by rule: {:?}", ip, entry.rule);
+                return AclDecision {
+                    allowed: true,
+                    rule: Some(entry.text.clone()),
+                };
             }
         }
 
         // If no allow rules match, deny by default
         debug!("IP {} denied (no matching allow rule)", ip);
-        false
+        AclDecision {
+            allowed: false,
+            rule: None,
+        }
     }
 
-    fn matches_rule(&self, rule: &IpRule, ip: &IpAddr) -> bool {
-        match rule {
-            IpRule::All => true,
-            IpRule::Single(rule_ip) => ip == rule_ip,
-            IpRule::Network { network, prefix } => self.ip_in_network(ip, network, *prefix),
+    /// Add a rule to the allow or deny list. Returns false if the list
+    /// already contains it.
+    pub fn add_rule(&mut self, list: AclList, text: &str) -> Result<bool, String> {
+        let text = text.trim();
+        let rule = parse_ip_rule(text)?;
+        let rules = self.rules_mut(list);
+        if rules.iter().any(|entry| entry.text == text) {
+            return Ok(false);
         }
+
+        rules.push(AclEntry {
+            text: text.to_string(),
+            rule,
+        });
+        Ok(true)
     }
 
-    fn ip_in_network(&self, ip: &IpAddr, network: &IpAddr, prefix: u8) -> bool {
-        match (ip, network) {
-            (IpAddr::V4(ip), IpAddr::V4(net)) => {
-                let ip_bits = u32::from(*ip);
-                let net_bits = u32::from(*net);
-                let mask = if prefix == 0 {
-                    0
-                } else {
-                    !((1u32 << (32 - prefix)) - 1)
-                };
-                (ip_bits & mask) == (net_bits & mask)
+    /// Remove a rule from the allow or deny list. Returns false if it was
+    /// not present.
+    pub fn remove_rule(&mut self, list: AclList, text: &str) -> bool {
+        let text = text.trim();
+        let rules = self.rules_mut(list);
+        let before = rules.len();
+        rules.retain(|entry| entry.text != text);
+        rules.len() != before
+    }
+
+    pub fn rules(&self, list: AclList) -> Vec<&str> {
+        let rules = match list {
+            AclList::Allow => &self.allow_rules,
+            AclList::Deny => &self.deny_rules,
+        };
+        rules.iter().map(|entry| entry.text.as_str()).collect()
+    }
+
+    fn rules_mut(&mut self, list: AclList) -> &mut Vec<AclEntry> {
+        match list {
+            AclList::Allow => &mut self.allow_rules,
+            AclList::Deny => &mut self.deny_rules,
+        }
+    }
+}
+
+/// A list of addresses and networks, e.g. from a repeatable config
+/// directive.
+#[derive(Debug, Clone, Default)]
+pub struct IpList {
+    rules: Vec<IpRule>,
+}
+
+impl IpList {
+    /// Parse `entries`, skipping invalid ones with a warning that names
+    /// the directive they came from.
+    pub fn new(entries: &[String], directive: &str) -> Self {
+        let mut rules = Vec::new();
+
+        for entry in entries {
+            if let Ok(ip_rule) = parse_ip_rule(entry) {
+                rules.push(ip_rule);
+            } else {
+                warn!("Invalid {} rule: {}", directive, entry);
             }
-            (IpAddr::V6(ip), IpAddr::V6(net)) => {
-                let ip_bits = u128::from(*ip);
-                let net_bits = u128::from(*net);
-                let mask = if prefix == 0 {
-                    0
-                } else {
-                    !((1u128 << (128 - prefix)) - 1)
-                };
-                (ip_bits & mask) == (net_bits & mask)
+        }
+
+        Self { rules }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        self.rules.iter().any(|rule| rule.matches(ip))
+    }
+}
+
+/// Peers whose reported client address (PROXY protocol header or
+/// X-Forwarded-For) is trusted as the effective client IP.
+pub struct TrustedProxies {
+    proxies: IpList,
+}
+
+impl TrustedProxies {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            proxies: IpList::new(&config.trusted_proxies, "TrustedProxies"),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.proxies.is_empty()
+    }
+
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        self.proxies.contains(ip)
+    }
+
+    /// Pick the client address out of an X-Forwarded-For chain received from
+    /// the trusted peer `peer`. The chain is walked right to left, skipping
+    /// hops that are themselves trusted proxies; the first untrusted hop is
+    /// the client. Unparseable entries stop the walk.
+    pub fn client_from_forwarded_for(&self, peer: IpAddr, forwarded_for: &str) -> IpAddr {
+        let mut client = peer;
+
+        for hop in forwarded_for.rsplit(',') {
+            let ip = match parse_forwarded_ip(hop.trim()) {
+                Some(ip) => ip,
+                None => break,
+            };
+
+            client = ip;
+            if !self.contains(&ip) {
+                break;
             }
-            _ => false, // IPv4 vs IPv6 mismatch
         }
+
+        client
     }
 }
 
+impl IpRule {
+    fn matches(&self, ip: &IpAddr) -> bool {
+        match self {
+            IpRule::All => true,
+            IpRule::Single(rule_ip) => ip == rule_ip,
+            IpRule::Network { network, prefix } => ip_in_network(ip, network, *prefix),
+        }
+    }
+}
+
+fn ip_in_network(ip: &IpAddr, network: &IpAddr, prefix: u8) -> bool {
+    match (ip, network) {
+        (IpAddr::V4(ip), IpAddr::V4(net)) => {
+            let ip_bits = u32::from(*ip);
+            let net_bits = u32::from(*net);
+            let mask = if prefix == 0 {
+                0
+            } else {
+                !((1u32 << (32 - prefix)) - 1)
+            };
+            (ip_bits & mask) == (net_bits & mask)
+        }
+        (IpAddr::V6(ip), IpAddr::V6(net)) => {
+            let ip_bits = u128::from(*ip);
+            let net_bits = u128::from(*net);
+            let mask = if prefix == 0 {
+                0
+            } else {
+                !((1u128 << (128 - prefix)) - 1)
+            };
+            (ip_bits & mask) == (net_bits & mask)
+        }
+        _ => false, // IPv4 vs IPv6 mismatch
+    }
+}
+
+/// Parse one X-Forwarded-For entry, tolerating `ip:port` and `[v6]:port`.
+fn parse_forwarded_ip(hop: &str) -> Option<IpAddr> {
+    if let Ok(ip) = IpAddr::from_str(hop) {
+        return Some(ip);
+    }
+
+    SocketAddr::from_str(hop).ok().map(|addr| addr.ip())
+}
+
 fn parse_ip_rule(rule: &str) -> Result<IpRule, String> {
     let rule = rule.trim();
 
@@ -171,17 +340,12 @@ mod tests {
 
     #[test]
     fn test_ip_in_network() {
-        let acl = AccessControl {
-            allow_rules: vec![],
-            deny_rules: vec![],
-        };
-
         let network = IpAddr::V4(Ipv4Addr::new(192, 168, 1, 0));
         let ip1 = IpAddr::V4(Ipv4Addr::new(192, 168, 1, 100));
         let ip2 = IpAddr::V4(Ipv4Addr::new(192, 168, 2, 100));
 
-        assert!(acl.ip_in_network(&ip1, &network, 24));
-        assert!(!acl.ip_in_network(&ip2, &network, 24));
+        assert!(ip_in_network(&ip1, &network, 24));
+        assert!(!ip_in_network(&ip2, &network, 24));
     }
 
     #[test]
@@ -200,4 +364,67 @@ mod tests {
         assert!(!acl.is_allowed(&denied_addr)); // Explicitly denied
         assert!(!acl.is_allowed(&blocked_addr)); // Not in allow list
     }
+
+    #[test]
+    fn test_runtime_rules() {
+        let mut acl = AccessControl::new(&Config::default());
+        let ip: IpAddr = "10.1.2.3".parse().unwrap();
+
+        assert_eq!(
+            acl.check(&ip),
+            AclDecision {
+                allowed: true,
+                rule: None
+            }
+        );
+
+        assert_eq!(acl.add_rule(AclList::Deny, "10.0.0.0/8"), Ok(true));
+        assert_eq!(acl.add_rule(AclList::Deny, "10.0.0.0/8"), Ok(false));
+        assert!(acl.add_rule(AclList::Allow, "bogus").is_err());
+        assert_eq!(
+            acl.check(&ip),
+            AclDecision {
+                allowed: false,
+                rule: Some("10.0.0.0/8".to_string())
+            }
+        );
+
+        assert!(acl.remove_rule(AclList::Deny, "10.0.0.0/8"));
+        assert!(!acl.remove_rule(AclList::Deny, "10.0.0.0/8"));
+        assert!(acl.rules(AclList::Deny).is_empty());
+        assert!(acl.check(&ip).allowed);
+    }
+
+    #[test]
+    fn test_trusted_proxies_forwarded_for() {
+        let config = Config {
+            trusted_proxies: vec!["10.0.0.0/8".to_string()],
+            ..Default::default()
+        };
+
+        let trusted = TrustedProxies::new(&config);
+        let peer = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+
+        assert!(trusted.contains(&peer));
+        assert!(!trusted.contains(&IpAddr::V4(Ipv4Addr::new(192, 168, 1, 1))));
+
+        // Rightmost untrusted hop wins, trusted hops are skipped
+        assert_eq!(
+            trusted.client_from_forwarded_for(peer, "203.0.113.7, 198.51.100.2, 10.0.0.9"),
+            IpAddr::V4(Ipv4Addr::new(198, 51, 100, 2))
+        );
+
+        // Ports and bracketed IPv6 entries are tolerated
+        assert_eq!(
+            trusted.client_from_forwarded_for(peer, "203.0.113.7:4711"),
+            IpAddr::V4(Ipv4Addr::new(203, 0, 113, 7))
+        );
+        assert_eq!(
+            trusted.client_from_forwarded_for(peer, "[2001:db8::1]:443"),
+            "2001:db8::1".parse::<IpAddr>().unwrap()
+        );
+
+        // Garbage leaves the peer address in place
+        assert_eq!(trusted.client_from_forwarded_for(peer, "unknown"), peer);
+    }
 }
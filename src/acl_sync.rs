@@ -0,0 +1,297 @@
+use crate::acl::{AccessControl, AclList};
+use crate::config::{Config, RemoteAccessListConfig};
+use crate::connector::Connector;
+#[cfg(any(feature = "rustls", feature = "native-tls"))]
+use crate::tls::TlsConnector;
+use crate::utils::{parse_http_response, HeadScanner};
+use log::{debug, info, warn};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::time::timeout;
+
+/// Largest access list accepted, including the response head.
+const MAX_LIST_SIZE: usize = 1024 * 1024;
+
+/// Longest time a fetch may take.
+const MAX_FETCH_TIME: Duration = Duration::from_secs(30);
+
+/// Allow/Deny rules shared by a fleet of proxies through a list served over
+/// HTTP(S). The list holds `Allow` and `Deny` lines like the configuration
+/// file and is fetched periodically, sending the last ETag so an unchanged
+/// list is not transferred again.
+///
+/// Each list that parses completely replaces the previous one at once; the
+/// rules of the configuration file stay in effect alongside it. Lists that
+/// cannot be fetched or contain invalid rules are ignored, keeping the
+/// rules currently in use. Rules changed through the admin API last until
+/// the list changes.
+pub struct RemoteAccessList {
+    config: Option<RemoteAccessListConfig>,
+    allow: Vec<String>,
+    deny: Vec<String>,
+    installed: Mutex<InstalledList>,
+}
+
+/// The list currently in effect.
+#[derive(Default)]
+struct InstalledList {
+    etag: Option<String>,
+    body: String,
+}
+
+/// Result of fetching the list.
+#[derive(Debug, PartialEq, Eq)]
+enum Fetched {
+    NotModified,
+    List { body: String, etag: Option<String> },
+}
+
+impl RemoteAccessList {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            config: config.remote_access_list.clone(),
+            allow: config.allow.clone(),
+            deny: config.deny.clone(),
+            installed: Mutex::new(InstalledList::default()),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.config.is_some()
+    }
+
+    /// Fetch the list periodically, forever, installing it into `acl`.
+    /// Returns at once if no list is configured.
+    pub async fn run(&self, connector: Arc<dyn Connector>, acl: &RwLock<AccessControl>) {
+        let config = match &self.config {
+            Some(config) => config,
+            None => return,
+        };
+
+        let mut interval = tokio::time::interval(Duration::from_secs(config.interval));
+        loop {
+            interval.tick().await;
+            if let Err(e) = self.sync(connector.clone(), acl).await {
+                warn!("Keeping current access rules, {}: {}", config.url, e);
+            }
+        }
+    }
+
+    /// Fetch the list once, installing it into `acl` if it changed.
+    /// Returns whether the rules were replaced.
+    pub async fn sync(
+        &self,
+        connector: Arc<dyn Connector>,
+        acl: &RwLock<AccessControl>,
+    ) -> Result<bool, String> {
+        let config = match &self.config {
+            Some(config) => config,
+            None => return Ok(false),
+        };
+
+        let etag = self.installed.lock().unwrap().etag.clone();
+        let (body, etag) = match fetch(connector, &config.url, etag.as_deref()).await? {
+            Fetched::List { body, etag } if body != self.installed.lock().unwrap().body => {
+                (body, etag)
+            }
+            _ => {
+                debug!("Access list {} not modified", config.url);
+                return Ok(false);
+            }
+        };
+
+        let (rules, count) = self.parse_list(&body)?;
+        *acl.write().unwrap() = rules;
+        *self.installed.lock().unwrap() = InstalledList { etag, body };
+        info!("Loaded {} access rules from {}", count, config.url);
+        Ok(true)
+    }
+
+    /// The configured rules plus those of a list, and how many the list
+    /// held. Fails on the first invalid line.
+    fn parse_list(&self, body: &str) -> Result<(AccessControl, usize), String> {
+        let mut acl = AccessControl::from_rules(&self.allow, &self.deny);
+        let mut count = 0;
+        for (number, line) in body.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let (directive, rule) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+            let list = match directive.to_lowercase().as_str() {
+                "allow" => AclList::Allow,
+                "deny" => AclList::Deny,
+                _ => return Err(format!("line {}: expected Allow or Deny", number + 1)),
+            };
+            acl.add_rule(list, rule)
+                .map_err(|e| format!("line {}: {}", number + 1, e))?;
+            count += 1;
+        }
+        Ok((acl, count))
+    }
+}
+
+/// Connector speaking TLS over `connector`, for https:// sources.
+fn https_connector(connector: Arc<dyn Connector>) -> Result<Arc<dyn Connector>, String> {
+    #[cfg(any(feature = "rustls", feature = "native-tls"))]
+    {
+        let connector = TlsConnector::new(connector).map_err(|e| e.to_string())?;
+        Ok(Arc::new(connector))
+    }
+    #[cfg(not(any(feature = "rustls", feature = "native-tls")))]
+    {
+        let _ = connector;
+        Err("built without TLS support for https:// sources".to_string())
+    }
+}
+
+/// GET `url`, conditionally on `etag`.
+async fn fetch(
+    connector: Arc<dyn Connector>,
+    url: &str,
+    etag: Option<&str>,
+) -> Result<Fetched, String> {
+    let parsed = url::Url::parse(url).map_err(|e| e.to_string())?;
+    let host = parsed.host_str().ok_or("URL has no host")?;
+    let port = parsed.port_or_known_default().ok_or("URL has no port")?;
+    let authority = match parsed.port() {
+        Some(port) => format!("{}:{}", host, port),
+        None => host.to_string(),
+    };
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let connector = match parsed.scheme() {
+        "https" => https_connector(connector)?,
+        _ => connector,
+    };
+
+    let mut request = format!(
+        "GET {} HTTP/1.0\r\nHost: {}\r\nUser-Agent: tinyproxy-rust\r\n",
+        &parsed[url::Position::BeforePath..],
+        authority
+    );
+    if let Some(etag) = etag {
+        request.push_str(&format!("If-None-Match: {}\r\n", etag));
+    }
+    request.push_str("\r\n");
+
+    let exchange = async {
+        let mut stream = connector
+            .connect(host, port)
+            .await
+            .map_err(|e| e.to_string())?;
+        stream
+            .write_all(request.as_bytes())
+            .await
+            .map_err(|e| e.to_string())?;
+
+        // HTTP/1.0 responses are delimited by the connection closing
+        let mut response = Vec::new();
+        (&mut stream)
+            .take(MAX_LIST_SIZE as u64 + 1)
+            .read_to_end(&mut response)
+            .await
+            .map_err(|e| e.to_string())?;
+        if response.len() > MAX_LIST_SIZE {
+            return Err("access list too large".to_string());
+        }
+        Ok(response)
+    };
+    let response = timeout(MAX_FETCH_TIME, exchange)
+        .await
+        .map_err(|_| "timed out".to_string())??;
+
+    let end = HeadScanner::new()
+        .find(&response)
+        .ok_or("incomplete response")?;
+    let head = parse_http_response(&response[..end + 4]).map_err(|e| e.to_string())?;
+    match head.status {
+        200 => Ok(Fetched::List {
+            body: String::from_utf8_lossy(&response[end + 4..]).into_owned(),
+            etag: head.headers.get("etag").map(str::to_string),
+        }),
+        304 => Ok(Fetched::NotModified),
+        status => Err(format!("server answered {}", status)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::connector::{BoxedStream, ConnectFailure};
+    use async_trait::async_trait;
+
+    /// Server answering with `list` and ETag "v1", or 304 when the request
+    /// already has that version.
+    struct ListServer {
+        list: &'static str,
+    }
+
+    #[async_trait]
+    impl Connector for ListServer {
+        async fn connect(&self, _host: &str, _port: u16) -> Result<BoxedStream, ConnectFailure> {
+            let (proxy_side, mut server) = tokio::io::duplex(4096);
+            let list = self.list;
+            tokio::spawn(async move {
+                let mut buf = [0u8; 1024];
+                let n = server.read(&mut buf).await.unwrap();
+                let request = String::from_utf8_lossy(&buf[..n]).to_string();
+                assert!(request.starts_with("GET /acl?fleet=a HTTP/1.0\r\nHost: acl.test\r\n"));
+                let response = if request.contains("If-None-Match: \"v1\"") {
+                    "HTTP/1.0 304 Not Modified\r\n\r\n".to_string()
+                } else {
+                    format!("HTTP/1.0 200 OK\r\nETag: \"v1\"\r\n\r\n{}", list)
+                };
+                server.write_all(response.as_bytes()).await.unwrap();
+            });
+            Ok(Box::new(proxy_side))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_sync() {
+        let config = Config::parse_config(
+            "Allow 192.0.2.0/24\nRemoteAccessList http://acl.test/acl?fleet=a 60",
+        )
+        .unwrap();
+        let remote = RemoteAccessList::new(&config);
+        let acl = RwLock::new(AccessControl::new(&config));
+        let allowed = |ip: &str| acl.read().unwrap().check(&ip.parse().unwrap()).allowed;
+
+        let server = Arc::new(ListServer {
+            list: "# fleet policy\nAllow 198.51.100.0/24\ndeny 192.0.2.66\n",
+        });
+        assert_eq!(remote.sync(server.clone(), &acl).await, Ok(true));
+        assert!(allowed("198.51.100.7"));
+        assert!(allowed("192.0.2.1"));
+        assert!(!allowed("192.0.2.66"));
+
+        // Unchanged lists are not installed again
+        assert_eq!(remote.sync(server, &acl).await, Ok(false));
+
+        // Invalid lists leave the current rules in place
+        remote.installed.lock().unwrap().etag = None;
+        let invalid = Arc::new(ListServer {
+            list: "Allow 203.0.113.0/24\nAllow not-an-address\n",
+        });
+        assert!(remote
+            .sync(invalid, &acl)
+            .await
+            .unwrap_err()
+            .starts_with("line 2:"));
+        assert!(allowed("198.51.100.7"));
+        assert!(!allowed("203.0.113.1"));
+    }
+
+    #[cfg(not(any(feature = "rustls", feature = "native-tls")))]
+    #[tokio::test]
+    async fn test_https_without_tls() {
+        let server = Arc::new(ListServer { list: "" });
+        let error = match fetch(server, "https://acl.test/acl", None).await {
+            Err(error) => error,
+            Ok(_) => panic!("fetched over https without TLS support"),
+        };
+        assert!(error.contains("without TLS support"));
+    }
+}
@@ -0,0 +1,970 @@
+use crate::acl::{AccessControl, AclList};
+use crate::auth::verify_basic_auth;
+use crate::config::Config;
+use crate::logging;
+use crate::state::ServerState;
+use anyhow::{Context, Result};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{header, Body, Method, Request, Response, Server, StatusCode};
+use log::{error, info, warn};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::convert::Infallible;
+use std::net::{IpAddr, SocketAddr, TcpListener};
+use std::sync::Arc;
+
+/// Largest request body accepted by the admin API.
+const MAX_BODY_SIZE: usize = 64 * 1024;
+
+/// Paths served by the admin API, for telling 404 from 405.
+const ENDPOINTS: &[&str] = &[
+    "/stats",
+    "/reload",
+    "/log/level",
+    "/filter/rules",
+    "/acl",
+    "/acl/check",
+    "/acl/allow",
+    "/acl/deny",
+    "/connections",
+    "/reverse/backends",
+    "/dns/cache",
+];
+
+/// HTTP API for runtime management, listening on `AdminListen:AdminPort`
+/// and protected by the `AdminAuth` credentials.
+pub struct AdminServer {
+    state: Arc<ServerState>,
+    listener: TcpListener,
+}
+
+#[derive(Deserialize)]
+struct AclRuleRequest {
+    rule: String,
+}
+
+#[derive(Deserialize)]
+struct LogLevelRequest {
+    level: String,
+}
+
+#[derive(Deserialize)]
+struct FilterRuleRequest {
+    pattern: String,
+    #[serde(default)]
+    persist: bool,
+}
+
+impl AdminServer {
+    pub fn bind(state: Arc<ServerState>) -> Result<Self> {
+        let config = &state.config;
+        let port = config
+            .admin_port
+            .ok_or_else(|| anyhow::anyhow!("AdminPort is not configured"))?;
+        if config.admin_auth.is_none() {
+            return Err(anyhow::anyhow!("AdminPort requires AdminAuth credentials"));
+        }
+
+        let addr = SocketAddr::new(config.admin_listen, port);
+        let listener = TcpListener::bind(addr)
+            .with_context(|| format!("Failed to bind admin API to {}", addr))?;
+        listener.set_nonblocking(true)?;
+
+        Ok(Self { state, listener })
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.listener.local_addr()?)
+    }
+
+    pub async fn run(self) {
+        let state = self.state;
+        let make_service = make_service_fn(move |_| {
+            let state = state.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |request| {
+                    let state = state.clone();
+                    async move { Ok::<_, Infallible>(handle(state, request).await) }
+                }))
+            }
+        });
+
+        let server = match Server::from_tcp(self.listener) {
+            Ok(builder) => builder.serve(make_service),
+            Err(e) => {
+                error!("Failed to start admin API: {}", e);
+                return;
+            }
+        };
+
+        if let Err(e) = server.await {
+            error!("Admin API error: {}", e);
+        }
+    }
+}
+
+async fn handle(state: Arc<ServerState>, request: Request<Body>) -> Response<Body> {
+    let authorized = state.config.admin_auth.as_ref().is_some_and(|auth| {
+        let header = request
+            .headers()
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok());
+        verify_basic_auth(header, auth)
+    });
+    if !authorized {
+        warn!(
+            "Unauthorized admin request: {} {}",
+            request.method(),
+            request.uri()
+        );
+        return Response::builder()
+            .status(StatusCode::UNAUTHORIZED)
+            .header(header::WWW_AUTHENTICATE, "Basic realm=\"Tinyproxy Admin\"")
+            .body(Body::empty())
+            .unwrap();
+    }
+
+    if !matches!(*request.method(), Method::GET | Method::HEAD) && !same_site(&state, &request) {
+        warn!(
+            "Cross-site admin request refused: {} {}",
+            request.method(),
+            request.uri()
+        );
+        return error_response(StatusCode::FORBIDDEN, "Cross-site request refused");
+    }
+
+    let method = request.method().clone();
+    let path = request.uri().path().to_string();
+    match (method, path.as_str()) {
+        (Method::GET, "/stats") => match serde_json::to_value(state.stats_snapshot().await) {
+            Ok(stats) => json_response(StatusCode::OK, stats),
+            Err(e) => error_response(StatusCode::INTERNAL_SERVER_ERROR, &e.to_string()),
+        },
+        (Method::POST, "/reload") => reload(&state),
+        (Method::GET, "/log/level") => json_response(
+            StatusCode::OK,
+            json!({ "level": logging::level().as_str().to_lowercase() }),
+        ),
+        (Method::PUT, "/log/level") => match read_json(request).await {
+            Ok(level) => set_log_level(level),
+            Err(response) => response,
+        },
+        (Method::GET, "/filter/rules") => list_filter_rules(&state),
+        (Method::POST, "/filter/rules") => match read_json(request).await {
+            Ok(rule) => add_filter_rule(&state, rule),
+            Err(response) => response,
+        },
+        (Method::DELETE, "/filter/rules") => match read_json(request).await {
+            Ok(rule) => remove_filter_rule(&state, rule),
+            Err(response) => response,
+        },
+        (Method::GET, "/acl") => list_acl_rules(&state),
+        (Method::GET, "/acl/check") => check_acl(&state, request.uri().query()),
+        (Method::POST, "/acl/allow") => match read_json(request).await {
+            Ok(rule) => add_acl_rule(&state, AclList::Allow, rule),
+            Err(response) => response,
+        },
+        (Method::DELETE, "/acl/allow") => match read_json(request).await {
+            Ok(rule) => remove_acl_rule(&state, AclList::Allow, rule),
+            Err(response) => response,
+        },
+        (Method::POST, "/acl/deny") => match read_json(request).await {
+            Ok(rule) => add_acl_rule(&state, AclList::Deny, rule),
+            Err(response) => response,
+        },
+        (Method::DELETE, "/acl/deny") => match read_json(request).await {
+            Ok(rule) => remove_acl_rule(&state, AclList::Deny, rule),
+            Err(response) => response,
+        },
+        (Method::GET, "/connections") => {
+            json_response(StatusCode::OK, json!(state.connections.list()))
+        }
+        (Method::GET, "/reverse/backends") => {
+            json_response(StatusCode::OK, json!(state.reverse_proxy.backends()))
+        }
+        (Method::GET, "/dns/cache") => {
+            json_response(StatusCode::OK, json!(state.dns_cache.stats()))
+        }
+        (Method::DELETE, "/dns/cache") => flush_dns_cache(&state, request.uri().query()),
+        (Method::POST, path) if connection_close_id(path).is_some() => {
+            close_connection(&state, connection_close_id(path).unwrap())
+        }
+        (_, path) if ENDPOINTS.contains(&path) || connection_close_id(path).is_some() => {
+            error_response(StatusCode::METHOD_NOT_ALLOWED, "Method not allowed")
+        }
+        _ => error_response(StatusCode::NOT_FOUND, "Not found"),
+    }
+}
+
+/// Whether a request changing something was not made by a page on another
+/// site, with the admin credentials the browser remembers (CSRF). Browsers
+/// name the page's origin in POST, PUT and DELETE requests; it must be the
+/// API's own or the statistics page's, whose close buttons post here.
+/// Other clients send no Origin.
+fn same_site(state: &ServerState, request: &Request<Body>) -> bool {
+    let header = |name: &str| {
+        request
+            .headers()
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+    };
+    let origin = match header("origin") {
+        Some(origin) => origin,
+        // Browsers that leave Origin out still tell where a request is from
+        None => {
+            return header("sec-fetch-site")
+                .is_none_or(|site| site == "same-origin" || site == "none")
+        }
+    };
+    let authority = match origin
+        .strip_prefix("http://")
+        .or_else(|| origin.strip_prefix("https://"))
+    {
+        Some(authority) => authority,
+        None => return false,
+    };
+    let host = authority
+        .rsplit_once(':')
+        .filter(|(_, port)| port.bytes().all(|b| b.is_ascii_digit()))
+        .map_or(authority, |(host, _)| host);
+    header("host").is_some_and(|own| own.eq_ignore_ascii_case(authority))
+        || state
+            .config
+            .stat_host
+            .as_deref()
+            .is_some_and(|stat_host| stat_host.eq_ignore_ascii_case(host))
+}
+
+/// Settings a reload applies, by field name; changing others needs a
+/// restart. Allow and Deny are kept by RemoteAccessList too, so they need
+/// one when it is in use.
+const RELOADABLE: &[&str] = &[
+    "log_level",
+    "allow",
+    "deny",
+    "filter_extended",
+    "filter_casesensitive",
+    "filter_default_deny",
+    "filter_type",
+];
+
+/// Read the configuration file again and apply the settings that can
+/// change at runtime, then read the filter files, ErrorFile templates and
+/// BasicAuthFile again, as SIGHUP does for the latter two. Whatever fails
+/// to load is kept as it was and reported; so are changed settings that
+/// need a restart, with 409 Conflict.
+fn reload(state: &ServerState) -> Response<Body> {
+    let (config, applied, restart_required) = match reload_config(state) {
+        Ok(reloaded) => reloaded,
+        Err(e) => {
+            warn!("Reloading the configuration via admin API failed: {:#}", e);
+            return error_response(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                &format!("Cannot reload the configuration: {:#}", e),
+            );
+        }
+    };
+
+    let mut errors = Vec::new();
+    let mut report = |name: &str, result: Result<usize, String>| match result {
+        Ok(count) => json!(count),
+        Err(e) => {
+            warn!("Reloading {} via admin API failed: {}", name, e);
+            errors.push(format!("{}: {}", name, e));
+            Value::Null
+        }
+    };
+
+    let filter_rules = report(
+        "filter",
+        state
+            .filter
+            .write()
+            .unwrap()
+            .reload(&config)
+            .map_err(|e| e.to_string()),
+    );
+    let error_pages = report("error pages", state.error_pages.reload());
+    let users = match state.authenticator.has_user_file() {
+        true => report("users", state.authenticator.reload()),
+        false => Value::Null,
+    };
+    #[cfg(feature = "rustls")]
+    let tls_intercept_ca = match state.tls_interception.is_configured() {
+        true => report(
+            "TLS interception CA",
+            state.tls_interception.reload().map(|()| 1),
+        ),
+        false => Value::Null,
+    };
+    #[cfg(not(feature = "rustls"))]
+    let tls_intercept_ca = Value::Null;
+    info!("Reloaded via admin API");
+
+    let status = if !errors.is_empty() {
+        StatusCode::INTERNAL_SERVER_ERROR
+    } else if !restart_required.is_empty() {
+        warn!(
+            "Restart needed to change {} in the configuration",
+            restart_required.join(", ")
+        );
+        errors.push(format!(
+            "restart needed to change {}",
+            restart_required.join(", ")
+        ));
+        StatusCode::CONFLICT
+    } else {
+        StatusCode::OK
+    };
+    json_response(
+        status,
+        json!({
+            "applied": applied,
+            "restart_required": restart_required,
+            "filter_rules": filter_rules,
+            "error_pages": error_pages,
+            "users": users,
+            "tls_intercept_ca": tls_intercept_ca,
+            "errors": errors,
+        }),
+    )
+}
+
+/// Read the configuration file again, if the configuration came from one,
+/// and apply the settings that changed and can change at runtime. Returns
+/// the configuration in effect, the settings applied and the changed ones
+/// that need a restart. Nothing is applied if the file is invalid.
+fn reload_config(state: &ServerState) -> Result<(Config, Vec<String>, Vec<String>)> {
+    let current = &state.config;
+    let mut config = (**current).clone();
+    let Some(path) = &current.config_file else {
+        return Ok((config, Vec::new(), Vec::new()));
+    };
+    let mut file = Config::from_file(path)?;
+    let level = logging::parse_level(&file.log_level)
+        .with_context(|| format!("Unknown LogLevel {}", file.log_level))?;
+    // The command line may have set these
+    file.debug = current.debug;
+    file.worker_threads = current.worker_threads;
+    file.max_blocking_threads = current.max_blocking_threads;
+
+    let reloadable = |name: &str| {
+        RELOADABLE.contains(&name)
+            && !(state.remote_access_list.is_enabled() && (name == "allow" || name == "deny"))
+    };
+    let (applied, restart_required): (Vec<String>, Vec<String>) = current
+        .changed_settings(&file)
+        .into_iter()
+        .partition(|name| reloadable(name));
+
+    if applied.iter().any(|name| name == "log_level") {
+        logging::set_level(level);
+        config.log_level = file.log_level;
+    }
+    if applied.iter().any(|name| name == "allow" || name == "deny") {
+        config.allow = file.allow;
+        config.deny = file.deny;
+        *state.acl.write().unwrap() = AccessControl::new(&config);
+    }
+    config.filter_extended = file.filter_extended;
+    config.filter_casesensitive = file.filter_casesensitive;
+    config.filter_default_deny = file.filter_default_deny;
+    config.filter_type = file.filter_type;
+    if !applied.is_empty() {
+        info!("Applied {} from {}", applied.join(", "), path);
+    }
+    Ok((config, applied, restart_required))
+}
+
+fn set_log_level(request: LogLevelRequest) -> Response<Body> {
+    let Some(level) = logging::parse_level(&request.level) else {
+        return error_response(StatusCode::BAD_REQUEST, "Unknown log level");
+    };
+    logging::set_level(level);
+    info!("Log level set to {} via admin API", level);
+    json_response(
+        StatusCode::OK,
+        json!({ "level": level.as_str().to_lowercase() }),
+    )
+}
+
+fn list_filter_rules(state: &ServerState) -> Response<Body> {
+    let filter = state.filter.read().unwrap();
+    let rules: Vec<Value> = filter
+        .rules()
+        .into_iter()
+        .map(|(pattern, kind)| json!({ "pattern": pattern, "type": kind }))
+        .collect();
+
+    json_response(
+        StatusCode::OK,
+        json!({ "enabled": filter.is_enabled(), "rules": rules }),
+    )
+}
+
+fn add_filter_rule(state: &ServerState, rule: FilterRuleRequest) -> Response<Body> {
+    let filter_file = state.config.filter_file.as_deref();
+    if rule.persist && filter_file.is_none() {
+        return no_filter_file();
+    }
+
+    let mut filter = state.filter.write().unwrap();
+    // Rules go to the main filter only, which is off without FilterURLs
+    if !filter.is_enabled() {
+        return error_response(
+            StatusCode::CONFLICT,
+            "URL filtering is disabled (FilterURLs No)",
+        );
+    }
+    let added = match filter.add_rule(&rule.pattern) {
+        Ok(added) => added,
+        Err(e) => return error_response(StatusCode::BAD_REQUEST, &e.to_string()),
+    };
+
+    if added {
+        if let (true, Some(path)) = (rule.persist, filter_file) {
+            if let Err(e) = filter.persist_added(path, &rule.pattern) {
+                filter.remove_rule(&rule.pattern);
+                return error_response(StatusCode::INTERNAL_SERVER_ERROR, &e.to_string());
+            }
+        }
+        info!("Filter rule added via admin API: {}", rule.pattern);
+    }
+
+    let status = if added {
+        StatusCode::CREATED
+    } else {
+        StatusCode::OK
+    };
+    json_response(status, json!({ "pattern": rule.pattern, "added": added }))
+}
+
+fn remove_filter_rule(state: &ServerState, rule: FilterRuleRequest) -> Response<Body> {
+    let filter_file = state.config.filter_file.as_deref();
+    if rule.persist && filter_file.is_none() {
+        return no_filter_file();
+    }
+
+    let mut filter = state.filter.write().unwrap();
+    if !filter.remove_rule(&rule.pattern) {
+        return error_response(StatusCode::NOT_FOUND, "No such filter rule");
+    }
+
+    if let (true, Some(path)) = (rule.persist, filter_file) {
+        if let Err(e) = filter.persist_removed(path, &rule.pattern) {
+            let _ = filter.add_rule(&rule.pattern);
+            return error_response(StatusCode::INTERNAL_SERVER_ERROR, &e.to_string());
+        }
+    }
+    info!("Filter rule removed via admin API: {}", rule.pattern);
+
+    json_response(
+        StatusCode::OK,
+        json!({ "pattern": rule.pattern, "removed": true }),
+    )
+}
+
+fn list_acl_rules(state: &ServerState) -> Response<Body> {
+    let acl = state.acl.read().unwrap();
+    json_response(
+        StatusCode::OK,
+        json!({
+            "allow": acl.rules(AclList::Allow),
+            "deny": acl.rules(AclList::Deny),
+        }),
+    )
+}
+
+fn check_acl(state: &ServerState, query: Option<&str>) -> Response<Body> {
+    let ip = url::form_urlencoded::parse(query.unwrap_or("").as_bytes())
+        .find(|(name, _)| name == "ip")
+        .map(|(_, value)| value.into_owned());
+    let ip: IpAddr = match ip.as_deref().map(str::parse) {
+        Some(Ok(ip)) => ip,
+        _ => return error_response(StatusCode::BAD_REQUEST, "Expected ?ip=<address>"),
+    };
+
+    let decision = state.acl.read().unwrap().check(&ip);
+    json_response(
+        StatusCode::OK,
+        json!({ "ip": ip, "allowed": decision.allowed, "rule": decision.rule }),
+    )
+}
+
+fn add_acl_rule(state: &ServerState, list: AclList, rule: AclRuleRequest) -> Response<Body> {
+    match state.acl.write().unwrap().add_rule(list, &rule.rule) {
+        Ok(added) => {
+            if added {
+                info!("ACL {:?} rule added via admin API: {}", list, rule.rule);
+            }
+            let status = if added {
+                StatusCode::CREATED
+            } else {
+                StatusCode::OK
+            };
+            json_response(status, json!({ "rule": rule.rule, "added": added }))
+        }
+        Err(e) => error_response(StatusCode::BAD_REQUEST, &e),
+    }
+}
+
+fn remove_acl_rule(state: &ServerState, list: AclList, rule: AclRuleRequest) -> Response<Body> {
+    if !state.acl.write().unwrap().remove_rule(list, &rule.rule) {
+        return error_response(StatusCode::NOT_FOUND, "No such ACL rule");
+    }
+    info!("ACL {:?} rule removed via admin API: {}", list, rule.rule);
+    json_response(
+        StatusCode::OK,
+        json!({ "rule": rule.rule, "removed": true }),
+    )
+}
+
+/// Connection ID of a `/connections/{id}/close` path.
+fn connection_close_id(path: &str) -> Option<u64> {
+    path.strip_prefix("/connections/")?
+        .strip_suffix("/close")?
+        .parse()
+        .ok()
+}
+
+fn close_connection(state: &ServerState, id: u64) -> Response<Body> {
+    if !state.connections.close(id) {
+        return error_response(StatusCode::NOT_FOUND, "No such connection");
+    }
+    info!("Connection {} closed via admin API", id);
+    json_response(StatusCode::OK, json!({ "id": id, "closed": true }))
+}
+
+/// Flush the DNS cache, or only the name given as `?host=`.
+fn flush_dns_cache(state: &ServerState, query: Option<&str>) -> Response<Body> {
+    let host = url::form_urlencoded::parse(query.unwrap_or("").as_bytes())
+        .find(|(name, _)| name == "host")
+        .map(|(_, value)| value.into_owned());
+
+    match host {
+        Some(host) => {
+            if !state.dns_cache.evict(&host) {
+                return error_response(StatusCode::NOT_FOUND, "Host not cached");
+            }
+            info!("DNS cache entry for {} evicted via admin API", host);
+            json_response(StatusCode::OK, json!({ "host": host, "evicted": true }))
+        }
+        None => {
+            let flushed = state.dns_cache.flush();
+            info!("DNS cache flushed via admin API ({} entries)", flushed);
+            json_response(StatusCode::OK, json!({ "flushed": flushed }))
+        }
+    }
+}
+
+fn no_filter_file() -> Response<Body> {
+    error_response(
+        StatusCode::CONFLICT,
+        "No filter file configured to persist to",
+    )
+}
+
+async fn read_json<T: for<'de> Deserialize<'de>>(
+    request: Request<Body>,
+) -> Result<T, Response<Body>> {
+    let declared_length = request
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<usize>().ok())
+        .unwrap_or(0);
+    if declared_length > MAX_BODY_SIZE {
+        return Err(error_response(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            "Request body too large",
+        ));
+    }
+
+    let body = hyper::body::to_bytes(request.into_body())
+        .await
+        .map_err(|e| error_response(StatusCode::BAD_REQUEST, &e.to_string()))?;
+    if body.len() > MAX_BODY_SIZE {
+        return Err(error_response(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            "Request body too large",
+        ));
+    }
+
+    serde_json::from_slice(&body)
+        .map_err(|e| error_response(StatusCode::BAD_REQUEST, &format!("Invalid JSON: {}", e)))
+}
+
+fn json_response(status: StatusCode, value: Value) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Body::from(value.to_string()))
+        .unwrap()
+}
+
+fn error_response(status: StatusCode, message: &str) -> Response<Body> {
+    json_response(status, json!({ "error": message }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::connection::ConnectionHandler;
+    use crate::error::ProxyError;
+    use base64::{engine::general_purpose::STANDARD, Engine as _};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    fn admin_state(config: &str) -> Arc<ServerState> {
+        let config = Config::parse_config(config).unwrap();
+        Arc::new(ServerState::new(Arc::new(config)))
+    }
+
+    fn admin_request(method: Method, path: &str, body: &str) -> Request<Body> {
+        Request::builder()
+            .method(method)
+            .uri(path)
+            .header(
+                header::AUTHORIZATION,
+                format!("Basic {}", STANDARD.encode("admin:secret")),
+            )
+            .body(Body::from(body.to_string()))
+            .unwrap()
+    }
+
+    async fn body_json(response: Response<Body>) -> Value {
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_admin_requires_auth() {
+        let state = admin_state("AdminPort 0\nAdminAuth admin:secret");
+
+        let request = Request::get("/filter/rules").body(Body::empty()).unwrap();
+        let response = handle(state.clone(), request).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        let response = handle(state, admin_request(Method::GET, "/nope", "")).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_admin_acl_rules() {
+        let state = admin_state("Allow 127.0.0.1\nAdminPort 0\nAdminAuth admin:secret");
+
+        let deny = r#"{"rule": "127.0.0.0/8"}"#;
+        let response = handle(
+            state.clone(),
+            admin_request(Method::POST, "/acl/deny", deny),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::CREATED);
+
+        let response = handle(
+            state.clone(),
+            admin_request(Method::GET, "/acl/check?ip=127.0.0.1", ""),
+        )
+        .await;
+        assert_eq!(
+            body_json(response).await,
+            json!({ "ip": "127.0.0.1", "allowed": false, "rule": "127.0.0.0/8" })
+        );
+
+        let response = handle(
+            state.clone(),
+            admin_request(Method::DELETE, "/acl/deny", deny),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = handle(state.clone(), admin_request(Method::GET, "/acl", "")).await;
+        assert_eq!(
+            body_json(response).await,
+            json!({ "allow": ["127.0.0.1"], "deny": [] })
+        );
+
+        let bogus = r#"{"rule": "bogus"}"#;
+        let response = handle(state, admin_request(Method::POST, "/acl/allow", bogus)).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_admin_reload() {
+        let dir = tempfile::tempdir().unwrap();
+        let filter_file = dir.path().join("filter");
+        std::fs::write(&filter_file, "ads.example\n").unwrap();
+        let state = admin_state(&format!(
+            "FilterURLs Yes\nFilter {}\nAdminPort 0\nAdminAuth admin:secret",
+            filter_file.display()
+        ));
+
+        std::fs::write(&filter_file, "ads.example\ntracker.example\n").unwrap();
+        let response = handle(state.clone(), admin_request(Method::POST, "/reload", "")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let reloaded = body_json(response).await;
+        assert_eq!(reloaded["filter_rules"], 2);
+        assert_eq!(reloaded["error_pages"], 0);
+        assert!(reloaded["users"].is_null());
+        assert!(reloaded["tls_intercept_ca"].is_null());
+
+        // A file that cannot be read leaves the rules as they were
+        std::fs::remove_file(&filter_file).unwrap();
+        let response = handle(state.clone(), admin_request(Method::POST, "/reload", "")).await;
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(state.filter.read().unwrap().rule_count(), 2);
+
+        let response = handle(state.clone(), admin_request(Method::GET, "/stats", "")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(body_json(response).await.is_object());
+    }
+
+    #[tokio::test]
+    async fn test_admin_reload_config() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("proxy.conf");
+        let base = "AdminPort 0\nAdminAuth admin:secret\n";
+        std::fs::write(&path, format!("{}Allow 192.0.2.1", base)).unwrap();
+        let config = Config::from_file(&path).unwrap();
+        let state = Arc::new(ServerState::new(Arc::new(config)));
+        let allowed = |ip: &str| {
+            state
+                .acl
+                .read()
+                .unwrap()
+                .check(&ip.parse().unwrap())
+                .allowed
+        };
+        assert!(allowed("192.0.2.1"));
+
+        // Settings that can change at runtime take effect
+        std::fs::write(
+            &path,
+            format!("{}Allow 192.0.2.2\nFilterCaseSensitive Yes", base),
+        )
+        .unwrap();
+        let response = handle(state.clone(), admin_request(Method::POST, "/reload", "")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let reloaded = body_json(response).await;
+        assert_eq!(
+            reloaded["applied"],
+            json!(["allow", "filter_casesensitive"])
+        );
+        assert_eq!(reloaded["restart_required"], json!([]));
+        assert!(!allowed("192.0.2.1"));
+        assert!(allowed("192.0.2.2"));
+
+        // Others are reported, and the rest still applied
+        std::fs::write(&path, format!("{}Port 9999\nAllow 192.0.2.3", base)).unwrap();
+        let response = handle(state.clone(), admin_request(Method::POST, "/reload", "")).await;
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+        let reloaded = body_json(response).await;
+        assert_eq!(reloaded["restart_required"], json!(["port"]));
+        assert_eq!(reloaded["errors"], json!(["restart needed to change port"]));
+        assert!(allowed("192.0.2.3"));
+
+        // An invalid file changes nothing
+        std::fs::write(&path, format!("{}Port none\nAllow 192.0.2.4", base)).unwrap();
+        let response = handle(state.clone(), admin_request(Method::POST, "/reload", "")).await;
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert!(!allowed("192.0.2.4"));
+        assert!(allowed("192.0.2.3"));
+    }
+
+    #[tokio::test]
+    async fn test_admin_log_level() {
+        let state = admin_state("AdminPort 0\nAdminAuth admin:secret");
+        let previous = logging::level();
+
+        let body = r#"{"level": "Debug"}"#;
+        let response = handle(
+            state.clone(),
+            admin_request(Method::PUT, "/log/level", body),
+        )
+        .await;
+        assert_eq!(body_json(response).await, json!({ "level": "debug" }));
+        let response = handle(state.clone(), admin_request(Method::GET, "/log/level", "")).await;
+        assert_eq!(body_json(response).await, json!({ "level": "debug" }));
+
+        let body = r#"{"level": "loud"}"#;
+        let response = handle(
+            state.clone(),
+            admin_request(Method::PUT, "/log/level", body),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let response = handle(state, admin_request(Method::POST, "/log/level", "")).await;
+        assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+        logging::set_level(previous);
+    }
+
+    #[tokio::test]
+    async fn test_admin_dns_cache() {
+        let state = admin_state("DnsCacheTtl 60\nAdminPort 0\nAdminAuth admin:secret");
+        state.dns_cache.resolve("localhost", 80).await.unwrap();
+
+        let response = handle(state.clone(), admin_request(Method::GET, "/dns/cache", "")).await;
+        let stats = body_json(response).await;
+        assert_eq!(stats["entries"], 1);
+        assert_eq!(stats["top_lookups"], json!([["localhost", 1]]));
+
+        let path = "/dns/cache?host=localhost";
+        let response = handle(state.clone(), admin_request(Method::DELETE, path, "")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = handle(state.clone(), admin_request(Method::DELETE, path, "")).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        let response = handle(state, admin_request(Method::DELETE, "/dns/cache", "")).await;
+        assert_eq!(body_json(response).await, json!({ "flushed": 0 }));
+    }
+
+    #[tokio::test]
+    async fn test_admin_close_connection() {
+        let state = admin_state("AdminPort 0\nAdminAuth admin:secret");
+        let registration = state
+            .connections
+            .register("192.0.2.1:40000".parse().unwrap());
+
+        let response = handle(
+            state.clone(),
+            admin_request(Method::GET, "/connections", ""),
+        )
+        .await;
+        let connections = body_json(response).await;
+        assert_eq!(connections[0]["client_addr"], "192.0.2.1:40000");
+
+        let path = format!("/connections/{}/close", registration.id());
+        let response = handle(state.clone(), admin_request(Method::POST, &path, "")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        registration.closed().await;
+
+        drop(registration);
+        let response = handle(state, admin_request(Method::POST, &path, "")).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_admin_cross_site_requests() {
+        let state = admin_state("StatHost stats.test\nAdminPort 0\nAdminAuth admin:secret");
+        let request = |origin: Option<&str>, site: Option<&str>| {
+            let mut request = admin_request(Method::POST, "/reload", "");
+            let headers = request.headers_mut();
+            headers.insert(header::HOST, "127.0.0.1:8081".parse().unwrap());
+            if let Some(origin) = origin {
+                headers.insert(header::ORIGIN, origin.parse().unwrap());
+            }
+            if let Some(site) = site {
+                headers.insert("sec-fetch-site", site.parse().unwrap());
+            }
+            request
+        };
+
+        // Pages elsewhere cannot use the browser's remembered credentials
+        for (origin, site) in [
+            (Some("http://evil.example"), Some("cross-site")),
+            (Some("null"), None),
+            (Some("http://stats.test.evil.example"), None),
+            (None, Some("cross-site")),
+        ] {
+            let response = handle(state.clone(), request(origin, site)).await;
+            assert_eq!(response.status(), StatusCode::FORBIDDEN, "{:?}", origin);
+        }
+
+        // The API's own pages, the statistics page and other clients can
+        for (origin, site) in [
+            (Some("http://127.0.0.1:8081"), Some("same-origin")),
+            (Some("http://stats.test"), Some("cross-site")),
+            (None, None),
+        ] {
+            let response = handle(state.clone(), request(origin, site)).await;
+            assert_eq!(response.status(), StatusCode::OK, "{:?}", origin);
+        }
+
+        // Reading needs no check
+        let mut read = admin_request(Method::GET, "/log/level", "");
+        read.headers_mut()
+            .insert(header::ORIGIN, "http://evil.example".parse().unwrap());
+        assert_eq!(handle(state, read).await.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_admin_filter_rules() {
+        let state = admin_state("FilterURLs Yes\nAdminPort 0\nAdminAuth admin:secret");
+
+        let add = r#"{"pattern": ".ads.example"}"#;
+        let response = handle(
+            state.clone(),
+            admin_request(Method::POST, "/filter/rules", add),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::CREATED);
+        assert!(!state
+            .filter
+            .read()
+            .unwrap()
+            .is_allowed("http://x.ads.example/", [192, 0, 2, 1].into(), None)
+            .unwrap());
+
+        let response = handle(
+            state.clone(),
+            admin_request(Method::GET, "/filter/rules", ""),
+        )
+        .await;
+        let rules = body_json(response).await;
+        assert_eq!(
+            rules["rules"],
+            json!([{ "pattern": ".ads.example", "type": "domain" }])
+        );
+
+        let response = handle(
+            state.clone(),
+            admin_request(Method::DELETE, "/filter/rules", add),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = handle(state, admin_request(Method::DELETE, "/filter/rules", add)).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_admin_filter_rule_blocks_requests() {
+        let state = admin_state("FilterURLs Yes\nAdminPort 0\nAdminAuth admin:secret");
+        let add = r#"{"pattern": ".ads.example"}"#;
+        let response = handle(
+            state.clone(),
+            admin_request(Method::POST, "/filter/rules", add),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::CREATED);
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = tokio::net::TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (stream, addr) = listener.accept().await.unwrap();
+        let handler = tokio::spawn(ConnectionHandler::new(stream, addr, state).handle());
+        client
+            .write_all(b"GET http://x.ads.example/ HTTP/1.1\r\nHost: x.ads.example\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).await.unwrap();
+        assert!(response.starts_with(b"HTTP/1.1 403"));
+        assert!(matches!(
+            handler.await.unwrap(),
+            Err(ProxyError::FilterBlocked(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_admin_filter_rules_without_filtering() {
+        let state = admin_state("AdminPort 0\nAdminAuth admin:secret");
+        let add = r#"{"pattern": ".ads.example"}"#;
+        let response = handle(
+            state.clone(),
+            admin_request(Method::POST, "/filter/rules", add),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+        assert!(state.filter.read().unwrap().rules().is_empty());
+    }
+}
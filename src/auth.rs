@@ -2,25 +2,95 @@ use crate::config::{BasicAuthConfig, Config};
 use crate::error::{ProxyError, ProxyResult};
 use crate::utils::HttpRequest;
 use base64::{engine::general_purpose::STANDARD, Engine as _};
-use log::debug;
+use log::{debug, info, warn};
+use sha1::{Digest, Sha1};
+use std::collections::{HashMap, HashSet};
+use std::sync::{Mutex, RwLock};
 
+/// Credentials remembered as verified against a bcrypt hash; the memory
+/// starts over when it is full.
+const MAX_VERIFIED: usize = 1024;
+
+/// Proxy Basic authentication against the BasicAuth user and the users of
+/// BasicAuthFile, an htpasswd file that is read again on
+/// [`Authenticator::reload`] (SIGHUP).
 pub struct Authenticator {
     auth_config: Option<BasicAuthConfig>,
+    user_file: Option<String>,
+    users: RwLock<HashMap<String, StoredPassword>>,
+    /// SHA-1 digests of credentials whose bcrypt hash matched, so later
+    /// requests skip the deliberately slow check.
+    verified: Mutex<HashSet<[u8; 20]>>,
+}
+
+/// A password as stored in BasicAuthFile.
+#[derive(Debug, PartialEq)]
+pub(crate) enum StoredPassword {
+    /// `htpasswd -B`
+    Bcrypt(String),
+    /// `htpasswd -s`: unsalted SHA-1.
+    Sha1(Vec<u8>),
+    Plain(String),
 }
 
 impl Authenticator {
     pub fn new(config: &Config) -> Self {
-        Self {
+        let authenticator = Self {
             auth_config: config.basic_auth.clone(),
+            user_file: config.basic_auth_file.clone(),
+            users: RwLock::default(),
+            verified: Mutex::default(),
+        };
+        if let Err(e) = authenticator.reload() {
+            warn!("No users from BasicAuthFile: {}", e);
+        }
+        authenticator
+    }
+
+    /// Read BasicAuthFile again, returning the number of users. A file
+    /// that cannot be read or parsed leaves the current users in place.
+    pub fn reload(&self) -> Result<usize, String> {
+        let path = match &self.user_file {
+            Some(path) => path,
+            None => return Ok(0),
+        };
+        let users = read_user_file(path)?;
+        let count = users.len();
+        *self.users.write().unwrap() = users;
+        self.verified.lock().unwrap().clear();
+        info!("Loaded {} users from {}", count, path);
+        Ok(count)
+    }
+
+    /// Whether users come from a file that can be reloaded.
+    pub fn has_user_file(&self) -> bool {
+        self.user_file.is_some()
+    }
+
+    /// Reload BasicAuthFile whenever the process gets SIGHUP.
+    #[cfg(unix)]
+    pub async fn reload_on_hangup(&self) {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut hangups = match signal(SignalKind::hangup()) {
+            Ok(hangups) => hangups,
+            Err(e) => {
+                warn!("Unable to listen for SIGHUP: {}", e);
+                return;
+            }
+        };
+        while hangups.recv().await.is_some() {
+            if let Err(e) = self.reload() {
+                warn!("Keeping the current BasicAuthFile users: {}", e);
+            }
         }
     }
 
     pub fn authenticate(&self, request: &HttpRequest) -> ProxyResult<bool> {
         // If no authentication is configured, allow all requests
-        let auth_config = match &self.auth_config {
-            Some(config) => config,
-            None => return Ok(true),
-        };
+        if !self.is_enabled() {
+            return Ok(true);
+        }
 
         // Check for Proxy-Authorization header
         let auth_header = match request.headers.get("proxy-authorization") {
@@ -58,7 +128,7 @@ impl Authenticator {
         let password = parts[1];
 
         // Verify credentials
-        if username == auth_config.username && password == auth_config.password {
+        if self.verify(username, password) {
             debug!("Authentication successful for user: {}", username);
             Ok(true)
         } else {
@@ -68,7 +138,39 @@ impl Authenticator {
     }
 
     pub fn is_enabled(&self) -> bool {
-        self.auth_config.is_some()
+        self.auth_config.is_some() || self.user_file.is_some()
+    }
+
+    fn verify(&self, username: &str, password: &str) -> bool {
+        if let Some(config) = &self.auth_config {
+            if username == config.username && password == config.password {
+                return true;
+            }
+        }
+
+        let users = self.users.read().unwrap();
+        match users.get(username) {
+            Some(StoredPassword::Plain(stored)) => password == stored,
+            Some(StoredPassword::Sha1(stored)) => {
+                Sha1::digest(password.as_bytes()).as_slice() == stored.as_slice()
+            }
+            Some(StoredPassword::Bcrypt(hash)) => {
+                let key: [u8; 20] = Sha1::digest(format!("{}:{}", username, password)).into();
+                if self.verified.lock().unwrap().contains(&key) {
+                    return true;
+                }
+                let matches = bcrypt::verify(password, hash).unwrap_or(false);
+                if matches {
+                    let mut verified = self.verified.lock().unwrap();
+                    if verified.len() >= MAX_VERIFIED {
+                        verified.clear();
+                    }
+                    verified.insert(key);
+                }
+                matches
+            }
+            None => false,
+        }
     }
 
     pub fn get_realm(&self) -> String {
@@ -79,15 +181,86 @@ impl Authenticator {
     }
 }
 
+/// Read an htpasswd file of `user:password` lines, where passwords may be
+/// bcrypt (`$2y$...`) or SHA-1 (`{SHA}...`) hashes or plain text. Blank
+/// lines and `#` comments are skipped.
+pub(crate) fn read_user_file(path: &str) -> Result<HashMap<String, StoredPassword>, String> {
+    let content =
+        std::fs::read_to_string(path).map_err(|e| format!("cannot read {}: {}", path, e))?;
+
+    let mut users = HashMap::new();
+    for (number, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let error = |reason: &str| format!("{} line {}: {}", path, number + 1, reason);
+        let (username, password) = line
+            .split_once(':')
+            .ok_or_else(|| error("expected user:password"))?;
+        let password = if ["$2a$", "$2b$", "$2x$", "$2y$"]
+            .iter()
+            .any(|prefix| password.starts_with(prefix))
+        {
+            StoredPassword::Bcrypt(password.to_string())
+        } else if let Some(digest) = password.strip_prefix("{SHA}") {
+            match STANDARD.decode(digest) {
+                Ok(digest) if digest.len() == 20 => StoredPassword::Sha1(digest),
+                _ => return Err(error("invalid {SHA} hash")),
+            }
+        } else if ["$apr1$", "$1$", "$5$", "$6$"]
+            .iter()
+            .any(|prefix| password.starts_with(prefix))
+        {
+            return Err(error(
+                "unsupported hash, use htpasswd -B (bcrypt) or -s (SHA-1)",
+            ));
+        } else {
+            StoredPassword::Plain(password.to_string())
+        };
+        users.insert(username.to_string(), password);
+    }
+    Ok(users)
+}
+
+/// Check an `Authorization: Basic ...` header value against the expected
+/// credentials.
+pub fn verify_basic_auth(header: Option<&str>, expected: &BasicAuthConfig) -> bool {
+    let credentials = header
+        .and_then(|header| header.strip_prefix("Basic "))
+        .and_then(|encoded| STANDARD.decode(encoded.trim()).ok())
+        .and_then(|decoded| String::from_utf8(decoded).ok());
+
+    match credentials.as_deref().and_then(|c| c.split_once(':')) {
+        Some((username, password)) => {
+            username == expected.username && password == expected.password
+        }
+        None => false,
+    }
+}
+
+/// User name sent in a `Basic` credentials header value, if it can be
+/// decoded.
+pub fn basic_auth_username(header: &str) -> Option<String> {
+    let decoded = STANDARD
+        .decode(header.strip_prefix("Basic ")?.trim())
+        .ok()?;
+    let credentials = String::from_utf8(decoded).ok()?;
+    credentials
+        .split_once(':')
+        .map(|(username, _)| username.to_string())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::collections::HashMap;
+    use crate::headers::Headers;
+    use std::io::Write;
 
     fn create_test_request_with_auth(auth_header: Option<&str>) -> HttpRequest {
-        let mut headers = HashMap::new();
+        let mut headers = Headers::new();
         if let Some(header) = auth_header {
-            headers.insert("proxy-authorization".to_string(), header.to_string());
+            headers.insert("Proxy-Authorization", header);
         }
 
         HttpRequest {
@@ -176,4 +349,57 @@ mod tests {
 
         assert!(auth.authenticate(&request).is_err());
     }
+
+    #[test]
+    fn test_user_file() {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        writeln!(
+            file,
+            "# htpasswd\n\
+             alice:s3cret\n\
+             bob:{{SHA}}W6ph5Mm5Pz8GgiULbPgzG37mj9g=\n\
+             carol:{}",
+            bcrypt::hash("hunter2", 4).unwrap()
+        )
+        .unwrap();
+        let config =
+            Config::parse_config(&format!("BasicAuthFile {}", file.path().display())).unwrap();
+        let auth = Authenticator::new(&config);
+        assert!(auth.is_enabled());
+
+        let login = |credentials: &str| {
+            let header = format!("Basic {}", STANDARD.encode(credentials));
+            auth.authenticate(&create_test_request_with_auth(Some(&header)))
+                .unwrap()
+        };
+        assert!(login("alice:s3cret"));
+        assert!(login("bob:password"));
+        assert!(login("carol:hunter2"));
+        assert!(login("carol:hunter2"));
+        assert!(!login("carol:hunter3"));
+        assert!(!login("alice:password"));
+        assert!(!login("mallory:s3cret"));
+
+        // Edits are picked up on reload, unless the file became invalid
+        std::fs::write(file.path(), "alice:changed\n").unwrap();
+        assert_eq!(auth.reload(), Ok(1));
+        assert!(!login("alice:s3cret"));
+        assert!(!login("carol:hunter2"));
+        std::fs::write(file.path(), "dave:$apr1$salt$hash\n").unwrap();
+        assert!(auth
+            .reload()
+            .unwrap_err()
+            .contains("line 1: unsupported hash"));
+        assert!(login("alice:changed"));
+    }
+
+    #[test]
+    fn test_basic_auth_username() {
+        assert_eq!(
+            basic_auth_username("Basic dXNlcjpwYXNz").as_deref(),
+            Some("user")
+        );
+        assert_eq!(basic_auth_username("Basic !!!"), None);
+        assert_eq!(basic_auth_username("Bearer token"), None);
+    }
 }
@@ -0,0 +1,141 @@
+use crate::config::Config;
+use log::{info, warn};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Circuit breakers for upstream connections, keyed by `host:port`.
+///
+/// After `threshold` consecutive connection failures the circuit opens and
+/// connections fail fast until the cooldown has passed. The next attempt
+/// then acts as a probe: success closes the circuit, failure opens it
+/// again for another cooldown.
+pub struct CircuitBreakers {
+    threshold: u32,
+    cooldown: Duration,
+    circuits: Mutex<HashMap<String, Circuit>>,
+}
+
+#[derive(Default)]
+struct Circuit {
+    failures: u32,
+    opened_at: Option<Instant>,
+}
+
+impl CircuitBreakers {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            threshold: config.circuit_breaker_threshold,
+            cooldown: Duration::from_secs(config.circuit_breaker_cooldown),
+            circuits: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.threshold > 0
+    }
+
+    /// Returns the remaining cooldown if the circuit for `target` is open.
+    pub fn check(&self, target: &str) -> Result<(), Duration> {
+        self.check_at(target, Instant::now())
+    }
+
+    pub fn record_success(&self, target: &str) {
+        if !self.is_enabled() {
+            return;
+        }
+
+        let mut circuits = self.circuits.lock().unwrap();
+        if let Some(circuit) = circuits.remove(target) {
+            if circuit.opened_at.is_some() {
+                info!("Circuit for {} closed", target);
+            }
+        }
+    }
+
+    pub fn record_failure(&self, target: &str) {
+        self.record_failure_at(target, Instant::now());
+    }
+
+    fn check_at(&self, target: &str, now: Instant) -> Result<(), Duration> {
+        if !self.is_enabled() {
+            return Ok(());
+        }
+
+        let circuits = self.circuits.lock().unwrap();
+        match circuits.get(target).and_then(|circuit| circuit.opened_at) {
+            Some(opened_at) => {
+                let elapsed = now.saturating_duration_since(opened_at);
+                if elapsed < self.cooldown {
+                    Err(self.cooldown - elapsed)
+                } else {
+                    Ok(())
+                }
+            }
+            None => Ok(()),
+        }
+    }
+
+    fn record_failure_at(&self, target: &str, now: Instant) {
+        if !self.is_enabled() {
+            return;
+        }
+
+        let mut circuits = self.circuits.lock().unwrap();
+        let circuit = circuits.entry(target.to_string()).or_default();
+        circuit.failures = circuit.failures.saturating_add(1);
+        if circuit.failures >= self.threshold {
+            if circuit.opened_at.is_none() {
+                warn!(
+                    "Circuit for {} opened after {} consecutive failures",
+                    target, circuit.failures
+                );
+            }
+            circuit.opened_at = Some(now);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_circuit_breaker() {
+        let config =
+            Config::parse_config("CircuitBreakerThreshold 3\nCircuitBreakerCooldown 10").unwrap();
+        let breakers = CircuitBreakers::new(&config);
+        let start = Instant::now();
+
+        breakers.record_failure_at("origin:80", start);
+        breakers.record_failure_at("origin:80", start);
+        assert!(breakers.check_at("origin:80", start).is_ok());
+
+        breakers.record_failure_at("origin:80", start);
+        let remaining = breakers.check_at("origin:80", start).unwrap_err();
+        assert_eq!(remaining, Duration::from_secs(10));
+        assert!(breakers.check_at("other:80", start).is_ok());
+
+        // After the cooldown a probe is let through; another failure
+        // re-opens the circuit right away
+        let later = start + Duration::from_secs(10);
+        assert!(breakers.check_at("origin:80", later).is_ok());
+        breakers.record_failure_at("origin:80", later);
+        assert!(breakers.check_at("origin:80", later).is_err());
+
+        // A success closes it
+        breakers.record_success("origin:80");
+        assert!(breakers.check_at("origin:80", later).is_ok());
+    }
+
+    #[test]
+    fn test_circuit_breaker_disabled() {
+        let breakers = CircuitBreakers::new(&Config::default());
+        let now = Instant::now();
+
+        for _ in 0..100 {
+            breakers.record_failure_at("origin:80", now);
+        }
+        assert!(breakers.check_at("origin:80", now).is_ok());
+    }
+}
@@ -1,6 +1,8 @@
 use anyhow::{Context, Result};
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
 use std::collections::HashMap;
+use std::fmt;
 use std::fs;
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 use std::path::Path;
@@ -11,17 +13,65 @@ pub struct Config {
     pub port: u16,
     pub bind_address: IpAddr,
     pub listen_addresses: Vec<IpAddr>,
+    pub require_all_listeners: bool,
     pub bind_same: bool,
+    pub outgoing_interface: Option<String>,
+    pub outgoing_mark: Option<u32>,
+    /// Disable Nagle's algorithm (TCP_NODELAY) on client and origin sockets.
+    pub tcp_nodelay: bool,
+    /// Seconds a connection is idle before keepalive probes start, 0 for
+    /// no keepalive.
+    pub tcp_keepalive_idle: u64,
+    /// Seconds between keepalive probes, 0 for the system default.
+    pub tcp_keepalive_interval: u64,
+    /// SO_RCVBUF and SO_SNDBUF sizes in bytes, 0 for the system default.
+    pub socket_receive_buffer: u64,
+    pub socket_send_buffer: u64,
+    /// Most seconds resolved addresses are cached, within their records'
+    /// TTLs (0 does not cache them).
+    pub dns_cache_ttl: u64,
+    /// Most seconds failed lookups are cached (0 retries every time).
+    pub dns_negative_ttl: u64,
+    /// Most host names the DNS cache holds.
+    pub dns_cache_size: usize,
 
     // Process configuration
     pub user: Option<String>,
     pub group: Option<String>,
     pub daemon: bool,
     pub pidfile: Option<String>,
+    /// Threads running connections, 0 for one per CPU core.
+    pub worker_threads: usize,
+    /// Most threads kept for blocking work such as file access.
+    pub max_blocking_threads: usize,
 
     // Connection configuration
     pub timeout: u64,
+    /// Seconds an idle client connection is kept open for another
+    /// request; 0 closes it after every response.
+    pub keep_alive_timeout: u64,
+    /// Seconds open connections may take to finish when shutting down.
+    pub shutdown_timeout: u64,
+    /// Seconds allowed for connecting to a target or upstream proxy.
+    pub connect_timeout: u64,
+    /// Seconds a client may take to send a whole request head, 0 for no
+    /// limit beyond `timeout` per read.
+    pub client_header_timeout: u64,
+    /// Bytes per second a client must keep up while sending a request
+    /// head, 0 for no minimum.
+    pub client_header_min_rate: u64,
+    /// Seconds a tunnel may pass no data either way before it is closed,
+    /// 0 to keep it open until one side closes.
+    pub idle_timeout: u64,
     pub max_clients: usize,
+    /// Seconds a connection over MaxClients waits for a free slot before
+    /// it is turned away.
+    pub accept_queue_timeout: u64,
+    /// Simultaneous connections allowed from one address, 0 for no cap.
+    pub max_clients_per_ip: usize,
+    pub max_request_body_size: u64,
+    pub max_request_duration: u64,
+    pub host_mismatch: HostMismatchPolicy,
     pub max_requests_per_child: usize,
     pub max_spare_servers: usize,
     pub min_spare_servers: usize,
@@ -32,69 +82,369 @@ pub struct Config {
     pub syslog: bool,
     pub log_level: String,
     pub debug: bool,
+    pub denial_log: Option<String>,
+    pub access_log: Option<String>,
+    /// `common`, `combined` or a format string for the access log.
+    pub log_format: String,
 
     // Access control
     pub allow: Vec<String>,
     pub deny: Vec<String>,
+    pub remote_access_list: Option<RemoteAccessListConfig>,
+    pub trusted_proxies: Vec<String>,
+    /// Networks the proxy refuses to connect to on a client's behalf.
+    pub deny_target_networks: Vec<String>,
+    pub proxy_protocol: bool,
+    pub ident_lookup: bool,
 
     // Authentication
     pub basic_auth: Option<BasicAuthConfig>,
+    /// htpasswd file with further users.
+    pub basic_auth_file: Option<String>,
 
     // Proxy configuration
     pub upstream: Vec<UpstreamConfig>,
+    pub no_upstream: Vec<String>,
+    pub sni_routes: Vec<SniRouteConfig>,
+    /// Decrypt CONNECT tunnels with certificates from the TlsInterceptCa.
+    pub tls_intercept: bool,
+    /// Certificate and key files of the interception CA.
+    pub tls_intercept_ca: Option<(String, String)>,
+    /// Server name patterns whose tunnels are never decrypted.
+    pub no_tls_intercept: Vec<String>,
+    /// Directory keeping the certificates made for servers across restarts.
+    pub tls_intercept_cache_dir: Option<String>,
     pub reverse_proxy: Vec<ReverseProxyConfig>,
-    pub transparent_proxy: bool,
+    pub reverse_sticky_cookie: Option<String>,
+    pub reverse_health_check: Option<HealthCheckConfig>,
+    pub reverse_only: bool,
+    pub recording: Option<RecordingConfig>,
 
     // Filtering
     pub filter_file: Option<String>,
     pub filter_urls: bool,
     pub filter_extended: bool,
     pub filter_casesensitive: bool,
+    /// Allow only URLs matching the filter, denying everything else.
+    pub filter_default_deny: bool,
+    pub filter_type: FilterType,
+    pub filter_groups: Vec<FilterGroupConfig>,
 
     // Headers
     pub anonymous: Vec<String>,
     pub via_proxy_name: Option<String>,
     pub x_tinyproxy: bool,
-    pub add_headers: HashMap<String, String>,
+    pub forwarded_headers: ForwardedHeaders,
+    pub add_headers: Vec<(String, String)>,
+    pub header_rewrites: Vec<HeaderRewriteConfig>,
+    pub url_rewrites: Vec<UrlRewriteConfig>,
+    pub redirects: Vec<RedirectConfig>,
+    pub mirrors: Vec<MirrorConfig>,
 
     // SSL/TLS
-    pub connect_ports: Vec<u16>,
+    pub connect_ports: Vec<ConnectPortConfig>,
     pub disable_via_header: bool,
 
     // Statistics
     pub stat_host: Option<String>,
     pub stat_file: Option<String>,
 
+    // Admin API
+    pub admin_port: Option<u16>,
+    pub admin_listen: IpAddr,
+    pub admin_auth: Option<BasicAuthConfig>,
+
     // Error pages
     pub error_files: HashMap<u16, String>,
     pub default_error_file: Option<String>,
+    pub error_diagnostics: bool,
+    pub error_diagnostics_allow: Vec<String>,
 
     // Performance
     pub buffer_size: usize,
+    /// Idle origin connections kept for reuse, 0 to close them.
     pub connection_pool_size: usize,
+    /// Seconds an origin connection may stay idle in the pool.
+    pub pool_idle_timeout: u64,
+
+    // Bandwidth shaping (bytes per second, 0 means unlimited)
+    pub upload_limit: u64,
+    pub download_limit: u64,
+    pub client_rate_limits: Vec<ClientRateLimitConfig>,
+
+    // Request rate limiting
+    pub destination_rate_limits: Vec<DestinationRateLimitConfig>,
+    pub destination_connection_limits: Vec<DestinationConnectionLimitConfig>,
+
+    // Circuit breaker (0 failures means disabled)
+    pub circuit_breaker_threshold: u32,
+    pub circuit_breaker_cooldown: u64,
+
+    // Tarpit for denied clients (0 seconds means disabled)
+    pub tarpit_duration: u64,
+    pub tarpit_auth_failures: u32,
+    pub tarpit_max_clients: usize,
+
+    /// File the configuration was read from, read again on reloads.
+    #[serde(skip)]
+    pub config_file: Option<String>,
+}
+
+/// How to handle an absolute-form request whose Host header names a
+/// different authority than its URI.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum HostMismatchPolicy {
+    /// Use the URI and replace the Host header (RFC 9112).
+    PreferUri,
+    /// Use the Host header and rewrite the URI.
+    PreferHost,
+    /// Answer 400 Bad Request.
+    Reject,
+}
+
+/// Whether upstream exchanges are saved to disk or answered from it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RecordingMode {
+    Record,
+    Replay,
+}
+
+/// Format of the filter file (FilterType).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FilterType {
+    /// Told apart by the file's first rules.
+    Auto,
+    /// One pattern per line.
+    Plain,
+    /// /etc/hosts lines, such as `0.0.0.0 ads.example.com`.
+    Hosts,
+    /// Adblock Plus domain rules, such as `||ads.example.com^`.
+    Adblock,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RecordingConfig {
+    pub mode: RecordingMode,
+    pub dir: String,
+}
+
+/// Placeholder shown instead of secrets in debug output and config dumps.
+const REDACTED: &str = "[redacted]";
+
+/// Loopback, private and link-local networks, denied as targets unless
+/// `DenyTargetNetworks none` clears them; `default` adds them again.
+const DEFAULT_DENIED_TARGETS: &[&str] = &[
+    "0.0.0.0/8",
+    "127.0.0.0/8",
+    "10.0.0.0/8",
+    "172.16.0.0/12",
+    "192.168.0.0/16",
+    "169.254.0.0/16",
+    "::/128",
+    "::1/128",
+    "fc00::/7",
+    "fe80::/10",
+];
+
+fn default_denied_targets() -> Vec<String> {
+    DEFAULT_DENIED_TARGETS
+        .iter()
+        .map(|s| s.to_string())
+        .collect()
+}
+
+/// Headers telling origins about the client (ForwardedHeaders).
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ForwardedHeaders {
+    pub x_forwarded_for: bool,
+    pub x_forwarded_proto: bool,
+    pub x_forwarded_host: bool,
+    /// RFC 7239 Forwarded
+    pub forwarded: bool,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
 pub struct BasicAuthConfig {
     pub username: String,
+    #[serde(serialize_with = "serialize_redacted")]
     pub password: String,
     pub realm: String,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+impl fmt::Debug for BasicAuthConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BasicAuthConfig")
+            .field("username", &self.username)
+            .field("password", &REDACTED)
+            .field("realm", &self.realm)
+            .finish()
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
 pub struct UpstreamConfig {
     pub upstream_type: String, // "http" or "socks5"
     pub host: String,
     pub port: u16,
     pub username: Option<String>,
+    #[serde(serialize_with = "serialize_redacted_option")]
     pub password: Option<String>,
     pub domain: Option<String>, // For domain-specific upstream
+    /// Client networks this upstream is used for; empty means everyone.
+    pub clients: Vec<String>,
+}
+
+impl UpstreamConfig {
+    /// `Proxy-Authorization` value for requests to this upstream proxy,
+    /// if it has credentials.
+    pub fn proxy_authorization(&self) -> Option<String> {
+        use base64::{engine::general_purpose::STANDARD, Engine as _};
+
+        let username = self.username.as_ref()?;
+        let password = self.password.as_deref().unwrap_or("");
+        Some(format!(
+            "Basic {}",
+            STANDARD.encode(format!("{}:{}", username, password))
+        ))
+    }
+}
+
+impl fmt::Debug for UpstreamConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("UpstreamConfig")
+            .field("upstream_type", &self.upstream_type)
+            .field("host", &self.host)
+            .field("port", &self.port)
+            .field("username", &self.username)
+            .field("password", &self.password.as_ref().map(|_| REDACTED))
+            .field("domain", &self.domain)
+            .field("clients", &self.clients)
+            .finish()
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HeaderRewriteConfig {
+    pub header: String,
+    pub pattern: String,
+    pub replacement: Option<String>, // None deletes the matching header
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UrlRewriteConfig {
+    pub pattern: String,
+    pub replacement: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RedirectConfig {
+    pub status: u16,
+    pub pattern: String,
+    pub location: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MirrorConfig {
+    pub pattern: String,
+    pub host: String,
+    pub port: u16,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ClientRateLimitConfig {
+    pub network: Option<String>, // address or CIDR, None for every client
+    pub rate: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DestinationRateLimitConfig {
+    pub host: String, // exact host, or .example.com for a whole domain
+    pub requests_per_second: f64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DestinationConnectionLimitConfig {
+    pub host: String, // exact host, or .example.com for a whole domain
+    pub max_connections: usize,
+    /// Seconds a request may wait for a connection to free up.
+    pub queue_timeout: u64,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ReverseProxyConfig {
+    /// Host header pattern the rule is limited to, lowercase, `*` wildcards.
+    pub host: Option<String>,
     pub path: String,
+    pub backends: Vec<ReverseBackendConfig>,
+    pub balance: BalanceStrategy,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReverseBackendConfig {
     pub url: String,
+    pub weight: u32,
+}
+
+/// Egress for CONNECT tunnels whose TLS server name matches `pattern`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SniRouteConfig {
+    /// Host name, possibly with `*` wildcards.
+    pub pattern: String,
+    pub interface: Option<String>,
+    pub mark: Option<u32>,
+    /// HTTP proxy to tunnel through, as host and port.
+    pub upstream: Option<(String, u16)>,
+}
+
+/// Ports CONNECT may reach, for every client or only for those matching
+/// `client` and `user`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ConnectPortConfig {
+    pub first: u16,
+    pub last: u16,
+    /// Client address or network the rule is limited to.
+    pub client: Option<String>,
+    /// Authenticated user the rule is limited to.
+    pub user: Option<String>,
+}
+
+/// A filter file of its own for some clients (FilterGroup).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FilterGroupConfig {
+    pub name: String,
+    pub file: String,
+    /// Client addresses and networks of the group.
+    pub clients: Vec<String>,
+    /// Authenticated users of the group.
+    pub users: Vec<String>,
+}
+
+/// Allow/Deny rules fetched periodically from a URL.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RemoteAccessListConfig {
+    pub url: String,
+    /// Seconds between fetches.
+    pub interval: u64,
+}
+
+/// Periodic request reverse proxy backends must answer with `status` to
+/// stay in rotation.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HealthCheckConfig {
+    pub path: String,
+    pub status: u16,
+    /// Seconds between checks.
+    pub interval: u64,
+}
+
+/// How a reverse proxy path with several backends picks one per request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum BalanceStrategy {
+    RoundRobin,
+    /// The backend with the fewest requests in progress.
+    LeastConnections,
+    /// Round robin, giving each backend as many turns as its weight.
+    Weighted,
 }
 
 impl Default for Config {
@@ -103,15 +453,39 @@ impl Default for Config {
             port: 8888,
             bind_address: IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)),
             listen_addresses: vec![],
+            require_all_listeners: true,
             bind_same: false,
+            outgoing_interface: None,
+            outgoing_mark: None,
+            tcp_nodelay: false,
+            tcp_keepalive_idle: 0,
+            tcp_keepalive_interval: 0,
+            socket_receive_buffer: 0,
+            socket_send_buffer: 0,
+            dns_cache_ttl: 300,
+            dns_negative_ttl: 0,
+            dns_cache_size: 10_000,
 
-            user: Some("nobody".to_string()),
-            group: Some("nobody".to_string()),
+            user: None,
+            group: None,
             daemon: false,
             pidfile: Some("/var/run/tinyproxy.pid".to_string()),
+            worker_threads: 0,
+            max_blocking_threads: 512,
 
             timeout: 600,
+            keep_alive_timeout: 15,
+            shutdown_timeout: 30,
+            connect_timeout: 30,
+            client_header_timeout: 60,
+            client_header_min_rate: 0,
+            idle_timeout: 0,
             max_clients: 100,
+            accept_queue_timeout: 0,
+            max_clients_per_ip: 0,
+            max_request_body_size: 0, // 0 means unlimited
+            max_request_duration: 0,  // 0 means unlimited
+            host_mismatch: HostMismatchPolicy::PreferUri,
             max_requests_per_child: 0, // 0 means unlimited
             max_spare_servers: 20,
             min_spare_servers: 5,
@@ -121,42 +495,96 @@ impl Default for Config {
             syslog: false,
             log_level: "Info".to_string(),
             debug: false,
+            denial_log: None,
+            access_log: None,
+            log_format: "common".to_string(),
 
             allow: vec![],
             deny: vec![],
+            remote_access_list: None,
+            trusted_proxies: vec![],
+            deny_target_networks: default_denied_targets(),
+            proxy_protocol: false,
+            ident_lookup: false,
 
             basic_auth: None,
+            basic_auth_file: None,
 
             upstream: vec![],
+            no_upstream: vec![],
+            sni_routes: vec![],
+            tls_intercept: false,
+            tls_intercept_ca: None,
+            no_tls_intercept: vec![],
+            tls_intercept_cache_dir: None,
             reverse_proxy: vec![],
-            transparent_proxy: false,
+            reverse_sticky_cookie: None,
+            reverse_health_check: None,
+            reverse_only: false,
+            recording: None,
 
             filter_file: None,
             filter_urls: false,
             filter_extended: false,
             filter_casesensitive: false,
+            filter_default_deny: false,
+            filter_type: FilterType::Auto,
+            filter_groups: vec![],
 
             anonymous: vec![],
-            via_proxy_name: Some("tinyproxy".to_string()),
+            via_proxy_name: None,
             x_tinyproxy: false,
-            add_headers: HashMap::new(),
+            forwarded_headers: ForwardedHeaders::default(),
+            add_headers: vec![],
+            header_rewrites: vec![],
+            url_rewrites: vec![],
+            redirects: vec![],
+            mirrors: vec![],
 
-            connect_ports: vec![443, 563],
+            connect_ports: Vec::new(),
             disable_via_header: false,
 
             stat_host: None,
             stat_file: None,
 
+            admin_port: None,
+            admin_listen: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            admin_auth: None,
+
             error_files: HashMap::new(),
             default_error_file: None,
+            error_diagnostics: false,
+            error_diagnostics_allow: vec![],
 
             buffer_size: 8192,
             connection_pool_size: 100,
+            pool_idle_timeout: 30,
+
+            upload_limit: 0,
+            download_limit: 0,
+            client_rate_limits: vec![],
+
+            destination_rate_limits: vec![],
+            destination_connection_limits: vec![],
+
+            circuit_breaker_threshold: 0,
+            circuit_breaker_cooldown: 30,
+
+            tarpit_duration: 0,
+            tarpit_auth_failures: 0,
+            tarpit_max_clients: 64,
+
+            config_file: None,
         }
     }
 }
 
 impl Config {
+    /// Whether clients must authenticate, with BasicAuth or BasicAuthFile.
+    pub fn requires_auth(&self) -> bool {
+        self.basic_auth.is_some() || self.basic_auth_file.is_some()
+    }
+
     pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self> {
         let path = path.as_ref();
 
@@ -167,10 +595,53 @@ impl Config {
         let content = fs::read_to_string(path)
             .with_context(|| format!("Failed to read config file: {}", path.display()))?;
 
-        Self::parse_config(&content)
+        let mut config = Self::parse_config(&content)?;
+        config.config_file = Some(path.display().to_string());
+        Ok(config)
     }
 
-    fn parse_config(content: &str) -> Result<Self> {
+    /// Names of the settings that differ between this configuration and
+    /// `other`, as their fields are called.
+    pub fn changed_settings(&self, other: &Config) -> Vec<String> {
+        let (Ok(Value::Object(ours)), Ok(Value::Object(theirs))) =
+            (serde_json::to_value(self), serde_json::to_value(other))
+        else {
+            return Vec::new();
+        };
+        let mut changed: Vec<String> = ours
+            .into_iter()
+            .filter(|(name, value)| theirs.get(name) != Some(value))
+            .map(|(name, _)| name)
+            .collect();
+
+        // Passwords are serialized redacted
+        let password = |auth: &Option<BasicAuthConfig>| auth.as_ref().map(|a| a.password.clone());
+        let passwords = [
+            (
+                "basic_auth",
+                password(&self.basic_auth) != password(&other.basic_auth),
+            ),
+            (
+                "admin_auth",
+                password(&self.admin_auth) != password(&other.admin_auth),
+            ),
+            (
+                "upstream",
+                self.upstream
+                    .iter()
+                    .map(|upstream| &upstream.password)
+                    .ne(other.upstream.iter().map(|upstream| &upstream.password)),
+            ),
+        ];
+        for (name, differs) in passwords {
+            if differs && !changed.iter().any(|changed| changed == name) {
+                changed.push(name.to_string());
+            }
+        }
+        changed
+    }
+
+    pub fn parse_config(content: &str) -> Result<Self> {
         let mut config = Self::default();
 
         for line in content.lines() {
@@ -206,9 +677,60 @@ impl Config {
                         .with_context(|| format!("Invalid listen address: {}", value))?;
                     config.listen_addresses.push(addr);
                 }
+                "requirealllisteners" => {
+                    config.require_all_listeners = parse_bool(value)?;
+                }
                 "bindsame" => {
                     config.bind_same = parse_bool(value)?;
                 }
+                "outgoinginterface" => {
+                    config.outgoing_interface = Some(value.to_string());
+                }
+                "outgoingmark" => {
+                    config.outgoing_mark = Some(parse_mark(value)?);
+                }
+                "tcpnodelay" => {
+                    config.tcp_nodelay = parse_bool(value)?;
+                }
+                "tcpkeepalive" => {
+                    // TcpKeepAlive idle [interval]
+                    let args = split_args(value);
+                    let seconds = |arg: &String| {
+                        arg.parse::<u64>()
+                            .with_context(|| format!("Invalid TCP keepalive time: {}", arg))
+                    };
+                    config.tcp_keepalive_idle = match args.first() {
+                        Some(idle) => seconds(idle)?,
+                        None => {
+                            return Err(anyhow::anyhow!("Invalid TcpKeepAlive format: {}", value))
+                        }
+                    };
+                    config.tcp_keepalive_interval = match args.get(1) {
+                        Some(interval) => seconds(interval)?,
+                        None => 0,
+                    };
+                }
+                "socketreceivebuffer" => {
+                    config.socket_receive_buffer = parse_size(value)?;
+                }
+                "socketsendbuffer" => {
+                    config.socket_send_buffer = parse_size(value)?;
+                }
+                "dnscachettl" => {
+                    config.dns_cache_ttl = value
+                        .parse()
+                        .with_context(|| format!("Invalid DNS cache TTL: {}", value))?;
+                }
+                "dnsnegativettl" => {
+                    config.dns_negative_ttl = value
+                        .parse()
+                        .with_context(|| format!("Invalid DNS negative TTL: {}", value))?;
+                }
+                "dnscachesize" => {
+                    config.dns_cache_size = value
+                        .parse()
+                        .with_context(|| format!("Invalid DNS cache size: {}", value))?;
+                }
                 "user" => {
                     config.user = Some(value.to_string());
                 }
@@ -218,16 +740,99 @@ impl Config {
                 "pidfile" => {
                     config.pidfile = Some(value.to_string());
                 }
+                "workerthreads" => {
+                    config.worker_threads = value
+                        .parse()
+                        .with_context(|| format!("Invalid worker threads: {}", value))?;
+                }
+                "maxblockingthreads" => {
+                    config.max_blocking_threads = value
+                        .parse()
+                        .ok()
+                        .filter(|&threads| threads > 0)
+                        .with_context(|| format!("Invalid max blocking threads: {}", value))?;
+                }
                 "timeout" => {
                     config.timeout = value
                         .parse()
                         .with_context(|| format!("Invalid timeout value: {}", value))?;
                 }
+                "keepalivetimeout" => {
+                    config.keep_alive_timeout = value
+                        .parse()
+                        .with_context(|| format!("Invalid keep-alive timeout: {}", value))?;
+                }
+                "shutdowntimeout" => {
+                    config.shutdown_timeout = value
+                        .parse()
+                        .with_context(|| format!("Invalid shutdown timeout: {}", value))?;
+                }
+                "connecttimeout" => {
+                    config.connect_timeout = value
+                        .parse()
+                        .ok()
+                        .filter(|&seconds| seconds > 0)
+                        .with_context(|| format!("Invalid connect timeout: {}", value))?;
+                }
+                "clientheadertimeout" => {
+                    config.client_header_timeout = value
+                        .parse()
+                        .with_context(|| format!("Invalid client header timeout: {}", value))?;
+                }
+                "clientheaderminrate" => {
+                    config.client_header_min_rate = parse_rate(value)?;
+                }
+                "idletimeout" => {
+                    config.idle_timeout = value
+                        .parse()
+                        .with_context(|| format!("Invalid idle timeout: {}", value))?;
+                }
+                "connectionpoolsize" => {
+                    config.connection_pool_size = value
+                        .parse()
+                        .with_context(|| format!("Invalid connection pool size: {}", value))?;
+                }
+                "poolidletimeout" => {
+                    config.pool_idle_timeout = value
+                        .parse()
+                        .with_context(|| format!("Invalid pool idle timeout: {}", value))?;
+                }
                 "maxclients" => {
                     config.max_clients = value
                         .parse()
                         .with_context(|| format!("Invalid max clients value: {}", value))?;
                 }
+                "acceptqueuetimeout" => {
+                    config.accept_queue_timeout = value
+                        .parse()
+                        .with_context(|| format!("Invalid accept queue timeout: {}", value))?;
+                }
+                "maxclientsperip" => {
+                    config.max_clients_per_ip = value
+                        .parse()
+                        .with_context(|| format!("Invalid max clients per IP value: {}", value))?;
+                }
+                "maxrequestbodysize" => {
+                    config.max_request_body_size = parse_size(value)?;
+                }
+                "maxrequestduration" => {
+                    config.max_request_duration = value
+                        .parse()
+                        .with_context(|| format!("Invalid max request duration: {}", value))?;
+                }
+                "hostmismatch" => {
+                    config.host_mismatch = match value.to_lowercase().as_str() {
+                        "uri" => HostMismatchPolicy::PreferUri,
+                        "host" => HostMismatchPolicy::PreferHost,
+                        "reject" => HostMismatchPolicy::Reject,
+                        _ => {
+                            return Err(anyhow::anyhow!(
+                                "Invalid HostMismatch value: {} (expected uri, host or reject)",
+                                value
+                            ))
+                        }
+                    };
+                }
                 "maxrequestsperchild" => {
                     config.max_requests_per_child = value.parse().with_context(|| {
                         format!("Invalid max requests per child value: {}", value)
@@ -239,6 +844,16 @@ impl Config {
                 "syslog" => {
                     config.syslog = parse_bool(value)?;
                 }
+                "accesslog" => {
+                    config.access_log = split_args(value).into_iter().next();
+                }
+                "logformat" => {
+                    // Format: LogFormat common|combined|"format string"
+                    config.log_format = split_args(value).join(" ");
+                }
+                "deniallog" => {
+                    config.denial_log = Some(value.to_string());
+                }
                 "loglevel" => {
                     config.log_level = value.to_string();
                 }
@@ -248,16 +863,51 @@ impl Config {
                 "deny" => {
                     config.deny.push(value.to_string());
                 }
+                "remoteaccesslist" => {
+                    // Format: RemoteAccessList url [interval]
+                    config.remote_access_list = Some(parse_remote_access_list(value)?);
+                }
+                "trustedproxies" => {
+                    config
+                        .trusted_proxies
+                        .extend(value.split_whitespace().map(|s| s.to_string()));
+                }
+                "denytargetnetworks" => {
+                    for network in value.split_whitespace() {
+                        if network.eq_ignore_ascii_case("none") {
+                            config.deny_target_networks.clear();
+                        } else if network.eq_ignore_ascii_case("default") {
+                            for network in default_denied_targets() {
+                                if !config.deny_target_networks.contains(&network) {
+                                    config.deny_target_networks.push(network);
+                                }
+                            }
+                        } else {
+                            config.deny_target_networks.push(network.to_string());
+                        }
+                    }
+                }
+                "proxyprotocol" => {
+                    config.proxy_protocol = parse_bool(value)?;
+                }
+                "identlookup" => {
+                    config.ident_lookup = parse_bool(value)?;
+                }
                 "basicauth" => {
                     let parts: Vec<&str> = value.splitn(2, ':').collect();
                     if parts.len() == 2 {
                         config.basic_auth = Some(BasicAuthConfig {
                             username: parts[0].to_string(),
-                            password: parts[1].to_string(),
+                            password: resolve_secret(parts[1])?,
                             realm: "Tinyproxy".to_string(),
                         });
                     }
                 }
+                "basicauthfile" => {
+                    crate::auth::read_user_file(value)
+                        .map_err(|e| anyhow::anyhow!("Invalid BasicAuthFile: {}", e))?;
+                    config.basic_auth_file = Some(value.to_string());
+                }
                 "upstream" => {
                     // Parse upstream configuration
                     // Format: upstream type:host:port [username:password] [domain]
@@ -265,8 +915,45 @@ impl Config {
                         config.upstream.push(upstream);
                     }
                 }
+                "noupstream" => {
+                    // Format: NoUpstream entry [entry...], like no_proxy
+                    config.no_upstream.extend(
+                        value
+                            .split(|c: char| c == ',' || c.is_whitespace())
+                            .filter(|entry| !entry.is_empty())
+                            .map(|entry| entry.to_lowercase()),
+                    );
+                }
+                "reversepath" => {
+                    // Format: ReversePath path url [url...] [host=pattern]
+                    config.reverse_proxy.push(parse_reverse_path(value)?);
+                }
+                "reversestickycookie" => {
+                    if value.is_empty()
+                        || !value
+                            .chars()
+                            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+                    {
+                        return Err(anyhow::anyhow!("Invalid cookie name: {}", value));
+                    }
+                    config.reverse_sticky_cookie = Some(value.to_string());
+                }
+                "reversehealthcheck" => {
+                    // Format: ReverseHealthCheck path [status] [interval]
+                    config.reverse_health_check = Some(parse_health_check(value)?);
+                }
                 "reverseonly" => {
-                    config.transparent_proxy = parse_bool(value)?;
+                    config.reverse_only = parse_bool(value)?;
+                }
+                "record" | "replay" => {
+                    config.recording = Some(RecordingConfig {
+                        mode: if key == "record" {
+                            RecordingMode::Record
+                        } else {
+                            RecordingMode::Replay
+                        },
+                        dir: value.to_string(),
+                    });
                 }
                 "filter" => {
                     config.filter_file = Some(value.to_string());
@@ -280,20 +967,105 @@ impl Config {
                 "filtercasesensitive" => {
                     config.filter_casesensitive = parse_bool(value)?;
                 }
+                "filtertype" => {
+                    config.filter_type = match value.to_lowercase().as_str() {
+                        "auto" => FilterType::Auto,
+                        "plain" => FilterType::Plain,
+                        "hosts" => FilterType::Hosts,
+                        "adblock" => FilterType::Adblock,
+                        _ => return Err(anyhow::anyhow!("Invalid filter type: {}", value)),
+                    };
+                }
+                "filtergroup" => {
+                    config.filter_groups.push(parse_filter_group(value)?);
+                }
+                "filterdefaultdeny" => {
+                    config.filter_default_deny = parse_bool(value)?;
+                }
                 "anonymous" => {
-                    config.anonymous.push(value.to_string());
+                    config.anonymous.extend(split_args(value));
+                }
+                "addheader" => {
+                    // Format: AddHeader name value
+                    match <[String; 2]>::try_from(split_args(value)) {
+                        Ok([name, value]) => config.add_headers.push((name, value)),
+                        Err(_) => {
+                            return Err(anyhow::anyhow!("Invalid AddHeader format: {}", value))
+                        }
+                    }
                 }
                 "viaproxyname" => {
-                    config.via_proxy_name = Some(value.to_string());
+                    config.via_proxy_name = Some(value.trim_matches('"').to_string());
                 }
                 "xtinyproxy" => {
                     config.x_tinyproxy = parse_bool(value)?;
                 }
+                "forwardedheaders" => {
+                    // Format: ForwardedHeaders header... | all
+                    let headers = &mut config.forwarded_headers;
+                    for name in value.split_whitespace() {
+                        match name.trim_matches('"').to_lowercase().as_str() {
+                            "x-forwarded-for" => headers.x_forwarded_for = true,
+                            "x-forwarded-proto" => headers.x_forwarded_proto = true,
+                            "x-forwarded-host" => headers.x_forwarded_host = true,
+                            "forwarded" => headers.forwarded = true,
+                            "all" => {
+                                *headers = ForwardedHeaders {
+                                    x_forwarded_for: true,
+                                    x_forwarded_proto: true,
+                                    x_forwarded_host: true,
+                                    forwarded: true,
+                                }
+                            }
+                            _ => return Err(anyhow::anyhow!("Unknown forwarded header: {}", name)),
+                        }
+                    }
+                }
+                "headerrewrite" => {
+                    // Format: HeaderRewrite header regex [replacement]
+                    config.header_rewrites.push(parse_header_rewrite(value)?);
+                }
+                "urlrewrite" => {
+                    // Format: URLRewrite regex replacement
+                    config.url_rewrites.push(parse_url_rewrite(value)?);
+                }
+                "redirect" => {
+                    // Format: Redirect [status] regex location
+                    config.redirects.push(parse_redirect(value)?);
+                }
+                "mirror" => {
+                    // Format: Mirror regex host:port
+                    config.mirrors.push(parse_mirror(value)?);
+                }
+                "tlsintercept" => {
+                    config.tls_intercept = parse_bool(value)?;
+                }
+                "tlsinterceptca" => {
+                    // Format: TlsInterceptCa cert.pem key.pem
+                    match <[String; 2]>::try_from(split_args(value)) {
+                        Ok([cert, key]) => config.tls_intercept_ca = Some((cert, key)),
+                        Err(_) => {
+                            return Err(anyhow::anyhow!("Invalid TlsInterceptCa format: {}", value))
+                        }
+                    }
+                }
+                "notlsintercept" => {
+                    config.no_tls_intercept.extend(
+                        split_args(value)
+                            .iter()
+                            .map(|pattern| pattern.to_lowercase()),
+                    );
+                }
+                "tlsinterceptcachedir" => {
+                    config.tls_intercept_cache_dir = Some(value.trim_matches('"').to_string());
+                }
+                "sniroute" => {
+                    // Format: SniRoute pattern [interface=name] [mark=n] [upstream=host:port]
+                    config.sni_routes.push(parse_sni_route(value)?);
+                }
                 "connectport" => {
-                    let port: u16 = value
-                        .parse()
-                        .with_context(|| format!("Invalid connect port value: {}", value))?;
-                    config.connect_ports.push(port);
+                    // Format: ConnectPort port|first-last|*|all [client=network] [user=name]
+                    config.connect_ports.push(parse_connect_port(value)?);
                 }
                 "disableviaheader" => {
                     config.disable_via_header = parse_bool(value)?;
@@ -307,15 +1079,139 @@ impl Config {
                 "errorfile" => {
                     // Parse error file configuration
                     // Format: errorfile code file
-                    let parts: Vec<&str> = value.splitn(2, char::is_whitespace).collect();
-                    if parts.len() == 2 {
-                        if let Ok(code) = parts[0].parse::<u16>() {
-                            config.error_files.insert(code, parts[1].to_string());
+                    let args = split_args(value);
+                    if let [code, file] = args.as_slice() {
+                        if let Ok(code) = code.parse::<u16>() {
+                            config.error_files.insert(code, file.clone());
                         }
                     }
                 }
                 "defaulterrorfile" => {
-                    config.default_error_file = Some(value.to_string());
+                    config.default_error_file = split_args(value).into_iter().next();
+                }
+                "adminport" => {
+                    config.admin_port = Some(
+                        value
+                            .parse()
+                            .with_context(|| format!("Invalid admin port: {}", value))?,
+                    );
+                }
+                "adminlisten" => {
+                    config.admin_listen = value
+                        .parse()
+                        .with_context(|| format!("Invalid admin listen address: {}", value))?;
+                }
+                "adminauth" => {
+                    let (username, password) = value
+                        .split_once(':')
+                        .ok_or_else(|| anyhow::anyhow!("Invalid admin auth format: {}", value))?;
+                    config.admin_auth = Some(BasicAuthConfig {
+                        username: username.to_string(),
+                        password: resolve_secret(password)?,
+                        realm: "Tinyproxy Admin".to_string(),
+                    });
+                }
+                "errordiagnostics" => {
+                    config.error_diagnostics = parse_bool(value)?;
+                }
+                "errordiagnosticsallow" => {
+                    config
+                        .error_diagnostics_allow
+                        .extend(value.split_whitespace().map(String::from));
+                }
+                "uploadlimit" => {
+                    config.upload_limit = parse_rate(value)?;
+                }
+                "downloadlimit" => {
+                    config.download_limit = parse_rate(value)?;
+                }
+                "ratelimit" => {
+                    // Format: RateLimit [network] rate
+                    let parts: Vec<&str> = value.split_whitespace().collect();
+                    let (network, rate) = match parts[..] {
+                        [rate] => (None, rate),
+                        [network, rate] => (Some(network.to_string()), rate),
+                        _ => return Err(anyhow::anyhow!("Invalid rate limit format: {}", value)),
+                    };
+                    config.client_rate_limits.push(ClientRateLimitConfig {
+                        network,
+                        rate: parse_rate(rate)?,
+                    });
+                }
+                "destinationratelimit" => {
+                    // Format: DestinationRateLimit host requests[/s|/m|/h]
+                    let parts: Vec<&str> = value.split_whitespace().collect();
+                    if parts.len() != 2 {
+                        return Err(anyhow::anyhow!(
+                            "Invalid destination rate limit format: {}",
+                            value
+                        ));
+                    }
+                    config
+                        .destination_rate_limits
+                        .push(DestinationRateLimitConfig {
+                            host: parts[0].to_lowercase(),
+                            requests_per_second: parse_request_rate(parts[1])?,
+                        });
+                }
+                "destinationconnectionlimit" => {
+                    // Format: DestinationConnectionLimit host connections [wait seconds]
+                    let parts: Vec<&str> = value.split_whitespace().collect();
+                    if !(2..=3).contains(&parts.len()) {
+                        return Err(anyhow::anyhow!(
+                            "Invalid destination connection limit format: {}",
+                            value
+                        ));
+                    }
+                    let max_connections =
+                        parts[1]
+                            .parse()
+                            .ok()
+                            .filter(|&max| max > 0)
+                            .ok_or_else(|| {
+                                anyhow::anyhow!(
+                                    "Invalid destination connection limit: {}",
+                                    parts[1]
+                                )
+                            })?;
+                    let queue_timeout = match parts.get(2) {
+                        Some(wait) => wait.parse().with_context(|| {
+                            format!("Invalid destination connection queue timeout: {}", wait)
+                        })?,
+                        None => 0,
+                    };
+                    config
+                        .destination_connection_limits
+                        .push(DestinationConnectionLimitConfig {
+                            host: parts[0].to_lowercase(),
+                            max_connections,
+                            queue_timeout,
+                        });
+                }
+                "circuitbreakerthreshold" => {
+                    config.circuit_breaker_threshold = value
+                        .parse()
+                        .with_context(|| format!("Invalid circuit breaker threshold: {}", value))?;
+                }
+                "circuitbreakercooldown" => {
+                    config.circuit_breaker_cooldown = value
+                        .parse()
+                        .with_context(|| format!("Invalid circuit breaker cooldown: {}", value))?;
+                }
+                "tarpit" => {
+                    config.tarpit_duration = value
+                        .parse()
+                        .with_context(|| format!("Invalid tarpit duration: {}", value))?;
+                }
+                "tarpitauthfailures" => {
+                    config.tarpit_auth_failures = value
+                        .parse()
+                        .with_context(|| format!("Invalid tarpit auth failures: {}", value))?;
+                }
+                "tarpitmaxclients" => {
+                    config.tarpit_max_clients = value
+                        .parse()
+                        .with_context(|| format!("Invalid tarpit max clients: {}", value))?;
                 }
                 _ => {
                     // Unknown configuration option, log warning
@@ -327,6 +1223,17 @@ impl Config {
         Ok(config)
     }
 
+    /// Adapt the configuration for a trial instance serving on `port` next
+    /// to the running proxy. The trial stays in the foreground and leaves
+    /// the pidfile and admin port to the running instance.
+    pub fn into_canary(mut self, port: u16) -> Self {
+        self.port = port;
+        self.daemon = false;
+        self.pidfile = None;
+        self.admin_port = None;
+        self
+    }
+
     pub fn get_listen_addresses(&self) -> Vec<SocketAddr> {
         if self.listen_addresses.is_empty() {
             vec![SocketAddr::new(self.bind_address, self.port)]
@@ -347,19 +1254,622 @@ fn parse_bool(value: &str) -> Result<bool> {
     }
 }
 
+/// Parse a byte count with an optional K/M/G suffix (powers of 1024).
+pub fn parse_size(value: &str) -> Result<u64> {
+    let value = value.trim();
+    let upper = value.to_uppercase();
+    let digits = upper.trim_end_matches('B');
+    let (number, multiplier) = match digits.chars().last() {
+        Some('K') => (&digits[..digits.len() - 1], 1024),
+        Some('M') => (&digits[..digits.len() - 1], 1024 * 1024),
+        Some('G') => (&digits[..digits.len() - 1], 1024 * 1024 * 1024),
+        _ => (digits, 1),
+    };
+
+    let number: u64 = number
+        .trim()
+        .parse()
+        .with_context(|| format!("Invalid size value: {}", value))?;
+    number
+        .checked_mul(multiplier)
+        .ok_or_else(|| anyhow::anyhow!("Size value too large: {}", value))
+}
+
+/// Parse a firewall mark, in decimal or in hexadecimal with a `0x` prefix.
+fn parse_mark(value: &str) -> Result<u32> {
+    let parsed = match value
+        .strip_prefix("0x")
+        .or_else(|| value.strip_prefix("0X"))
+    {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => value.parse(),
+    };
+    parsed.with_context(|| format!("Invalid mark value: {}", value))
+}
+
+/// Parse a transfer rate in bytes per second, e.g. `512K`, `2MBps` or
+/// `1M/s`.
+pub fn parse_rate(value: &str) -> Result<u64> {
+    let value = value.trim();
+    let size = value
+        .strip_suffix("/s")
+        .or_else(|| value.strip_suffix("ps"))
+        .unwrap_or(value);
+    parse_size(size).with_context(|| format!("Invalid rate value: {}", value))
+}
+
+/// Parse a request rate such as `10/s`, `600/m` or `1000/h` into
+/// requests per second. A bare number is taken as per second.
+fn parse_request_rate(value: &str) -> Result<f64> {
+    let (count, seconds) = match value.split_once('/') {
+        Some((count, "s")) | Some((count, "sec")) => (count, 1.0),
+        Some((count, "m")) | Some((count, "min")) => (count, 60.0),
+        Some((count, "h")) | Some((count, "hour")) => (count, 3600.0),
+        Some(_) => return Err(anyhow::anyhow!("Invalid request rate: {}", value)),
+        None => (value, 1.0),
+    };
+
+    let count: f64 = count
+        .parse()
+        .with_context(|| format!("Invalid request rate: {}", value))?;
+    if count <= 0.0 || !count.is_finite() {
+        return Err(anyhow::anyhow!("Invalid request rate: {}", value));
+    }
+
+    Ok(count / seconds)
+}
+
 fn parse_upstream(value: &str) -> Result<UpstreamConfig> {
     // Simple upstream parsing - can be extended for more complex formats
-    let parts: Vec<&str> = value.split(':').collect();
-    if parts.len() >= 3 {
-        Ok(UpstreamConfig {
-            upstream_type: parts[0].to_string(),
-            host: parts[1].to_string(),
-            port: parts[2].parse()?,
-            username: None,
-            password: None,
-            domain: None,
-        })
+    let args: Vec<&str> = value.split_whitespace().collect();
+    let parts: Vec<&str> = args.first().copied().unwrap_or("").split(':').collect();
+    if parts.len() < 3 {
+        return Err(anyhow::anyhow!("Invalid upstream format: {}", value));
+    }
+
+    let mut upstream = UpstreamConfig {
+        upstream_type: parts[0].to_string(),
+        host: parts[1].to_string(),
+        port: parts[2].parse()?,
+        username: None,
+        password: None,
+        domain: None,
+        clients: vec![],
+    };
+
+    for arg in &args[1..] {
+        if let Some(client) = arg.strip_prefix("client=") {
+            upstream.clients.push(client.to_string());
+            continue;
+        }
+        match arg.split_once(':') {
+            Some((username, password)) if upstream.username.is_none() => {
+                upstream.username = Some(username.to_string());
+                upstream.password = Some(resolve_secret(password)?);
+            }
+            _ => upstream.domain = Some(arg.to_string()),
+        }
+    }
+
+    Ok(upstream)
+}
+
+/// Resolve a secret given inline, as `env:NAME` (environment variable) or
+/// as `file:/path` (file contents without the trailing newline), so that
+/// credentials don't have to be written into the config file.
+pub fn resolve_secret(value: &str) -> Result<String> {
+    if let Some(name) = value.strip_prefix("env:") {
+        std::env::var(name).with_context(|| format!("Secret variable {} is not set", name))
+    } else if let Some(path) = value.strip_prefix("file:") {
+        let secret = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read secret file: {}", path))?;
+        Ok(secret.trim_end_matches(['\r', '\n']).to_string())
     } else {
-        Err(anyhow::anyhow!("Invalid upstream format: {}", value))
+        Ok(value.to_string())
+    }
+}
+
+fn serialize_redacted<S: serde::Serializer>(_: &str, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(REDACTED)
+}
+
+fn serialize_redacted_option<S: serde::Serializer>(
+    value: &Option<String>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    match value {
+        Some(_) => serializer.serialize_some(REDACTED),
+        None => serializer.serialize_none(),
+    }
+}
+
+/// Split a directive value into arguments, honoring double quotes, inside
+/// which `\"` stands for a quote.
+fn split_args(value: &str) -> Vec<String> {
+    let mut args = Vec::new();
+    let mut current = String::new();
+    let mut in_quotes = false;
+    let mut has_arg = false;
+
+    let mut chars = value.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            // A quote inside quotes, as in LogFormat "%h \"%r\""
+            '\\' if in_quotes && chars.peek() == Some(&'"') => {
+                current.push('"');
+                chars.next();
+            }
+            '"' => {
+                in_quotes = !in_quotes;
+                has_arg = true;
+            }
+            c if c.is_whitespace() && !in_quotes => {
+                if has_arg {
+                    args.push(std::mem::take(&mut current));
+                    has_arg = false;
+                }
+            }
+            c => {
+                current.push(c);
+                has_arg = true;
+            }
+        }
+    }
+
+    if has_arg {
+        args.push(current);
+    }
+
+    args
+}
+
+fn parse_header_rewrite(value: &str) -> Result<HeaderRewriteConfig> {
+    let args = split_args(value);
+    if args.len() < 2 || args.len() > 3 {
+        return Err(anyhow::anyhow!("Invalid header rewrite format: {}", value));
+    }
+
+    regex::Regex::new(&args[1])
+        .with_context(|| format!("Invalid header rewrite pattern: {}", args[1]))?;
+
+    Ok(HeaderRewriteConfig {
+        header: args[0].clone(),
+        pattern: args[1].clone(),
+        replacement: args.get(2).cloned(),
+    })
+}
+
+fn parse_url_rewrite(value: &str) -> Result<UrlRewriteConfig> {
+    let args = split_args(value);
+    if args.len() != 2 {
+        return Err(anyhow::anyhow!("Invalid URL rewrite format: {}", value));
+    }
+
+    regex::Regex::new(&args[0])
+        .with_context(|| format!("Invalid URL rewrite pattern: {}", args[0]))?;
+
+    Ok(UrlRewriteConfig {
+        pattern: args[0].clone(),
+        replacement: args[1].clone(),
+    })
+}
+
+fn parse_redirect(value: &str) -> Result<RedirectConfig> {
+    let mut args = split_args(value);
+
+    let status = match args.len() {
+        2 => 302,
+        3 => {
+            let status: u16 = args
+                .remove(0)
+                .parse()
+                .with_context(|| format!("Invalid redirect status: {}", value))?;
+            if !matches!(status, 301 | 302 | 303 | 307 | 308) {
+                return Err(anyhow::anyhow!("Invalid redirect status: {}", status));
+            }
+            status
+        }
+        _ => return Err(anyhow::anyhow!("Invalid redirect format: {}", value)),
+    };
+
+    regex::Regex::new(&args[0])
+        .with_context(|| format!("Invalid redirect pattern: {}", args[0]))?;
+
+    Ok(RedirectConfig {
+        status,
+        pattern: args[0].clone(),
+        location: args[1].clone(),
+    })
+}
+
+fn parse_reverse_path(value: &str) -> Result<ReverseProxyConfig> {
+    let mut args = split_args(value).into_iter();
+    let path = match args.next() {
+        Some(path) if path.starts_with('/') => path,
+        _ => return Err(anyhow::anyhow!("Invalid reverse path format: {}", value)),
+    };
+
+    let mut backends: Vec<ReverseBackendConfig> = Vec::new();
+    let mut balance = BalanceStrategy::RoundRobin;
+    let mut host = None;
+    for arg in args {
+        if let Some(pattern) = arg.strip_prefix("host=") {
+            host = Some(pattern.to_lowercase());
+        } else if let Some(weight) = arg.strip_prefix("weight=") {
+            let backend = backends
+                .last_mut()
+                .ok_or_else(|| anyhow::anyhow!("Weight before any URL: {}", value))?;
+            backend.weight = weight
+                .parse()
+                .ok()
+                .filter(|&weight| weight > 0)
+                .ok_or_else(|| anyhow::anyhow!("Invalid backend weight: {}", weight))?;
+        } else if let Some(strategy) = arg.strip_prefix("balance=") {
+            balance = match strategy.to_lowercase().as_str() {
+                "round-robin" => BalanceStrategy::RoundRobin,
+                "least-connections" => BalanceStrategy::LeastConnections,
+                "weighted" => BalanceStrategy::Weighted,
+                _ => return Err(anyhow::anyhow!("Invalid balance strategy: {}", strategy)),
+            };
+        } else {
+            let parsed = url::Url::parse(&arg)
+                .with_context(|| format!("Invalid reverse path URL: {}", arg))?;
+            if parsed.scheme() != "http" || parsed.host_str().is_none() {
+                return Err(anyhow::anyhow!("Invalid reverse path URL: {}", arg));
+            }
+            backends.push(ReverseBackendConfig {
+                url: arg,
+                weight: 1,
+            });
+        }
+    }
+
+    if backends.is_empty() {
+        return Err(anyhow::anyhow!("Invalid reverse path format: {}", value));
+    }
+    Ok(ReverseProxyConfig {
+        host,
+        path,
+        backends,
+        balance,
+    })
+}
+
+fn parse_health_check(value: &str) -> Result<HealthCheckConfig> {
+    let args = split_args(value);
+    let path = match args.first() {
+        Some(path) if path.starts_with('/') && args.len() <= 3 => path.clone(),
+        _ => return Err(anyhow::anyhow!("Invalid health check format: {}", value)),
+    };
+    let status = match args.get(1) {
+        Some(status) => status
+            .parse()
+            .ok()
+            .filter(|status| (100..600).contains(status))
+            .ok_or_else(|| anyhow::anyhow!("Invalid health check status: {}", status))?,
+        None => 200,
+    };
+    let interval = match args.get(2) {
+        Some(interval) => interval
+            .parse()
+            .ok()
+            .filter(|&interval| interval > 0)
+            .ok_or_else(|| anyhow::anyhow!("Invalid health check interval: {}", interval))?,
+        None => 10,
+    };
+
+    Ok(HealthCheckConfig {
+        path,
+        status,
+        interval,
+    })
+}
+
+fn parse_remote_access_list(value: &str) -> Result<RemoteAccessListConfig> {
+    let args = split_args(value);
+    let url = match args.first() {
+        Some(url) if args.len() <= 2 => url.clone(),
+        _ => {
+            return Err(anyhow::anyhow!(
+                "Invalid remote access list format: {}",
+                value
+            ))
+        }
+    };
+    match url::Url::parse(&url) {
+        Ok(parsed) if matches!(parsed.scheme(), "http" | "https") && parsed.has_host() => {}
+        _ => return Err(anyhow::anyhow!("Invalid remote access list URL: {}", url)),
+    }
+    let interval = match args.get(1) {
+        Some(interval) => interval
+            .parse()
+            .ok()
+            .filter(|&interval| interval > 0)
+            .ok_or_else(|| anyhow::anyhow!("Invalid remote access list interval: {}", interval))?,
+        None => 300,
+    };
+
+    Ok(RemoteAccessListConfig { url, interval })
+}
+
+fn parse_sni_route(value: &str) -> Result<SniRouteConfig> {
+    let mut args = split_args(value).into_iter();
+    let pattern = args
+        .next()
+        .ok_or_else(|| anyhow::anyhow!("Invalid SNI route format: {}", value))?;
+
+    let mut route = SniRouteConfig {
+        pattern: pattern.to_lowercase(),
+        interface: None,
+        mark: None,
+        upstream: None,
+    };
+    for arg in args {
+        if let Some(interface) = arg.strip_prefix("interface=") {
+            route.interface = Some(interface.to_string());
+        } else if let Some(mark) = arg.strip_prefix("mark=") {
+            route.mark = Some(parse_mark(mark)?);
+        } else if let Some(upstream) = arg.strip_prefix("upstream=") {
+            let (host, port) = upstream
+                .rsplit_once(':')
+                .and_then(|(host, port)| Some((host, port.parse().ok()?)))
+                .ok_or_else(|| anyhow::anyhow!("Invalid SNI route upstream: {}", upstream))?;
+            route.upstream = Some((host.to_string(), port));
+        } else {
+            return Err(anyhow::anyhow!("Invalid SNI route option: {}", arg));
+        }
+    }
+
+    if route.interface.is_none() && route.mark.is_none() && route.upstream.is_none() {
+        return Err(anyhow::anyhow!("SNI route without egress: {}", value));
+    }
+    Ok(route)
+}
+
+fn parse_connect_port(value: &str) -> Result<ConnectPortConfig> {
+    let mut args = split_args(value).into_iter();
+    let ports = args
+        .next()
+        .ok_or_else(|| anyhow::anyhow!("Invalid connect port format: {}", value))?;
+    let parse_port = |port: &str| {
+        port.parse::<u16>()
+            .with_context(|| format!("Invalid connect port value: {}", port))
+    };
+    // 0, * and all lift the restriction
+    let (first, last) = match ports.split_once('-') {
+        _ if ports == "*" || ports == "0" || ports.eq_ignore_ascii_case("all") => (0, u16::MAX),
+        Some((first, last)) => (parse_port(first)?, parse_port(last)?),
+        None => (parse_port(&ports)?, parse_port(&ports)?),
+    };
+    if first > last {
+        return Err(anyhow::anyhow!("Invalid connect port range: {}", ports));
+    }
+
+    let mut rule = ConnectPortConfig {
+        first,
+        last,
+        client: None,
+        user: None,
+    };
+    for arg in args {
+        if let Some(client) = arg.strip_prefix("client=") {
+            rule.client = Some(client.to_string());
+        } else if let Some(user) = arg.strip_prefix("user=") {
+            rule.user = Some(user.to_string());
+        } else {
+            return Err(anyhow::anyhow!("Invalid connect port option: {}", arg));
+        }
+    }
+    Ok(rule)
+}
+
+/// `FilterGroup name [client=]network... [user=name]... file`
+fn parse_filter_group(value: &str) -> Result<FilterGroupConfig> {
+    let mut args = split_args(value);
+    if args.len() < 3 {
+        return Err(anyhow::anyhow!("Invalid filter group format: {}", value));
+    }
+    let file = args.pop().unwrap_or_default();
+    let mut group = FilterGroupConfig {
+        name: args.remove(0),
+        file,
+        clients: vec![],
+        users: vec![],
+    };
+    for arg in args {
+        if let Some(user) = arg.strip_prefix("user=") {
+            group.users.push(user.to_string());
+        } else {
+            let client = arg.strip_prefix("client=").unwrap_or(&arg);
+            client
+                .split('/')
+                .next()
+                .unwrap_or_default()
+                .parse::<IpAddr>()
+                .with_context(|| format!("Invalid filter group client: {}", arg))?;
+            group.clients.push(client.to_string());
+        }
+    }
+    Ok(group)
+}
+
+fn parse_mirror(value: &str) -> Result<MirrorConfig> {
+    let args = split_args(value);
+    if args.len() != 2 {
+        return Err(anyhow::anyhow!("Invalid mirror format: {}", value));
+    }
+
+    regex::Regex::new(&args[0]).with_context(|| format!("Invalid mirror pattern: {}", args[0]))?;
+    let (host, port) = args[1]
+        .rsplit_once(':')
+        .ok_or_else(|| anyhow::anyhow!("Invalid mirror target: {}", args[1]))?;
+    let port = port
+        .parse()
+        .with_context(|| format!("Invalid mirror port: {}", port))?;
+
+    Ok(MirrorConfig {
+        pattern: args[0].clone(),
+        host: host.to_string(),
+        port,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use tempfile::NamedTempFile;
+
+    #[test]
+    fn test_resolve_secret() {
+        assert_eq!(resolve_secret("plain").unwrap(), "plain");
+
+        std::env::set_var("TINYPROXY_TEST_SECRET", "from-env");
+        assert_eq!(
+            resolve_secret("env:TINYPROXY_TEST_SECRET").unwrap(),
+            "from-env"
+        );
+        assert!(resolve_secret("env:TINYPROXY_TEST_SECRET_UNSET").is_err());
+
+        let mut file = NamedTempFile::new().unwrap();
+        writeln!(file, "from-file").unwrap();
+        let reference = format!("file:{}", file.path().display());
+        assert_eq!(resolve_secret(&reference).unwrap(), "from-file");
+    }
+
+    #[test]
+    fn test_basic_auth_file() {
+        let mut file = NamedTempFile::new().unwrap();
+        writeln!(file, "# proxy credentials\n\nalice:s3cret").unwrap();
+
+        let config =
+            Config::parse_config(&format!("BasicAuthFile {}", file.path().display())).unwrap();
+        assert!(config.requires_auth());
+        assert!(config.basic_auth.is_none());
+
+        writeln!(file, "bob").unwrap();
+        assert!(Config::parse_config(&format!("BasicAuthFile {}", file.path().display())).is_err());
+        assert!(Config::parse_config("BasicAuthFile /nonexistent/htpasswd").is_err());
+    }
+
+    #[test]
+    fn test_secrets_are_redacted() {
+        let config = Config::parse_config(
+            "BasicAuth alice:s3cret\nUpstream http:parent:3128 bob:hunter2 .example.com",
+        )
+        .unwrap();
+        assert_eq!(config.upstream[0].username.as_deref(), Some("bob"));
+        assert_eq!(config.upstream[0].password.as_deref(), Some("hunter2"));
+        assert_eq!(config.upstream[0].domain.as_deref(), Some(".example.com"));
+        assert_eq!(
+            config.upstream[0].proxy_authorization().as_deref(),
+            Some("Basic Ym9iOmh1bnRlcjI=")
+        );
+
+        let debug = format!("{:?}", config);
+        let json = serde_json::to_string(&config).unwrap();
+        for dump in [debug, json] {
+            assert!(!dump.contains("s3cret"));
+            assert!(!dump.contains("hunter2"));
+            assert!(dump.contains(REDACTED));
+        }
+    }
+
+    #[test]
+    fn test_into_canary() {
+        let config = Config::parse_config(
+            "Port 8888\nListen 192.0.2.1\nPidFile /run/tinyproxy.pid\nAdminPort 9000\nMaxClients 7",
+        )
+        .unwrap()
+        .into_canary(18888);
+
+        assert_eq!(
+            config.get_listen_addresses(),
+            vec!["192.0.2.1:18888".parse().unwrap()]
+        );
+        assert!(!config.daemon);
+        assert_eq!(config.pidfile, None);
+        assert_eq!(config.admin_port, None);
+        assert_eq!(config.max_clients, 7);
+    }
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("0").unwrap(), 0);
+        assert_eq!(parse_size("1500").unwrap(), 1500);
+        assert_eq!(parse_size("64K").unwrap(), 64 * 1024);
+        assert_eq!(parse_size("10MB").unwrap(), 10 * 1024 * 1024);
+        assert_eq!(parse_size("2g").unwrap(), 2 * 1024 * 1024 * 1024);
+        assert!(parse_size("lots").is_err());
+        assert!(parse_size("M").is_err());
+    }
+
+    #[test]
+    fn test_parse_reverse_path() {
+        let rule = parse_reverse_path(
+            "\"/app/\" \"http://a.internal/\" weight=3 \"http://b.internal/\" balance=weighted",
+        )
+        .unwrap();
+        assert_eq!(rule.path, "/app/");
+        assert_eq!(rule.balance, BalanceStrategy::Weighted);
+        let weights: Vec<u32> = rule.backends.iter().map(|backend| backend.weight).collect();
+        assert_eq!(weights, [3, 1]);
+
+        assert!(parse_reverse_path("\"/app/\"").is_err());
+        assert!(parse_reverse_path("\"/app/\" weight=2 \"http://a.internal/\"").is_err());
+        assert!(parse_reverse_path("\"/app/\" \"http://a.internal/\" weight=0").is_err());
+        assert!(parse_reverse_path("\"/app/\" \"ftp://a.internal/\"").is_err());
+        assert!(parse_reverse_path("\"/app/\" \"http://a.internal/\" balance=random").is_err());
+
+        let check = parse_health_check("/healthz").unwrap();
+        assert_eq!((check.status, check.interval), (200, 10));
+        assert!(parse_health_check("/healthz 204 0").is_err());
+        assert!(parse_health_check("healthz").is_err());
+    }
+
+    #[test]
+    fn test_parse_mark() {
+        assert_eq!(parse_mark("42").unwrap(), 42);
+        assert_eq!(parse_mark("0x1F").unwrap(), 0x1f);
+        assert!(parse_mark("0x").is_err());
+        assert!(parse_mark("-1").is_err());
+    }
+
+    #[test]
+    fn test_parse_rate() {
+        assert_eq!(parse_rate("2MBps").unwrap(), 2 * 1024 * 1024);
+        assert_eq!(parse_rate("512K/s").unwrap(), 512 * 1024);
+        assert_eq!(parse_rate("100000").unwrap(), 100000);
+        assert!(parse_rate("fast").is_err());
+    }
+
+    #[test]
+    fn test_parse_request_rate() {
+        assert_eq!(parse_request_rate("10/s").unwrap(), 10.0);
+        assert_eq!(parse_request_rate("10").unwrap(), 10.0);
+        assert_eq!(parse_request_rate("120/m").unwrap(), 2.0);
+        assert_eq!(parse_request_rate("3600/h").unwrap(), 1.0);
+        assert!(parse_request_rate("0/s").is_err());
+        assert!(parse_request_rate("10/fortnight").is_err());
+    }
+
+    #[test]
+    fn test_deny_target_networks() {
+        let defaults = default_denied_targets();
+        assert_eq!(Config::default().deny_target_networks, defaults);
+
+        // Lines add to the defaults, which "default" does not repeat
+        let config = Config::parse_config("DenyTargetNetworks default 100.64.0.0/10").unwrap();
+        assert_eq!(config.deny_target_networks.len(), defaults.len() + 1);
+        assert_eq!(config.deny_target_networks.last().unwrap(), "100.64.0.0/10");
+
+        // "none" clears what was listed before it
+        let config = Config::parse_config(
+            "DenyTargetNetworks 100.64.0.0/10\nDenyTargetNetworks none\nDenyTargetNetworks ::1",
+        )
+        .unwrap();
+        assert_eq!(config.deny_target_networks, vec!["::1".to_string()]);
+        let config = Config::parse_config("DenyTargetNetworks none").unwrap();
+        assert!(config.deny_target_networks.is_empty());
     }
 }
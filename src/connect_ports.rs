@@ -0,0 +1,105 @@
+use crate::acl::IpList;
+use crate::config::Config;
+use std::net::IpAddr;
+use std::ops::RangeInclusive;
+
+/// Ports CONNECT may reach when no ConnectPort rule applies to everyone.
+const DEFAULT_PORTS: [u16; 2] = [443, 563];
+
+/// Which ports CONNECT requests may reach (ConnectPort). Rules naming a
+/// client network or user only widen what those clients may reach; rules
+/// naming neither apply to everyone and replace the default of 443 and 563.
+pub struct ConnectPorts {
+    rules: Vec<PortRule>,
+}
+
+struct PortRule {
+    ports: RangeInclusive<u16>,
+    clients: Option<IpList>,
+    user: Option<String>,
+}
+
+impl ConnectPorts {
+    pub fn new(config: &Config) -> Self {
+        let mut rules: Vec<PortRule> = config
+            .connect_ports
+            .iter()
+            .map(|rule| PortRule {
+                ports: rule.first..=rule.last,
+                clients: rule
+                    .client
+                    .as_ref()
+                    .map(|client| IpList::new(std::slice::from_ref(client), "ConnectPort")),
+                user: rule.user.clone(),
+            })
+            .collect();
+
+        if !rules
+            .iter()
+            .any(|rule| rule.clients.is_none() && rule.user.is_none())
+        {
+            rules.extend(DEFAULT_PORTS.iter().map(|&port| PortRule {
+                ports: port..=port,
+                clients: None,
+                user: None,
+            }));
+        }
+        Self { rules }
+    }
+
+    /// Whether `client`, authenticated as `user` if at all, may CONNECT to
+    /// `port`.
+    pub fn allows(&self, port: u16, client: IpAddr, user: Option<&str>) -> bool {
+        self.rules.iter().any(|rule| {
+            rule.ports.contains(&port)
+                && rule
+                    .clients
+                    .as_ref()
+                    .is_none_or(|clients| clients.contains(&client))
+                && rule.user.as_deref().is_none_or(|name| user == Some(name))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_connect_ports() {
+        let client: IpAddr = "192.0.2.7".parse().unwrap();
+        let other: IpAddr = "198.51.100.1".parse().unwrap();
+        let ports = |config: &str| ConnectPorts::new(&Config::parse_config(config).unwrap());
+
+        let default = ports("");
+        assert!(default.allows(443, client, None));
+        assert!(default.allows(563, client, None));
+        assert!(!default.allows(8443, client, None));
+
+        let policy = ports(
+            "ConnectPort 443\nConnectPort 8000-8100\n\
+             ConnectPort 22 client=192.0.2.0/24\nConnectPort * user=admin",
+        );
+        assert!(policy.allows(443, other, None));
+        assert!(!policy.allows(563, other, None));
+        assert!(policy.allows(8000, other, None));
+        assert!(policy.allows(8100, other, None));
+        assert!(!policy.allows(8101, other, None));
+        assert!(policy.allows(22, client, None));
+        assert!(!policy.allows(22, other, Some("bob")));
+        assert!(policy.allows(25, other, Some("admin")));
+
+        // Per-client rules keep the default for everyone else
+        let policy = ports("ConnectPort 22 client=192.0.2.7 user=ops");
+        assert!(policy.allows(443, other, None));
+        assert!(policy.allows(22, client, Some("ops")));
+        assert!(!policy.allows(22, client, None));
+        assert!(!policy.allows(22, other, Some("ops")));
+
+        let open = ports("ConnectPort 0");
+        assert!(open.allows(1, other, None));
+        assert!(open.allows(65535, other, None));
+        let open = ports("ConnectPort all");
+        assert!(open.allows(22, other, None));
+    }
+}
@@ -1,92 +1,257 @@
-use crate::acl::AccessControl;
-use crate::auth::Authenticator;
-use crate::config::Config;
+use crate::access_log::{AccessEntry, Exchange, ResponseMeter};
+use crate::acl::{IpList, TrustedProxies};
+use crate::auth::basic_auth_username;
+use crate::config::{Config, UpstreamConfig};
+use crate::connector::{
+    BoxedStream, ConnectFailure, Connector, FailureKind, HttpForwardConnector, HttpTunnelConnector,
+    Socks5Connector,
+};
+use crate::denial::Denial;
 use crate::error::{ProxyError, ProxyResult};
-use crate::filter::Filter;
-use crate::stats::Stats;
-use crate::utils::{copy_bidirectional, parse_http_request, HttpRequest};
+use crate::framing::{encode_chunk, remove_hop_by_hop, BodyFraming, BodyLength, RequestBody};
+use crate::gzip::accepts_gzip;
+use crate::ident::{self, IDENT_TIMEOUT};
+use crate::interceptor::{InterceptedResponse, LocalResponse, RequestContext, Verdict};
+use crate::proxy_protocol::parse_proxy_header;
+use crate::ratelimit::DestinationPermit;
+use crate::sni::{parse_client_hello, ClientHello, MAX_CLIENT_HELLO};
+use crate::state::ServerState;
+use crate::stats::{user_agent_family, Counter, Histogram, Stats};
+use crate::throttle::{RateLimiter, Throttled};
+#[cfg(any(feature = "rustls", feature = "native-tls"))]
+use crate::tls::TlsConnector;
+use crate::utils::{
+    authority, copy_bidirectional, html_escape, origin_form, parse_http_request, relay_exchange,
+    HeadScanner, HttpRequest,
+};
 
-use bytes::BytesMut;
-use log::{debug, warn};
-use std::net::SocketAddr;
+use bytes::{Buf, BytesMut};
+use log::{debug, info, warn};
+use std::net::{Ipv6Addr, SocketAddr};
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tokio::net::TcpStream;
 use tokio::sync::RwLock;
-use tokio::time::{timeout, Duration};
+use tokio::time::{timeout, Duration, Instant};
+
+/// Attempts made for an idempotent request whose upstream connection dies
+/// before any response arrives.
+const MAX_REQUEST_ATTEMPTS: u32 = 2;
+
+/// How long SNI routed tunnels wait for the client's ClientHello.
+const CLIENT_HELLO_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long a request head may take before ClientHeaderMinRate applies.
+const MIN_RATE_GRACE: Duration = Duration::from_secs(5);
 
 pub struct ConnectionHandler {
-    stream: TcpStream,
+    id: u64,
+    stream: BoxedStream,
+    local_addr: Option<SocketAddr>,
+    peer_addr: SocketAddr,
     client_addr: SocketAddr,
     config: Arc<Config>,
     stats: Arc<RwLock<Stats>>,
-    acl: AccessControl,
-    auth: Authenticator,
-    filter: Filter,
+    trusted_proxies: TrustedProxies,
+    diagnostics_clients: IpList,
+    state: Arc<ServerState>,
+    /// HTTP version of the responses the proxy writes to this client.
+    response_version: &'static str,
+    /// Whether pages the proxy serves itself may be sent gzipped.
+    accepts_gzip: bool,
+    /// User reported by the client's ident server.
+    ident: Option<String>,
+    /// Measurements of the request being handled, for the access log.
+    exchange: Exchange,
+    /// Request line of the request being handled, for error pages.
+    request_line: String,
+    /// The upstream connection's place under its DestinationConnectionLimit.
+    destination_permit: Option<DestinationPermit>,
+    /// Whether the connection stays open for another request once the
+    /// current one is done.
+    keep_alive: bool,
+    /// Target of the intercepted CONNECT tunnel whose decrypted requests
+    /// this handler serves (TlsIntercept).
+    intercepted: Option<String>,
+    /// Whether ReverseRoute sent the request being handled to a ReversePath
+    /// backend, which is connected even in DenyTargetNetworks.
+    reverse_routed: bool,
+    /// User the intercepted tunnel's CONNECT authenticated as.
+    tunnel_user: Option<String>,
 }
 
 impl ConnectionHandler {
-    pub fn new(
-        stream: TcpStream,
+    pub fn new(stream: TcpStream, client_addr: SocketAddr, state: Arc<ServerState>) -> Self {
+        let local_addr = stream.local_addr().ok();
+        Self::with_stream(Box::new(stream), local_addr, client_addr, state)
+    }
+
+    fn with_stream(
+        stream: BoxedStream,
+        local_addr: Option<SocketAddr>,
         client_addr: SocketAddr,
-        config: Arc<Config>,
-        stats: Arc<RwLock<Stats>>,
+        state: Arc<ServerState>,
     ) -> Self {
-        let acl = AccessControl::new(&config);
-        let auth = Authenticator::new(&config);
-        let filter = Filter::new(&config);
+        let config = state.config.clone();
+        let stats = state.stats.clone();
+        let trusted_proxies = TrustedProxies::new(&config);
+        let diagnostics_clients =
+            IpList::new(&config.error_diagnostics_allow, "ErrorDiagnosticsAllow");
 
         Self {
+            id: 0,
             stream,
+            local_addr,
+            peer_addr: client_addr,
             client_addr,
             config,
             stats,
-            acl,
-            auth,
-            filter,
+            trusted_proxies,
+            diagnostics_clients,
+            state,
+            response_version: "1.1",
+            accepts_gzip: false,
+            ident: None,
+            exchange: Exchange::new(),
+            request_line: String::new(),
+            destination_permit: None,
+            keep_alive: false,
+            intercepted: None,
+            reverse_routed: false,
+            tunnel_user: None,
         }
     }
 
     pub async fn handle(mut self) -> ProxyResult<()> {
-        debug!("Handling connection from {}", self.client_addr);
-
-        // Check access control
-        if !self.acl.is_allowed(&self.client_addr) {
-            warn!("Access denied for {}", self.client_addr);
-            self.send_error_response(403, "Forbidden").await?;
-            return Err(ProxyError::AccessDenied(format!(
-                "IP {} is not allowed",
-                self.client_addr.ip()
-            )));
+        let registration = self.state.connections.register(self.peer_addr);
+        self.id = registration.id();
+        let stream = std::mem::replace(&mut self.stream, Box::new(tokio::io::empty()));
+        self.stream = Box::new(registration.meter(stream));
+
+        tokio::select! {
+            result = self.serve() => result,
+            _ = registration.closed() => {
+                let by = if self.state.connections.is_draining() {
+                    "for shutdown"
+                } else {
+                    "by administrator"
+                };
+                warn!("Connection {} from {} closed {}", self.id, self.client_addr, by);
+                Ok(())
+            }
         }
+    }
 
-        // Read the initial request
-        let mut buffer = BytesMut::with_capacity(self.config.buffer_size);
-        let mut total_read = 0;
+    /// Turn the client away with "503 Service Unavailable" without
+    /// serving it, e.g. when it already holds too many connections.
+    pub async fn reject(mut self, reason: &str) -> ProxyResult<()> {
+        self.state.counters.add(Counter::RequestsDenied, 1);
+        let error = ProxyError::ResourceExhausted(reason.to_string());
+        let detail = detail_paragraph(&error.error_message());
+        self.send_error_page(503, "Service Unavailable", &detail, Some("1"))
+            .await?;
+        self.stream.shutdown().await.map_err(ProxyError::Io)?;
+
+        // Read what the client sent meanwhile, so closing with unread data
+        // does not reset the connection before the page arrives
+        let mut discard = [0u8; 1024];
+        let _ = timeout(Duration::from_secs(1), async {
+            while matches!(self.stream.read(&mut discard).await, Ok(n) if n > 0) {}
+        })
+        .await;
+        Ok(())
+    }
 
+    async fn serve(&mut self) -> ProxyResult<()> {
+        debug!("Handling connection from {}", self.peer_addr);
+
+        // Connections from trusted proxies carry the real client address in
+        // a PROXY header or X-Forwarded-For, which access control applies to.
+        // Intercepted tunnels carry requests of a client already known.
+        let peer_trusted =
+            self.intercepted.is_none() && self.trusted_proxies.contains(&self.peer_addr.ip());
+        let mut expect_proxy_header = peer_trusted && self.config.proxy_protocol;
+
+        // Ask the client's ident server while the request arrives
+        let mut ident_lookup = match self.local_addr {
+            Some(local) if self.config.ident_lookup && self.intercepted.is_none() => Some(
+                tokio::spawn(ident::lookup(self.peer_addr, local, IDENT_TIMEOUT)),
+            ),
+            _ => None,
+        };
+
+        // Requests follow each other on the connection while it is kept
+        // alive; bytes read past one request stay buffered for the next
+        let mut buffer = BytesMut::with_capacity(self.config.buffer_size);
+        let mut idle_timeout = None;
         loop {
-            let timeout_duration = Duration::from_secs(self.config.timeout);
-            let n = timeout(timeout_duration, self.stream.read_buf(&mut buffer))
-                .await
-                .map_err(|_| ProxyError::Timeout)?
-                .map_err(ProxyError::Io)?;
+            let mut request = match self
+                .read_request(&mut buffer, &mut expect_proxy_header, idle_timeout)
+                .await?
+            {
+                Some(request) => request,
+                None => return Ok(()),
+            };
 
-            if n == 0 {
-                if total_read == 0 {
-                    debug!("Client closed connection before sending any data");
-                    return Ok(());
+            // Requests in intercepted tunnels name just the path
+            if let Some(authority) = &self.intercepted {
+                if request.uri.starts_with('/') {
+                    request.uri = format!("https://{}{}", authority, request.uri);
                 }
-                break;
             }
 
-            total_read += n;
+            if peer_trusted && !self.config.proxy_protocol {
+                if let Some(forwarded_for) = request.headers.get_combined("x-forwarded-for") {
+                    let client_ip = self
+                        .trusted_proxies
+                        .client_from_forwarded_for(self.peer_addr.ip(), &forwarded_for);
+                    debug!(
+                        "X-Forwarded-For from {} reports client {}",
+                        self.peer_addr, client_ip
+                    );
+                    self.client_addr = SocketAddr::new(client_ip, self.peer_addr.port());
+                    self.state.connections.set_client(self.id, self.client_addr);
+                }
+            }
 
-            // Check if we have a complete HTTP request
-            if let Some(end_of_headers) = find_end_of_headers(&buffer) {
-                let request_data = buffer.split_to(end_of_headers + 4); // +4 for \r\n\r\n
-                let request = parse_http_request(&request_data)?;
+            if let Some(lookup) = ident_lookup.take() {
+                self.ident = lookup.await.ok().flatten();
+                if let Some(user) = &self.ident {
+                    info!("Ident for {}: {}", self.peer_addr, user);
+                }
+            }
 
-                return self.handle_request(request, buffer).await;
+            self.handle_request(request, &mut buffer).await?;
+            if !self.keep_alive || self.state.connections.is_draining() {
+                return Ok(());
+            }
+            debug!("Keeping connection from {} open", self.client_addr);
+            idle_timeout = Some(Duration::from_secs(self.config.keep_alive_timeout));
+        }
+    }
+
+    /// Read the next request head into `buffer`. `idle_timeout` is how long
+    /// a kept-alive connection may wait for it; the connection then closes
+    /// quietly, as it does when the client closes it between requests.
+    /// Once the head has begun, it must be complete within
+    /// ClientHeaderTimeout.
+    async fn read_request(
+        &mut self,
+        buffer: &mut BytesMut,
+        expect_proxy_header: &mut bool,
+        idle_timeout: Option<Duration>,
+    ) -> ProxyResult<Option<HttpRequest>> {
+        let mut head_scanner = HeadScanner::new();
+        let mut head_started = None;
+
+        loop {
+            // Check if we have a complete HTTP request
+            if !*expect_proxy_header {
+                if let Some(end_of_headers) = head_scanner.find(buffer) {
+                    let request_data = buffer.split_to(end_of_headers + 4); // +4 for \r\n\r\n
+                    return parse_http_request(&request_data).map(Some);
+                }
             }
 
             // Prevent buffer from growing too large
@@ -95,58 +260,211 @@ impl ConnectionHandler {
                     "Request headers too large".to_string(),
                 ));
             }
+
+            let waiting = buffer.is_empty();
+            if !waiting {
+                head_started.get_or_insert_with(Instant::now);
+            }
+            let head_deadline =
+                head_started.and_then(|started| self.head_deadline(started, buffer.len()));
+            let timeout_duration = match (idle_timeout, head_deadline) {
+                (Some(idle), _) if waiting => idle,
+                (_, Some(deadline)) => deadline
+                    .saturating_duration_since(Instant::now())
+                    .min(Duration::from_secs(self.config.timeout)),
+                _ => Duration::from_secs(self.config.timeout),
+            };
+            let idle = waiting && idle_timeout.is_some();
+            let read = tokio::select! {
+                read = timeout(timeout_duration, self.stream.read_buf(buffer)) => read,
+                _ = self.state.connections.draining(), if idle => {
+                    debug!("Closing idle connection from {} for shutdown", self.client_addr);
+                    return Ok(None);
+                }
+            };
+            let n = match read {
+                Ok(result) => result.map_err(ProxyError::Io)?,
+                Err(_) if idle => {
+                    debug!("Idle connection from {} timed out", self.client_addr);
+                    return Ok(None);
+                }
+                Err(_) if head_deadline.is_some_and(|deadline| Instant::now() >= deadline) => {
+                    warn!(
+                        "Closing connection from {}: request head too slow, {} bytes in {:?}",
+                        self.client_addr,
+                        buffer.len(),
+                        head_started
+                            .map(|started| started.elapsed())
+                            .unwrap_or_default()
+                    );
+                    self.state.counters.add(Counter::SlowClients, 1);
+                    return Ok(None);
+                }
+                Err(_) => return Err(ProxyError::Timeout),
+            };
+
+            if n == 0 {
+                if waiting {
+                    debug!("Client closed connection before sending any data");
+                    return Ok(None);
+                }
+                return Err(ProxyError::InvalidRequest("Incomplete request".to_string()));
+            }
+
+            if *expect_proxy_header {
+                if let Some(header) = parse_proxy_header(buffer)? {
+                    buffer.advance(header.length);
+                    head_scanner.reset();
+                    if let Some(source) = header.source {
+                        debug!(
+                            "PROXY header from {} reports client {}",
+                            self.peer_addr, source
+                        );
+                        self.client_addr = source;
+                        self.state.connections.set_client(self.id, source);
+                    }
+                    *expect_proxy_header = false;
+                }
+            }
         }
+    }
 
-        Err(ProxyError::InvalidRequest("Incomplete request".to_string()))
+    /// When a request head begun at `started`, with `received` bytes so
+    /// far, must be complete: within ClientHeaderTimeout, and by the time
+    /// `received` stops meeting ClientHeaderMinRate.
+    fn head_deadline(&self, started: Instant, received: usize) -> Option<Instant> {
+        let overall = match self.config.client_header_timeout {
+            0 => None,
+            seconds => Some(started + Duration::from_secs(seconds)),
+        };
+        let by_rate = match self.config.client_header_min_rate {
+            0 => None,
+            rate => {
+                let allowed = Duration::from_secs_f64(received as f64 / rate as f64);
+                Some(started + allowed.max(MIN_RATE_GRACE))
+            }
+        };
+        overall.into_iter().chain(by_rate).min()
     }
 
     async fn handle_request(
         &mut self,
         request: HttpRequest,
-        remaining_data: BytesMut,
+        buffer: &mut BytesMut,
     ) -> ProxyResult<()> {
-        debug!(
-            "Processing {} {} HTTP/{}",
+        let request_line = format!(
+            "{} {} HTTP/{}",
             request.method, request.uri, request.version
         );
+        debug!("Processing {}", request_line);
+        let agent = user_agent_family(request.headers.get("user-agent"));
+        let tunnel = request.method == "CONNECT";
+        let method = request.method.clone();
+        let host = request_host(&request);
+        let logged = self
+            .state
+            .access_log
+            .is_enabled()
+            .then(|| (request.clone(), chrono::Local::now()));
 
-        // Update stats
-        {
-            let mut stats = self.stats.write().await;
-            stats.requests_processed += 1;
+        self.exchange = Exchange::new();
+        self.request_line.clone_from(&request_line);
+        self.keep_alive = false;
+        let result = self.process_request(request, buffer).await;
+        self.destination_permit = None;
+        let duration = self.exchange.started().elapsed();
+        // The fields are what JSON logging shows of the request
+        info!(
+            client_ip:% = self.client_addr.ip(),
+            method = method.as_str(),
+            host = host.as_deref(),
+            status = self.exchange.status,
+            bytes = self.exchange.response_bytes,
+            duration_ms = duration.as_millis() as u64;
+            "{}",
+            self.exchange
+                .format_line(self.client_addr.ip(), &request_line, duration)
+        );
+        if let Some((request, time)) = &logged {
+            let user = self
+                .authenticated_user(request)
+                .filter(|_| self.state.authenticator.is_enabled());
+            self.state.access_log.record(&AccessEntry {
+                client: self.client_addr.ip(),
+                ident: self.ident.as_deref(),
+                user: user.as_deref(),
+                time: *time,
+                request,
+                exchange: &self.exchange,
+                duration,
+            });
         }
 
-        // Check authentication if required
-        if let Some(_) = &self.config.basic_auth {
-            if !self.auth.authenticate(&request)? {
-                self.send_proxy_auth_required().await?;
-                return Err(ProxyError::AuthenticationFailed);
-            }
-        }
+        self.state.counters.record_user_agent(
+            &agent,
+            self.exchange.request_bytes + self.exchange.response_bytes,
+        );
+        let histogram = if tunnel {
+            Histogram::TunnelDurations
+        } else {
+            Histogram::RequestDurations
+        };
+        self.state.counters.record_duration(histogram, duration);
+        result
+    }
+
+    async fn process_request(
+        &mut self,
+        mut request: HttpRequest,
+        buffer: &mut BytesMut,
+    ) -> ProxyResult<()> {
+        self.state.counters.add(Counter::RequestsProcessed, 1);
+        self.response_version = request.response_version();
 
-        // Check for statistics request
-        if let Some(stat_host) = &self.config.stat_host {
-            let host_header = request.headers.get("host").unwrap_or(&request.uri);
-            if host_header.contains(stat_host) {
-                return self.handle_stats_request().await;
+        // WebSocket URIs stand for the HTTP(S) requests opening them
+        for (websocket, http) in [("ws://", "http://"), ("wss://", "https://")] {
+            if let Some(rest) = request.uri.strip_prefix(websocket) {
+                request.uri = format!("{}{}", http, rest);
             }
         }
+        self.accepts_gzip = request
+            .headers
+            .get_combined("Accept-Encoding")
+            .is_some_and(|value| accepts_gzip(&value));
 
-        // Apply filters
-        if self.config.filter_urls && !self.filter.is_allowed(&request.uri)? {
-            warn!("Request blocked by filter: {}", request.uri);
-            self.send_error_response(403, "Forbidden by filter").await?;
-            return Err(ProxyError::FilterBlocked(request.uri.clone()));
+        // Run the policy stages; any of them may answer the request itself
+        let ctx = self.request_context();
+        if let Verdict::Respond(response) = self
+            .state
+            .interceptors
+            .on_request(&ctx, &mut request)
+            .await?
+        {
+            self.send_response(&response).await?;
+            return response.error.map_or(Ok(()), Err);
         }
+        self.reverse_routed = ctx.reverse_routed.load(Ordering::Relaxed);
 
         // Handle different request methods
         match request.method.as_str() {
             "CONNECT" => self.handle_connect_request(request).await,
             "GET" | "POST" | "PUT" | "DELETE" | "HEAD" | "OPTIONS" | "PATCH" => {
-                self.handle_http_request(request, remaining_data).await
+                // Upgraded connections last as long as tunnels do
+                match self.config.max_request_duration {
+                    0 => self.handle_http_request(request, buffer).await,
+                    _ if request.is_upgrade() => self.handle_http_request(request, buffer).await,
+                    limit => {
+                        let limit = Duration::from_secs(limit);
+                        match timeout(limit, self.handle_http_request(request, buffer)).await {
+                            Ok(result) => result,
+                            Err(_) => self.abort_request(limit).await,
+                        }
+                    }
+                }
             }
             _ => {
-                self.send_error_response(405, "Method Not Allowed").await?;
+                self.send_error_page(405, "Method Not Allowed", "", None)
+                    .await?;
                 Err(ProxyError::InvalidRequest(format!(
                     "Unsupported method: {}",
                     request.method
@@ -160,256 +478,1742 @@ impl ConnectionHandler {
 
         // Parse the target host and port
         let (host, port) = parse_host_port(&request.uri)?;
+        #[cfg(feature = "rustls")]
+        if self.intercepted.is_none() && self.state.tls_interception.intercepts(&host) {
+            let user = self
+                .authenticated_user(&request)
+                .filter(|_| self.state.authenticator.is_enabled());
+            return self.intercept_tunnel(&host, port, user).await;
+        }
+        if self.state.sni_router.is_enabled() {
+            return self.handle_routed_tunnel(&host, port).await;
+        }
+
+        // Connect to the target server
+        let connector = self.tunnel_connector(self.upstream_for(&host).as_ref());
+        let target_stream = self.connect_to_target(&host, port, connector).await?;
+        let _gauge = UpstreamGauge::open(self.stats.clone(), &host).await;
 
-        // Check if the port is allowed for CONNECT requests
-        if !self.config.connect_ports.contains(&port) {
-            warn!("CONNECT to port {} not allowed", port);
-            self.send_error_response(403, "Port not allowed").await?;
-            return Err(ProxyError::AccessDenied(format!(
-                "CONNECT to port {} is not allowed",
-                port
+        self.send_connection_established().await?;
+        self.relay_tunnel(target_stream, &[]).await
+    }
+
+    /// Decrypt a CONNECT tunnel (TlsIntercept) and handle the requests in it
+    /// like any other, encrypting them again towards the origin.
+    #[cfg(feature = "rustls")]
+    async fn intercept_tunnel(
+        &mut self,
+        host: &str,
+        port: u16,
+        user: Option<String>,
+    ) -> ProxyResult<()> {
+        self.send_connection_established().await?;
+
+        let stream = std::mem::replace(&mut self.stream, Box::new(tokio::io::empty()));
+        let handshake = self.state.tls_interception.accept(stream, host);
+        let stream = match timeout(Duration::from_secs(self.config.timeout), handshake).await {
+            Ok(Ok(stream)) => stream,
+            Ok(Err(e)) => {
+                return Err(ProxyError::Tls(format!(
+                    "TLS handshake with {} for {} failed: {}",
+                    self.client_addr, host, e
+                )))
+            }
+            Err(_) => return Err(ProxyError::Timeout),
+        };
+        debug!(
+            "Intercepting tunnel of {} to {}:{}",
+            self.client_addr, host, port
+        );
+
+        let mut handler =
+            Self::with_stream(stream, self.local_addr, self.peer_addr, self.state.clone());
+        handler.id = self.id;
+        handler.client_addr = self.client_addr;
+        handler.ident = self.ident.clone();
+        handler.tunnel_user = user;
+        let target = authority(host, port);
+        handler.intercepted = Some(match target.strip_suffix(":443") {
+            Some(default_port) => default_port.to_string(),
+            None => target,
+        });
+        // Boxed, since serving those requests is what led here
+        let result = Box::pin(handler.serve()).await;
+        let _ = handler.stream.shutdown().await;
+        result
+    }
+
+    /// CONNECT tunnel whose egress depends on the TLS server name the client
+    /// sends (SniRoute). The name only arrives once the tunnel is open, so
+    /// the target is connected after the client was told it is established
+    /// and failures can only close the connection.
+    async fn handle_routed_tunnel(&mut self, host: &str, port: u16) -> ProxyResult<()> {
+        self.send_connection_established().await?;
+        let hello = self.read_client_hello().await?;
+
+        // Tunnels without a server name are routed by their target
+        let server_name = match parse_client_hello(&hello) {
+            ClientHello::ServerName(name) => name,
+            _ => host.to_string(),
+        };
+        let connector = match self.state.sni_router.route(&server_name) {
+            Some(connector) => {
+                debug!("Tunnel to {}:{} routed by SNI {}", host, port, server_name);
+                connector
+            }
+            None => self.tunnel_connector(self.upstream_for(host).as_ref()),
+        };
+
+        self.state
+            .connections
+            .set_target(self.id, &authority(host, port));
+        self.destination_permit = self.state.connection_limits.acquire(host).await;
+        if self.destination_permit.is_none() {
+            warn!(
+                "Connection limit for {} reached, closing tunnel of {}",
+                host, self.client_addr
+            );
+            self.state.counters.add(Counter::RequestsDenied, 1);
+            return Err(ProxyError::ResourceExhausted(format!(
+                "too many connections to {}",
+                host
             )));
         }
+        let connecting = Instant::now();
+        let target_stream = connector.connect(host, port).await.map_err(|failure| {
+            warn!("Upstream connection failed: {}", failure);
+            self.record_target_denial(&failure);
+            failure.to_error()
+        })?;
+        self.exchange.connect_time = Some(connecting.elapsed());
+        let _gauge = UpstreamGauge::open(self.stats.clone(), host).await;
 
-        // Connect to the target server
-        let target_addr = format!("{}:{}", host, port);
-        let target_stream = timeout(Duration::from_secs(30), TcpStream::connect(&target_addr))
-            .await
-            .map_err(|_| ProxyError::Timeout)?
-            .map_err(|e| {
-                ProxyError::Upstream(format!("Failed to connect to {}: {}", target_addr, e))
-            })?;
+        self.relay_tunnel(target_stream, &hello).await
+    }
 
-        debug!("Connected to {}", target_addr);
+    /// Read the start of a tunnel until it holds a whole ClientHello or is
+    /// clearly something else. Clients of protocols where the server speaks
+    /// first send nothing, so the wait is short.
+    async fn read_client_hello(&mut self) -> ProxyResult<BytesMut> {
+        let mut hello = BytesMut::with_capacity(2048);
+        let read = async {
+            while parse_client_hello(&hello) == ClientHello::Incomplete
+                && hello.len() < MAX_CLIENT_HELLO
+            {
+                if self.stream.read_buf(&mut hello).await? == 0 {
+                    break;
+                }
+            }
+            Ok::<(), std::io::Error>(())
+        };
+        match timeout(CLIENT_HELLO_TIMEOUT, read).await {
+            Ok(result) => result.map_err(ProxyError::Io)?,
+            Err(_) => debug!("No ClientHello from {}", self.client_addr),
+        }
+        Ok(hello)
+    }
 
-        // Send 200 Connection Established response
-        let response = b"HTTP/1.1 200 Connection established\r\n\r\n";
+    async fn send_connection_established(&mut self) -> ProxyResult<()> {
+        let response = format!(
+            "HTTP/{} 200 Connection established\r\n\r\n",
+            self.response_version
+        );
         self.stream
-            .write_all(response)
+            .write_all(response.as_bytes())
             .await
             .map_err(ProxyError::Io)?;
+        self.exchange.status = Some(200);
+        Ok(())
+    }
 
-        // Start bidirectional copying
-        let (client_read, client_write) = self.stream.split();
-        let (target_read, target_write) = target_stream.into_split();
+    /// Relay a CONNECT tunnel until either side closes, first sending
+    /// `initial`, data already read from the client.
+    async fn relay_tunnel(
+        &mut self,
+        mut target_stream: BoxedStream,
+        initial: &[u8],
+    ) -> ProxyResult<()> {
+        target_stream
+            .write_all(initial)
+            .await
+            .map_err(ProxyError::Io)?;
+
+        let (upload_limiters, download_limiters) = self.bandwidth_limiters();
+        let idle_timeout = self.tunnel_idle_timeout();
+        let (client_read, client_write) = tokio::io::split(&mut self.stream);
+        let (target_read, target_write) = tokio::io::split(target_stream);
+        let client_read = Throttled::new(client_read, upload_limiters);
+        let target_read = Throttled::new(target_read, download_limiters);
 
-        let bytes_transferred =
-            copy_bidirectional(client_read, target_write, target_read, client_write).await?;
+        let (uploaded, downloaded) = copy_bidirectional(
+            client_read,
+            target_write,
+            target_read,
+            client_write,
+            idle_timeout,
+        )
+        .await?;
+        self.exchange.request_bytes = initial.len() as u64 + uploaded;
+        self.exchange.response_bytes = downloaded;
 
         debug!(
             "CONNECT tunnel closed, transferred {} bytes",
-            bytes_transferred
+            self.exchange.request_bytes + downloaded
         );
 
-        // Update stats
-        {
-            let mut stats = self.stats.write().await;
-            stats.bytes_transferred += bytes_transferred;
-        }
+        self.record_traffic();
 
         Ok(())
     }
 
     async fn handle_http_request(
         &mut self,
-        request: HttpRequest,
-        remaining_data: BytesMut,
+        mut request: HttpRequest,
+        buffer: &mut BytesMut,
     ) -> ProxyResult<()> {
         debug!("Handling HTTP request to {}", request.uri);
 
         // Handle both absolute and relative URLs
-        let (host, port, target_uri) = if request.uri.starts_with("http://") || request.uri.starts_with("https://") {
-            // Absolute URL
-            let url = url::Url::parse(&request.uri)
-                .map_err(|e| ProxyError::InvalidRequest(format!("Invalid URL: {}", e)))?;
-            
-            let host = url.host_str()
-                .ok_or_else(|| ProxyError::InvalidRequest("No host in URL".to_string()))?;
-            let port = url.port().unwrap_or(if url.scheme() == "https" { 443 } else { 80 });
-            
-            (host.to_string(), port, request.uri.clone())
-        } else {
-            // Relative URL - extract host from Host header
-            let host = request.headers.get("host")
-                .ok_or_else(|| ProxyError::InvalidRequest("No Host header for relative URL".to_string()))?;
-            
-            // Parse host:port
-            let (hostname, port) = if let Some(colon_pos) = host.rfind(':') {
-                let hostname = &host[..colon_pos];
-                let port_str = &host[colon_pos + 1..];
-                let port = port_str.parse::<u16>()
-                    .map_err(|_| ProxyError::InvalidRequest(format!("Invalid port in Host header: {}", port_str)))?;
-                (hostname.to_string(), port)
+        let (host, port) =
+            if request.uri.starts_with("http://") || request.uri.starts_with("https://") {
+                // Absolute URL
+                let url = url::Url::parse(&request.uri)
+                    .map_err(|e| ProxyError::InvalidRequest(format!("Invalid URL: {}", e)))?;
+
+                let host = url
+                    .host_str()
+                    .ok_or_else(|| ProxyError::InvalidRequest("No host in URL".to_string()))?;
+                let port = url
+                    .port()
+                    .unwrap_or(if url.scheme() == "https" { 443 } else { 80 });
+
+                (host.trim_matches(['[', ']']).to_string(), port)
             } else {
-                (host.clone(), 80)
+                // Relative URL - extract host from Host header
+                let host = request.headers.get("host").ok_or_else(|| {
+                    ProxyError::InvalidRequest("No Host header for relative URL".to_string())
+                })?;
+                parse_host_port(host)?
             };
-            
-            // Construct absolute URL for upstream
-            let target_uri = format!("http://{}:{}{}", hostname, port, request.uri);
-            (hostname, port, target_uri)
-        };
 
-        // Connect to the target server
-        let target_addr = format!("{}:{}", host, port);
-        let mut target_stream = timeout(Duration::from_secs(30), TcpStream::connect(&target_addr))
-            .await
-            .map_err(|_| ProxyError::Timeout)?
-            .map_err(|e| {
-                ProxyError::Upstream(format!("Failed to connect to {}: {}", target_addr, e))
-            })?;
+        // Requests to switch protocols, such as WebSocket handshakes, keep
+        // their Upgrade and are relayed until either side closes. Others end
+        // with their response, after which the client may send another
+        // request. Direct HTTP/1.1 origin connections are then kept in the
+        // pool for the next request to the same origin.
+        let upgrade = request.is_upgrade();
+        let keep_alive = !upgrade && self.config.keep_alive_timeout > 0 && request.keep_alive();
+        let upstream = self.upstream_for(&host);
+        let https = request.uri.starts_with("https://");
+        let pool_key = (!upgrade
+            && upstream.is_none()
+            && request.version == "1.1"
+            && self.state.origin_pool.is_enabled())
+        .then(|| {
+            // Backend connections skip DenyTargetNetworks, so they are not
+            // handed to requests that did not come through ReverseRoute
+            let kind = if self.reverse_routed { "backend+" } else { "" };
+            let scheme = if https { "https" } else { "http" };
+            format!("{}{}://{}", kind, scheme, authority(&host, port))
+        });
+        let protocol = request.headers.get("upgrade").map(str::to_string);
+        remove_hop_by_hop(&mut request.headers);
+        request.headers.remove("Upgrade");
+        match protocol.filter(|_| upgrade) {
+            Some(protocol) => {
+                request.headers.insert("Upgrade", protocol);
+                request.headers.insert("Connection", "Upgrade");
+            }
+            None if pool_key.is_none() => request.headers.insert("Connection", "close"),
+            None => {}
+        }
 
-        debug!("Connected to {}", target_addr);
+        // A body that arrived with the head is sent along with it. Chunked
+        // ones are decoded, and sent with a Content-Length when no other
+        // transfer coding applies.
+        let body_length = if upgrade {
+            BodyLength::UntilClose
+        } else {
+            BodyLength::of_request(&request)
+        };
+        let mut framing = BodyFraming::new(body_length);
+        let mut payload = BytesMut::new();
+        let buffered = match framing.decode(buffer, &mut payload) {
+            Ok(buffered) => buffered,
+            Err(e) => {
+                self.send_error_page(400, "Bad Request", "", None).await?;
+                return Err(ProxyError::InvalidRequest(format!(
+                    "Invalid request body: {}",
+                    e
+                )));
+            }
+        };
+        let body_limit = match self.config.max_request_body_size {
+            0 => None,
+            _ if upgrade => None,
+            limit => Some(limit),
+        };
+        if body_limit.is_some_and(|limit| payload.len() as u64 > limit) {
+            return self.reject_body().await;
+        }
+        let body_buffered = framing.is_done();
+        let mut body = BytesMut::new();
+        if body_buffered {
+            buffer.advance(buffered);
+            let only_chunked = request
+                .headers
+                .get_combined("transfer-encoding")
+                .is_some_and(|codings| codings.trim().eq_ignore_ascii_case("chunked"));
+            if only_chunked {
+                request.headers.remove("Transfer-Encoding");
+                request
+                    .headers
+                    .insert("Content-Length", payload.len().to_string());
+                body = payload;
+            } else if body_length == BodyLength::Chunked {
+                encode_chunk(&payload, &mut body);
+                encode_chunk(b"", &mut body);
+            } else {
+                body = payload;
+            }
+        }
 
-        // Reconstruct and send the HTTP request
-        let mut request_data = reconstruct_http_request(&request, &target_uri);
-        if !remaining_data.is_empty() {
-            request_data.extend_from_slice(&remaining_data);
+        // Reconstruct the HTTP request, in absolute form for an upstream HTTP
+        // proxy. SOCKS5 proxies just carry it to the origin, as do tunnels
+        // through either for HTTPS origins.
+        let (connector, mut request_data): (Arc<dyn Connector>, _) = match upstream {
+            upstream if https => match self.https_connector(upstream.as_ref()) {
+                Ok(connector) => (connector, reconstruct_http_request(&request)),
+                Err(error) => {
+                    let detail = detail_paragraph(&error.error_message());
+                    self.send_error_page(502, "Bad Gateway", &detail, None)
+                        .await?;
+                    return Err(error);
+                }
+            },
+            Some(upstream) if upstream.upstream_type == "socks5" => (
+                self.tunnel_connector(Some(&upstream)),
+                reconstruct_http_request(&request),
+            ),
+            Some(upstream) => {
+                let target = authority(&host, port);
+                let authority = target.strip_suffix(":80").unwrap_or(&target);
+                (
+                    Arc::new(HttpForwardConnector::new(
+                        self.state.connector.clone(),
+                        &upstream.host,
+                        upstream.port,
+                    )),
+                    reconstruct_upstream_request(
+                        &request,
+                        authority,
+                        upstream.proxy_authorization().as_deref(),
+                    ),
+                )
+            }
+            None => (
+                self.tunnel_connector(None),
+                reconstruct_http_request(&request),
+            ),
+        };
+
+        if body_buffered {
+            request_data.extend_from_slice(&body);
+            if !self.state.mirrors.is_empty() {
+                self.state
+                    .mirrors
+                    .mirror(&self.state.connector, &request, &body);
+            }
+        } else if !self.state.mirrors.is_empty() {
+            debug!("Not mirroring {}: body not buffered", request.uri);
         }
 
-        target_stream
-            .write_all(&request_data)
-            .await
-            .map_err(ProxyError::Io)?;
+        // Idempotent requests that are completely buffered can be sent again
+        // if the upstream connection dies before the response starts.
+        let retryable = is_idempotent(&request.method) && body_buffered;
+
+        let state = self.state.clone();
+        let _backend = state.reverse_proxy.track(&request.uri);
+
+        // Pooled connections may have been closed by the origin just now,
+        // so they only carry requests that can be sent again.
+        let mut attempt = 1;
+        let (mut target_stream, _gauge, response_start) = loop {
+            let pooled = match &pool_key {
+                Some(key) if retryable => self.checkout_pooled(key, &host, port).await,
+                _ => None,
+            };
+            let reused = pooled.is_some();
+            let mut target_stream = match pooled {
+                Some(stream) => stream,
+                None => {
+                    self.connect_to_target(&host, port, connector.clone())
+                        .await?
+                }
+            };
+            let gauge = UpstreamGauge::open(self.stats.clone(), &host).await;
+
+            if !retryable {
+                target_stream
+                    .write_all(&request_data)
+                    .await
+                    .map_err(ProxyError::Io)?;
+                break (target_stream, gauge, BytesMut::new());
+            }
+
+            match send_and_await_response(&mut target_stream, &request_data).await {
+                Ok(response_start) => break (target_stream, gauge, response_start),
+                Err(e) if reused => {
+                    debug!(
+                        "Pooled connection to {}:{} failed ({}), retrying {} {}",
+                        host, port, e, request.method, request.uri
+                    );
+                }
+                Err(e) if attempt < MAX_REQUEST_ATTEMPTS => {
+                    debug!(
+                        "Upstream {}:{} failed before responding ({}), retrying {} {}",
+                        host, port, e, request.method, request.uri
+                    );
+                    attempt += 1;
+                }
+                Err(e) => {
+                    let error = ProxyError::Upstream(format!(
+                        "{}:{} closed the connection without a response: {}",
+                        host, port, e
+                    ));
+                    let detail = detail_paragraph(&error.error_message());
+                    self.send_error_page(502, "Bad Gateway", &detail, None)
+                        .await?;
+                    return Err(error);
+                }
+            }
+        };
+
+        self.exchange.request_bytes = request_data.len() as u64;
 
         // Start relaying data between client and server
-        let (client_read, client_write) = self.stream.split();
-        let (target_read, target_write) = target_stream.into_split();
+        let interceptors = self.state.interceptors.clone();
+        let ctx = self.request_context();
+        let (upload_limiters, download_limiters) = self.bandwidth_limiters();
+        let idle_timeout = self.tunnel_idle_timeout();
+        let (client_read, client_write) = tokio::io::split(&mut self.stream);
+        let (target_read, target_write) = tokio::io::split(&mut target_stream);
+        let client_read = Throttled::new(client_read, upload_limiters);
+        let target_read = Throttled::new(target_read, download_limiters);
 
-        let bytes_transferred =
-            copy_bidirectional(client_read, target_write, target_read, client_write).await?;
+        let mut request_body = RequestBody::new(
+            client_read,
+            buffer,
+            if body_buffered {
+                BodyLength::Fixed(0)
+            } else {
+                body_length
+            },
+        );
+        if let Some(limit) = body_limit {
+            request_body = request_body.with_limit(limit);
+        }
+        let mut response =
+            InterceptedResponse::new(target_read, response_start, interceptors, ctx, request)
+                .with_keep_alive(keep_alive);
+        let target_read = ResponseMeter::new(&mut response, &mut self.exchange);
+        let relayed = if upgrade {
+            copy_bidirectional(
+                &mut request_body,
+                target_write,
+                target_read,
+                client_write,
+                idle_timeout,
+            )
+            .await
+        } else {
+            relay_exchange(&mut request_body, target_write, target_read, client_write).await
+        };
+        // Chunked bodies only show their size as they arrive
+        if request_body.exceeds_limit() {
+            drop(request_body);
+            return self.reject_body().await;
+        }
+        let (uploaded, _) = relayed?;
+        self.keep_alive = response.keeps_connection() && request_body.is_done();
+        let reusable = response.reuses_origin() && request_body.is_done();
+        drop(response);
+        if let Some(key) = pool_key.filter(|_| reusable) {
+            debug!("Keeping connection to {} for reuse", key);
+            self.state.origin_pool.checkin(&key, target_stream);
+        }
+        self.exchange.request_bytes += uploaded;
+        let bytes_transferred = self.exchange.request_bytes + self.exchange.response_bytes;
 
         debug!(
             "HTTP request completed, transferred {} bytes",
             bytes_transferred
         );
 
-        // Update stats
-        {
-            let mut stats = self.stats.write().await;
-            stats.bytes_transferred += bytes_transferred;
-        }
+        self.record_traffic();
 
         Ok(())
     }
 
-    async fn send_error_response(&mut self, status_code: u16, reason: &str) -> ProxyResult<()> {
-        let response = format!(
-            "HTTP/1.1 {} {}\r\n\
-             Content-Type: text/html\r\n\
-             Content-Length: {}\r\n\
-             Connection: close\r\n\
-             \r\n\
-             <html><body><h1>{} {}</h1></body></html>",
-            status_code,
-            reason,
-            reason.len() + 32, // Approximate HTML length
-            status_code,
-            reason
+    /// Answer a request whose body grew past MaxRequestBodySize with "413
+    /// Payload Too Large", unless its response has started already.
+    async fn reject_body(&mut self) -> ProxyResult<()> {
+        let error = ProxyError::PayloadTooLarge(format!(
+            "request body exceeds limit of {} bytes",
+            self.config.max_request_body_size
+        ));
+        warn!("Rejecting request from {}: {}", self.client_addr, error);
+        if self.exchange.response_bytes == 0 {
+            self.send_error_page(413, "Payload Too Large", "", None)
+                .await?;
+        }
+        Err(error)
+    }
+
+    /// IdleTimeout for tunnels and upgraded connections, if set.
+    fn tunnel_idle_timeout(&self) -> Option<Duration> {
+        match self.config.idle_timeout {
+            0 => None,
+            seconds => Some(Duration::from_secs(seconds)),
+        }
+    }
+
+    /// Add the exchange's bytes to the totals, per direction and combined.
+    fn record_traffic(&self) {
+        let counters = &self.state.counters;
+        let received = self.exchange.request_bytes;
+        let sent = self.exchange.response_bytes;
+        counters.add(Counter::BytesReceived, received);
+        counters.add(Counter::BytesSent, sent);
+        counters.add(Counter::BytesTransferred, received + sent);
+    }
+
+    /// Give up on a request that exceeded MaxRequestDuration, answering the
+    /// client unless part of the response was already sent.
+    async fn abort_request(&mut self, limit: Duration) -> ProxyResult<()> {
+        let error = ProxyError::GatewayTimeout(format!(
+            "request not completed within {} seconds",
+            limit.as_secs()
+        ));
+        warn!("Aborting request from {}: {}", self.client_addr, error);
+        if self.exchange.response_bytes == 0 {
+            let detail = detail_paragraph(&error.error_message());
+            self.send_error_page(504, "Gateway Timeout", &detail, None)
+                .await?;
+        }
+        Err(error)
+    }
+
+    /// Upstream HTTP or SOCKS5 proxy for this client's requests to `host`,
+    /// if one is configured.
+    fn upstream_for(&self, host: &str) -> Option<UpstreamConfig> {
+        let upstream = self
+            .state
+            .proxy
+            .should_use_upstream(host, self.client_addr.ip())?;
+        if !matches!(upstream.upstream_type.as_str(), "http" | "socks5") {
+            warn!(
+                "Unsupported upstream type {}, connecting to {} directly",
+                upstream.upstream_type, host
+            );
+            return None;
+        }
+        debug!(
+            "Using upstream {}:{} for {}",
+            upstream.host, upstream.port, host
         );
+        Some(upstream.clone())
+    }
 
-        self.stream
-            .write_all(response.as_bytes())
-            .await
-            .map_err(ProxyError::Io)?;
-        Ok(())
+    /// Connector for tunnels through `upstream`, with a CONNECT of its own
+    /// or the SOCKS5 equivalent, or the usual one without an upstream.
+    fn tunnel_connector(&self, upstream: Option<&UpstreamConfig>) -> Arc<dyn Connector> {
+        match upstream {
+            Some(upstream) if upstream.upstream_type == "socks5" => Arc::new(
+                Socks5Connector::new(self.state.connector.clone(), &upstream.host, upstream.port)
+                    .with_credentials(
+                        upstream.username.clone().map(|username| {
+                            (username, upstream.password.clone().unwrap_or_default())
+                        }),
+                    ),
+            ),
+            Some(upstream) => Arc::new(
+                HttpTunnelConnector::new(
+                    self.state.connector.clone(),
+                    &upstream.host,
+                    upstream.port,
+                )
+                .with_authorization(upstream.proxy_authorization()),
+            ),
+            None if self.reverse_routed => self.state.connector.clone(),
+            None => self.state.target_connector(self.state.connector.clone()),
+        }
     }
 
-    async fn send_proxy_auth_required(&mut self) -> ProxyResult<()> {
-        let response = b"HTTP/1.1 407 Proxy Authentication Required\r\n\
-                        Proxy-Authenticate: Basic realm=\"Tinyproxy\"\r\n\
-                        Content-Type: text/html\r\n\
-                        Content-Length: 72\r\n\
-                        Connection: close\r\n\
-                        \r\n\
-                        <html><body><h1>407 Proxy Authentication Required</h1></body></html>";
+    /// An idle connection to `origin` from the pool, counted under the
+    /// destination's connection limit like a new one. Without room under
+    /// the limit, the connection is left to [`Self::connect_to_target`],
+    /// which answers the client.
+    async fn checkout_pooled(
+        &mut self,
+        origin: &str,
+        host: &str,
+        port: u16,
+    ) -> Option<BoxedStream> {
+        let stream = self.state.origin_pool.checkout(origin)?;
+        self.destination_permit = None;
+        self.destination_permit = self.state.connection_limits.acquire(host).await;
+        self.destination_permit.as_ref()?;
+        debug!("Reusing pooled connection to {}", origin);
+        self.state
+            .connections
+            .set_target(self.id, &authority(host, port));
+        Some(stream)
+    }
 
-        self.stream
-            .write_all(response)
-            .await
-            .map_err(ProxyError::Io)?;
-        Ok(())
+    /// Connector speaking TLS to HTTPS origins, over a tunnel through
+    /// `upstream` if there is one.
+    fn https_connector(
+        &self,
+        upstream: Option<&UpstreamConfig>,
+    ) -> ProxyResult<Arc<dyn Connector>> {
+        #[cfg(any(feature = "rustls", feature = "native-tls"))]
+        {
+            let connector = TlsConnector::new(self.tunnel_connector(upstream))?;
+            Ok(Arc::new(connector))
+        }
+        #[cfg(not(any(feature = "rustls", feature = "native-tls")))]
+        {
+            let _ = upstream;
+            Err(ProxyError::Tls(
+                "built without TLS support for HTTPS origins".to_string(),
+            ))
+        }
     }
 
-    async fn handle_stats_request(&mut self) -> ProxyResult<()> {
-        debug!("Handling statistics request");
+    /// Connect to the target server over `connector`, answering the client
+    /// with an error page if that fails.
+    async fn connect_to_target(
+        &mut self,
+        host: &str,
+        port: u16,
+        connector: Arc<dyn Connector>,
+    ) -> ProxyResult<BoxedStream> {
+        let target_addr = authority(host, port);
+        self.state.connections.set_target(self.id, &target_addr);
 
-        // Get current statistics
-        let stats = self.stats.read().await;
-        let stats_html = stats.to_html();
+        if let Err(remaining) = self.state.circuit_breakers.check(&target_addr) {
+            let error = ProxyError::CircuitOpen(format!(
+                "{} failed repeatedly, retrying in {} seconds",
+                target_addr,
+                remaining.as_secs().max(1)
+            ));
+            let retry_after = remaining.as_secs().max(1).to_string();
+            let detail = detail_paragraph(&error.error_message());
+            self.send_error_page(502, "Bad Gateway", &detail, Some(&retry_after))
+                .await?;
+            return Err(error);
+        }
 
-        let response = format!(
-            "HTTP/1.1 200 OK\r\n\
-             Content-Type: text/html; charset=utf-8\r\n\
-             Content-Length: {}\r\n\
-             Connection: close\r\n\
-             Cache-Control: no-cache\r\n\
-             \r\n\
-             {}",
-            stats_html.len(),
-            stats_html
-        );
+        // A retry gives up the previous attempt's place first
+        self.destination_permit = None;
+        self.destination_permit = self.state.connection_limits.acquire(host).await;
+        if self.destination_permit.is_none() {
+            warn!(
+                "Connection limit for {} reached, rejecting {}",
+                host, self.client_addr
+            );
+            self.state.counters.add(Counter::RequestsDenied, 1);
+            let error = ProxyError::ResourceExhausted(format!("too many connections to {}", host));
+            let detail = detail_paragraph(&error.error_message());
+            self.send_error_page(503, "Service Unavailable", &detail, Some("1"))
+                .await?;
+            return Err(error);
+        }
 
-        self.stream
-            .write_all(response.as_bytes())
-            .await
-            .map_err(ProxyError::Io)?;
+        let connecting = Instant::now();
+        match connector.connect(host, port).await {
+            Ok(target_stream) => {
+                self.exchange.connect_time = Some(connecting.elapsed());
+                self.state.circuit_breakers.record_success(&target_addr);
+                Ok(target_stream)
+            }
+            Err(failure) => {
+                warn!("Upstream connection failed: {}", failure);
+                if failure.kind == FailureKind::Denied {
+                    self.record_target_denial(&failure);
+                } else {
+                    self.state.circuit_breakers.record_failure(&target_addr);
+                }
+
+                let error = failure.to_error();
+                let detail = if self.show_diagnostics() {
+                    failure.to_html()
+                } else {
+                    detail_paragraph(&error.error_message())
+                };
+                let status_code = failure.status_code();
+                let reason = match status_code {
+                    403 => "Forbidden",
+                    504 => "Gateway Timeout",
+                    _ => "Bad Gateway",
+                };
+                self.send_error_page(status_code, reason, &detail, None)
+                    .await?;
+                Err(error)
+            }
+        }
+    }
+
+    /// Note a refused DenyTargetNetworks destination in the denial log.
+    fn record_target_denial(&self, failure: &ConnectFailure) {
+        if failure.kind == FailureKind::Denied {
+            self.state.counters.add(Counter::RequestsDenied, 1);
+            self.state.denial_log.record(
+                self.client_addr.ip(),
+                Denial::TargetDenied,
+                &[("target", &failure.target)],
+            );
+        }
+    }
+
+    /// Whether this client gets detailed diagnostics on upstream failures.
+    fn show_diagnostics(&self) -> bool {
+        self.config.error_diagnostics
+            && (self.diagnostics_clients.is_empty()
+                || self.diagnostics_clients.contains(&self.client_addr.ip()))
+    }
+
+    /// Rate limiters for the client->origin (upload) and origin->client
+    /// (download) directions of this connection.
+    fn bandwidth_limiters(&self) -> (Vec<Arc<RateLimiter>>, Vec<Arc<RateLimiter>>) {
+        let limiter = |rate: u64| {
+            if rate > 0 {
+                vec![RateLimiter::new(rate)]
+            } else {
+                vec![]
+            }
+        };
+
+        let mut upload = limiter(self.config.upload_limit);
+        let mut download = limiter(self.config.download_limit);
+        if let Some(client) = self.state.client_bandwidth.limiter(self.client_addr.ip()) {
+            upload.push(client.clone());
+            download.push(client);
+        }
+        (upload, download)
+    }
+
+    /// User named by the credentials of `request`, or the intercepted
+    /// tunnel's user for requests inside it.
+    fn authenticated_user(&self, request: &HttpRequest) -> Option<String> {
+        match &self.intercepted {
+            Some(_) => self.tunnel_user.clone(),
+            None => request
+                .headers
+                .get("proxy-authorization")
+                .and_then(basic_auth_username),
+        }
+    }
+
+    fn request_context(&self) -> RequestContext {
+        // Trusted peers name the client in X-Forwarded-For, unless it came
+        // in a PROXY header
+        let peer = self.peer_addr.ip();
+        let forwarded_by = (self.intercepted.is_none()
+            && !self.config.proxy_protocol
+            && self.trusted_proxies.contains(&peer))
+        .then_some(peer);
+        RequestContext {
+            connection_id: self.id,
+            client_addr: self.client_addr,
+            local_addr: self.local_addr,
+            forwarded_by,
+            ident: self.ident.clone(),
+            reverse_routed: Arc::default(),
+            intercepted: self.intercepted.is_some(),
+            tunnel_user: self.tunnel_user.clone(),
+            state: self.state.clone(),
+        }
+    }
 
+    /// Send an error page with an HTML fragment explaining the failure in
+    /// more detail (may be empty), optionally asking the client to retry
+    /// after some seconds.
+    async fn send_error_page(
+        &mut self,
+        status_code: u16,
+        reason: &str,
+        detail_html: &str,
+        retry_after: Option<&str>,
+    ) -> ProxyResult<()> {
+        let mut response = LocalResponse::error_page(status_code, reason, detail_html);
+        if let Some(retry_after) = retry_after {
+            response = response.with_header("Retry-After", retry_after);
+        }
+        self.send_response(&response).await
+    }
+
+    async fn send_response(&mut self, response: &LocalResponse) -> ProxyResult<()> {
+        let rendered = response.detail.as_deref().and_then(|detail| {
+            let client_ip = self.client_addr.ip().to_string();
+            let url = self.request_line.split(' ').nth(1).unwrap_or_default();
+            self.state.error_pages.render(
+                response.status,
+                &response.reason,
+                detail,
+                &[
+                    ("clientip", &client_ip),
+                    ("url", url),
+                    ("request", &self.request_line),
+                ],
+            )
+        });
+        let data = response.to_bytes_with_body(
+            self.response_version,
+            self.accepts_gzip,
+            rendered.as_deref().unwrap_or(&response.body),
+        );
+        self.exchange.status = Some(response.status);
+        self.exchange.response_bytes += data.len() as u64;
+        if response.tarpit {
+            if let Some(_slot) = self.state.tarpit.try_hold() {
+                info!("Tarpitting {}", self.client_addr);
+                return self
+                    .state
+                    .tarpit
+                    .drip(&mut self.stream, &data)
+                    .await
+                    .map_err(ProxyError::Io);
+            }
+        }
+
+        self.stream.write_all(&data).await.map_err(ProxyError::Io)?;
         Ok(())
     }
 }
 
-fn find_end_of_headers(buffer: &[u8]) -> Option<usize> {
-    for i in 0..buffer.len().saturating_sub(3) {
-        if &buffer[i..i + 4] == b"\r\n\r\n" {
-            return Some(i);
+fn detail_paragraph(text: &str) -> String {
+    format!("<p>{}</p>", html_escape(text))
+}
+
+fn is_idempotent(method: &str) -> bool {
+    matches!(method, "GET" | "HEAD")
+}
+
+/// Write a request upstream and wait for the first bytes of the response.
+/// A connection closed before anything arrived is reported as an error.
+async fn send_and_await_response<S: AsyncRead + AsyncWrite + Unpin>(
+    target_stream: &mut S,
+    request_data: &[u8],
+) -> std::io::Result<BytesMut> {
+    target_stream.write_all(request_data).await?;
+
+    let mut response_start = BytesMut::with_capacity(8192);
+    if target_stream.read_buf(&mut response_start).await? == 0 {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::UnexpectedEof,
+            "connection closed",
+        ));
+    }
+    Ok(response_start)
+}
+
+/// Counts an open upstream connection in the per-destination gauges for
+/// as long as it is alive.
+struct UpstreamGauge {
+    stats: Arc<RwLock<Stats>>,
+    host: String,
+}
+
+impl UpstreamGauge {
+    async fn open(stats: Arc<RwLock<Stats>>, host: &str) -> Self {
+        stats.write().await.upstream_connection_opened(host);
+        Self {
+            stats,
+            host: host.to_string(),
         }
     }
-    None
 }
 
-fn parse_host_port(uri: &str) -> ProxyResult<(String, u16)> {
-    let parts: Vec<&str> = uri.split(':').collect();
-    match parts.len() {
-        1 => Ok((parts[0].to_string(), 80)),
-        2 => {
-            let port = parts[1]
-                .parse::<u16>()
-                .map_err(|_| ProxyError::InvalidRequest(format!("Invalid port: {}", parts[1])))?;
-            Ok((parts[0].to_string(), port))
-        }
-        _ => Err(ProxyError::InvalidRequest(format!(
-            "Invalid host:port format: {}",
-            uri
-        ))),
+impl Drop for UpstreamGauge {
+    fn drop(&mut self) {
+        let stats = self.stats.clone();
+        let host = std::mem::take(&mut self.host);
+        tokio::spawn(async move {
+            stats.write().await.upstream_connection_closed(&host);
+        });
+    }
+}
+
+/// Destination host of a request, from the CONNECT target, the absolute
+/// URI or the Host header.
+pub(crate) fn request_host(request: &HttpRequest) -> Option<String> {
+    if request.method == "CONNECT" {
+        return parse_host_port(&request.uri).ok().map(|(host, _)| host);
     }
+
+    if let Ok(url) = url::Url::parse(&request.uri) {
+        if let Some(host) = url.host_str() {
+            return Some(host.trim_matches(['[', ']']).to_string());
+        }
+    }
+
+    let host = request.headers.get("host")?;
+    parse_host_port(host).ok().map(|(host, _)| host)
 }
 
-fn reconstruct_http_request(request: &HttpRequest, target_uri: &str) -> Vec<u8> {
-    let mut data = Vec::new();
+/// Host and port of a CONNECT target or Host header, port 80 if none is
+/// given. IPv6 literals come in brackets, as in `[2001:db8::1]:443`, and
+/// are returned without them.
+pub(crate) fn parse_host_port(uri: &str) -> ProxyResult<(String, u16)> {
+    let invalid = || ProxyError::InvalidRequest(format!("Invalid host:port format: {}", uri));
+    let (host, port) = match uri.strip_prefix('[') {
+        Some(bracketed) => {
+            let (host, rest) = bracketed.split_once(']').ok_or_else(invalid)?;
+            host.parse::<Ipv6Addr>().map_err(|_| invalid())?;
+            match rest {
+                "" => (host, None),
+                _ => (host, Some(rest.strip_prefix(':').ok_or_else(invalid)?)),
+            }
+        }
+        None => match uri.split_once(':') {
+            Some((_, port)) if port.contains(':') => return Err(invalid()),
+            Some((host, port)) => (host, Some(port)),
+            None => (uri, None),
+        },
+    };
 
-    // Request line - use the target URI for absolute URLs
-    let uri_to_use = if target_uri.starts_with("http://") || target_uri.starts_with("https://") {
-        // For absolute URLs, use the original relative path
-        &request.uri
+    let port = match port {
+        Some(port) => port
+            .parse::<u16>()
+            .map_err(|_| ProxyError::InvalidRequest(format!("Invalid port: {}", port)))?,
+        None => 80,
+    };
+    Ok((host.to_string(), port))
+}
+
+pub(crate) fn reconstruct_http_request(request: &HttpRequest) -> Vec<u8> {
+    // Origin servers get the origin-form target (path and query)
+    format!(
+        "{} {} HTTP/{}\r\n{}\r\n",
+        request.method,
+        origin_form(&request.uri),
+        request.version,
+        request.headers.to_lines()
+    )
+    .into_bytes()
+}
+
+/// Request for an upstream HTTP proxy, which needs the absolute-form
+/// target and its own credentials. Relative targets are completed with
+/// `authority`.
+pub(crate) fn reconstruct_upstream_request(
+    request: &HttpRequest,
+    authority: &str,
+    authorization: Option<&str>,
+) -> Vec<u8> {
+    let target = if request.uri.contains("://") {
+        request.uri.clone()
     } else {
-        target_uri
+        format!("http://{}{}", authority, request.uri)
     };
-    
-    data.extend_from_slice(
-        format!(
-            "{} {} HTTP/{}\r\n",
-            request.method, uri_to_use, request.version
-        )
-        .as_bytes(),
+    let mut data = format!(
+        "{} {} HTTP/{}\r\n{}",
+        request.method,
+        target,
+        request.version,
+        request.headers.to_lines()
     );
+    if let Some(authorization) = authorization {
+        data.push_str(&format!("Proxy-Authorization: {}\r\n", authorization));
+    }
+    data.push_str("\r\n");
+    data.into_bytes()
+}
 
-    // Headers
-    for (name, value) in &request.headers {
-        data.extend_from_slice(format!("{}: {}\r\n", name, value).as_bytes());
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::connector::{ConnectFailure, Connector};
+    use async_trait::async_trait;
+    use tokio::net::TcpListener;
+
+    /// Configuration from `directives` that lets clients reach in-memory
+    /// origins, whose names would not resolve for DenyTargetNetworks.
+    fn unguarded_config(directives: &str) -> Config {
+        let mut config = Config::parse_config(directives).unwrap();
+        config.deny_target_networks.clear();
+        config
     }
 
-    // End of headers
-    data.extend_from_slice(b"\r\n");
+    /// Connect a client to a new handler serving `state`.
+    async fn connect_client(
+        state: &Arc<ServerState>,
+    ) -> (TcpStream, tokio::task::JoinHandle<ProxyResult<()>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (stream, addr) = listener.accept().await.unwrap();
+        let handler = tokio::spawn(ConnectionHandler::new(stream, addr, state.clone()).handle());
+        (client, handler)
+    }
+
+    /// Send `request` on a new connection, returning what the client read
+    /// until the proxy closed it and how handling the connection ended.
+    async fn exchange(state: &Arc<ServerState>, request: &[u8]) -> (Vec<u8>, ProxyResult<()>) {
+        let (mut client, handler) = connect_client(state).await;
+        client.write_all(request).await.unwrap();
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).await.unwrap();
+        (response, handler.await.unwrap())
+    }
+
+    /// Connects every target to an in-memory origin answering "ok".
+    struct InMemoryConnector;
+
+    #[async_trait]
+    impl Connector for InMemoryConnector {
+        async fn connect(&self, _host: &str, _port: u16) -> Result<BoxedStream, ConnectFailure> {
+            let (proxy_side, mut origin) = tokio::io::duplex(4096);
+            tokio::spawn(async move {
+                let mut buf = [0u8; 1024];
+                let _ = origin.read(&mut buf).await.unwrap();
+                origin
+                    .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok")
+                    .await
+                    .unwrap();
+            });
+            Ok(Box::new(proxy_side))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_custom_connector() {
+        let mut state = ServerState::new(Arc::new(unguarded_config("")));
+        state.connector = Arc::new(InMemoryConnector);
+        let state = Arc::new(state);
+
+        let (response, result) = exchange(
+            &state,
+            b"GET http://memory.test/ HTTP/1.1\r\nHost: memory.test\r\nConnection: close\r\n\r\n",
+        )
+        .await;
+
+        assert!(response.starts_with(b"HTTP/1.1 200 OK"));
+        assert!(response.ends_with(b"ok"));
+        result.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_keep_alive() {
+        let mut state = ServerState::new(Arc::new(unguarded_config("")));
+        state.connector = Arc::new(InMemoryConnector);
+        let state = Arc::new(state);
+
+        // A request with a body, then one pipelined behind it asking to close
+        let (response, result) = exchange(
+            &state,
+            b"POST http://memory.test/ HTTP/1.1\r\nContent-Length: 5\r\n\r\nhello\
+              GET http://memory.test/ HTTP/1.1\r\nProxy-Connection: close\r\n\r\n",
+        )
+        .await;
+        result.unwrap();
+
+        let response = String::from_utf8(response).unwrap();
+        assert_eq!(response.matches("HTTP/1.1 200 OK\r\n").count(), 2);
+        let (first, second) = response.split_at(response.rfind("HTTP/1.1").unwrap());
+        assert!(!first.contains("Connection:"));
+        assert!(first.contains("Keep-Alive: timeout=15\r\n"));
+        assert!(first.ends_with("ok"));
+        assert!(second.contains("Connection: close\r\n"));
+        assert!(!second.contains("Keep-Alive:"));
+        assert!(second.ends_with("ok"));
+    }
+
+    /// Connects to an in-memory origin that notes everything it receives
+    /// until the request's body ends.
+    #[derive(Default)]
+    struct BodyRecorder {
+        received: Arc<std::sync::Mutex<Vec<u8>>>,
+    }
+
+    #[async_trait]
+    impl Connector for BodyRecorder {
+        async fn connect(&self, _host: &str, _port: u16) -> Result<BoxedStream, ConnectFailure> {
+            let (proxy_side, mut origin) = tokio::io::duplex(4096);
+            let received = self.received.clone();
+            tokio::spawn(async move {
+                let mut buf = [0u8; 1024];
+                loop {
+                    let n = origin.read(&mut buf).await.unwrap();
+                    let mut received = received.lock().unwrap();
+                    received.extend_from_slice(&buf[..n]);
+                    if n == 0 || received.ends_with(b"hello") || received.ends_with(b"0\r\n\r\n") {
+                        break;
+                    }
+                }
+                // The proxy may have given up on the request meanwhile
+                let _ = origin
+                    .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok")
+                    .await;
+            });
+            Ok(Box::new(proxy_side))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_chunked_request_body() {
+        let connector = BodyRecorder::default();
+        let received = connector.received.clone();
+        let mut state = ServerState::new(Arc::new(unguarded_config("")));
+        state.connector = Arc::new(connector);
+        let state = Arc::new(state);
+
+        // Arriving with the head, the body is sent with a Content-Length
+        let head = "POST http://memory.test/ HTTP/1.1\r\n\
+                    Transfer-Encoding: chunked\r\nConnection: close\r\n\r\n";
+        let body = "5;ext=1\r\nhello\r\n0\r\nX-Sum: 1\r\n\r\n";
+        let (response, result) = exchange(&state, format!("{}{}", head, body).as_bytes()).await;
+        result.unwrap();
+        assert!(response.ends_with(b"ok"));
+        let request = String::from_utf8(std::mem::take(&mut *received.lock().unwrap())).unwrap();
+        assert!(!request.contains("Transfer-Encoding"));
+        assert!(request.ends_with("Content-Length: 5\r\n\r\nhello"));
+
+        // Arriving later, it is passed on in plain chunks
+        let (mut client, handler) = connect_client(&state).await;
+        client.write_all(head.as_bytes()).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        client.write_all(b"5;ext=1\nhello\r\n").await.unwrap();
+        client
+            .write_all(b"6\r\n world\r\n0\r\nX-Sum: 1\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).await.unwrap();
+        handler.await.unwrap().unwrap();
+        assert!(response.ends_with(b"ok"));
+        let request = String::from_utf8(received.lock().unwrap().clone()).unwrap();
+        assert!(request.contains("Transfer-Encoding: chunked\r\n"));
+        // However the chunks arrived, extensions and trailer are gone
+        let (_, chunks) = request.split_once("\r\n\r\n").unwrap();
+        let mut payload = BytesMut::new();
+        let mut framing = BodyFraming::new(BodyLength::Chunked);
+        framing.decode(chunks.as_bytes(), &mut payload).unwrap();
+        assert!(framing.is_done());
+        assert_eq!(&payload[..], b"hello world");
+        assert!(!chunks.contains("ext") && chunks.ends_with("\r\n0\r\n\r\n"));
+    }
+
+    #[tokio::test]
+    async fn test_chunked_body_size_limit() {
+        let connector = BodyRecorder::default();
+        let received = connector.received.clone();
+        let config = unguarded_config("MaxRequestBodySize 8");
+        let mut state = ServerState::new(Arc::new(config));
+        state.connector = Arc::new(connector);
+        let state = Arc::new(state);
+        let head = "POST http://memory.test/ HTTP/1.1\r\n\
+                    Transfer-Encoding: chunked\r\nConnection: close\r\n\r\n";
+
+        // Arriving with the head, the body never reaches the origin
+        let body = "5\r\nhello\r\n6\r\n world\r\n0\r\n\r\n";
+        let (response, result) = exchange(&state, format!("{}{}", head, body).as_bytes()).await;
+        assert!(matches!(result, Err(ProxyError::PayloadTooLarge(_))));
+        assert!(response.starts_with(b"HTTP/1.1 413 Payload Too Large\r\n"));
+        assert!(received.lock().unwrap().is_empty());
+
+        // Streamed, it is cut off where it crosses the limit
+        let (mut client, handler) = connect_client(&state).await;
+        client.write_all(head.as_bytes()).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        client.write_all(b"5\r\nhello\r\n").await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        client.write_all(b"6\r\n world\r\n").await.unwrap();
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).await.unwrap();
+        assert!(matches!(
+            handler.await.unwrap(),
+            Err(ProxyError::PayloadTooLarge(_))
+        ));
+        assert!(response.starts_with(b"HTTP/1.1 413 Payload Too Large\r\n"));
+        let request = String::from_utf8(received.lock().unwrap().clone()).unwrap();
+        assert!(request.ends_with("\r\n\r\n5\r\nhello\r\n"));
+    }
+
+    /// Connects to an in-memory upstream proxy, noting where the proxy
+    /// connected and the request head the upstream received.
+    #[derive(Default)]
+    struct UpstreamProxy {
+        seen: Arc<std::sync::Mutex<Vec<(String, String)>>>,
+    }
 
-    data
+    #[async_trait]
+    impl Connector for UpstreamProxy {
+        async fn connect(&self, host: &str, port: u16) -> Result<BoxedStream, ConnectFailure> {
+            let (proxy_side, mut upstream) = tokio::io::duplex(4096);
+            let address = format!("{}:{}", host, port);
+            let seen = self.seen.clone();
+            tokio::spawn(async move {
+                let mut buf = [0u8; 1024];
+                let n = upstream.read(&mut buf).await.unwrap();
+                let head = String::from_utf8_lossy(&buf[..n]).to_string();
+                let tunnel = head.starts_with("CONNECT ");
+                seen.lock().unwrap().push((address, head));
+                if tunnel {
+                    upstream
+                        .write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")
+                        .await
+                        .unwrap();
+                    let n = upstream.read(&mut buf).await.unwrap();
+                    upstream.write_all(&buf[..n]).await.unwrap();
+                } else {
+                    upstream
+                        .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok")
+                        .await
+                        .unwrap();
+                }
+            });
+            Ok(Box::new(proxy_side))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_upstream_proxy() {
+        let config =
+            unguarded_config("Upstream http:parent.test:3128 bob:hunter2\nNoUpstream direct.test");
+        let connector = UpstreamProxy::default();
+        let seen = connector.seen.clone();
+        let mut state = ServerState::new(Arc::new(config));
+        state.connector = Arc::new(connector);
+        let state = Arc::new(state);
+
+        // Plain requests go to the upstream in absolute form
+        let (response, result) = exchange(
+            &state,
+            b"GET /a?b HTTP/1.1\r\nHost: memory.test:8080\r\nProxy-Authorization: Basic eA==\r\n\
+              Connection: close\r\n\r\n",
+        )
+        .await;
+        assert!(response.ends_with(b"ok"));
+        result.unwrap();
+        let (address, head) = seen.lock().unwrap().pop().unwrap();
+        assert_eq!(address, "parent.test:3128");
+        assert!(head.starts_with("GET http://memory.test:8080/a?b HTTP/1.1\r\n"));
+        assert!(head.contains("Proxy-Authorization: Basic Ym9iOmh1bnRlcjI=\r\n"));
+        assert!(!head.contains("Basic eA=="));
+
+        // Tunnels are opened by the upstream
+        let (mut client, handler) = connect_client(&state).await;
+        client
+            .write_all(b"CONNECT memory.test:443 HTTP/1.1\r\n\r\n")
+            .await
+            .unwrap();
+        let mut established = [0u8; 39];
+        client.read_exact(&mut established).await.unwrap();
+        assert!(established.starts_with(b"HTTP/1.1 200 Connection established"));
+        client.write_all(b"tunnelled").await.unwrap();
+        let mut echo = [0u8; 9];
+        client.read_exact(&mut echo).await.unwrap();
+        assert_eq!(&echo, b"tunnelled");
+        drop(client);
+        handler.await.unwrap().unwrap();
+        let (address, head) = seen.lock().unwrap().remove(0);
+        assert_eq!(address, "parent.test:3128");
+        assert!(head.starts_with("CONNECT memory.test:443 HTTP/1.1\r\n"));
+
+        // NoUpstream destinations are reached directly
+        let (response, result) = exchange(
+            &state,
+            b"GET http://direct.test/ HTTP/1.1\r\nConnection: close\r\n\r\n",
+        )
+        .await;
+        assert!(response.ends_with(b"ok"));
+        result.unwrap();
+        let (address, head) = seen.lock().unwrap().pop().unwrap();
+        assert_eq!(address, "direct.test:80");
+        assert!(head.starts_with("GET / HTTP/1.1\r\n"));
+    }
+
+    /// Connects to an origin answering every request on a connection,
+    /// counting the connections.
+    #[derive(Default)]
+    struct PersistentOrigin {
+        connections: Arc<std::sync::atomic::AtomicUsize>,
+    }
+
+    #[async_trait]
+    impl Connector for PersistentOrigin {
+        async fn connect(&self, _host: &str, _port: u16) -> Result<BoxedStream, ConnectFailure> {
+            let (proxy_side, mut origin) = tokio::io::duplex(4096);
+            self.connections
+                .fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            tokio::spawn(async move {
+                let mut buf = [0u8; 1024];
+                loop {
+                    let n = origin.read(&mut buf).await.unwrap_or(0);
+                    if n == 0 {
+                        break;
+                    }
+                    let close = String::from_utf8_lossy(&buf[..n]).contains("X-Origin: close");
+                    let response: &[u8] = if close {
+                        b"HTTP/1.1 200 OK\r\nConnection: close\r\nContent-Length: 2\r\n\r\nok"
+                    } else {
+                        b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok"
+                    };
+                    origin.write_all(response).await.unwrap();
+                    if close {
+                        break;
+                    }
+                }
+            });
+            Ok(Box::new(proxy_side))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_origin_pool() {
+        let connector = PersistentOrigin::default();
+        let connections = connector.connections.clone();
+        let mut state = ServerState::new(Arc::new(unguarded_config("")));
+        state.connector = Arc::new(connector);
+        let state = Arc::new(state);
+
+        // Clients' requests share the origin connection
+        for _ in 0..3 {
+            let (response, result) = exchange(
+                &state,
+                b"GET http://pooled.test/ HTTP/1.1\r\nConnection: close\r\n\r\n",
+            )
+            .await;
+            assert!(response.ends_with(b"ok"));
+            result.unwrap();
+        }
+        assert_eq!(connections.load(std::sync::atomic::Ordering::SeqCst), 1);
+        assert_eq!(state.origin_pool.idle_count(), 1);
+
+        // Requests not sent again on failure get a new connection, which
+        // is not kept when the origin closes it
+        let (response, result) = exchange(
+            &state,
+            b"POST http://pooled.test/ HTTP/1.1\r\nX-Origin: close\r\nContent-Length: 0\r\n\
+              Connection: close\r\n\r\n",
+        )
+        .await;
+        assert!(response.ends_with(b"ok"));
+        result.unwrap();
+        assert_eq!(connections.load(std::sync::atomic::Ordering::SeqCst), 2);
+        assert_eq!(state.origin_pool.idle_count(), 1);
+    }
+
+    /// Connects to an origin accepting WebSocket handshakes and echoing
+    /// what follows, recording the handshake.
+    #[derive(Default)]
+    struct WebSocketOrigin {
+        handshake: Arc<std::sync::Mutex<String>>,
+    }
+
+    #[async_trait]
+    impl Connector for WebSocketOrigin {
+        async fn connect(&self, _host: &str, _port: u16) -> Result<BoxedStream, ConnectFailure> {
+            let (proxy_side, mut origin) = tokio::io::duplex(4096);
+            let handshake = self.handshake.clone();
+            tokio::spawn(async move {
+                let mut buf = [0u8; 1024];
+                let n = origin.read(&mut buf).await.unwrap();
+                *handshake.lock().unwrap() = String::from_utf8_lossy(&buf[..n]).to_string();
+                origin
+                    .write_all(
+                        b"HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\n\
+                          Connection: Upgrade\r\nSec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n\r\n",
+                    )
+                    .await
+                    .unwrap();
+                loop {
+                    let n = origin.read(&mut buf).await.unwrap_or(0);
+                    if n == 0 || origin.write_all(&buf[..n]).await.is_err() {
+                        break;
+                    }
+                }
+            });
+            Ok(Box::new(proxy_side))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_websocket_upgrade() {
+        let config = unguarded_config("Anonymous Host\nMaxRequestDuration 1");
+        let connector = WebSocketOrigin::default();
+        let handshake = connector.handshake.clone();
+        let mut state = ServerState::new(Arc::new(config));
+        state.connector = Arc::new(connector);
+        let state = Arc::new(state);
+
+        let (mut client, handler) = connect_client(&state).await;
+
+        client
+            .write_all(
+                b"GET ws://chat.test/socket HTTP/1.1\r\nHost: chat.test\r\n\
+                  Upgrade: websocket\r\nConnection: Upgrade\r\nProxy-Connection: keep-alive\r\n\
+                  Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n",
+            )
+            .await
+            .unwrap();
+        let mut response = vec![0u8; 1024];
+        let n = client.read(&mut response).await.unwrap();
+        let response = String::from_utf8_lossy(&response[..n]).to_string();
+        assert!(response.starts_with("HTTP/1.1 101 Switching Protocols\r\n"));
+        assert!(response.contains("Upgrade: websocket\r\n"));
+
+        // The handshake arrives whole, without the proxy's own headers
+        let handshake = handshake.lock().unwrap().clone();
+        assert!(handshake.starts_with("GET /socket HTTP/1.1\r\n"));
+        for header in [
+            "Upgrade: websocket\r\n",
+            "Connection: Upgrade\r\n",
+            "Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n",
+            "Sec-WebSocket-Version: 13\r\n",
+        ] {
+            assert!(handshake.contains(header), "{} missing", header);
+        }
+        assert!(!handshake.contains("Proxy-Connection"));
+
+        // Frames pass both ways, longer than MaxRequestDuration
+        tokio::time::sleep(Duration::from_millis(1200)).await;
+        client.write_all(b"\x81\x05hello").await.unwrap();
+        let mut echo = [0u8; 7];
+        client.read_exact(&mut echo).await.unwrap();
+        assert_eq!(&echo, b"\x81\x05hello");
+
+        drop(client);
+        handler.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_reverse_proxy() {
+        // Backends may be in DenyTargetNetworks, denied by default
+        let config = Config::parse_config(
+            "ReversePath \"/app/\" \"http://127.0.0.1:8080/v1/\"\nReverseOnly Yes",
+        )
+        .unwrap();
+        let connector = UpstreamProxy::default();
+        let seen = connector.seen.clone();
+        let mut state = ServerState::new(Arc::new(config));
+        state.connector = Arc::new(connector);
+        let state = Arc::new(state);
+
+        // Paths are sent to the backend with its Host
+        let (response, result) = exchange(
+            &state,
+            b"GET /app/items?id=1 HTTP/1.1\r\nHost: portal.test\r\nConnection: close\r\n\r\n",
+        )
+        .await;
+        assert!(response.starts_with(b"HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with(b"ok"));
+        result.unwrap();
+        let (address, head) = seen.lock().unwrap().pop().unwrap();
+        assert_eq!(address, "127.0.0.1:8080");
+        assert!(head.starts_with("GET /v1/items?id=1 HTTP/1.1\r\n"));
+        assert!(head.contains("Host: 127.0.0.1:8080\r\n"));
+
+        // Anything else is refused
+        for request in [
+            &b"GET /other HTTP/1.1\r\nHost: portal.test\r\n\r\n"[..],
+            b"GET http://elsewhere.test/ HTTP/1.1\r\n\r\n",
+            b"CONNECT elsewhere.test:443 HTTP/1.1\r\n\r\n",
+        ] {
+            let (response, result) = exchange(&state, request).await;
+            assert!(response.starts_with(b"HTTP/1.1 404 Not Found\r\n"));
+            assert!(result.is_err());
+        }
+        assert!(seen.lock().unwrap().is_empty());
+
+        // Only requests routed to a backend skip DenyTargetNetworks
+        let config =
+            Config::parse_config("ReversePath \"/app/\" \"http://127.0.0.1:8080/v1/\"").unwrap();
+        let mut state = ServerState::new(Arc::new(config));
+        state.connector = Arc::new(UpstreamProxy::default());
+        let state = Arc::new(state);
+        let (response, result) = exchange(
+            &state,
+            b"GET http://127.0.0.1:8080/v1/items HTTP/1.1\r\nConnection: close\r\n\r\n",
+        )
+        .await;
+        assert!(response.starts_with(b"HTTP/1.1 403 Forbidden\r\n"));
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_http_1_0_client() {
+        let mut state = ServerState::new(Arc::new(unguarded_config("")));
+        state.connector = Arc::new(InMemoryConnector);
+        let state = Arc::new(state);
+
+        // Absolute-form requests need no Host header
+        let (response, result) =
+            exchange(&state, b"GET http://memory.test/ HTTP/1.0\r\n\r\n").await;
+        assert!(response.ends_with(b"ok"));
+        result.unwrap();
+
+        // Errors are answered in the client's version
+        let (response, result) = exchange(&state, b"GET / HTTP/1.0\r\n\r\n").await;
+        assert!(response.starts_with(b"HTTP/1.0 400 Bad Request\r\n"));
+        assert!(result.is_err());
+    }
+
+    /// Open an intercepted tunnel to `host` with `connect_headers`, and
+    /// the TLS connection in it trusting the CA in `ca`.
+    #[cfg(feature = "rustls")]
+    async fn intercepted_tunnel(
+        state: &Arc<ServerState>,
+        host: &str,
+        connect_headers: &str,
+        ca: &std::path::Path,
+    ) -> (
+        tokio_rustls::client::TlsStream<TcpStream>,
+        tokio::task::JoinHandle<ProxyResult<()>>,
+    ) {
+        use tokio_rustls::rustls::crypto::ring::default_provider;
+        use tokio_rustls::rustls::pki_types::{CertificateDer, ServerName};
+        use tokio_rustls::rustls::{ClientConfig, RootCertStore};
+
+        let (mut client, handler) = connect_client(state).await;
+        client
+            .write_all(
+                format!(
+                    "CONNECT {0}:443 HTTP/1.1\r\nHost: {0}:443\r\n{1}\r\n",
+                    host, connect_headers
+                )
+                .as_bytes(),
+            )
+            .await
+            .unwrap();
+        let mut established = [0u8; 39];
+        client.read_exact(&mut established).await.unwrap();
+        assert_eq!(&established, b"HTTP/1.1 200 Connection established\r\n\r\n");
+
+        let mut roots = RootCertStore::empty();
+        let ca = crate::mitm::pem_decode(&std::fs::read_to_string(ca).unwrap(), "CERTIFICATE");
+        roots.add(CertificateDer::from(ca.unwrap())).unwrap();
+        let client_config = ClientConfig::builder_with_provider(Arc::new(default_provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let client = tokio_rustls::TlsConnector::from(Arc::new(client_config))
+            .connect(ServerName::try_from(host.to_string()).unwrap(), client)
+            .await
+            .unwrap();
+        (client, handler)
+    }
+
+    #[cfg(feature = "rustls")]
+    #[tokio::test]
+    async fn test_tls_interception() {
+        let dir = tempfile::tempdir().unwrap();
+        let ca = dir.path().join("ca.pem");
+        let config = Config::parse_config(&format!(
+            "TlsIntercept Yes\nTlsInterceptCa \"{}\" \"{}\"\n\
+             Redirect \"^https://secure\\.test/old\" \"https://secure.test/new\"",
+            ca.display(),
+            dir.path().join("ca.key").display()
+        ))
+        .unwrap();
+        let state = Arc::new(ServerState::new(Arc::new(config)));
+
+        // The client trusting the CA sees a certificate for the server, and
+        // its requests go through the usual stages
+        let (mut client, handler) = intercepted_tunnel(&state, "secure.test", "", &ca).await;
+        client
+            .write_all(b"GET /old HTTP/1.1\r\nHost: secure.test\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).await.unwrap();
+        let response = String::from_utf8_lossy(&response);
+
+        assert!(response.starts_with("HTTP/1.1 302 Found\r\n"));
+        assert!(response.contains("Location: https://secure.test/new\r\n"));
+        handler.await.unwrap().unwrap();
+    }
+
+    #[cfg(feature = "rustls")]
+    #[tokio::test]
+    async fn test_tls_interception_with_auth() {
+        let dir = tempfile::tempdir().unwrap();
+        let ca = dir.path().join("ca.pem");
+        let filter = dir.path().join("alice.filter");
+        std::fs::write(&filter, "/private\n").unwrap();
+        let config = Config::parse_config(&format!(
+            "TlsIntercept Yes\nTlsInterceptCa \"{}\" \"{}\"\n\
+             BasicAuth alice:secret\nFilterURLs Yes\n\
+             FilterGroup restricted user=alice \"{}\"\n\
+             Redirect \"^https://secure\\.test/old\" \"https://secure.test/new\"",
+            ca.display(),
+            dir.path().join("ca.key").display(),
+            filter.display()
+        ))
+        .unwrap();
+        let state = Arc::new(ServerState::new(Arc::new(config)));
+        // alice:secret
+        let credentials = "Proxy-Authorization: Basic YWxpY2U6c2VjcmV0\r\n";
+
+        // Requests inside carry no credentials: the tunnel's user applies
+        for (path, status) in [
+            ("/old", "HTTP/1.1 302 Found\r\n"),
+            ("/private", "HTTP/1.1 403 Forbidden by filter\r\n"),
+        ] {
+            let (mut client, handler) =
+                intercepted_tunnel(&state, "secure.test", credentials, &ca).await;
+            client
+                .write_all(
+                    format!(
+                        "GET {} HTTP/1.1\r\nHost: secure.test\r\nConnection: close\r\n\r\n",
+                        path
+                    )
+                    .as_bytes(),
+                )
+                .await
+                .unwrap();
+            let mut response = Vec::new();
+            client.read_to_end(&mut response).await.unwrap();
+            assert!(String::from_utf8_lossy(&response).starts_with(status));
+            let _ = handler.await.unwrap();
+        }
+
+        // The tunnel itself still needs them
+        let (response, result) = exchange(
+            &state,
+            b"CONNECT secure.test:443 HTTP/1.1\r\nHost: secure.test:443\r\n\r\n",
+        )
+        .await;
+        assert!(response.starts_with(b"HTTP/1.1 407 Proxy Authentication Required\r\n"));
+        assert!(result.is_err());
+    }
+
+    /// Connects every target to an origin that never answers.
+    struct StalledConnector;
+
+    #[async_trait]
+    impl Connector for StalledConnector {
+        async fn connect(&self, _host: &str, _port: u16) -> Result<BoxedStream, ConnectFailure> {
+            let (proxy_side, mut origin) = tokio::io::duplex(4096);
+            tokio::spawn(async move {
+                let mut buf = [0u8; 1024];
+                while origin.read(&mut buf).await.unwrap_or(0) > 0 {}
+            });
+            Ok(Box::new(proxy_side))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_max_request_duration() {
+        let config = unguarded_config("MaxRequestDuration 1");
+        let mut state = ServerState::new(Arc::new(config));
+        state.connector = Arc::new(StalledConnector);
+        let state = Arc::new(state);
+
+        let (response, result) = exchange(
+            &state,
+            b"POST http://stalled.test/ HTTP/1.1\r\nContent-Length: 1\r\n\r\nx",
+        )
+        .await;
+
+        assert!(response.starts_with(b"HTTP/1.1 504 Gateway Timeout\r\n"));
+        assert!(matches!(result, Err(ProxyError::GatewayTimeout(_))));
+    }
+
+    #[tokio::test]
+    async fn test_slow_request_head() {
+        let config = Config::parse_config("ClientHeaderTimeout 1").unwrap();
+        let state = Arc::new(ServerState::new(Arc::new(config)));
+
+        let (mut client, handler) = connect_client(&state).await;
+
+        // Half a head, then nothing: closed without an answer
+        client
+            .write_all(b"GET http://slow.test/ HTTP/1.1\r\nHost: slow.test\r\n")
+            .await
+            .unwrap();
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).await.unwrap();
+
+        assert!(response.is_empty());
+        handler.await.unwrap().unwrap();
+        assert_eq!(state.counters.get(Counter::SlowClients), 1);
+    }
+
+    #[test]
+    fn test_parse_host_port() {
+        let parse = |target: &str| parse_host_port(target).ok();
+        let host_port = |host: &str, port| Some((host.to_string(), port));
+
+        assert_eq!(parse("example.com:443"), host_port("example.com", 443));
+        assert_eq!(parse("example.com"), host_port("example.com", 80));
+        assert_eq!(parse("[2001:db8::1]:443"), host_port("2001:db8::1", 443));
+        assert_eq!(parse("[::1]"), host_port("::1", 80));
+        assert_eq!(parse("2001:db8::1"), None);
+        assert_eq!(parse("[2001:db8::1]443"), None);
+        assert_eq!(parse("[example.com]:443"), None);
+        assert_eq!(parse("example.com:https"), None);
+        assert_eq!(authority("2001:db8::1", 443), "[2001:db8::1]:443");
+    }
+
+    #[tokio::test]
+    async fn test_default_denied_targets() {
+        let origin = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let state = Arc::new(ServerState::new(Arc::new(Config::default())));
+
+        // Loopback origins are out of reach without any configuration
+        let request = format!(
+            "GET http://{}/ HTTP/1.1\r\nConnection: close\r\n\r\n",
+            origin.local_addr().unwrap()
+        );
+        let (response, _) = exchange(&state, request.as_bytes()).await;
+
+        assert!(response.starts_with(b"HTTP/1.1 403 Forbidden\r\n"));
+        assert!(timeout(Duration::from_millis(50), origin.accept())
+            .await
+            .is_err());
+    }
+
+    #[test]
+    fn test_reconstruct_http_1_0_request() {
+        let mut request =
+            parse_http_request(b"GET http://example.com/a?b HTTP/1.0\r\nHost: example.com\r\n\r\n")
+                .unwrap();
+        request.headers.insert("Connection", "close");
+        assert_eq!(
+            reconstruct_http_request(&request),
+            b"GET /a?b HTTP/1.0\r\nHost: example.com\r\nConnection: close\r\n\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_send_and_await_response() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            // First connection dies without answering, the second responds
+            let (stream, _) = listener.accept().await.unwrap();
+            drop(stream);
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buf = [0u8; 64];
+            let _ = stream.read(&mut buf).await.unwrap();
+            stream
+                .write_all(b"HTTP/1.1 204 No Content\r\n\r\n")
+                .await
+                .unwrap();
+        });
+
+        let request = b"GET / HTTP/1.1\r\nHost: test\r\n\r\n";
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        assert!(send_and_await_response(&mut stream, request).await.is_err());
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let response = send_and_await_response(&mut stream, request).await.unwrap();
+        assert!(response.starts_with(b"HTTP/1.1 204"));
+    }
 }
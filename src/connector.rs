@@ -0,0 +1,1050 @@
+use crate::acl::IpList;
+use crate::config::Config;
+use crate::dns::DnsCache;
+use crate::error::ProxyError;
+use crate::utils::{authority, find_end_of_headers, html_escape, parse_http_response};
+use async_trait::async_trait;
+use log::debug;
+use socket2::{SockRef, TcpKeepalive};
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpSocket, TcpStream};
+use tokio::time::timeout;
+
+/// A byte stream to an upstream target.
+pub trait TargetStream: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<T: AsyncRead + AsyncWrite + Send + Unpin> TargetStream for T {}
+
+pub type BoxedStream = Box<dyn TargetStream>;
+
+/// Transport used to reach upstream targets. The proxy asks its connector
+/// for a stream to `host:port` and speaks HTTP or tunnels bytes over it,
+/// so embedders and tests can supply their own transports.
+#[async_trait]
+pub trait Connector: Send + Sync {
+    async fn connect(&self, host: &str, port: u16) -> Result<BoxedStream, ConnectFailure>;
+}
+
+/// Options set on outbound sockets before they connect.
+#[derive(Debug, Clone, Default)]
+pub struct SocketOptions {
+    /// Network interface to send through (SO_BINDTODEVICE).
+    pub interface: Option<String>,
+    /// Firewall mark for policy routing (SO_MARK).
+    pub mark: Option<u32>,
+    pub tuning: SocketTuning,
+}
+
+impl SocketOptions {
+    fn apply(&self, socket: &TcpSocket) -> io::Result<()> {
+        if let Some(interface) = &self.interface {
+            bind_device(socket, interface)?;
+        }
+        if let Some(mark) = self.mark {
+            set_mark(socket, mark)?;
+        }
+        self.tuning.apply(SockRef::from(socket))
+    }
+}
+
+/// TCP settings for client and origin sockets: TcpNoDelay, TcpKeepAlive,
+/// SocketReceiveBuffer and SocketSendBuffer.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SocketTuning {
+    nodelay: bool,
+    keepalive_idle: Option<Duration>,
+    keepalive_interval: Option<Duration>,
+    receive_buffer: Option<usize>,
+    send_buffer: Option<usize>,
+}
+
+impl SocketTuning {
+    pub fn new(config: &Config) -> Self {
+        let seconds = |seconds: u64| Some(Duration::from_secs(seconds)).filter(|_| seconds > 0);
+        let size = |size: u64| Some(size as usize).filter(|_| size > 0);
+        Self {
+            nodelay: config.tcp_nodelay,
+            keepalive_idle: seconds(config.tcp_keepalive_idle),
+            keepalive_interval: seconds(config.tcp_keepalive_interval),
+            receive_buffer: size(config.socket_receive_buffer),
+            send_buffer: size(config.socket_send_buffer),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.nodelay
+            || self.keepalive_idle.is_some()
+            || self.receive_buffer.is_some()
+            || self.send_buffer.is_some()
+    }
+
+    /// Set the options on a socket, connected or not.
+    pub fn apply(&self, socket: SockRef) -> io::Result<()> {
+        if self.nodelay {
+            socket.set_tcp_nodelay(true)?;
+        }
+        if let Some(idle) = self.keepalive_idle {
+            #[allow(unused_mut)]
+            let mut keepalive = TcpKeepalive::new().with_time(idle);
+            #[cfg(any(
+                target_os = "android",
+                target_os = "freebsd",
+                target_os = "linux",
+                target_os = "macos",
+                target_os = "windows"
+            ))]
+            if let Some(interval) = self.keepalive_interval {
+                keepalive = keepalive.with_interval(interval);
+            }
+            socket.set_tcp_keepalive(&keepalive)?;
+        }
+        if let Some(size) = self.receive_buffer {
+            socket.set_recv_buffer_size(size)?;
+        }
+        if let Some(size) = self.send_buffer {
+            socket.set_send_buffer_size(size)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+fn bind_device(socket: &TcpSocket, interface: &str) -> io::Result<()> {
+    socket.bind_device(Some(interface.as_bytes())).map_err(|e| {
+        io::Error::new(
+            e.kind(),
+            format!("cannot bind to interface {}: {}", interface, e),
+        )
+    })
+}
+
+#[cfg(not(any(target_os = "android", target_os = "fuchsia", target_os = "linux")))]
+fn bind_device(_socket: &TcpSocket, interface: &str) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        format!(
+            "cannot bind to interface {}: not supported on this platform",
+            interface
+        ),
+    ))
+}
+
+#[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+fn set_mark(socket: &TcpSocket, mark: u32) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    // SAFETY: the descriptor is open for the lifetime of `socket`, and the
+    // option value is a c_uint as SO_MARK expects.
+    let result = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_MARK,
+            &mark as *const u32 as *const libc::c_void,
+            std::mem::size_of::<u32>() as libc::socklen_t,
+        )
+    };
+    if result != 0 {
+        let e = io::Error::last_os_error();
+        return Err(io::Error::new(
+            e.kind(),
+            format!("cannot set mark {:#x}: {}", mark, e),
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(not(any(target_os = "android", target_os = "fuchsia", target_os = "linux")))]
+fn set_mark(_socket: &TcpSocket, mark: u32) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        format!(
+            "cannot set mark {:#x}: not supported on this platform",
+            mark
+        ),
+    ))
+}
+
+/// Plain TCP connections, trying each resolved address in turn.
+pub struct DirectConnector {
+    connect_timeout: Duration,
+    options: SocketOptions,
+    dns: Arc<DnsCache>,
+}
+
+impl DirectConnector {
+    pub fn new(connect_timeout: Duration) -> Self {
+        Self {
+            connect_timeout,
+            options: SocketOptions::default(),
+            dns: Arc::new(DnsCache::default()),
+        }
+    }
+
+    pub fn with_options(mut self, options: SocketOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// Resolve targets through `dns` instead of looking up every time.
+    pub fn with_dns_cache(mut self, dns: Arc<DnsCache>) -> Self {
+        self.dns = dns;
+        self
+    }
+}
+
+#[async_trait]
+impl Connector for DirectConnector {
+    async fn connect(&self, host: &str, port: u16) -> Result<BoxedStream, ConnectFailure> {
+        let stream = connect(host, port, self.connect_timeout, &self.options, &self.dns).await?;
+        Ok(Box::new(stream))
+    }
+}
+
+/// Refuses targets resolving to addresses in a deny list
+/// (DenyTargetNetworks), connecting through another connector to the
+/// checked addresses themselves, so the name cannot resolve differently
+/// for the connection than for the check.
+pub struct GuardedConnector {
+    inner: Arc<dyn Connector>,
+    denied: Arc<IpList>,
+    dns: Arc<DnsCache>,
+    resolve_timeout: Duration,
+}
+
+impl GuardedConnector {
+    pub fn new(inner: Arc<dyn Connector>, denied: Arc<IpList>, dns: Arc<DnsCache>) -> Self {
+        Self {
+            inner,
+            denied,
+            dns,
+            resolve_timeout: Duration::from_secs(30),
+        }
+    }
+}
+
+#[async_trait]
+impl Connector for GuardedConnector {
+    async fn connect(&self, host: &str, port: u16) -> Result<BoxedStream, ConnectFailure> {
+        let target = authority(host, port);
+        let addresses = match timeout(self.resolve_timeout, self.dns.resolve(host, port)).await {
+            Ok(Ok(addresses)) => addresses,
+            Ok(Err(e)) => {
+                return Err(ConnectFailure::new(
+                    &target,
+                    FailureKind::Dns,
+                    e.to_string(),
+                ))
+            }
+            Err(_) => {
+                return Err(ConnectFailure::new(
+                    &target,
+                    FailureKind::Timeout,
+                    "DNS lookup did not complete",
+                ))
+            }
+        };
+
+        // IPv4-mapped IPv6 addresses are checked as the IPv4 ones they are
+        let (denied, allowed): (Vec<SocketAddr>, Vec<SocketAddr>) = addresses
+            .into_iter()
+            .partition(|address| self.denied.contains(&address.ip().to_canonical()));
+        if allowed.is_empty() {
+            let addresses: Vec<String> = denied.iter().map(|a| a.ip().to_string()).collect();
+            return Err(ConnectFailure::new(
+                &target,
+                FailureKind::Denied,
+                format!("{} is not an allowed destination", addresses.join(", ")),
+            ));
+        }
+        for address in &denied {
+            debug!("Not connecting to {} at denied address {}", target, address);
+        }
+
+        let mut failure = None;
+        for address in allowed {
+            match self.inner.connect(&address.ip().to_string(), port).await {
+                Ok(stream) => return Ok(stream),
+                Err(e) => failure = Some(e),
+            }
+        }
+        let mut failure = failure.expect("at least one address was tried");
+        failure.target = target;
+        Err(failure)
+    }
+}
+
+/// Longest response head accepted from an upstream proxy.
+const MAX_TUNNEL_RESPONSE: usize = 8192;
+
+/// Tunnels to targets through an HTTP proxy with CONNECT, reaching the
+/// proxy over another connector.
+pub struct HttpTunnelConnector {
+    inner: Arc<dyn Connector>,
+    proxy_host: String,
+    proxy_port: u16,
+    /// Proxy-Authorization value sent to the proxy.
+    authorization: Option<String>,
+    timeout: Duration,
+}
+
+impl HttpTunnelConnector {
+    pub fn new(inner: Arc<dyn Connector>, proxy_host: &str, proxy_port: u16) -> Self {
+        Self {
+            inner,
+            proxy_host: proxy_host.to_string(),
+            proxy_port,
+            authorization: None,
+            timeout: Duration::from_secs(30),
+        }
+    }
+
+    pub fn with_authorization(mut self, authorization: Option<String>) -> Self {
+        self.authorization = authorization;
+        self
+    }
+
+    /// Ask the proxy for a tunnel to `target` over `stream`.
+    async fn open_tunnel(&self, stream: &mut BoxedStream, target: &str) -> Result<(), String> {
+        let mut request = format!("CONNECT {} HTTP/1.1\r\nHost: {}\r\n", target, target);
+        if let Some(authorization) = &self.authorization {
+            request.push_str(&format!("Proxy-Authorization: {}\r\n", authorization));
+        }
+        request.push_str("\r\n");
+        stream
+            .write_all(request.as_bytes())
+            .await
+            .map_err(|e| e.to_string())?;
+
+        // Read the head a byte at a time so no tunnelled data is consumed
+        let mut head = Vec::new();
+        while find_end_of_headers(&head).is_none() {
+            if head.len() >= MAX_TUNNEL_RESPONSE {
+                return Err("response head too large".to_string());
+            }
+            let byte = stream.read_u8().await.map_err(|e| e.to_string())?;
+            head.push(byte);
+        }
+
+        let response = parse_http_response(&head).map_err(|e| e.to_string())?;
+        if !(200..300).contains(&response.status) {
+            return Err(format!("answered {} {}", response.status, response.reason));
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Connector for HttpTunnelConnector {
+    async fn connect(&self, host: &str, port: u16) -> Result<BoxedStream, ConnectFailure> {
+        let target = if host.contains(':') {
+            format!("[{}]:{}", host, port)
+        } else {
+            format!("{}:{}", host, port)
+        };
+        let mut stream = self
+            .inner
+            .connect(&self.proxy_host, self.proxy_port)
+            .await?;
+
+        let proxy = format!("{}:{}", self.proxy_host, self.proxy_port);
+        match timeout(self.timeout, self.open_tunnel(&mut stream, &target)).await {
+            Ok(Ok(())) => {
+                debug!("Tunnel to {} opened through {}", target, proxy);
+                Ok(stream)
+            }
+            Ok(Err(e)) => Err(ConnectFailure::new(
+                &target,
+                FailureKind::Other,
+                format!("upstream proxy {}: {}", proxy, e),
+            )),
+            Err(_) => Err(ConnectFailure::new(
+                &target,
+                FailureKind::Timeout,
+                format!("upstream proxy {} did not answer", proxy),
+            )),
+        }
+    }
+}
+
+/// Reaches every target through an HTTP proxy that plain HTTP requests
+/// are sent to in absolute form, connecting to the proxy over another
+/// connector.
+pub struct HttpForwardConnector {
+    inner: Arc<dyn Connector>,
+    proxy_host: String,
+    proxy_port: u16,
+}
+
+impl HttpForwardConnector {
+    pub fn new(inner: Arc<dyn Connector>, proxy_host: &str, proxy_port: u16) -> Self {
+        Self {
+            inner,
+            proxy_host: proxy_host.to_string(),
+            proxy_port,
+        }
+    }
+}
+
+#[async_trait]
+impl Connector for HttpForwardConnector {
+    async fn connect(&self, host: &str, port: u16) -> Result<BoxedStream, ConnectFailure> {
+        debug!(
+            "Forwarding request for {}:{} to {}:{}",
+            host, port, self.proxy_host, self.proxy_port
+        );
+        self.inner.connect(&self.proxy_host, self.proxy_port).await
+    }
+}
+
+/// Tunnels to targets through a SOCKS5 proxy (RFC 1928), logging in with
+/// a username and password (RFC 1929) if it has them, reaching the proxy
+/// over another connector. Host names are resolved by the proxy.
+pub struct Socks5Connector {
+    inner: Arc<dyn Connector>,
+    proxy_host: String,
+    proxy_port: u16,
+    credentials: Option<(String, String)>,
+    timeout: Duration,
+}
+
+/// Why a SOCKS5 proxy refused a connection.
+struct Socks5Error {
+    kind: FailureKind,
+    message: String,
+}
+
+impl Socks5Error {
+    fn other(message: impl Into<String>) -> Self {
+        Self {
+            kind: FailureKind::Other,
+            message: message.into(),
+        }
+    }
+}
+
+impl From<io::Error> for Socks5Error {
+    fn from(error: io::Error) -> Self {
+        Self::other(error.to_string())
+    }
+}
+
+impl Socks5Connector {
+    pub fn new(inner: Arc<dyn Connector>, proxy_host: &str, proxy_port: u16) -> Self {
+        Self {
+            inner,
+            proxy_host: proxy_host.to_string(),
+            proxy_port,
+            credentials: None,
+            timeout: Duration::from_secs(30),
+        }
+    }
+
+    pub fn with_credentials(mut self, credentials: Option<(String, String)>) -> Self {
+        self.credentials = credentials;
+        self
+    }
+
+    /// Greet the proxy over `stream`, log in and ask it to connect to
+    /// `host:port`.
+    async fn open_tunnel(
+        &self,
+        stream: &mut BoxedStream,
+        host: &str,
+        port: u16,
+    ) -> Result<(), Socks5Error> {
+        // Offer no authentication, and username/password if configured
+        let greeting: &[u8] = match self.credentials {
+            Some(_) => &[5, 2, 0, 2],
+            None => &[5, 1, 0],
+        };
+        stream.write_all(greeting).await?;
+        let mut choice = [0u8; 2];
+        stream.read_exact(&mut choice).await?;
+        if choice[0] != 5 {
+            return Err(Socks5Error::other("not a SOCKS5 proxy"));
+        }
+        match (choice[1], &self.credentials) {
+            (0, _) => {}
+            (2, Some((username, password))) => {
+                if username.len() > 255 || password.len() > 255 {
+                    return Err(Socks5Error::other("credentials too long"));
+                }
+                let mut login = vec![1, username.len() as u8];
+                login.extend_from_slice(username.as_bytes());
+                login.push(password.len() as u8);
+                login.extend_from_slice(password.as_bytes());
+                stream.write_all(&login).await?;
+
+                let mut status = [0u8; 2];
+                stream.read_exact(&mut status).await?;
+                if status[1] != 0 {
+                    return Err(Socks5Error::other("login rejected"));
+                }
+            }
+            _ => return Err(Socks5Error::other("no acceptable authentication method")),
+        }
+
+        let mut request = vec![5, 1, 0];
+        match host.trim_matches(['[', ']']).parse::<IpAddr>() {
+            Ok(IpAddr::V4(ip)) => {
+                request.push(1);
+                request.extend_from_slice(&ip.octets());
+            }
+            Ok(IpAddr::V6(ip)) => {
+                request.push(4);
+                request.extend_from_slice(&ip.octets());
+            }
+            Err(_) if host.len() > 255 => return Err(Socks5Error::other("host name too long")),
+            Err(_) => {
+                request.extend_from_slice(&[3, host.len() as u8]);
+                request.extend_from_slice(host.as_bytes());
+            }
+        }
+        request.extend_from_slice(&port.to_be_bytes());
+        stream.write_all(&request).await?;
+
+        // Version, reply, reserved and the type of the bound address
+        let mut reply = [0u8; 4];
+        stream.read_exact(&mut reply).await?;
+        if reply[1] != 0 {
+            let (kind, message) = match reply[1] {
+                2 => (FailureKind::Other, "connection not allowed by ruleset"),
+                3 => (FailureKind::Unreachable, "network unreachable"),
+                4 => (FailureKind::Unreachable, "host unreachable"),
+                5 => (FailureKind::Refused, "connection refused"),
+                6 => (FailureKind::Timeout, "TTL expired"),
+                _ => (FailureKind::Other, "general failure"),
+            };
+            return Err(Socks5Error {
+                kind,
+                message: format!("answered {} ({})", reply[1], message),
+            });
+        }
+        let address = match reply[3] {
+            1 => 4,
+            4 => 16,
+            3 => stream.read_u8().await? as usize,
+            _ => return Err(Socks5Error::other("invalid reply")),
+        };
+        // The bound address and port are of no use
+        let mut bound = vec![0u8; address + 2];
+        stream.read_exact(&mut bound).await?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Connector for Socks5Connector {
+    async fn connect(&self, host: &str, port: u16) -> Result<BoxedStream, ConnectFailure> {
+        let target = if host.contains(':') && !host.starts_with('[') {
+            format!("[{}]:{}", host, port)
+        } else {
+            format!("{}:{}", host, port)
+        };
+        let mut stream = self
+            .inner
+            .connect(&self.proxy_host, self.proxy_port)
+            .await?;
+
+        let proxy = format!("{}:{}", self.proxy_host, self.proxy_port);
+        match timeout(self.timeout, self.open_tunnel(&mut stream, host, port)).await {
+            Ok(Ok(())) => {
+                debug!("Tunnel to {} opened through SOCKS5 proxy {}", target, proxy);
+                Ok(stream)
+            }
+            Ok(Err(e)) => Err(ConnectFailure::new(
+                &target,
+                e.kind,
+                format!("SOCKS5 proxy {}: {}", proxy, e.message),
+            )),
+            Err(_) => Err(ConnectFailure::new(
+                &target,
+                FailureKind::Timeout,
+                format!("SOCKS5 proxy {} did not answer", proxy),
+            )),
+        }
+    }
+}
+
+/// Why a connection to an upstream target could not be established.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FailureKind {
+    Dns,
+    Refused,
+    Unreachable,
+    Timeout,
+    Tls,
+    /// The target's addresses are all in DenyTargetNetworks.
+    Denied,
+    Other,
+}
+
+impl fmt::Display for FailureKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let text = match self {
+            FailureKind::Dns => "DNS resolution failed",
+            FailureKind::Refused => "connection refused",
+            FailureKind::Unreachable => "host unreachable",
+            FailureKind::Timeout => "timed out",
+            FailureKind::Tls => "TLS handshake failed",
+            FailureKind::Denied => "destination denied",
+            FailureKind::Other => "connection failed",
+        };
+        f.write_str(text)
+    }
+}
+
+impl FailureKind {
+    fn from_io_error(error: &io::Error) -> Self {
+        match error.kind() {
+            io::ErrorKind::ConnectionRefused => FailureKind::Refused,
+            io::ErrorKind::TimedOut => FailureKind::Timeout,
+            io::ErrorKind::HostUnreachable | io::ErrorKind::NetworkUnreachable => {
+                FailureKind::Unreachable
+            }
+            _ => FailureKind::Other,
+        }
+    }
+}
+
+/// A single connection attempt to one of the target's addresses.
+#[derive(Debug, Clone)]
+pub struct ConnectAttempt {
+    pub address: SocketAddr,
+    pub kind: FailureKind,
+    pub error: String,
+    pub elapsed: Duration,
+}
+
+/// Everything known about a failed connection to an upstream target,
+/// used for logging and the diagnostics error page.
+#[derive(Debug, Clone)]
+pub struct ConnectFailure {
+    pub target: String,
+    pub kind: FailureKind,
+    pub error: String,
+    pub resolve_time: Duration,
+    pub attempts: Vec<ConnectAttempt>,
+    pub elapsed: Duration,
+}
+
+impl fmt::Display for ConnectFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}: {}", self.target, self.kind, self.error)
+    }
+}
+
+impl ConnectFailure {
+    /// A failure without per-address details, for connectors that do not
+    /// resolve targets themselves.
+    pub fn new(target: &str, kind: FailureKind, error: impl Into<String>) -> Self {
+        Self {
+            target: target.to_string(),
+            kind,
+            error: error.into(),
+            resolve_time: Duration::ZERO,
+            attempts: vec![],
+            elapsed: Duration::ZERO,
+        }
+    }
+
+    pub fn status_code(&self) -> u16 {
+        match self.kind {
+            FailureKind::Timeout => 504,
+            FailureKind::Denied => 403,
+            _ => 502,
+        }
+    }
+
+    pub fn to_error(&self) -> ProxyError {
+        match self.kind {
+            FailureKind::Dns => ProxyError::DnsResolution(self.to_string()),
+            FailureKind::Timeout => ProxyError::GatewayTimeout(self.to_string()),
+            FailureKind::Tls => ProxyError::Tls(self.to_string()),
+            FailureKind::Denied => ProxyError::AccessDenied(self.to_string()),
+            _ => ProxyError::Upstream(self.to_string()),
+        }
+    }
+
+    /// HTML fragment describing the failure in detail.
+    pub fn to_html(&self) -> String {
+        let mut html = format!(
+            "<h2>Upstream diagnostics</h2>\n\
+             <table>\n\
+             <tr><td>Target</td><td>{}</td></tr>\n\
+             <tr><td>Error</td><td>{}: {}</td></tr>\n\
+             <tr><td>DNS resolution</td><td>{} ms</td></tr>\n\
+             <tr><td>Total time</td><td>{} ms</td></tr>\n\
+             </table>\n",
+            html_escape(&self.target),
+            self.kind,
+            html_escape(&self.error),
+            self.resolve_time.as_millis(),
+            self.elapsed.as_millis()
+        );
+
+        if !self.attempts.is_empty() {
+            html.push_str(
+                "<h3>Addresses attempted</h3>\n<table>\n\
+                 <tr><th>Address</th><th>Result</th><th>Time</th></tr>\n",
+            );
+            for attempt in &self.attempts {
+                html.push_str(&format!(
+                    "<tr><td>{}</td><td>{}: {}</td><td>{} ms</td></tr>\n",
+                    attempt.address,
+                    attempt.kind,
+                    html_escape(&attempt.error),
+                    attempt.elapsed.as_millis()
+                ));
+            }
+            html.push_str("</table>\n");
+        }
+
+        html
+    }
+}
+
+/// Resolve `host` through `dns` and connect to its addresses in turn until
+/// one accepts, giving up once `connect_timeout` has passed overall.
+pub async fn connect(
+    host: &str,
+    port: u16,
+    connect_timeout: Duration,
+    options: &SocketOptions,
+    dns: &DnsCache,
+) -> Result<TcpStream, ConnectFailure> {
+    let start = Instant::now();
+    let target = authority(host, port);
+    let failure = |kind, error: String, resolve_time, attempts| ConnectFailure {
+        target: target.clone(),
+        kind,
+        error,
+        resolve_time,
+        attempts,
+        elapsed: start.elapsed(),
+    };
+
+    let addresses: Vec<SocketAddr> = match timeout(connect_timeout, dns.resolve(host, port)).await {
+        Ok(Ok(addresses)) => addresses,
+        Ok(Err(e)) => {
+            return Err(failure(
+                FailureKind::Dns,
+                e.to_string(),
+                start.elapsed(),
+                vec![],
+            ))
+        }
+        Err(_) => {
+            return Err(failure(
+                FailureKind::Timeout,
+                "DNS lookup did not complete".to_string(),
+                start.elapsed(),
+                vec![],
+            ))
+        }
+    };
+    let resolve_time = start.elapsed();
+
+    if addresses.is_empty() {
+        return Err(failure(
+            FailureKind::Dns,
+            "no addresses found".to_string(),
+            resolve_time,
+            vec![],
+        ));
+    }
+
+    let mut attempts = Vec::new();
+    for address in addresses {
+        let remaining = match connect_timeout.checked_sub(start.elapsed()) {
+            Some(remaining) if !remaining.is_zero() => remaining,
+            _ => break,
+        };
+
+        let attempt_start = Instant::now();
+        let (kind, error) = match timeout(remaining, connect_socket(address, options)).await {
+            Ok(Ok(stream)) => {
+                debug!(
+                    "Connected to {} ({}) in {} ms",
+                    target,
+                    address,
+                    start.elapsed().as_millis()
+                );
+                return Ok(stream);
+            }
+            Ok(Err(e)) => (FailureKind::from_io_error(&e), e.to_string()),
+            Err(_) => (FailureKind::Timeout, "no answer".to_string()),
+        };
+
+        debug!("Connecting to {} ({}) failed: {}", target, address, error);
+        attempts.push(ConnectAttempt {
+            address,
+            kind,
+            error,
+            elapsed: attempt_start.elapsed(),
+        });
+    }
+
+    // Report the last attempt's outcome, or a timeout if the deadline ran
+    // out before every address could be tried
+    let (kind, error) = match attempts.last() {
+        Some(last) if start.elapsed() < connect_timeout => (last.kind, last.error.clone()),
+        _ => (
+            FailureKind::Timeout,
+            format!("no connection within {} seconds", connect_timeout.as_secs()),
+        ),
+    };
+    Err(failure(kind, error, resolve_time, attempts))
+}
+
+async fn connect_socket(address: SocketAddr, options: &SocketOptions) -> io::Result<TcpStream> {
+    let socket = if address.is_ipv4() {
+        TcpSocket::new_v4()?
+    } else {
+        TcpSocket::new_v6()?
+    };
+    options.apply(&socket)?;
+    socket.connect(address).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn test_connect_diagnostics() {
+        let options = SocketOptions::default();
+        let dns = DnsCache::default();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        assert!(
+            connect("127.0.0.1", port, Duration::from_secs(5), &options, &dns)
+                .await
+                .is_ok()
+        );
+
+        // Nothing listens on the port any more
+        drop(listener);
+        let failure = connect("127.0.0.1", port, Duration::from_secs(5), &options, &dns)
+            .await
+            .unwrap_err();
+        assert_eq!(failure.kind, FailureKind::Refused);
+        assert_eq!(failure.status_code(), 502);
+        assert_eq!(failure.attempts.len(), 1);
+        assert!(failure.to_html().contains("connection refused"));
+
+        let failure = connect("host.invalid", 80, Duration::from_secs(5), &options, &dns)
+            .await
+            .unwrap_err();
+        assert_eq!(failure.kind, FailureKind::Dns);
+        assert!(failure.attempts.is_empty());
+    }
+
+    #[test]
+    fn test_socket_tuning() {
+        let config = Config::parse_config(
+            "TcpNoDelay yes\nTcpKeepAlive 60 10\nSocketReceiveBuffer 64K\nSocketSendBuffer 64K",
+        )
+        .unwrap();
+        let tuning = SocketTuning::new(&config);
+        assert!(tuning.is_enabled());
+        assert!(!SocketTuning::new(&Config::default()).is_enabled());
+
+        let socket = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        tuning.apply(SockRef::from(&socket)).unwrap();
+        let socket = SockRef::from(&socket);
+        assert!(socket.tcp_nodelay().unwrap());
+        assert!(socket.keepalive().unwrap());
+        assert!(socket.recv_buffer_size().unwrap() >= 64 * 1024);
+        assert!(socket.send_buffer_size().unwrap() >= 64 * 1024);
+    }
+
+    /// Accepts every connection, remembering the hosts it was asked for.
+    #[derive(Default)]
+    struct Recorder(std::sync::Mutex<Vec<String>>);
+
+    #[async_trait]
+    impl Connector for Recorder {
+        async fn connect(&self, host: &str, _port: u16) -> Result<BoxedStream, ConnectFailure> {
+            self.0.lock().unwrap().push(host.to_string());
+            Ok(Box::new(tokio::io::empty()))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_guarded_connector() {
+        let recorder = Arc::new(Recorder::default());
+        let denied = IpList::new(
+            &["127.0.0.0/8".to_string(), "::1".to_string()],
+            "DenyTargetNetworks",
+        );
+        let guarded = GuardedConnector::new(
+            recorder.clone(),
+            Arc::new(denied),
+            Arc::new(DnsCache::default()),
+        );
+
+        // Names are checked by their addresses, mapped IPv4 as IPv4
+        for host in ["127.0.0.1", "localhost", "[::1]", "::ffff:127.0.0.2"] {
+            let failure = match guarded.connect(host, 80).await {
+                Err(failure) => failure,
+                Ok(_) => panic!("{} was not denied", host),
+            };
+            assert_eq!(failure.kind, FailureKind::Denied);
+            assert_eq!(failure.status_code(), 403);
+        }
+        assert!(recorder.0.lock().unwrap().is_empty());
+
+        // Allowed targets are reached by the address that was checked
+        assert!(guarded.connect("192.0.2.1", 80).await.is_ok());
+        assert!(guarded.connect("2001:db8::1", 443).await.is_ok());
+        assert_eq!(
+            *recorder.0.lock().unwrap(),
+            vec!["192.0.2.1".to_string(), "2001:db8::1".to_string()]
+        );
+    }
+
+    /// Connects to an in-memory HTTP proxy that opens tunnels for
+    /// requests with the right credentials and echoes the tunnelled data.
+    struct ParentProxy;
+
+    #[async_trait]
+    impl Connector for ParentProxy {
+        async fn connect(&self, host: &str, port: u16) -> Result<BoxedStream, ConnectFailure> {
+            assert_eq!((host, port), ("parent.test", 3128));
+            let (proxy_side, mut parent) = tokio::io::duplex(4096);
+            tokio::spawn(async move {
+                let mut buf = [0u8; 1024];
+                let n = parent.read(&mut buf).await.unwrap();
+                let request = String::from_utf8_lossy(&buf[..n]).to_string();
+                if !request.contains("Proxy-Authorization: Basic Ym9iOmh1bnRlcjI=\r\n") {
+                    let _ = parent
+                        .write_all(b"HTTP/1.1 407 Proxy Authentication Required\r\n\r\n")
+                        .await;
+                    return;
+                }
+                assert!(request.starts_with("CONNECT [2001:db8::1]:443 HTTP/1.1\r\n"));
+                parent
+                    .write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")
+                    .await
+                    .unwrap();
+                let n = parent.read(&mut buf).await.unwrap();
+                parent.write_all(&buf[..n]).await.unwrap();
+            });
+            Ok(Box::new(proxy_side))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_http_tunnel_connector() {
+        let tunnel = HttpTunnelConnector::new(Arc::new(ParentProxy), "parent.test", 3128)
+            .with_authorization(Some("Basic Ym9iOmh1bnRlcjI=".to_string()));
+        let mut stream = tunnel.connect("2001:db8::1", 443).await.unwrap();
+        stream.write_all(b"hello").await.unwrap();
+        let mut echo = [0u8; 5];
+        stream.read_exact(&mut echo).await.unwrap();
+        assert_eq!(&echo, b"hello");
+
+        let tunnel = HttpTunnelConnector::new(Arc::new(ParentProxy), "parent.test", 3128);
+        let failure = match tunnel.connect("2001:db8::1", 443).await {
+            Err(failure) => failure,
+            Ok(_) => panic!("tunnel opened without credentials"),
+        };
+        assert_eq!(failure.status_code(), 502);
+        assert!(failure.error.contains("answered 407"));
+    }
+
+    /// Connects to an in-memory SOCKS5 proxy accepting bob's login, which
+    /// refuses port 1 and echoes the tunnelled data otherwise.
+    struct SocksProxy;
+
+    #[async_trait]
+    impl Connector for SocksProxy {
+        async fn connect(&self, host: &str, port: u16) -> Result<BoxedStream, ConnectFailure> {
+            assert_eq!((host, port), ("socks.test", 1080));
+            let (proxy_side, mut parent) = tokio::io::duplex(4096);
+            tokio::spawn(async move {
+                let mut greeting = [0u8; 4];
+                parent.read_exact(&mut greeting).await.unwrap();
+                assert_eq!(greeting, [5, 2, 0, 2]);
+                parent.write_all(&[5, 2]).await.unwrap();
+
+                let mut login = [0u8; 13];
+                parent.read_exact(&mut login).await.unwrap();
+                let accepted = &login == b"\x01\x03bob\x07hunter2";
+                parent.write_all(&[1, !accepted as u8]).await.unwrap();
+                if !accepted {
+                    return;
+                }
+
+                let mut request = [0u8; 5];
+                parent.read_exact(&mut request).await.unwrap();
+                assert_eq!(request[..4], [5, 1, 0, 3]);
+                let mut target = vec![0u8; request[4] as usize + 2];
+                parent.read_exact(&mut target).await.unwrap();
+                assert!(target.starts_with(b"example.com"));
+                let port = u16::from_be_bytes([target[11], target[12]]);
+                let reply = if port == 1 { 5 } else { 0 };
+                parent
+                    .write_all(&[5, reply, 0, 1, 127, 0, 0, 1, 0x1f, 0x90])
+                    .await
+                    .unwrap();
+
+                let mut buf = [0u8; 64];
+                let n = parent.read(&mut buf).await.unwrap();
+                parent.write_all(&buf[..n]).await.unwrap();
+            });
+            Ok(Box::new(proxy_side))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_socks5_connector() {
+        let socks = |password: &str| {
+            Socks5Connector::new(Arc::new(SocksProxy), "socks.test", 1080)
+                .with_credentials(Some(("bob".to_string(), password.to_string())))
+        };
+        let mut stream = socks("hunter2").connect("example.com", 443).await.unwrap();
+        stream.write_all(b"hello").await.unwrap();
+        let mut echo = [0u8; 5];
+        stream.read_exact(&mut echo).await.unwrap();
+        assert_eq!(&echo, b"hello");
+
+        let failure = match socks("hunter2").connect("example.com", 1).await {
+            Err(failure) => failure,
+            Ok(_) => panic!("refused connection succeeded"),
+        };
+        assert_eq!(failure.kind, FailureKind::Refused);
+        assert_eq!(failure.target, "example.com:1");
+
+        let failure = match socks("wrong!!").connect("example.com", 443).await {
+            Err(failure) => failure,
+            Ok(_) => panic!("tunnel opened with wrong credentials"),
+        };
+        assert!(failure.error.contains("login rejected"));
+    }
+
+    #[tokio::test]
+    async fn test_missing_outgoing_interface() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let options = SocketOptions {
+            interface: Some("nonexistent0".to_string()),
+            ..SocketOptions::default()
+        };
+        let dns = DnsCache::default();
+
+        let failure = connect("127.0.0.1", port, Duration::from_secs(5), &options, &dns)
+            .await
+            .unwrap_err();
+        assert_eq!(failure.attempts.len(), 1);
+        assert!(failure.error.contains("nonexistent0"));
+    }
+}
@@ -0,0 +1,148 @@
+use crate::config::Config;
+use log::warn;
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::net::IpAddr;
+use std::sync::Mutex;
+
+/// Why a client was refused.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Denial {
+    /// Refused by the Allow/Deny rules.
+    Acl,
+    /// Sent credentials that were not accepted.
+    AuthFailure,
+    /// Asked for a URL blocked by the filter.
+    Filtered,
+    /// Asked for a destination in DenyTargetNetworks.
+    TargetDenied,
+}
+
+impl fmt::Display for Denial {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Denial::Acl => "acl-denied",
+            Denial::AuthFailure => "auth-failure",
+            Denial::Filtered => "filter-blocked",
+            Denial::TargetDenied => "target-denied",
+        })
+    }
+}
+
+/// Log of refused clients for tools like fail2ban and CrowdSec, one line
+/// per denial in a fixed format:
+///
+/// ```text
+/// 192.0.2.7 2024-05-01T12:00:00Z auth-failure user="bob" url="http://example.com/"
+/// ```
+///
+/// The client address comes first, followed by the UTC time, the reason
+/// and `key="value"` details. Values are escaped so that clients cannot
+/// forge lines.
+pub struct DenialLog {
+    file: Option<Mutex<File>>,
+}
+
+impl DenialLog {
+    pub fn new(config: &Config) -> Self {
+        let file = config.denial_log.as_ref().and_then(|path| {
+            match OpenOptions::new().create(true).append(true).open(path) {
+                Ok(file) => Some(Mutex::new(file)),
+                Err(e) => {
+                    warn!("Failed to open denial log {}: {}", path, e);
+                    None
+                }
+            }
+        });
+        Self { file }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.file.is_some()
+    }
+
+    /// Log that `ip` was refused, with `(key, value)` details.
+    pub fn record(&self, ip: IpAddr, denial: Denial, details: &[(&str, &str)]) {
+        let file = match &self.file {
+            Some(file) => file,
+            None => return,
+        };
+
+        let now = chrono::Utc::now().format("%Y-%m-%dT%H:%M:%SZ");
+        let line = format_line(&ip.to_string(), &now.to_string(), denial, details);
+        if let Err(e) = file.lock().unwrap().write_all(line.as_bytes()) {
+            warn!("Failed to write denial log: {}", e);
+        }
+    }
+}
+
+fn format_line(ip: &str, time: &str, denial: Denial, details: &[(&str, &str)]) -> String {
+    let mut line = format!("{} {} {}", ip, time, denial);
+    for (key, value) in details {
+        line.push_str(&format!(" {}=\"{}\"", key, escape(value)));
+    }
+    line.push('\n');
+    line
+}
+
+/// Escape a value for the inside of double quotes, keeping it on one line.
+fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if c.is_control() => escaped.push_str(&format!("\\x{:02x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_line() {
+        assert_eq!(
+            format_line(
+                "192.0.2.7",
+                "2024-05-01T12:00:00Z",
+                Denial::AuthFailure,
+                &[
+                    ("user", "bob\"\n10.0.0.1 x"),
+                    ("url", "http://example.com/")
+                ]
+            ),
+            "192.0.2.7 2024-05-01T12:00:00Z auth-failure user=\"bob\\\"\\x0a10.0.0.1 x\" \
+             url=\"http://example.com/\"\n"
+        );
+    }
+
+    #[test]
+    fn test_denial_log() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("denials.log");
+        let config = Config {
+            denial_log: Some(path.to_string_lossy().to_string()),
+            ..Default::default()
+        };
+
+        let log = DenialLog::new(&config);
+        assert!(log.is_enabled());
+        let ip: IpAddr = "2001:db8::1".parse().unwrap();
+        log.record(ip, Denial::Acl, &[]);
+        log.record(ip, Denial::Filtered, &[("url", "http://ads.example/")]);
+
+        let content = std::fs::read_to_string(&path).unwrap();
+        let lines: Vec<&str> = content.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].starts_with("2001:db8::1 "));
+        assert!(lines[0].ends_with(" acl-denied"));
+        assert!(lines[1].ends_with(" filter-blocked url=\"http://ads.example/\""));
+
+        assert!(!DenialLog::new(&Config::default()).is_enabled());
+    }
+}
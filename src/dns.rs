@@ -0,0 +1,319 @@
+use crate::config::Config;
+use crate::utils::html_escape;
+use log::{debug, warn};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+use trust_dns_resolver::config::{LookupIpStrategy, ResolverConfig, ResolverOpts};
+use trust_dns_resolver::error::ResolveErrorKind;
+use trust_dns_resolver::TokioAsyncResolver;
+
+/// Host names listed on the stats page and by the admin API.
+const TOP_LOOKUPS: usize = 10;
+
+/// Addresses of recently resolved host names, kept as long as their
+/// records' TTLs allow but at most DnsCacheTtl seconds, so busy
+/// destinations are not looked up on every request, and failed lookups,
+/// kept for at most DnsNegativeTtl seconds. At most DnsCacheSize names are
+/// held. With both TTLs 0 every lookup goes to the resolver.
+#[derive(Default)]
+pub struct DnsCache {
+    ttl: Duration,
+    negative_ttl: Duration,
+    max_entries: usize,
+    /// Asynchronous resolver using the system's name servers and hosts
+    /// file, made on first use. Its own cache is off, this one replaces it.
+    resolver: OnceLock<TokioAsyncResolver>,
+    entries: Mutex<HashMap<String, CachedLookup>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+struct CachedLookup {
+    /// The addresses, or why the name did not resolve.
+    answer: Result<Vec<IpAddr>, String>,
+    expires: Instant,
+    /// Lookups of the name while cached, carried over when it is renewed.
+    lookups: u64,
+}
+
+/// What the DNS cache holds and how well it does.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct DnsCacheStats {
+    pub entries: usize,
+    pub hits: u64,
+    pub misses: u64,
+    /// Share of lookups answered from the cache, in percent.
+    pub hit_rate: f64,
+    /// Most looked up host names and their lookup counts.
+    pub top_lookups: Vec<(String, u64)>,
+}
+
+impl DnsCache {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            ttl: Duration::from_secs(config.dns_cache_ttl),
+            negative_ttl: Duration::from_secs(config.dns_negative_ttl),
+            max_entries: config.dns_cache_size,
+            ..Self::default()
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.max_entries > 0 && !(self.ttl.is_zero() && self.negative_ttl.is_zero())
+    }
+
+    /// Addresses of `host` with `port`, from the cache if possible.
+    pub async fn resolve(&self, host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+        // Literal addresses need no lookup
+        if let Ok(ip) = host.trim_matches(['[', ']']).parse::<IpAddr>() {
+            return Ok(vec![SocketAddr::new(ip, port)]);
+        }
+        let name = host.to_lowercase();
+        let with_port = |addresses: &[IpAddr]| {
+            addresses
+                .iter()
+                .map(|&ip| SocketAddr::new(ip, port))
+                .collect()
+        };
+        if !self.is_enabled() {
+            return match self.lookup(&name).await {
+                Ok((addresses, _)) => Ok(with_port(&addresses)),
+                Err((error, _)) => Err(io::Error::other(error)),
+            };
+        }
+
+        let lookups = {
+            let mut entries = self.entries.lock().unwrap();
+            match entries.get_mut(&name) {
+                Some(entry) if entry.expires > Instant::now() => {
+                    entry.lookups += 1;
+                    self.hits.fetch_add(1, Ordering::Relaxed);
+                    return match &entry.answer {
+                        Ok(addresses) => Ok(with_port(addresses)),
+                        Err(error) => Err(io::Error::other(format!("{} (cached)", error))),
+                    };
+                }
+                Some(entry) => entry.lookups,
+                None => 0,
+            }
+        };
+        self.misses.fetch_add(1, Ordering::Relaxed);
+
+        // Answers last as long as their records, within the configured TTLs
+        let (answer, valid) = match self.lookup(&name).await {
+            Ok((addresses, valid)) => (Ok(addresses), Some(valid)),
+            Err((error, valid)) => (Err(error), valid),
+        };
+        let limit = if answer.is_ok() {
+            self.ttl
+        } else {
+            self.negative_ttl
+        };
+        let ttl = valid.map_or(limit, |valid| valid.min(limit));
+        if !ttl.is_zero() {
+            self.insert(name, answer.clone(), ttl, lookups + 1);
+        }
+        match answer {
+            Ok(addresses) => Ok(with_port(&addresses)),
+            Err(error) => Err(io::Error::other(error)),
+        }
+    }
+
+    /// Look up the addresses of `name`, with how long the answer is valid,
+    /// or why there are none, with how long that holds if the name servers
+    /// said.
+    async fn lookup(
+        &self,
+        name: &str,
+    ) -> Result<(Vec<IpAddr>, Duration), (String, Option<Duration>)> {
+        let resolver = self.resolver.get_or_init(|| {
+            let (config, mut options) = match trust_dns_resolver::system_conf::read_system_conf() {
+                Ok(system) => system,
+                Err(e) => {
+                    warn!("Cannot read the system resolver configuration: {}", e);
+                    (ResolverConfig::default(), ResolverOpts::default())
+                }
+            };
+            options.ip_strategy = LookupIpStrategy::Ipv4AndIpv6;
+            options.cache_size = 0;
+            TokioAsyncResolver::tokio(config, options)
+        });
+        match resolver.lookup_ip(name).await {
+            Ok(found) => {
+                let addresses: Vec<IpAddr> = found.iter().collect();
+                debug!("Resolved {} to {:?}", name, addresses);
+                if addresses.is_empty() {
+                    return Err(("no addresses found".to_string(), None));
+                }
+                let valid = found
+                    .valid_until()
+                    .saturating_duration_since(Instant::now());
+                Ok((addresses, valid))
+            }
+            Err(e) => {
+                debug!("Could not resolve {}: {}", name, e);
+                let valid = match e.kind() {
+                    ResolveErrorKind::NoRecordsFound { negative_ttl, .. } => {
+                        negative_ttl.map(|ttl| Duration::from_secs(ttl.into()))
+                    }
+                    _ => None,
+                };
+                Err((e.to_string(), valid))
+            }
+        }
+    }
+
+    /// Cache `answer` for `name`, pruning expired names when full. A new
+    /// name that still does not fit is not cached.
+    fn insert(
+        &self,
+        name: String,
+        answer: Result<Vec<IpAddr>, String>,
+        ttl: Duration,
+        lookups: u64,
+    ) {
+        let now = Instant::now();
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() >= self.max_entries && !entries.contains_key(&name) {
+            entries.retain(|_, entry| entry.expires > now);
+        }
+        if entries.len() < self.max_entries || entries.contains_key(&name) {
+            entries.insert(
+                name,
+                CachedLookup {
+                    answer,
+                    expires: now + ttl,
+                    lookups,
+                },
+            );
+        }
+    }
+
+    /// Forget every cached name, returning how many there were.
+    pub fn flush(&self) -> usize {
+        let mut entries = self.entries.lock().unwrap();
+        let count = entries.len();
+        entries.clear();
+        count
+    }
+
+    /// Forget `host`, returning whether it was cached.
+    pub fn evict(&self, host: &str) -> bool {
+        let mut entries = self.entries.lock().unwrap();
+        entries.remove(&host.to_lowercase()).is_some()
+    }
+
+    pub fn stats(&self) -> DnsCacheStats {
+        let hits = self.hits.load(Ordering::Relaxed);
+        let misses = self.misses.load(Ordering::Relaxed);
+        let entries = self.entries.lock().unwrap();
+
+        let mut top_lookups: Vec<(String, u64)> = entries
+            .iter()
+            .map(|(name, entry)| (name.clone(), entry.lookups))
+            .collect();
+        top_lookups.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        top_lookups.truncate(TOP_LOOKUPS);
+
+        DnsCacheStats {
+            entries: entries.len(),
+            hits,
+            misses,
+            hit_rate: if hits + misses > 0 {
+                hits as f64 / (hits + misses) as f64 * 100.0
+            } else {
+                0.0
+            },
+            top_lookups,
+        }
+    }
+
+    pub fn to_html(&self) -> String {
+        let stats = self.stats();
+        let rows: String = stats
+            .top_lookups
+            .iter()
+            .map(|(name, lookups)| {
+                format!(
+                    "            <tr><td>{}</td><td class=\"value\">{}</td></tr>\n",
+                    html_escape(name),
+                    lookups
+                )
+            })
+            .collect();
+
+        format!(
+            r#"    <div class="section">
+        <h2>DNS Cache</h2>
+        <table>
+            <tr><th>Metric</th><th>Value</th></tr>
+            <tr><td>Cached Names</td><td class="value">{}</td></tr>
+            <tr><td>Hits</td><td class="value">{}</td></tr>
+            <tr><td>Misses</td><td class="value">{}</td></tr>
+            <tr><td>Hit Rate</td><td class="value">{:.1}%</td></tr>
+        </table>
+        <table>
+            <tr><th>Host</th><th>Lookups</th></tr>
+{}        </table>
+    </div>
+"#,
+            stats.entries, stats.hits, stats.misses, stats.hit_rate, rows
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_dns_cache() {
+        assert!(DnsCache::new(&Config::default()).is_enabled());
+        let cache = DnsCache::new(&Config::parse_config("DnsCacheTtl 60").unwrap());
+        assert!(cache.is_enabled());
+
+        let addresses = cache.resolve("localhost", 8080).await.unwrap();
+        assert!(addresses.iter().all(|address| address.port() == 8080));
+        let addresses = cache.resolve("LocalHost", 443).await.unwrap();
+        assert!(addresses.iter().all(|address| address.port() == 443));
+        cache.resolve("127.0.0.1", 80).await.unwrap();
+        assert_eq!(
+            cache.resolve("[::1]", 80).await.unwrap(),
+            vec!["[::1]:80".parse().unwrap()]
+        );
+
+        let stats = cache.stats();
+        assert_eq!((stats.entries, stats.hits, stats.misses), (1, 1, 1));
+        assert_eq!(stats.hit_rate, 50.0);
+        assert_eq!(stats.top_lookups, vec![("localhost".to_string(), 2)]);
+        assert!(cache.to_html().contains("<td>localhost</td>"));
+
+        assert!(cache.evict("localhost"));
+        assert!(!cache.evict("localhost"));
+        cache.resolve("localhost", 80).await.unwrap();
+        assert_eq!(cache.flush(), 1);
+        assert_eq!(cache.stats().entries, 0);
+
+        // Failed lookups are cached for DnsNegativeTtl, within DnsCacheSize
+        let cache =
+            DnsCache::new(&Config::parse_config("DnsNegativeTtl 60\nDnsCacheSize 1").unwrap());
+        assert!(cache.is_enabled());
+        assert!(cache.resolve("host.invalid", 80).await.is_err());
+        let error = cache.resolve("HOST.invalid", 80).await.unwrap_err();
+        assert!(error.to_string().ends_with("(cached)"));
+        assert!(cache.resolve("other.invalid", 80).await.is_err());
+        cache.resolve("localhost", 80).await.unwrap();
+        let stats = cache.stats();
+        assert_eq!((stats.entries, stats.hits, stats.misses), (1, 1, 3));
+
+        // Disabled caches resolve every time
+        let cache = DnsCache::new(&Config::parse_config("DnsCacheTtl 0").unwrap());
+        cache.resolve("localhost", 80).await.unwrap();
+        assert_eq!(cache.stats(), DnsCacheStats::default());
+    }
+}
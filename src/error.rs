@@ -23,14 +23,23 @@ pub enum ProxyError {
     #[error("Connection timeout")]
     Timeout,
 
+    #[error("Request body too large: {0}")]
+    PayloadTooLarge(String),
+
     #[error("Upstream error: {0}")]
     Upstream(String),
 
+    #[error("Gateway timeout: {0}")]
+    GatewayTimeout(String),
+
+    #[error("Circuit open: {0}")]
+    CircuitOpen(String),
+
     #[error("Filter blocked request: {0}")]
     FilterBlocked(String),
 
     #[error("TLS error: {0}")]
-    Tls(#[from] native_tls::Error),
+    Tls(String),
 
     #[error("DNS resolution failed: {0}")]
     DnsResolution(String),
@@ -41,6 +50,9 @@ pub enum ProxyError {
     #[error("Resource exhausted: {0}")]
     ResourceExhausted(String),
 
+    #[error("Rate limit exceeded: {0}")]
+    RateLimited(String),
+
     #[error("Internal server error: {0}")]
     Internal(String),
 }
@@ -52,10 +64,15 @@ impl ProxyError {
             ProxyError::AccessDenied(_) => 403,      // Forbidden
             ProxyError::InvalidRequest(_) => 400,    // Bad Request
             ProxyError::Timeout => 408,              // Request Timeout
+            ProxyError::PayloadTooLarge(_) => 413,   // Payload Too Large
             ProxyError::FilterBlocked(_) => 403,     // Forbidden
             ProxyError::DnsResolution(_) => 502,     // Bad Gateway
             ProxyError::Upstream(_) => 502,          // Bad Gateway
+            ProxyError::CircuitOpen(_) => 502,       // Bad Gateway
+            ProxyError::Tls(_) => 502,               // Bad Gateway
+            ProxyError::GatewayTimeout(_) => 504,    // Gateway Timeout
             ProxyError::ResourceExhausted(_) => 503, // Service Unavailable
+            ProxyError::RateLimited(_) => 429,       // Too Many Requests
             _ => 500,                                // Internal Server Error
         }
     }
@@ -70,6 +87,9 @@ impl ProxyError {
                 format!("Bad request: {}", msg)
             }
             ProxyError::Timeout => "Request timeout".to_string(),
+            ProxyError::PayloadTooLarge(msg) => {
+                format!("Request body too large: {}", msg)
+            }
             ProxyError::FilterBlocked(msg) => {
                 format!("Request blocked by filter: {}", msg)
             }
@@ -79,9 +99,21 @@ impl ProxyError {
             ProxyError::Upstream(msg) => {
                 format!("Upstream server error: {}", msg)
             }
+            ProxyError::GatewayTimeout(msg) => {
+                format!("Upstream server timeout: {}", msg)
+            }
+            ProxyError::Tls(msg) => {
+                format!("TLS error: {}", msg)
+            }
+            ProxyError::CircuitOpen(msg) => {
+                format!("Upstream temporarily disabled by circuit breaker: {}", msg)
+            }
             ProxyError::ResourceExhausted(msg) => {
                 format!("Service temporarily unavailable: {}", msg)
             }
+            ProxyError::RateLimited(msg) => {
+                format!("Too many requests: {}", msg)
+            }
             _ => "Internal server error".to_string(),
         }
     }
@@ -0,0 +1,230 @@
+use crate::config::Config;
+use crate::utils::html_escape;
+use log::{info, warn};
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+
+/// Error page templates from ErrorFile and DefaultErrorFile. The files are
+/// read at startup and again on [`ErrorPages::reload`] (SIGHUP); a reload
+/// replaces every template at once, and one that fails keeps the pages in
+/// use. Statuses without a template get the built-in page.
+pub struct ErrorPages {
+    files: HashMap<u16, String>,
+    default_file: Option<String>,
+    templates: RwLock<Arc<Templates>>,
+}
+
+#[derive(Default)]
+struct Templates {
+    by_status: HashMap<u16, String>,
+    default: Option<String>,
+}
+
+impl ErrorPages {
+    pub fn new(config: &Config) -> Self {
+        let pages = Self {
+            files: config.error_files.clone(),
+            default_file: config.default_error_file.clone(),
+            templates: RwLock::default(),
+        };
+        if let Err(e) = pages.reload() {
+            warn!("Using built-in error pages: {}", e);
+        }
+        pages
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.files.is_empty() || self.default_file.is_some()
+    }
+
+    /// Read every template file again, returning how many were loaded.
+    pub fn reload(&self) -> Result<usize, String> {
+        let read = |path: &String| {
+            std::fs::read_to_string(path).map_err(|e| format!("cannot read {}: {}", path, e))
+        };
+        let templates = Templates {
+            by_status: self
+                .files
+                .iter()
+                .map(|(&status, path)| Ok((status, read(path)?)))
+                .collect::<Result<_, String>>()?,
+            default: self.default_file.as_ref().map(read).transpose()?,
+        };
+
+        let count = templates.by_status.len() + templates.default.iter().count();
+        *self.templates.write().unwrap() = Arc::new(templates);
+        if count > 0 {
+            info!("Loaded {} error page templates", count);
+        }
+        Ok(count)
+    }
+
+    /// Reload the templates whenever the process gets SIGHUP.
+    #[cfg(unix)]
+    pub async fn reload_on_hangup(&self) {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut hangups = match signal(SignalKind::hangup()) {
+            Ok(hangups) => hangups,
+            Err(e) => {
+                warn!("Unable to listen for SIGHUP: {}", e);
+                return;
+            }
+        };
+        while hangups.recv().await.is_some() {
+            if let Err(e) = self.reload() {
+                warn!("Keeping the current error pages: {}", e);
+            }
+        }
+    }
+
+    /// The page for `status` from its template, or `None` for the
+    /// built-in page. `detail_html` is already HTML; the `request`
+    /// variables, such as `clientip` and `url`, are text.
+    pub fn render(
+        &self,
+        status: u16,
+        reason: &str,
+        detail_html: &str,
+        request: &[(&str, &str)],
+    ) -> Option<String> {
+        let templates = self.templates.read().unwrap().clone();
+        let template = templates
+            .by_status
+            .get(&status)
+            .or(templates.default.as_ref())?;
+
+        let errno = status.to_string();
+        let cause = html_escape(reason);
+        let date = chrono::Utc::now()
+            .format("%a, %d %b %Y %H:%M:%S GMT")
+            .to_string();
+        let request: Vec<(&str, String)> = request
+            .iter()
+            .map(|&(name, value)| (name, html_escape(value)))
+            .collect();
+        let mut variables = vec![
+            ("errno", errno.as_str()),
+            ("cause", cause.as_str()),
+            ("detail", detail_html),
+            ("package", env!("CARGO_PKG_NAME")),
+            ("version", env!("CARGO_PKG_VERSION")),
+            ("date", date.as_str()),
+        ];
+        variables.extend(request.iter().map(|(name, value)| (*name, value.as_str())));
+        Some(substitute(template, &variables))
+    }
+}
+
+/// Replace `{name}` in `template` with the value of each variable,
+/// leaving unknown names as they are.
+fn substitute(template: &str, variables: &[(&str, &str)]) -> String {
+    let mut page = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        page.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let value = rest.find('}').and_then(|end| {
+            let (_, value) = variables.iter().find(|(name, _)| *name == &rest[1..end])?;
+            Some((value, end))
+        });
+        match value {
+            Some((value, end)) => {
+                page.push_str(value);
+                rest = &rest[end + 1..];
+            }
+            None => {
+                page.push('{');
+                rest = &rest[1..];
+            }
+        }
+    }
+    page.push_str(rest);
+    page
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use tempfile::NamedTempFile;
+
+    #[test]
+    fn test_substitute() {
+        assert_eq!(
+            substitute(
+                "<h1>{errno} {cause}</h1>{unknown} {detail}{",
+                &[
+                    ("errno", "404"),
+                    ("cause", "Not Found"),
+                    ("detail", "<p>x</p>")
+                ]
+            ),
+            "<h1>404 Not Found</h1>{unknown} <p>x</p>{"
+        );
+    }
+
+    #[test]
+    fn test_reload() {
+        let mut not_found = NamedTempFile::new().unwrap();
+        write!(
+            not_found,
+            "<h1>Missing: {{cause}}</h1>{{detail}}<p>{{url}} for {{clientip}}</p>"
+        )
+        .unwrap();
+        let mut default = NamedTempFile::new().unwrap();
+        write!(default, "<h1>Error {{errno}}</h1>").unwrap();
+        let config = Config::parse_config(&format!(
+            "ErrorFile 404 \"{}\"\nDefaultErrorFile \"{}\"",
+            not_found.path().display(),
+            default.path().display()
+        ))
+        .unwrap();
+
+        let pages = ErrorPages::new(&config);
+        assert!(pages.is_enabled());
+        assert_eq!(
+            pages
+                .render(
+                    404,
+                    "Not <Found>",
+                    "<p>gone</p>",
+                    &[
+                        ("url", "http://example.com/?a<b"),
+                        ("clientip", "192.0.2.7")
+                    ]
+                )
+                .as_deref(),
+            Some(
+                "<h1>Missing: Not &lt;Found&gt;</h1><p>gone</p>\
+                 <p>http://example.com/?a&lt;b for 192.0.2.7</p>"
+            )
+        );
+        assert_eq!(
+            pages.render(502, "Bad Gateway", "", &[]).as_deref(),
+            Some("<h1>Error 502</h1>")
+        );
+
+        // Edited templates are picked up on reload
+        std::fs::write(not_found.path(), "<h1>Gone</h1>").unwrap();
+        assert_eq!(pages.reload(), Ok(2));
+        assert_eq!(
+            pages.render(404, "Not Found", "", &[]).as_deref(),
+            Some("<h1>Gone</h1>")
+        );
+
+        // A failed reload keeps the templates in use
+        let path = default.path().to_path_buf();
+        drop(default);
+        assert!(pages.reload().unwrap_err().contains("cannot read"));
+        assert!(!path.exists());
+        assert_eq!(
+            pages.render(500, "Internal Error", "", &[]).as_deref(),
+            Some("<h1>Error 500</h1>")
+        );
+
+        assert!(ErrorPages::new(&Config::default())
+            .render(404, "Not Found", "", &[])
+            .is_none());
+    }
+}
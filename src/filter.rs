@@ -1,32 +1,107 @@
-use crate::config::Config;
+use crate::acl::IpList;
+use crate::config::{Config, FilterType};
 use crate::error::{ProxyError, ProxyResult};
+use aho_corasick::AhoCorasick;
 use log::{debug, warn};
-use regex::Regex;
-use std::fs::File;
-use std::io::{BufRead, BufReader};
+use regex::{Regex, RegexSet, RegexSetBuilder};
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::net::IpAddr;
+
+/// Names in hosts files that are the machine's own, not blocked hosts.
+const LOCAL_HOST_NAMES: &[&str] = &[
+    "localhost",
+    "localhost.localdomain",
+    "local",
+    "broadcasthost",
+    "ip6-localhost",
+    "ip6-loopback",
+    "ip6-localnet",
+    "ip6-mcastprefix",
+    "ip6-allnodes",
+    "ip6-allrouters",
+    "ip6-allhosts",
+    "0.0.0.0",
+];
 
 pub struct Filter {
     enabled: bool,
-    rules: Vec<FilterRule>,
+    rules: Vec<FilterEntry>,
+    matcher: Matcher,
     case_sensitive: bool,
     extended: bool,
+    /// The rules list what is allowed rather than what is blocked.
+    default_deny: bool,
+    file_type: FilterType,
+    /// Rule sets for particular clients (FilterGroup), in config order.
+    groups: Vec<FilterGroup>,
+}
+
+/// A filter file used instead of the main one for the clients at `clients`
+/// and the users in `users`.
+struct FilterGroup {
+    name: String,
+    file: String,
+    clients: IpList,
+    users: Vec<String>,
+    filter: Filter,
+}
+
+impl FilterGroup {
+    fn applies_to(&self, client: IpAddr, user: Option<&str>) -> bool {
+        self.clients.contains(&client)
+            || user.is_some_and(|user| self.users.iter().any(|name| name == user))
+    }
+}
+
+/// Rule counts and problems found by `Filter::validate_file`.
+#[derive(Debug, Default)]
+pub struct FilterReport {
+    pub exact: usize,
+    pub domain: usize,
+    pub host: usize,
+    pub regex: usize,
+    pub invalid: Vec<InvalidRule>,
+}
+
+impl FilterReport {
+    pub fn rule_count(&self) -> usize {
+        self.exact + self.domain + self.host + self.regex
+    }
+}
+
+/// A filter file line whose regex does not compile.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvalidRule {
+    pub line: usize,
+    pub pattern: String,
+    pub error: String,
 }
 
+/// A filter rule together with the pattern it was created from.
+struct FilterEntry {
+    pattern: String,
+    rule: FilterRule,
+}
+
+/// A rule read from a filter file: its line number, pattern and the rule
+/// parsed from it.
+type FileRule = (usize, String, Result<FilterRule, regex::Error>);
+
 #[derive(Clone)]
 enum FilterRule {
     Exact(String),
     Regex(Regex),
+    /// A domain and its subdomains, as `.example.com`.
     Domain(String),
+    /// A single host name, from a hosts file.
+    Host(String),
 }
 
 impl Filter {
     pub fn new(config: &Config) -> Self {
-        let mut filter = Self {
-            enabled: config.filter_urls,
-            rules: Vec::new(),
-            case_sensitive: config.filter_casesensitive,
-            extended: config.filter_extended,
-        };
+        let mut filter = Self::empty(config, config.filter_urls);
 
         if config.filter_urls {
             if let Some(filter_file) = &config.filter_file {
@@ -36,10 +111,88 @@ impl Filter {
             }
         }
 
+        for group in &config.filter_groups {
+            let mut group_filter = Self::empty(config, true);
+            if let Err(e) = group_filter.load_filter_file(&group.file) {
+                warn!(
+                    "Failed to load filter file {} of group {}: {}",
+                    group.file, group.name, e
+                );
+            }
+            filter.groups.push(FilterGroup {
+                name: group.name.clone(),
+                file: group.file.clone(),
+                clients: IpList::new(&group.clients, "FilterGroup"),
+                users: group.users.clone(),
+                filter: group_filter,
+            });
+        }
+
         filter
     }
 
-    pub fn is_allowed(&self, url: &str) -> ProxyResult<bool> {
+    /// A filter with the settings of `config` and no rules.
+    fn empty(config: &Config, enabled: bool) -> Self {
+        Self {
+            enabled,
+            rules: Vec::new(),
+            matcher: Matcher::default(),
+            case_sensitive: config.filter_casesensitive,
+            extended: config.filter_extended,
+            default_deny: config.filter_default_deny,
+            file_type: config.filter_type,
+            groups: Vec::new(),
+        }
+    }
+
+    /// Read the filter files again with the filter settings of `config`,
+    /// replacing every rule, those added at runtime included, and returning
+    /// how many there are in all. The current rules stay if a file cannot
+    /// be read.
+    pub fn reload(&mut self, config: &Config) -> ProxyResult<usize> {
+        let mut reloaded = Self::empty(config, self.enabled);
+        if let (true, Some(filter_file)) = (config.filter_urls, &config.filter_file) {
+            reloaded.load_filter_file(filter_file)?;
+        }
+        let mut groups = Vec::with_capacity(self.groups.len());
+        for group in &self.groups {
+            let mut filter = Self::empty(config, true);
+            filter.load_filter_file(&group.file)?;
+            groups.push(filter);
+        }
+
+        for (group, filter) in self.groups.iter_mut().zip(groups) {
+            group.filter = filter;
+        }
+        reloaded.groups = std::mem::take(&mut self.groups);
+        *self = reloaded;
+        Ok(self.rules.len()
+            + self
+                .groups
+                .iter()
+                .map(|group| group.filter.rule_count())
+                .sum::<usize>())
+    }
+
+    /// Whether `client`, authenticated as `user` if at all, may request
+    /// `url`. The first FilterGroup for the client or user decides, or the
+    /// main filter if none is for them.
+    pub fn is_allowed(&self, url: &str, client: IpAddr, user: Option<&str>) -> ProxyResult<bool> {
+        match self
+            .groups
+            .iter()
+            .find(|group| group.applies_to(client, user))
+        {
+            Some(group) => {
+                debug!("Filtering {} for {} by group {}", url, client, group.name);
+                group.filter.check(url)
+            }
+            None => self.check(url),
+        }
+    }
+
+    /// Whether this rule set lets `url` through.
+    fn check(&self, url: &str) -> ProxyResult<bool> {
         if !self.enabled {
             return Ok(true);
         }
@@ -50,24 +203,130 @@ impl Filter {
             url.to_lowercase()
         };
 
-        for rule in &self.rules {
-            if self.matches_rule(rule, &url_to_check) {
-                debug!("URL {} blocked by filter rule: {:?}", url, rule);
-                return Ok(false);
+        if let Some(entry) = self.matching_rule(&url_to_check) {
+            if self.default_deny {
+                debug!("URL {} allowed by filter rule: {:?}", url, entry.rule);
+            } else {
+                debug!("URL {} blocked by filter rule: {:?}", url, entry.rule);
             }
+            return Ok(self.default_deny);
         }
 
-        debug!("URL {} allowed by filter", url);
-        Ok(true)
+        if self.default_deny {
+            debug!("URL {} matches no filter rule, denied by default", url);
+        } else {
+            debug!("URL {} allowed by filter", url);
+        }
+        Ok(!self.default_deny)
+    }
+
+    /// A rule matching `url`, if any.
+    fn matching_rule(&self, url: &str) -> Option<&FilterEntry> {
+        let matcher = &self.matcher;
+        let index = match url::Url::parse(url) {
+            Ok(parsed_url) => parsed_url.host_str().and_then(|host| {
+                let host = self.normalize(host);
+                matcher
+                    .hosts
+                    .get(&host)
+                    .copied()
+                    .or_else(|| matcher.domains.find(&host))
+            }),
+            // Unparsable URLs are matched by substring, rule by rule
+            Err(_) => matcher
+                .domains
+                .rules
+                .iter()
+                .chain(matcher.hosts.values())
+                .copied()
+                .find(|&index| self.matches_rule(&self.rules[index].rule, url)),
+        };
+
+        index
+            .or_else(|| match &matcher.exact {
+                Some(automaton) => automaton
+                    .find(url)
+                    .map(|found| matcher.exact_rules[found.pattern().as_usize()]),
+                None => self.find_in(&matcher.exact_rules, url),
+            })
+            .or_else(|| match &matcher.regexes {
+                Some(set) => set
+                    .matches(url)
+                    .iter()
+                    .next()
+                    .map(|found| matcher.regex_rules[found]),
+                None => self.find_in(&matcher.regex_rules, url),
+            })
+            .map(|index| &self.rules[index])
+    }
+
+    /// The first of the rules at `indices` matching `url`.
+    fn find_in(&self, indices: &[usize], url: &str) -> Option<usize> {
+        indices
+            .iter()
+            .copied()
+            .find(|&index| self.matches_rule(&self.rules[index].rule, url))
+    }
+
+    /// Compile the rules for matching, after they change.
+    fn compile(&mut self) {
+        self.matcher = Matcher::new(&self.rules);
     }
 
     fn load_filter_file(&mut self, filename: &str) -> ProxyResult<()> {
+        for (line_num, pattern, rule) in self.parse_file(filename)? {
+            let rule = match rule {
+                Ok(rule) => rule,
+                Err(_) => {
+                    // Fall back to exact match if regex compilation fails
+                    warn!(
+                        "Invalid regex pattern on line {}: {}, treating as exact match",
+                        line_num, pattern
+                    );
+                    FilterRule::Exact(self.normalize(&pattern))
+                }
+            };
+
+            self.rules.push(FilterEntry { pattern, rule });
+        }
+        self.compile();
+
+        debug!("Loaded {} filter rules from {}", self.rules.len(), filename);
+        Ok(())
+    }
+
+    /// Check a filter file with the filter settings of `config`, without
+    /// loading it.
+    pub fn validate_file(config: &Config, filename: &str) -> ProxyResult<FilterReport> {
+        let filter = Self::empty(config, true);
+
+        let mut report = FilterReport::default();
+        for (line, pattern, rule) in filter.parse_file(filename)? {
+            match rule {
+                Ok(FilterRule::Exact(_)) => report.exact += 1,
+                Ok(FilterRule::Domain(_)) => report.domain += 1,
+                Ok(FilterRule::Host(_)) => report.host += 1,
+                Ok(FilterRule::Regex(_)) => report.regex += 1,
+                Err(e) => report.invalid.push(InvalidRule {
+                    line,
+                    pattern,
+                    error: e.to_string(),
+                }),
+            }
+        }
+        Ok(report)
+    }
+
+    /// Rules of a filter file with their line numbers, skipping blank lines
+    /// and comments. Hosts files and Adblock lists become host and domain
+    /// rules.
+    fn parse_file(&self, filename: &str) -> ProxyResult<Vec<FileRule>> {
         let file = File::open(filename).map_err(|e| {
             ProxyError::Config(format!("Cannot open filter file {}: {}", filename, e))
         })?;
 
         let reader = BufReader::new(file);
-
+        let mut lines = Vec::new();
         for (line_num, line) in reader.lines().enumerate() {
             let line = line.map_err(|e| {
                 ProxyError::Config(format!(
@@ -76,49 +335,175 @@ impl Filter {
                     e
                 ))
             })?;
+            lines.push((line_num + 1, line));
+        }
 
-            let line = line.trim();
+        let file_type = match self.file_type {
+            FilterType::Auto => detect_type(lines.iter().map(|(_, line)| line.as_str())),
+            file_type => file_type,
+        };
+        debug!("Reading filter file {} as {:?}", filename, file_type);
 
-            // Skip empty lines and comments
-            if line.is_empty() || line.starts_with('#') {
-                continue;
+        let mut rules = Vec::new();
+        let mut unsupported = 0;
+        for (line_num, line) in &lines {
+            let line = line.trim();
+            match file_type {
+                FilterType::Hosts => {
+                    for name in hosts_names(line) {
+                        let name = self.normalize(name);
+                        rules.push((*line_num, name.clone(), Ok(FilterRule::Host(name))));
+                    }
+                }
+                FilterType::Adblock => {
+                    if line.is_empty() || line.starts_with('!') || line.starts_with('[') {
+                        continue;
+                    }
+                    match adblock_domain(line) {
+                        Some(domain) => {
+                            let pattern = format!(".{}", self.normalize(domain));
+                            let rule = FilterRule::Domain(pattern.clone());
+                            rules.push((*line_num, pattern, Ok(rule)));
+                        }
+                        None => unsupported += 1,
+                    }
+                }
+                FilterType::Plain | FilterType::Auto => {
+                    // Skip empty lines and comments
+                    if line.is_empty() || line.starts_with('#') {
+                        continue;
+                    }
+                    rules.push((*line_num, line.to_string(), self.parse_rule(line)));
+                }
             }
+        }
+        if unsupported > 0 {
+            debug!(
+                "Skipped {} Adblock rules of {} that are not plain domain rules",
+                unsupported, filename
+            );
+        }
 
-            let rule_text = if self.case_sensitive {
-                line.to_string()
-            } else {
-                line.to_lowercase()
-            };
+        Ok(rules)
+    }
 
-            let rule = if self.extended {
-                // Try to compile as regex
-                match Regex::new(&rule_text) {
-                    Ok(regex) => FilterRule::Regex(regex),
-                    Err(_) => {
-                        // Fall back to exact match if regex compilation fails
-                        warn!(
-                            "Invalid regex pattern on line {}: {}, treating as exact match",
-                            line_num + 1,
-                            line
-                        );
-                        FilterRule::Exact(rule_text)
-                    }
-                }
-            } else if line.starts_with('.') {
-                // Domain rule (e.g., .example.com)
-                FilterRule::Domain(rule_text)
-            } else {
-                // Exact match
-                FilterRule::Exact(rule_text)
-            };
+    fn parse_rule(&self, pattern: &str) -> Result<FilterRule, regex::Error> {
+        let rule_text = self.normalize(pattern);
+
+        Ok(if self.extended {
+            FilterRule::Regex(Regex::new(&rule_text)?)
+        } else if pattern.starts_with('.') {
+            // Domain rule (e.g., .example.com)
+            FilterRule::Domain(rule_text)
+        } else {
+            // Exact match
+            FilterRule::Exact(rule_text)
+        })
+    }
+
+    fn normalize(&self, pattern: &str) -> String {
+        if self.case_sensitive {
+            pattern.to_string()
+        } else {
+            pattern.to_lowercase()
+        }
+    }
 
-            self.rules.push(rule);
+    /// Add a rule at runtime. Returns false if the pattern is already
+    /// present.
+    pub fn add_rule(&mut self, pattern: &str) -> ProxyResult<bool> {
+        let pattern = pattern.trim();
+        if pattern.is_empty() || pattern.starts_with('#') {
+            return Err(ProxyError::InvalidRequest(format!(
+                "Invalid filter pattern: {:?}",
+                pattern
+            )));
+        }
+        if self.position(pattern).is_some() {
+            return Ok(false);
         }
 
-        debug!("Loaded {} filter rules from {}", self.rules.len(), filename);
+        let rule = self.parse_rule(pattern).map_err(|e| {
+            ProxyError::InvalidRequest(format!("Invalid filter regex {}: {}", pattern, e))
+        })?;
+        self.rules.push(FilterEntry {
+            pattern: pattern.to_string(),
+            rule,
+        });
+        self.compile();
+        Ok(true)
+    }
+
+    /// Remove a rule at runtime. Returns false if no rule has this pattern.
+    pub fn remove_rule(&mut self, pattern: &str) -> bool {
+        match self.position(pattern.trim()) {
+            Some(index) => {
+                self.rules.remove(index);
+                self.compile();
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn position(&self, pattern: &str) -> Option<usize> {
+        let pattern = self.normalize(pattern);
+        self.rules
+            .iter()
+            .position(|entry| self.normalize(&entry.pattern) == pattern)
+    }
+
+    /// Patterns of all rules with their kind ("exact", "domain", "host" or
+    /// "regex").
+    pub fn rules(&self) -> Vec<(&str, &'static str)> {
+        self.rules
+            .iter()
+            .map(|entry| {
+                let kind = match entry.rule {
+                    FilterRule::Exact(_) => "exact",
+                    FilterRule::Regex(_) => "regex",
+                    FilterRule::Domain(_) => "domain",
+                    FilterRule::Host(_) => "host",
+                };
+                (entry.pattern.as_str(), kind)
+            })
+            .collect()
+    }
+
+    /// Append a pattern added at runtime to the filter file.
+    pub fn persist_added(&self, filename: &str, pattern: &str) -> ProxyResult<()> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(filename)
+            .map_err(|e| {
+                ProxyError::Config(format!("Cannot open filter file {}: {}", filename, e))
+            })?;
+        writeln!(file, "{}", pattern.trim())?;
         Ok(())
     }
 
+    /// Remove the lines holding a pattern deleted at runtime from the
+    /// filter file, leaving comments and other rules untouched.
+    pub fn persist_removed(&self, filename: &str, pattern: &str) -> ProxyResult<()> {
+        let content = fs::read_to_string(filename).map_err(|e| {
+            ProxyError::Config(format!("Cannot read filter file {}: {}", filename, e))
+        })?;
+
+        let pattern = self.normalize(pattern.trim());
+        let mut kept = String::with_capacity(content.len());
+        for line in content.lines() {
+            if self.normalize(line.trim()) != pattern {
+                kept.push_str(line);
+                kept.push('\n');
+            }
+        }
+
+        fs::write(filename, kept).map_err(|e| {
+            ProxyError::Config(format!("Cannot write filter file {}: {}", filename, e))
+        })
+    }
+
     fn matches_rule(&self, rule: &FilterRule, url: &str) -> bool {
         match rule {
             FilterRule::Exact(pattern) => url.contains(pattern),
@@ -134,8 +519,8 @@ impl Filter {
                         };
 
                         // Check if the host ends with the domain (for .example.com rules)
-                        if domain.starts_with('.') {
-                            host.ends_with(domain) || host == &domain[1..]
+                        if let Some(bare) = domain.strip_prefix('.') {
+                            host.ends_with(domain) || host == bare
                         } else {
                             host == *domain
                         }
@@ -147,6 +532,12 @@ impl Filter {
                     url.contains(domain)
                 }
             }
+            FilterRule::Host(name) => match url::Url::parse(url) {
+                Ok(parsed_url) => parsed_url
+                    .host_str()
+                    .is_some_and(|host| self.normalize(host) == *name),
+                Err(_) => url.contains(name.as_str()),
+            },
         }
     }
 
@@ -165,10 +556,167 @@ impl std::fmt::Debug for FilterRule {
             FilterRule::Exact(pattern) => write!(f, "Exact({})", pattern),
             FilterRule::Regex(regex) => write!(f, "Regex({})", regex.as_str()),
             FilterRule::Domain(domain) => write!(f, "Domain({})", domain),
+            FilterRule::Host(name) => write!(f, "Host({})", name),
         }
     }
 }
 
+/// The rules compiled for matching each URL in about the same time
+/// however many there are, each pointing back to its rule's position.
+/// Exact rules match anywhere in the URL, so they share an Aho-Corasick
+/// automaton; regexes share a `RegexSet`. Should either fail to build,
+/// its rules are tried one by one instead.
+#[derive(Default)]
+struct Matcher {
+    exact: Option<AhoCorasick>,
+    exact_rules: Vec<usize>,
+    regexes: Option<RegexSet>,
+    regex_rules: Vec<usize>,
+    hosts: HashMap<String, usize>,
+    domains: DomainTrie,
+}
+
+/// Upper bound on the compiled size of the regex set.
+const REGEX_SET_SIZE_LIMIT: usize = 256 * 1024 * 1024;
+
+impl Matcher {
+    fn new(rules: &[FilterEntry]) -> Self {
+        let mut matcher = Self::default();
+        let mut exact = Vec::new();
+        let mut regexes = Vec::new();
+
+        for (index, entry) in rules.iter().enumerate() {
+            match &entry.rule {
+                FilterRule::Exact(pattern) => {
+                    exact.push(pattern.as_str());
+                    matcher.exact_rules.push(index);
+                }
+                FilterRule::Regex(regex) => {
+                    regexes.push(regex.as_str());
+                    matcher.regex_rules.push(index);
+                }
+                FilterRule::Host(name) => {
+                    matcher.hosts.entry(name.clone()).or_insert(index);
+                }
+                FilterRule::Domain(domain) => matcher.domains.insert(domain, index),
+            }
+        }
+
+        if !exact.is_empty() {
+            matcher.exact = AhoCorasick::new(&exact)
+                .map_err(|e| warn!("Cannot combine exact filter rules: {}", e))
+                .ok();
+        }
+        if !regexes.is_empty() {
+            matcher.regexes = RegexSetBuilder::new(&regexes)
+                .size_limit(REGEX_SET_SIZE_LIMIT)
+                .build()
+                .map_err(|e| warn!("Cannot combine regex filter rules: {}", e))
+                .ok();
+        }
+        matcher
+    }
+}
+
+/// Domain rules by their labels from the top level down, so that a host
+/// is matched against all of them by walking its own labels once.
+#[derive(Default)]
+struct DomainTrie {
+    root: TrieNode,
+    /// Positions of every domain rule.
+    rules: Vec<usize>,
+}
+
+#[derive(Default)]
+struct TrieNode {
+    children: HashMap<String, TrieNode>,
+    /// The rule for the domain ending at this node.
+    rule: Option<usize>,
+}
+
+impl DomainTrie {
+    /// Add the rule at `index` for `domain` (`.example.com`), keeping the
+    /// first rule of a domain.
+    fn insert(&mut self, domain: &str, index: usize) {
+        self.rules.push(index);
+        let mut node = &mut self.root;
+        for label in domain.trim_start_matches('.').rsplit('.') {
+            node = node.children.entry(label.to_string()).or_default();
+        }
+        node.rule.get_or_insert(index);
+    }
+
+    /// The rule for `host` or the closest of its parent domains.
+    fn find(&self, host: &str) -> Option<usize> {
+        let mut node = &self.root;
+        let mut found = None;
+        for label in host.rsplit('.') {
+            node = match node.children.get(label) {
+                Some(child) => child,
+                None => break,
+            };
+            found = node.rule.or(found);
+        }
+        found
+    }
+}
+
+/// The format of a filter file, told by its first rule: Adblock lists
+/// start with an `[Adblock Plus]` header, `!` comments or `||` rules, and
+/// hosts files with an address and a name.
+fn detect_type<'a>(lines: impl Iterator<Item = &'a str>) -> FilterType {
+    for line in lines.map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if line.starts_with('[') || line.starts_with('!') || line.starts_with("||") {
+            return FilterType::Adblock;
+        }
+        let mut fields = line.split_whitespace();
+        let address = fields.next().and_then(|field| field.parse::<IpAddr>().ok());
+        if address.is_some() && fields.next().is_some() {
+            return FilterType::Hosts;
+        }
+        return FilterType::Plain;
+    }
+    FilterType::Plain
+}
+
+/// The names of a hosts file line (`0.0.0.0 ads.example.com # comment`),
+/// without the machine's own names. Lines without an address are taken
+/// as bare lists of names.
+fn hosts_names(line: &str) -> Vec<&str> {
+    let line = line.split('#').next().unwrap_or_default();
+    let mut fields = line.split_whitespace().peekable();
+    if fields
+        .peek()
+        .is_some_and(|field| field.parse::<IpAddr>().is_ok())
+    {
+        fields.next();
+    }
+    fields
+        .filter(|name| {
+            !LOCAL_HOST_NAMES
+                .iter()
+                .any(|local| local.eq_ignore_ascii_case(name))
+        })
+        .collect()
+}
+
+/// The domain of an Adblock rule blocking a domain and its subdomains
+/// (`||ads.example.com^`). Exceptions, element hiding, rules with paths
+/// or options and other patterns cannot be expressed as domain rules and
+/// give `None`.
+fn adblock_domain(line: &str) -> Option<&str> {
+    let domain = line.strip_prefix("||")?;
+    let domain = domain.strip_suffix('^').unwrap_or(domain);
+    let valid = !domain.is_empty()
+        && domain
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '-' || c == '_');
+    valid.then_some(domain)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -187,7 +735,7 @@ mod tests {
         let filter = Filter::new(&config);
 
         assert!(!filter.is_enabled());
-        assert!(filter.is_allowed("http://example.com").unwrap());
+        assert!(filter.check("http://example.com").unwrap());
     }
 
     #[test]
@@ -204,10 +752,33 @@ mod tests {
         assert!(filter.is_enabled());
         assert_eq!(filter.rule_count(), 3); // ads, tracker, badsite.com
 
-        assert!(!filter.is_allowed("http://ads.example.com").unwrap());
-        assert!(!filter.is_allowed("http://tracker.evil.com").unwrap());
-        assert!(!filter.is_allowed("http://badsite.com").unwrap());
-        assert!(filter.is_allowed("http://goodsite.com").unwrap());
+        assert!(!filter.check("http://ads.example.com").unwrap());
+        assert!(!filter.check("http://tracker.evil.com").unwrap());
+        assert!(!filter.check("http://badsite.com").unwrap());
+        assert!(filter.check("http://goodsite.com").unwrap());
+    }
+
+    #[test]
+    fn test_default_deny_filter() {
+        let filter_file = create_test_filter_file("partner\n.example.org");
+        let config = Config {
+            filter_urls: true,
+            filter_default_deny: true,
+            filter_file: Some(filter_file.path().to_string_lossy().to_string()),
+            ..Default::default()
+        };
+
+        let filter = Filter::new(&config);
+        assert!(filter.check("http://partner.example.com/").unwrap());
+        assert!(filter.check("http://www.example.org/").unwrap());
+        assert!(!filter.check("http://ads.example.com/").unwrap());
+
+        // Without rules nothing is allowed
+        let config = Config {
+            filter_file: None,
+            ..config
+        };
+        assert!(!Filter::new(&config).check("http://example.org/").unwrap());
     }
 
     #[test]
@@ -221,15 +792,153 @@ mod tests {
 
         let filter = Filter::new(&config);
 
-        assert!(!filter.is_allowed("http://sub.evil.com").unwrap());
-        assert!(!filter.is_allowed("http://evil.com").unwrap());
-        assert!(!filter.is_allowed("http://tracker.ads.net").unwrap());
-        assert!(filter.is_allowed("http://good.com").unwrap());
+        assert!(!filter.check("http://sub.evil.com").unwrap());
+        assert!(!filter.check("http://evil.com").unwrap());
+        assert!(!filter.check("http://tracker.ads.net").unwrap());
+        assert!(filter.check("http://good.com").unwrap());
+    }
+
+    #[test]
+    fn test_many_rules() {
+        let mut content = String::new();
+        for i in 0..10_000 {
+            content.push_str(&format!(".ads{}.example.com\nexact{}/banner\n", i, i));
+        }
+        let filter_file = create_test_filter_file(&content);
+        let config = Config {
+            filter_urls: true,
+            filter_file: Some(filter_file.path().to_string_lossy().to_string()),
+            ..Default::default()
+        };
+
+        let mut filter = Filter::new(&config);
+        assert_eq!(filter.rule_count(), 20_000);
+        assert!(!filter.check("http://ads9999.example.com/").unwrap());
+        assert!(!filter.check("http://cdn.ads7.example.com/").unwrap());
+        assert!(!filter.check("http://example.net/exact123/banner").unwrap());
+        assert!(filter.check("http://example.com/").unwrap());
+        assert!(filter.check("http://notads7.example.com/").unwrap());
+
+        // Rules changed at runtime are compiled in
+        filter.add_rule(".evil.test").unwrap();
+        assert!(!filter.check("http://evil.test/").unwrap());
+        assert!(filter.remove_rule(".ads7.example.com"));
+        assert!(filter.check("http://cdn.ads7.example.com/").unwrap());
+
+        let mut content: String = (0..100)
+            .map(|i| format!("^https?://[a-z]+{}\\.test/\n", i))
+            .collect();
+        content.push_str("track(ing|er)\\d\n");
+        let filter_file = create_test_filter_file(&content);
+        let filter = Filter::new(&Config {
+            filter_extended: true,
+            filter_file: Some(filter_file.path().to_string_lossy().to_string()),
+            ..config
+        });
+        assert!(!filter.check("http://example.com/tracker1").unwrap());
+        assert!(!filter.check("https://www42.test/").unwrap());
+        assert!(filter.check("https://www42.example/").unwrap());
+    }
+
+    #[test]
+    fn test_filter_groups() {
+        let main_file = create_test_filter_file("ads\n");
+        let kids_file = create_test_filter_file("ads\ngames\n");
+        let config = Config::parse_config(&format!(
+            "FilterURLs Yes\nFilter {}\nFilterGroup kids 192.0.2.50 client=198.51.100.0/24 user=tim {}\nFilterGroup open user=admin {}",
+            main_file.path().display(),
+            kids_file.path().display(),
+            "/nonexistent/open.filter"
+        ))
+        .unwrap();
+        assert!(Config::parse_config("FilterGroup kids /etc/kids.filter").is_err());
+        assert!(Config::parse_config("FilterGroup kids laptop /etc/kids.filter").is_err());
+
+        let mut filter = Filter::new(&config);
+        let adult: IpAddr = "192.0.2.7".parse().unwrap();
+        let kid: IpAddr = "198.51.100.9".parse().unwrap();
+        let games = "http://games.example/";
+        assert!(filter.is_allowed(games, adult, None).unwrap());
+        assert!(!filter.is_allowed(games, adult, Some("tim")).unwrap());
+        assert!(!filter.is_allowed(games, kid, None).unwrap());
+        assert!(!filter
+            .is_allowed(games, "192.0.2.50".parse().unwrap(), None)
+            .unwrap());
+        assert!(!filter
+            .is_allowed("http://ads.example/", adult, None)
+            .unwrap());
+
+        // The first group for the client decides
+        assert!(!filter.is_allowed(games, kid, Some("admin")).unwrap());
+        assert!(filter.is_allowed(games, adult, Some("admin")).unwrap());
+
+        // A group whose file cannot be read keeps the reload from applying
+        assert!(filter.reload(&config).is_err());
+        assert!(!filter.is_allowed(games, kid, None).unwrap());
+    }
+
+    #[test]
+    fn test_hosts_filter() {
+        let filter_file = create_test_filter_file(
+            "# Blocklist\n127.0.0.1 localhost\n0.0.0.0 ads.example.com Tracker.example.net # both\n:: ads.example.org\n",
+        );
+        let config = Config {
+            filter_urls: true,
+            filter_file: Some(filter_file.path().to_string_lossy().to_string()),
+            ..Default::default()
+        };
+
+        let filter = Filter::new(&config);
+        assert_eq!(filter.rule_count(), 3);
+        assert_eq!(filter.rules()[1], ("tracker.example.net", "host"));
+        assert!(!filter.check("http://ads.example.com/banner").unwrap());
+        assert!(!filter.check("http://tracker.example.net/").unwrap());
+        assert!(!filter.check("https://ads.example.org:8443/").unwrap());
+        // Hosts entries name single hosts
+        assert!(filter.check("http://cdn.ads.example.com/").unwrap());
+        assert!(filter.check("http://example.com/").unwrap());
+        assert!(filter.check("http://localhost/").unwrap());
+    }
+
+    #[test]
+    fn test_adblock_filter() {
+        let filter_file = create_test_filter_file(
+            "[Adblock Plus 2.0]\n! Title: Ads\n||ads.example.com^\n||tracker.example.net^$third-party\n@@||good.example.com^\nexample.org##.banner\n||Metrics.example.org\n",
+        );
+        let config = Config {
+            filter_urls: true,
+            filter_file: Some(filter_file.path().to_string_lossy().to_string()),
+            ..Default::default()
+        };
+
+        let filter = Filter::new(&config);
+        assert_eq!(
+            filter.rules(),
+            vec![
+                (".ads.example.com", "domain"),
+                (".metrics.example.org", "domain")
+            ]
+        );
+        assert!(!filter.check("http://ads.example.com/").unwrap());
+        assert!(!filter.check("http://eu.ads.example.com/").unwrap());
+        assert!(!filter.check("http://metrics.example.org/").unwrap());
+        assert!(filter.check("http://tracker.example.net/").unwrap());
+
+        // The type can be given when the file does not tell
+        let filter_file = create_test_filter_file("ads.example.com\nmetrics.example.org\n");
+        let config = Config {
+            filter_type: FilterType::Hosts,
+            filter_file: Some(filter_file.path().to_string_lossy().to_string()),
+            ..config
+        };
+        let filter = Filter::new(&config);
+        assert!(!filter.check("http://metrics.example.org/").unwrap());
+        assert!(filter.check("http://example.com/ads.example.com").unwrap());
     }
 
     #[test]
     fn test_regex_filter() {
-        let filter_content = r"ads\d+\.com\n.*tracker.*";
+        let filter_content = "ads\\d+\\.com\n.*tracker.*";
         let filter_file = create_test_filter_file(filter_content);
 
         let mut config = Config::default();
@@ -239,10 +948,81 @@ mod tests {
 
         let filter = Filter::new(&config);
 
-        assert!(!filter.is_allowed("http://ads123.com").unwrap());
-        assert!(!filter.is_allowed("http://mytracker.evil.com").unwrap());
-        assert!(filter.is_allowed("http://ads.com").unwrap()); // No digits
-        assert!(filter.is_allowed("http://good.com").unwrap());
+        assert!(!filter.check("http://ads123.com").unwrap());
+        assert!(!filter.check("http://mytracker.evil.com").unwrap());
+        assert!(filter.check("http://ads.com").unwrap()); // No digits
+        assert!(filter.check("http://good.com").unwrap());
+    }
+
+    #[test]
+    fn test_runtime_rules() {
+        let filter_file = create_test_filter_file("# blocked\nads\n.evil.com");
+
+        let config = Config {
+            filter_urls: true,
+            filter_file: Some(filter_file.path().to_string_lossy().to_string()),
+            ..Default::default()
+        };
+        let mut filter = Filter::new(&config);
+        assert_eq!(
+            filter.rules(),
+            vec![("ads", "exact"), (".evil.com", "domain")]
+        );
+
+        assert!(filter.add_rule(".Tracker.net").unwrap());
+        assert!(!filter.add_rule(".tracker.net").unwrap());
+        assert!(filter.add_rule("# comment").is_err());
+        assert!(!filter.check("http://cdn.tracker.net/x").unwrap());
+
+        assert!(filter.remove_rule("ADS"));
+        assert!(!filter.remove_rule("ads"));
+        assert!(filter.check("http://ads.example.com").unwrap());
+
+        let path = filter_file.path().to_str().unwrap();
+        filter.persist_added(path, ".tracker.net").unwrap();
+        filter.persist_removed(path, "ads").unwrap();
+        let content = fs::read_to_string(path).unwrap();
+        assert_eq!(content, "# blocked\n.evil.com\n.tracker.net\n");
+    }
+
+    #[test]
+    fn test_invalid_runtime_regex() {
+        let config = Config {
+            filter_urls: true,
+            filter_extended: true,
+            ..Default::default()
+        };
+        let mut filter = Filter::new(&config);
+
+        assert!(filter.add_rule("ads[").is_err());
+        assert!(filter.add_rule("ads\\d+").unwrap());
+        assert_eq!(filter.rules(), vec![("ads\\d+", "regex")]);
+    }
+
+    #[test]
+    fn test_validate_file() {
+        let filter_file = create_test_filter_file("# ads\n.evil.com\nads\\d+\n\ntrack(er\nbanner[");
+        let path = filter_file.path().to_str().unwrap();
+
+        let report = Filter::validate_file(&Config::default(), path).unwrap();
+        assert_eq!((report.exact, report.domain, report.regex), (3, 1, 0));
+        assert!(report.invalid.is_empty());
+
+        let config = Config {
+            filter_extended: true,
+            ..Default::default()
+        };
+        let report = Filter::validate_file(&config, path).unwrap();
+        assert_eq!(report.rule_count(), 2);
+        assert_eq!(report.regex, 2);
+        let lines: Vec<_> = report
+            .invalid
+            .iter()
+            .map(|invalid| (invalid.line, invalid.pattern.as_str()))
+            .collect();
+        assert_eq!(lines, vec![(5, "track(er"), (6, "banner[")]);
+
+        assert!(Filter::validate_file(&config, "/nonexistent/filter").is_err());
     }
 
     #[test]
@@ -258,14 +1038,14 @@ mod tests {
 
         let filter = Filter::new(&config);
 
-        assert!(!filter.is_allowed("http://ads.example.com").unwrap());
-        assert!(!filter.is_allowed("http://TRACKER.com").unwrap());
+        assert!(!filter.check("http://ads.example.com").unwrap());
+        assert!(!filter.check("http://TRACKER.com").unwrap());
 
         // Case sensitive
         config.filter_casesensitive = true;
         let filter = Filter::new(&config);
 
-        assert!(filter.is_allowed("http://ads.example.com").unwrap()); // 'ads' != 'ADS'
-        assert!(!filter.is_allowed("http://ADS.example.com").unwrap());
+        assert!(filter.check("http://ads.example.com").unwrap()); // 'ads' != 'ADS'
+        assert!(!filter.check("http://ADS.example.com").unwrap());
     }
 }
@@ -0,0 +1,458 @@
+use crate::headers::Headers;
+use crate::utils::{HttpRequest, HttpResponse};
+use bytes::{Buf, BytesMut};
+use std::io;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use tokio::io::{AsyncRead, ReadBuf};
+
+/// Longest chunk size or trailer line accepted in a chunked body.
+const MAX_CHUNK_LINE: usize = 4096;
+
+/// How the end of an HTTP message body is found (RFC 9112, section 6.3).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BodyLength {
+    /// Exactly this many bytes; zero for messages without a body.
+    Fixed(u64),
+    Chunked,
+    /// Everything until the sender closes the connection.
+    UntilClose,
+}
+
+impl BodyLength {
+    /// Body length of a request whose framing MessageFraming accepted.
+    pub fn of_request(request: &HttpRequest) -> Self {
+        if request.headers.contains_key("transfer-encoding") {
+            return BodyLength::Chunked;
+        }
+        BodyLength::Fixed(
+            request
+                .headers
+                .get("content-length")
+                .and_then(|length| length.trim().parse().ok())
+                .unwrap_or(0),
+        )
+    }
+
+    /// Body length of the response to a `method` request.
+    pub fn of_response(method: &str, response: &HttpResponse) -> Self {
+        if response.status == 101 {
+            return BodyLength::UntilClose;
+        }
+        if method == "HEAD" || matches!(response.status, 100..=199 | 204 | 304) {
+            return BodyLength::Fixed(0);
+        }
+        if let Some(codings) = response.headers.get_combined("transfer-encoding") {
+            let last = codings.rsplit(',').next().unwrap_or("").trim();
+            return if last.eq_ignore_ascii_case("chunked") {
+                BodyLength::Chunked
+            } else {
+                BodyLength::UntilClose
+            };
+        }
+        match response
+            .headers
+            .get("content-length")
+            .and_then(|length| length.trim().parse().ok())
+        {
+            Some(length) => BodyLength::Fixed(length),
+            None => BodyLength::UntilClose,
+        }
+    }
+}
+
+/// Follows a message body through the bytes relayed, to tell where it
+/// ends. Chunked bodies are passed on as they are.
+#[derive(Debug)]
+pub struct BodyFraming {
+    state: State,
+}
+
+#[derive(Debug)]
+enum State {
+    Fixed(u64),
+    UntilClose,
+    ChunkSize(Vec<u8>),
+    ChunkData(u64),
+    /// The line break after a chunk's data.
+    ChunkEnd,
+    Trailer(Vec<u8>),
+    Done,
+}
+
+impl BodyFraming {
+    pub fn new(length: BodyLength) -> Self {
+        let state = match length {
+            BodyLength::Fixed(0) => State::Done,
+            BodyLength::Fixed(length) => State::Fixed(length),
+            BodyLength::Chunked => State::ChunkSize(Vec::new()),
+            BodyLength::UntilClose => State::UntilClose,
+        };
+        Self { state }
+    }
+
+    /// Whether the whole body has gone by.
+    pub fn is_done(&self) -> bool {
+        matches!(self.state, State::Done)
+    }
+
+    /// Take in the next bytes of the message, returning how many of them
+    /// belong to the body; the rest follow it.
+    pub fn advance(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.step(data, None)
+    }
+
+    /// Like [`BodyFraming::advance`], also adding the body's payload to
+    /// `payload`: chunked bodies without their chunk lines and trailer.
+    pub fn decode(&mut self, data: &[u8], payload: &mut BytesMut) -> io::Result<usize> {
+        self.step(data, Some(payload))
+    }
+
+    fn step(&mut self, data: &[u8], mut payload: Option<&mut BytesMut>) -> io::Result<usize> {
+        let mut used = 0;
+        while used < data.len() {
+            match &mut self.state {
+                State::Done => break,
+                State::UntilClose => {
+                    if let Some(payload) = payload {
+                        payload.extend_from_slice(&data[used..]);
+                    }
+                    return Ok(data.len());
+                }
+                State::Fixed(remaining) | State::ChunkData(remaining) => {
+                    let count = (*remaining).min((data.len() - used) as u64);
+                    *remaining -= count;
+                    if let Some(payload) = payload.as_deref_mut() {
+                        payload.extend_from_slice(&data[used..used + count as usize]);
+                    }
+                    used += count as usize;
+                    if *remaining == 0 {
+                        self.state = match self.state {
+                            State::Fixed(_) => State::Done,
+                            _ => State::ChunkEnd,
+                        };
+                    }
+                }
+                State::ChunkEnd => {
+                    used += 1;
+                    match data[used - 1] {
+                        b'\r' => {}
+                        b'\n' => self.state = State::ChunkSize(Vec::new()),
+                        _ => return Err(invalid("chunk data longer than its size")),
+                    }
+                }
+                State::ChunkSize(line) | State::Trailer(line) => {
+                    let byte = data[used];
+                    used += 1;
+                    if byte != b'\n' {
+                        if line.len() >= MAX_CHUNK_LINE {
+                            return Err(invalid("chunk line too long"));
+                        }
+                        line.push(byte);
+                        continue;
+                    }
+                    let text = String::from_utf8_lossy(line).trim().to_string();
+                    self.state = match self.state {
+                        State::Trailer(_) if text.is_empty() => State::Done,
+                        State::Trailer(_) => State::Trailer(Vec::new()),
+                        _ => {
+                            let size = text.split(';').next().unwrap_or("").trim();
+                            if !size.bytes().all(|b| b.is_ascii_hexdigit()) {
+                                return Err(invalid("invalid chunk size"));
+                            }
+                            match u64::from_str_radix(size, 16) {
+                                Ok(0) => State::Trailer(Vec::new()),
+                                Ok(size) => State::ChunkData(size),
+                                Err(_) => return Err(invalid("invalid chunk size")),
+                            }
+                        }
+                    };
+                }
+            }
+        }
+        Ok(used)
+    }
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Reader over the body of a request from a client connection: first the
+/// bytes that arrived with its head, then from `inner`, ending where the
+/// body does. Bytes past the body stay in `buffer` for the next request.
+///
+/// Chunked bodies are decoded and encoded again as they are read, so the
+/// origin gets plain chunks whatever extensions or line endings the client
+/// used. Their trailer fields are dropped.
+pub struct RequestBody<'a, R> {
+    inner: R,
+    buffer: &'a mut BytesMut,
+    framing: BodyFraming,
+    /// Encoded chunks not read yet, for chunked bodies.
+    chunks: Option<BytesMut>,
+    /// Most payload bytes read before reading fails, if limited.
+    limit: Option<u64>,
+    /// Payload bytes taken from the client so far.
+    received: u64,
+}
+
+impl<'a, R> RequestBody<'a, R> {
+    pub fn new(inner: R, buffer: &'a mut BytesMut, length: BodyLength) -> Self {
+        Self {
+            inner,
+            buffer,
+            framing: BodyFraming::new(length),
+            chunks: (length == BodyLength::Chunked).then(BytesMut::new),
+            limit: None,
+            received: 0,
+        }
+    }
+
+    /// Fail reading once the payload grows past `limit` bytes, however
+    /// the body is framed.
+    pub fn with_limit(mut self, limit: u64) -> Self {
+        self.limit = Some(limit);
+        self
+    }
+
+    /// Whether the whole body was read.
+    pub fn is_done(&self) -> bool {
+        self.framing.is_done() && self.chunks.as_ref().is_none_or(|chunks| chunks.is_empty())
+    }
+
+    /// Whether reading stopped because the payload exceeded the limit.
+    pub fn exceeds_limit(&self) -> bool {
+        self.limit.is_some_and(|limit| self.received > limit)
+    }
+
+    fn receive(&mut self, count: usize) -> io::Result<()> {
+        self.received += count as u64;
+        if self.exceeds_limit() {
+            return Err(invalid("request body too large"));
+        }
+        Ok(())
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for RequestBody<'_, R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        loop {
+            if let Some(chunks) = this.chunks.as_mut().filter(|chunks| !chunks.is_empty()) {
+                let count = chunks.len().min(buf.remaining());
+                buf.put_slice(&chunks[..count]);
+                chunks.advance(count);
+                return Poll::Ready(Ok(()));
+            }
+            if this.framing.is_done() {
+                return Poll::Ready(Ok(()));
+            }
+            if !this.buffer.is_empty() {
+                match &mut this.chunks {
+                    Some(chunks) => {
+                        let mut payload = BytesMut::new();
+                        let count = this.framing.decode(this.buffer, &mut payload)?;
+                        this.buffer.advance(count);
+                        if !payload.is_empty() {
+                            encode_chunk(&payload, chunks);
+                        }
+                        if this.framing.is_done() {
+                            encode_chunk(b"", chunks);
+                        }
+                        this.receive(payload.len())?;
+                        continue;
+                    }
+                    None => {
+                        let available = this.buffer.len().min(buf.remaining());
+                        let count = this.framing.advance(&this.buffer[..available])?;
+                        this.receive(count)?;
+                        buf.put_slice(&this.buffer[..count]);
+                        this.buffer.advance(count);
+                        return Poll::Ready(Ok(()));
+                    }
+                }
+            }
+
+            let mut chunk = [0u8; 8192];
+            let mut chunk_buf = ReadBuf::new(&mut chunk);
+            ready!(Pin::new(&mut this.inner).poll_read(cx, &mut chunk_buf))?;
+            if chunk_buf.filled().is_empty() {
+                // The client closed before the body was complete
+                return Poll::Ready(Ok(()));
+            }
+            this.buffer.extend_from_slice(chunk_buf.filled());
+        }
+    }
+}
+
+/// Append `payload` to `out` as one chunk; an empty payload makes the last
+/// chunk, ending the body without trailer fields.
+pub fn encode_chunk(payload: &[u8], out: &mut BytesMut) {
+    if payload.is_empty() {
+        out.extend_from_slice(b"0\r\n\r\n");
+        return;
+    }
+    out.extend_from_slice(format!("{:x}\r\n", payload.len()).as_bytes());
+    out.extend_from_slice(payload);
+    out.extend_from_slice(b"\r\n");
+}
+
+/// Remove the hop-by-hop fields of a message (RFC 9110, section 7.6.1):
+/// Connection and the fields it names, Keep-Alive and Proxy-Connection.
+pub fn remove_hop_by_hop(headers: &mut Headers) {
+    let named = headers.get_combined("connection").unwrap_or_default();
+    for name in named
+        .split(',')
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+    {
+        headers.remove(name);
+    }
+    for name in ["Connection", "Keep-Alive", "Proxy-Connection"] {
+        headers.remove(name);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::{parse_http_request, parse_http_response};
+    use tokio::io::AsyncReadExt;
+
+    #[test]
+    fn test_body_length() {
+        let request = |head: &[u8]| BodyLength::of_request(&parse_http_request(head).unwrap());
+        assert_eq!(request(b"GET / HTTP/1.1\r\n\r\n"), BodyLength::Fixed(0));
+        assert_eq!(
+            request(b"POST / HTTP/1.1\r\nContent-Length: 12\r\n\r\n"),
+            BodyLength::Fixed(12)
+        );
+        assert_eq!(
+            request(b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n"),
+            BodyLength::Chunked
+        );
+
+        let response = |method, head: &[u8]| {
+            BodyLength::of_response(method, &parse_http_response(head).unwrap())
+        };
+        let sized = b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\n";
+        assert_eq!(response("GET", sized), BodyLength::Fixed(5));
+        assert_eq!(response("HEAD", sized), BodyLength::Fixed(0));
+        assert_eq!(
+            response("GET", b"HTTP/1.1 304 Not Modified\r\n\r\n"),
+            BodyLength::Fixed(0)
+        );
+        assert_eq!(
+            response(
+                "GET",
+                b"HTTP/1.1 200 OK\r\nTransfer-Encoding: gzip, chunked\r\n\r\n"
+            ),
+            BodyLength::Chunked
+        );
+        assert_eq!(
+            response("GET", b"HTTP/1.0 200 OK\r\n\r\n"),
+            BodyLength::UntilClose
+        );
+        assert_eq!(
+            response("GET", b"HTTP/1.1 101 Switching Protocols\r\n\r\n"),
+            BodyLength::UntilClose
+        );
+    }
+
+    #[test]
+    fn test_chunked_framing() {
+        let body = b"4;name=value\r\nWiki\r\n5\r\npedia\r\n0\r\nExpires: never\r\n\r\n";
+        // Fed at once and a byte at a time, the body ends in the same place
+        let mut framing = BodyFraming::new(BodyLength::Chunked);
+        assert_eq!(
+            framing.advance(&[&body[..], b"GET /"].concat()).unwrap(),
+            body.len()
+        );
+        assert!(framing.is_done());
+
+        let mut framing = BodyFraming::new(BodyLength::Chunked);
+        for (i, byte) in body.iter().enumerate() {
+            assert!(!framing.is_done(), "done after {} bytes", i);
+            assert_eq!(framing.advance(&[*byte]).unwrap(), 1);
+        }
+        assert!(framing.is_done());
+
+        let mut payload = BytesMut::new();
+        let mut framing = BodyFraming::new(BodyLength::Chunked);
+        assert_eq!(framing.decode(body, &mut payload).unwrap(), body.len());
+        assert_eq!(&payload[..], b"Wikipedia");
+
+        for invalid in [&b"zz\r\n"[..], b"+4\r\n", b"4\r\nWikipedia\r\n"] {
+            let mut framing = BodyFraming::new(BodyLength::Chunked);
+            assert!(framing.advance(invalid).is_err());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_request_body() {
+        let mut buffer = BytesMut::from(&b"hel"[..]);
+        let mut body =
+            RequestBody::new(&b"lo GET / HTTP/1.1"[..], &mut buffer, BodyLength::Fixed(5));
+        let mut read = Vec::new();
+        body.read_to_end(&mut read).await.unwrap();
+        assert!(body.is_done());
+        assert_eq!(read, b"hello");
+        assert_eq!(&buffer[..], b" GET / HTTP/1.1");
+
+        // A client closing early leaves the body incomplete
+        let mut buffer = BytesMut::new();
+        let mut body = RequestBody::new(&b"hi"[..], &mut buffer, BodyLength::Fixed(5));
+        body.read_to_end(&mut Vec::new()).await.unwrap();
+        assert!(!body.is_done());
+    }
+
+    #[tokio::test]
+    async fn test_chunked_request_body() {
+        let mut buffer = BytesMut::from(&b"3;x=y\nabc\r"[..]);
+        let rest = b"\n2\r\nde\r\n0\r\nX-Sum: 5\r\n\r\nGET / HTTP/1.1";
+        let mut body = RequestBody::new(&rest[..], &mut buffer, BodyLength::Chunked);
+        let mut read = Vec::new();
+        body.read_to_end(&mut read).await.unwrap();
+        assert!(body.is_done());
+        assert_eq!(read, b"3\r\nabc\r\n2\r\nde\r\n0\r\n\r\n");
+        assert_eq!(&buffer[..], b"GET / HTTP/1.1");
+
+        let mut buffer = BytesMut::from(&b"3\r\nabcd\r\n"[..]);
+        let mut body = RequestBody::new(&b""[..], &mut buffer, BodyLength::Chunked);
+        assert!(body.read_to_end(&mut Vec::new()).await.is_err());
+        assert!(!body.exceeds_limit());
+
+        // Limits count the decoded payload, not the chunk lines
+        let rest = b"3\r\ndef\r\n0\r\n\r\n";
+        let mut buffer = BytesMut::from(&b"3\r\nabc\r\n"[..]);
+        let mut body = RequestBody::new(&rest[..], &mut buffer, BodyLength::Chunked).with_limit(6);
+        body.read_to_end(&mut Vec::new()).await.unwrap();
+        assert!(body.is_done() && !body.exceeds_limit());
+
+        let mut buffer = BytesMut::from(&b"3\r\nabc\r\n"[..]);
+        let mut body = RequestBody::new(&rest[..], &mut buffer, BodyLength::Chunked).with_limit(5);
+        assert!(body.read_to_end(&mut Vec::new()).await.is_err());
+        assert!(body.exceeds_limit());
+    }
+
+    #[test]
+    fn test_remove_hop_by_hop() {
+        let mut headers: Headers = [
+            ("Host", "example.com"),
+            ("Connection", "keep-alive, X-Trace"),
+            ("X-Trace", "1"),
+            ("Keep-Alive", "timeout=5"),
+            ("Proxy-Connection", "keep-alive"),
+            ("Accept", "*/*"),
+        ]
+        .into_iter()
+        .collect();
+        remove_hop_by_hop(&mut headers);
+        assert_eq!(headers.to_lines(), "Host: example.com\r\nAccept: */*\r\n");
+    }
+}
@@ -0,0 +1,163 @@
+/// HTTP header fields in the order they were received, with their original
+/// names. Lookups match names case-insensitively.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Headers {
+    fields: Vec<(String, String)>,
+}
+
+impl Headers {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.fields.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.fields.is_empty()
+    }
+
+    fn position(&self, name: &str) -> Option<usize> {
+        self.fields
+            .iter()
+            .position(|(field, _)| field.eq_ignore_ascii_case(name))
+    }
+
+    /// Value of the first field called `name`.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.position(name)
+            .map(|index| self.fields[index].1.as_str())
+    }
+
+    /// Values of every field called `name`, in order.
+    pub fn get_all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.fields
+            .iter()
+            .filter(move |(field, _)| field.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Values of every field called `name` combined into one list, as
+    /// repeated list-valued fields are equivalent to a single one.
+    pub fn get_combined(&self, name: &str) -> Option<String> {
+        let values: Vec<&str> = self.get_all(name).collect();
+        if values.is_empty() {
+            None
+        } else {
+            Some(values.join(", "))
+        }
+    }
+
+    pub fn contains_key(&self, name: &str) -> bool {
+        self.get(name).is_some()
+    }
+
+    /// Set a header to a single value. An existing field keeps its position
+    /// and spelling; otherwise the field is added at the end.
+    pub fn insert(&mut self, name: impl Into<String>, value: impl Into<String>) {
+        let name = name.into();
+        match self.position(&name) {
+            Some(index) => {
+                self.fields[index].1 = value.into();
+                let mut position = 0;
+                self.fields.retain(|(field, _)| {
+                    position += 1;
+                    position <= index + 1 || !field.eq_ignore_ascii_case(&name)
+                });
+            }
+            None => self.fields.push((name, value.into())),
+        }
+    }
+
+    /// Add a field after the existing ones, even if the name is present.
+    pub fn append(&mut self, name: impl Into<String>, value: impl Into<String>) {
+        self.fields.push((name.into(), value.into()));
+    }
+
+    /// Remove every field called `name`, returning the first value.
+    pub fn remove(&mut self, name: &str) -> Option<String> {
+        let mut removed = None;
+        self.fields.retain_mut(|(field, value)| {
+            if !field.eq_ignore_ascii_case(name) {
+                return true;
+            }
+            if removed.is_none() {
+                removed = Some(std::mem::take(value));
+            }
+            false
+        });
+        removed
+    }
+
+    /// Keep only the fields for which `keep` returns true; it may also
+    /// change the values of the fields it keeps.
+    pub fn retain_mut(&mut self, mut keep: impl FnMut(&str, &mut String) -> bool) {
+        self.fields.retain_mut(|(name, value)| keep(name, value));
+    }
+
+    /// All fields as `(name, value)` pairs, in order.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.fields
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.as_str()))
+    }
+
+    /// Header block lines, each terminated by CRLF.
+    pub fn to_lines(&self) -> String {
+        let mut lines = String::new();
+        for (name, value) in self.iter() {
+            lines.push_str(name);
+            lines.push_str(": ");
+            lines.push_str(value);
+            lines.push_str("\r\n");
+        }
+        lines
+    }
+}
+
+impl<N: Into<String>, V: Into<String>> FromIterator<(N, V)> for Headers {
+    fn from_iter<I: IntoIterator<Item = (N, V)>>(iter: I) -> Self {
+        let mut headers = Headers::new();
+        for (name, value) in iter {
+            headers.append(name, value);
+        }
+        headers
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_headers() {
+        let mut headers: Headers = [
+            ("Host", "example.com"),
+            ("User-Agent", "curl/8.0"),
+            ("X-Trace", "a"),
+            ("x-trace", "b"),
+        ]
+        .into_iter()
+        .collect();
+
+        assert_eq!(headers.get("HOST"), Some("example.com"));
+        assert_eq!(headers.get("X-TRACE"), Some("a"));
+        assert_eq!(headers.get_all("x-trace").collect::<Vec<_>>(), ["a", "b"]);
+        assert_eq!(headers.get_combined("x-trace").as_deref(), Some("a, b"));
+        assert_eq!(headers.get_combined("via"), None);
+
+        // Replacing keeps the field's position and spelling
+        headers.insert("user-agent", "Mozilla/5.0");
+        headers.insert("x-trace", "c");
+        headers.insert("Accept", "*/*");
+        assert_eq!(
+            headers.to_lines(),
+            "Host: example.com\r\nUser-Agent: Mozilla/5.0\r\nX-Trace: c\r\nAccept: */*\r\n"
+        );
+
+        assert_eq!(headers.remove("HOST"), Some("example.com".to_string()));
+        assert!(!headers.contains_key("host"));
+        assert_eq!(headers.len(), 3);
+    }
+}
@@ -0,0 +1,85 @@
+use log::debug;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+
+/// Port ident servers listen on.
+const IDENT_PORT: u16 = 113;
+
+/// Longest time a lookup may take.
+pub const IDENT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Longest user name accepted, as RFC 1413 allows.
+const MAX_USER_ID: usize = 512;
+
+/// Ask the ident server (RFC 1413) on the client's host which user owns
+/// the connection from `peer` to `local`. Returns `None` if the host runs
+/// no ident server, does not answer within `limit` or reports an error.
+pub async fn lookup(peer: SocketAddr, local: SocketAddr, limit: Duration) -> Option<String> {
+    let query = async {
+        let mut stream = TcpStream::connect(SocketAddr::new(peer.ip(), IDENT_PORT))
+            .await
+            .ok()?;
+        let request = format!("{}, {}\r\n", peer.port(), local.port());
+        stream.write_all(request.as_bytes()).await.ok()?;
+
+        let mut reply = String::new();
+        let mut reader = BufReader::new(stream).take(1024);
+        reader.read_line(&mut reply).await.ok()?;
+        parse_reply(&reply, peer.port(), local.port())
+    };
+
+    let user = timeout(limit, query).await.ok().flatten();
+    debug!("Ident lookup for {}: {:?}", peer, user);
+    user
+}
+
+/// User named by an ident reply such as
+/// `6193, 23 : USERID : UNIX : stjohns`, provided it is about the
+/// expected ports and safe to put in a log line or header.
+fn parse_reply(reply: &str, peer_port: u16, local_port: u16) -> Option<String> {
+    let mut fields = reply.trim_end_matches(['\r', '\n']).splitn(4, ':');
+    let (ports, kind) = (fields.next()?, fields.next()?);
+    let (_os, user) = (fields.next()?, fields.next()?);
+
+    let (remote, local) = ports.split_once(',')?;
+    if remote.trim().parse() != Ok(peer_port)
+        || local.trim().parse() != Ok(local_port)
+        || kind.trim() != "USERID"
+    {
+        return None;
+    }
+
+    let user = user.trim();
+    if user.is_empty()
+        || user.len() > MAX_USER_ID
+        || user.chars().any(|c| c.is_control() || c == '"')
+    {
+        return None;
+    }
+    Some(user.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_reply() {
+        let user = |reply| parse_reply(reply, 6193, 23);
+        assert_eq!(
+            user("6193, 23 : USERID : UNIX : stjohns\r\n").as_deref(),
+            Some("stjohns")
+        );
+        assert_eq!(
+            user("6193,23:USERID:UNIX , US-ASCII:a:b").as_deref(),
+            Some("a:b")
+        );
+        assert_eq!(user("6193, 23 : ERROR : NO-USER\r\n"), None);
+        assert_eq!(user("6194, 23 : USERID : UNIX : other\r\n"), None);
+        assert_eq!(user("6193, 23 : USERID : UNIX : \x1b[31m\r\n"), None);
+        assert_eq!(user("garbage"), None);
+    }
+}
@@ -0,0 +1,1716 @@
+use crate::auth::basic_auth_username;
+use crate::config::{Config, HostMismatchPolicy};
+use crate::connect_ports::ConnectPorts;
+use crate::connection::{parse_host_port, request_host};
+use crate::denial::Denial;
+use crate::error::{ProxyError, ProxyResult};
+use crate::framing::{remove_hop_by_hop, BodyFraming, BodyLength};
+use crate::headers::Headers;
+use crate::proxy::ProxyLogic;
+use crate::reverse::Route;
+use crate::state::ServerState;
+use crate::stats::Counter;
+use crate::utils::{
+    html_escape, origin_form, parse_http_response, HeadScanner, HttpRequest, HttpResponse,
+};
+use async_trait::async_trait;
+use bytes::{Buf, Bytes, BytesMut};
+use log::{debug, warn};
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+use tokio::io::{AsyncRead, ReadBuf};
+
+/// Response heads larger than this are relayed without being intercepted.
+const MAX_RESPONSE_HEAD: usize = 65536;
+
+/// The connection a request arrived on.
+#[derive(Clone)]
+pub struct RequestContext {
+    pub connection_id: u64,
+    /// Client address, after PROXY protocol and X-Forwarded-For handling.
+    pub client_addr: SocketAddr,
+    /// Address of the proxy listener the client connected to.
+    pub local_addr: Option<SocketAddr>,
+    /// Trusted proxy the request came through, whose X-Forwarded-For
+    /// named the client.
+    pub forwarded_by: Option<IpAddr>,
+    /// User owning the client's end of the connection, if IdentLookup is
+    /// enabled and the client's host told.
+    pub ident: Option<String>,
+    /// Set by ReverseRoute when it sends the request to a ReversePath
+    /// backend, which may be in DenyTargetNetworks.
+    pub reverse_routed: Arc<AtomicBool>,
+    /// Whether the request came decrypted from a CONNECT tunnel
+    /// (TlsIntercept), whose CONNECT already passed authentication.
+    pub intercepted: bool,
+    /// User the intercepted tunnel's CONNECT authenticated as.
+    pub tunnel_user: Option<String>,
+    pub state: Arc<ServerState>,
+}
+
+impl RequestContext {
+    /// User named by the credentials of `request`, or of the CONNECT of
+    /// the intercepted tunnel it came through, since clients send none
+    /// inside. Only trustworthy once Authentication checked them.
+    pub fn user(&self, request: &HttpRequest) -> Option<String> {
+        if self.intercepted {
+            return self.tunnel_user.clone();
+        }
+        request
+            .headers
+            .get("proxy-authorization")
+            .and_then(basic_auth_username)
+    }
+}
+
+/// Outcome of a request interceptor.
+pub enum Verdict {
+    /// Hand the request to the next stage.
+    Continue,
+    /// Stop processing and answer the client directly.
+    Respond(LocalResponse),
+}
+
+/// A policy stage applied to every request before it is forwarded.
+/// Interceptors may modify the request or answer it themselves.
+#[async_trait]
+pub trait RequestInterceptor: Send + Sync {
+    /// Name used in log messages.
+    fn name(&self) -> &str;
+
+    async fn on_request(
+        &self,
+        ctx: &RequestContext,
+        request: &mut HttpRequest,
+    ) -> ProxyResult<Verdict>;
+}
+
+/// A stage applied to response heads from origin servers before they are
+/// relayed to the client. Not called for CONNECT tunnels.
+pub trait ResponseInterceptor: Send + Sync {
+    /// Name used in log messages.
+    fn name(&self) -> &str;
+
+    fn on_response(&self, ctx: &RequestContext, request: &HttpRequest, response: &mut HttpResponse);
+}
+
+/// A response generated by the proxy itself rather than an origin server.
+#[derive(Debug)]
+pub struct LocalResponse {
+    pub status: u16,
+    pub reason: String,
+    pub headers: Headers,
+    pub body: String,
+    /// Error the request is reported with, if the response refuses it.
+    pub error: Option<ProxyError>,
+    /// Whether the client should be held in the tarpit while the response
+    /// is sent.
+    pub tarpit: bool,
+    /// Detail fragment of an error page, for rendering it from an
+    /// ErrorFile template instead.
+    pub detail: Option<String>,
+}
+
+impl LocalResponse {
+    pub fn html(status: u16, reason: &str, body: String) -> Self {
+        let mut headers = Headers::new();
+        headers.insert("Content-Type", "text/html");
+
+        Self {
+            status,
+            reason: reason.to_string(),
+            headers,
+            body,
+            error: None,
+            tarpit: false,
+            detail: None,
+        }
+    }
+
+    /// Error page with an HTML fragment explaining the failure in more
+    /// detail (may be empty).
+    pub fn error_page(status: u16, reason: &str, detail_html: &str) -> Self {
+        let body = format!(
+            "<html><body><h1>{} {}</h1>{}</body></html>",
+            status, reason, detail_html
+        );
+        Self {
+            detail: Some(detail_html.to_string()),
+            ..Self::html(status, reason, body)
+        }
+    }
+
+    pub fn redirect(status: u16, location: &str) -> Self {
+        let reason = match status {
+            301 => "Moved Permanently",
+            303 => "See Other",
+            307 => "Temporary Redirect",
+            308 => "Permanent Redirect",
+            _ => "Found",
+        };
+        let escaped = html_escape(location);
+        let body = format!(
+            "<html><body><h1>{} {}</h1><p><a href=\"{}\">{}</a></p></body></html>",
+            status, reason, escaped, escaped
+        );
+        Self::html(status, reason, body).with_header("Location", location)
+    }
+
+    pub fn with_header(mut self, name: &str, value: &str) -> Self {
+        self.headers.append(name, value);
+        self
+    }
+
+    pub fn with_error(mut self, error: ProxyError) -> Self {
+        self.error = Some(error);
+        self
+    }
+
+    /// Serialize the response with an HTTP/`version` status line, gzipping
+    /// the body if the client accepts that and it makes the body smaller.
+    pub fn to_bytes(&self, version: &str, gzip: bool) -> Vec<u8> {
+        self.to_bytes_with_body(version, gzip, &self.body)
+    }
+
+    /// Like [`LocalResponse::to_bytes`], with `body` in place of the
+    /// response's own.
+    pub fn to_bytes_with_body(&self, version: &str, gzip: bool, body: &str) -> Vec<u8> {
+        let compressed = if gzip {
+            Some(crate::gzip::compress(body.as_bytes()))
+                .filter(|compressed| compressed.len() < body.len())
+        } else {
+            None
+        };
+        let mut data = format!(
+            "HTTP/{} {} {}\r\n{}",
+            version,
+            self.status,
+            self.reason,
+            self.headers.to_lines()
+        );
+        let body = match &compressed {
+            Some(compressed) => {
+                data.push_str("Content-Encoding: gzip\r\n");
+                compressed.as_slice()
+            }
+            None => body.as_bytes(),
+        };
+        data.push_str(&format!(
+            "Vary: Accept-Encoding\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            body.len()
+        ));
+
+        let mut data = data.into_bytes();
+        data.extend_from_slice(body);
+        data
+    }
+}
+
+/// Ordered request and response interceptor chains.
+#[derive(Clone, Default)]
+pub struct Interceptors {
+    request: Vec<Arc<dyn RequestInterceptor>>,
+    response: Vec<Arc<dyn ResponseInterceptor>>,
+}
+
+impl Interceptors {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The built-in policy stages enabled by `config`, in the order they
+    /// apply: access control, message framing checks, reverse proxy routing,
+    /// resolving the target authority, authentication, CONNECT port
+    /// restrictions, the statistics page, redirects, URL rewriting,
+    /// filtering, destination rate limits, the request body size limit and
+    /// header rewriting. Responses get a Via header unless it is
+    /// disabled.
+    pub fn builtin(config: &Arc<Config>, proxy: &Arc<ProxyLogic>) -> Self {
+        let mut interceptors = Self::new();
+
+        interceptors.add_request(AccessCheck);
+        interceptors.add_request(MessageFraming);
+        if !config.reverse_proxy.is_empty() || config.reverse_only {
+            interceptors.add_request(ReverseRoute {
+                reverse_only: config.reverse_only,
+            });
+            if config.reverse_sticky_cookie.is_some() {
+                interceptors.add_response(StickySessions);
+            }
+        }
+        interceptors.add_request(EffectiveTarget {
+            policy: config.host_mismatch,
+        });
+        if config.requires_auth() {
+            interceptors.add_request(Authentication);
+        }
+        interceptors.add_request(ConnectPortCheck {
+            ports: ConnectPorts::new(config),
+            authenticated: config.requires_auth(),
+        });
+        if let Some(stat_host) = &config.stat_host {
+            interceptors.add_request(StatsPage {
+                stat_host: stat_host.clone(),
+            });
+        }
+        interceptors.add_request(Redirects {
+            proxy: proxy.clone(),
+        });
+        interceptors.add_request(UrlRewrite {
+            proxy: proxy.clone(),
+        });
+        if config.filter_urls || !config.filter_groups.is_empty() {
+            interceptors.add_request(UrlFilter {
+                authenticated: config.requires_auth(),
+            });
+        }
+        interceptors.add_request(DestinationRateLimit);
+        if config.max_request_body_size > 0 {
+            interceptors.add_request(BodySizeLimit {
+                limit: config.max_request_body_size,
+            });
+        }
+        interceptors.add_request(HeaderRewrite {
+            proxy: proxy.clone(),
+        });
+        if !config.disable_via_header {
+            interceptors.add_response(ResponseVia {
+                proxy: proxy.clone(),
+            });
+        }
+
+        interceptors
+    }
+
+    pub fn add_request(&mut self, interceptor: impl RequestInterceptor + 'static) -> &mut Self {
+        self.request.push(Arc::new(interceptor));
+        self
+    }
+
+    pub fn add_response(&mut self, interceptor: impl ResponseInterceptor + 'static) -> &mut Self {
+        self.response.push(Arc::new(interceptor));
+        self
+    }
+
+    /// Append the stages of another chain after this one's.
+    pub fn extend(&mut self, other: Interceptors) {
+        self.request.extend(other.request);
+        self.response.extend(other.response);
+    }
+
+    pub fn has_response_interceptors(&self) -> bool {
+        !self.response.is_empty()
+    }
+
+    /// Run the request chain until a stage answers the request.
+    pub async fn on_request(
+        &self,
+        ctx: &RequestContext,
+        request: &mut HttpRequest,
+    ) -> ProxyResult<Verdict> {
+        for interceptor in &self.request {
+            if let Verdict::Respond(response) = interceptor.on_request(ctx, request).await? {
+                debug!(
+                    "{} answered {} {} with {}",
+                    interceptor.name(),
+                    request.method,
+                    request.uri,
+                    response.status
+                );
+                return Ok(Verdict::Respond(response));
+            }
+        }
+        Ok(Verdict::Continue)
+    }
+
+    pub fn on_response(
+        &self,
+        ctx: &RequestContext,
+        request: &HttpRequest,
+        response: &mut HttpResponse,
+    ) {
+        for interceptor in &self.response {
+            interceptor.on_response(ctx, request, response);
+        }
+    }
+}
+
+/// Reader over an origin's response that passes the response head through
+/// the response interceptors and relays the body untouched, ending where
+/// the body does. Interim 1xx responses are relayed as they are.
+pub struct InterceptedResponse<R> {
+    inner: R,
+    head: Option<BytesMut>,
+    scanner: HeadScanner,
+    pending: Bytes,
+    interceptors: Interceptors,
+    ctx: RequestContext,
+    request: HttpRequest,
+    /// Whether the client's connection may stay open after the response.
+    keep_alive: bool,
+    /// Whether the origin's connection may take another request once the
+    /// response is read.
+    origin_reusable: bool,
+    /// The final response's body, once its head was relayed.
+    body: Option<BodyFraming>,
+}
+
+impl<R> InterceptedResponse<R> {
+    /// `prefix` holds response bytes already read from `inner`.
+    pub fn new(
+        inner: R,
+        prefix: BytesMut,
+        interceptors: Interceptors,
+        ctx: RequestContext,
+        request: HttpRequest,
+    ) -> Self {
+        Self {
+            inner,
+            head: Some(prefix),
+            scanner: HeadScanner::new(),
+            pending: Bytes::new(),
+            interceptors,
+            ctx,
+            request,
+            keep_alive: false,
+            origin_reusable: false,
+            body: None,
+        }
+    }
+
+    /// Keep the client's connection open after the response if its end can
+    /// be told, rather than closing it.
+    pub fn with_keep_alive(mut self, keep_alive: bool) -> Self {
+        self.keep_alive = keep_alive;
+        self
+    }
+
+    /// Whether the whole response was relayed and the client's connection
+    /// can take another request.
+    pub fn keeps_connection(&self) -> bool {
+        self.keep_alive && self.body.as_ref().is_some_and(BodyFraming::is_done)
+    }
+
+    /// Whether the whole response was read and the origin's connection can
+    /// take another request.
+    pub fn reuses_origin(&self) -> bool {
+        self.origin_reusable && self.body.as_ref().is_some_and(BodyFraming::is_done)
+    }
+
+    /// Try to complete the response head from the buffered data. Returns
+    /// false when more data is needed.
+    fn process_head(&mut self) -> io::Result<bool> {
+        let head = match self.head.as_mut() {
+            Some(head) => head,
+            None => return Ok(true),
+        };
+
+        let end = match self.scanner.find(head) {
+            Some(end) => end,
+            None if head.len() > MAX_RESPONSE_HEAD => {
+                debug!("Response head too large, relaying it unchanged");
+                self.pending = head.split().freeze();
+                self.head = None;
+                self.keep_alive = false;
+                self.body = Some(BodyFraming::new(BodyLength::UntilClose));
+                return Ok(true);
+            }
+            None => return Ok(false),
+        };
+
+        let data = head.split_to(end + 4);
+        self.scanner.reset();
+        let mut response = match parse_http_response(&data) {
+            Ok(response) => response,
+            Err(e) => {
+                debug!("Relaying unparsable response head unchanged: {}", e);
+                let mut output = BytesMut::from(&data[..]);
+                output.extend_from_slice(head);
+                self.pending = output.freeze();
+                self.head = None;
+                self.keep_alive = false;
+                self.body = Some(BodyFraming::new(BodyLength::UntilClose));
+                return Ok(true);
+            }
+        };
+
+        let mut output = BytesMut::new();
+        if (100..200).contains(&response.status) && response.status != 101 {
+            // The final response is still to come
+            output.extend_from_slice(&data);
+        } else {
+            self.interceptors
+                .on_response(&self.ctx, &self.request, &mut response);
+            let length = BodyLength::of_response(&self.request.method, &response);
+            if length == BodyLength::UntilClose {
+                self.keep_alive = false;
+            }
+            self.origin_reusable = length != BodyLength::UntilClose
+                && response.version == "1.1"
+                && !response
+                    .headers
+                    .get_combined("connection")
+                    .is_some_and(|tokens| {
+                        tokens
+                            .split(',')
+                            .any(|token| token.trim().eq_ignore_ascii_case("close"))
+                    });
+            // Switching protocols keeps the Upgrade the client asked for
+            if response.status != 101 {
+                remove_hop_by_hop(&mut response.headers);
+                if !self.keep_alive {
+                    response.headers.insert("Connection", "close");
+                } else {
+                    if self.request.version == "1.0" || response.version == "1.0" {
+                        // HTTP/1.0 peers assume the connection closes otherwise
+                        response.headers.insert("Connection", "keep-alive");
+                    }
+                    // How long the connection waits for the next request
+                    let timeout = self.ctx.state.config.keep_alive_timeout;
+                    response
+                        .headers
+                        .insert("Keep-Alive", format!("timeout={}", timeout));
+                }
+            }
+            output.extend_from_slice(&response.to_bytes());
+
+            let mut body = BodyFraming::new(length);
+            let count = body.advance(head)?;
+            output.extend_from_slice(&head[..count]);
+            // Anything after the response makes the connection unusable
+            if count < head.len() {
+                self.origin_reusable = false;
+            }
+            self.head = None;
+            self.body = Some(body);
+        }
+        self.pending = output.freeze();
+        Ok(true)
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for InterceptedResponse<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        loop {
+            if !this.pending.is_empty() {
+                let n = this.pending.len().min(buf.remaining());
+                buf.put_slice(&this.pending[..n]);
+                this.pending.advance(n);
+                return Poll::Ready(Ok(()));
+            }
+
+            let head = match this.head.as_mut() {
+                Some(head) => head,
+                None => {
+                    let body = match this.body.as_mut() {
+                        Some(body) if !body.is_done() => body,
+                        _ => return Poll::Ready(Ok(())),
+                    };
+                    let before = buf.filled().len();
+                    ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+                    let read = buf.filled().len() - before;
+                    let count = body.advance(&buf.filled()[before..])?;
+                    buf.set_filled(before + count);
+                    if count < read {
+                        this.origin_reusable = false;
+                    }
+                    return Poll::Ready(Ok(()));
+                }
+            };
+
+            if !head.is_empty() && this.process_head()? {
+                continue;
+            }
+
+            let mut chunk = [0u8; 8192];
+            let mut chunk_buf = ReadBuf::new(&mut chunk);
+            ready!(Pin::new(&mut this.inner).poll_read(cx, &mut chunk_buf))?;
+            let head = this.head.as_mut().unwrap();
+            if chunk_buf.filled().is_empty() {
+                // The origin closed before the head was complete
+                this.pending = head.split().freeze();
+                this.head = None;
+                if this.pending.is_empty() {
+                    return Poll::Ready(Ok(()));
+                }
+                continue;
+            }
+            head.extend_from_slice(chunk_buf.filled());
+        }
+    }
+}
+
+/// Allow/Deny rules, checked against the effective client address.
+struct AccessCheck;
+
+#[async_trait]
+impl RequestInterceptor for AccessCheck {
+    fn name(&self) -> &str {
+        "access control"
+    }
+
+    async fn on_request(
+        &self,
+        ctx: &RequestContext,
+        _request: &mut HttpRequest,
+    ) -> ProxyResult<Verdict> {
+        if ctx.state.acl.read().unwrap().is_allowed(&ctx.client_addr) {
+            return Ok(Verdict::Continue);
+        }
+
+        warn!("Access denied for {}", ctx.client_addr);
+        ctx.state
+            .denial_log
+            .record(ctx.client_addr.ip(), Denial::Acl, &[]);
+        let mut response = LocalResponse::error_page(403, "Forbidden", "").with_error(
+            ProxyError::AccessDenied(format!("IP {} is not allowed", ctx.client_addr.ip())),
+        );
+        response.tarpit = ctx.state.tarpit.is_enabled();
+        Ok(Verdict::Respond(response))
+    }
+}
+
+/// Rejects requests whose body length is ambiguous, which origins and
+/// intermediaries could otherwise disagree on (request smuggling).
+struct MessageFraming;
+
+#[async_trait]
+impl RequestInterceptor for MessageFraming {
+    fn name(&self) -> &str {
+        "message framing"
+    }
+
+    async fn on_request(
+        &self,
+        ctx: &RequestContext,
+        request: &mut HttpRequest,
+    ) -> ProxyResult<Verdict> {
+        match validate_framing(request) {
+            Ok(()) => Ok(Verdict::Continue),
+            Err(reason) => {
+                warn!("Rejecting request from {}: {}", ctx.client_addr, reason);
+                Ok(Verdict::Respond(
+                    LocalResponse::error_page(400, "Bad Request", "")
+                        .with_error(ProxyError::InvalidRequest(reason)),
+                ))
+            }
+        }
+    }
+}
+
+/// Check Content-Length and Transfer-Encoding for conflicts, collapsing
+/// repeated identical Content-Length values into one field.
+fn validate_framing(request: &mut HttpRequest) -> Result<(), String> {
+    let lengths: Vec<String> = request
+        .headers
+        .get_all("content-length")
+        .flat_map(|value| value.split(','))
+        .map(|length| length.trim().to_string())
+        .collect();
+    let codings: Vec<String> = request
+        .headers
+        .get_all("transfer-encoding")
+        .flat_map(|value| value.split(','))
+        .map(|coding| coding.trim().to_ascii_lowercase())
+        .filter(|coding| !coding.is_empty())
+        .collect();
+
+    if !lengths.is_empty() && request.headers.contains_key("transfer-encoding") {
+        return Err("both Content-Length and Transfer-Encoding present".to_string());
+    }
+
+    if let Some(length) = lengths.first() {
+        if length.is_empty() || !length.bytes().all(|b| b.is_ascii_digit()) {
+            return Err(format!("invalid Content-Length: {}", length));
+        }
+        if lengths.iter().any(|other| other != length) {
+            return Err(format!(
+                "conflicting Content-Length values: {}",
+                lengths.join(", ")
+            ));
+        }
+        if lengths.len() > 1 {
+            request.headers.insert("Content-Length", length.as_str());
+        }
+    }
+
+    if request.headers.contains_key("transfer-encoding") {
+        let chunked = codings.iter().filter(|coding| *coding == "chunked").count();
+        if codings.last().map(String::as_str) != Some("chunked") || chunked > 1 {
+            return Err(format!(
+                "chunked must be the final transfer coding, got: {}",
+                codings.join(", ")
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+/// ReversePath rules, sending requests for local paths to backends. With
+/// ReverseOnly, requests for anything else are refused.
+struct ReverseRoute {
+    reverse_only: bool,
+}
+
+#[async_trait]
+impl RequestInterceptor for ReverseRoute {
+    fn name(&self) -> &str {
+        "reverse proxy"
+    }
+
+    async fn on_request(
+        &self,
+        ctx: &RequestContext,
+        request: &mut HttpRequest,
+    ) -> ProxyResult<Verdict> {
+        let path = request.uri.clone();
+        let route = if request.method == "CONNECT" {
+            Route::NoMatch
+        } else {
+            ctx.state.reverse_proxy.route(request)
+        };
+        match route {
+            Route::Backend => {
+                debug!("Reverse proxying {} to {}", path, request.uri);
+                ctx.reverse_routed.store(true, Ordering::Relaxed);
+                return Ok(Verdict::Continue);
+            }
+            Route::Unavailable => {
+                warn!("No healthy backend for {}", path);
+                let retry_after = ctx.state.reverse_proxy.retry_after().as_secs();
+                return Ok(Verdict::Respond(
+                    LocalResponse::error_page(503, "Service Unavailable", "")
+                        .with_header("Retry-After", &retry_after.to_string())
+                        .with_error(ProxyError::ResourceExhausted(format!(
+                            "no healthy backend for {}",
+                            path
+                        ))),
+                ));
+            }
+            Route::NoMatch if !self.reverse_only => return Ok(Verdict::Continue),
+            Route::NoMatch => {}
+        }
+
+        Ok(Verdict::Respond(
+            LocalResponse::error_page(404, "Not Found", "").with_error(ProxyError::InvalidRequest(
+                format!("No reverse proxy path for {}", path),
+            )),
+        ))
+    }
+}
+
+/// Pins reverse proxy clients to the backend that served them with the
+/// ReverseStickyCookie cookie.
+struct StickySessions;
+
+impl ResponseInterceptor for StickySessions {
+    fn name(&self) -> &str {
+        "sticky sessions"
+    }
+
+    fn on_response(
+        &self,
+        ctx: &RequestContext,
+        request: &HttpRequest,
+        response: &mut HttpResponse,
+    ) {
+        if let Some(cookie) = ctx.state.reverse_proxy.sticky_cookie_for(request) {
+            response.headers.append("Set-Cookie", cookie);
+        }
+    }
+}
+
+/// Adds this proxy to the Via header of responses.
+struct ResponseVia {
+    proxy: Arc<ProxyLogic>,
+}
+
+impl ResponseInterceptor for ResponseVia {
+    fn name(&self) -> &str {
+        "response via"
+    }
+
+    fn on_response(
+        &self,
+        _ctx: &RequestContext,
+        _request: &HttpRequest,
+        response: &mut HttpResponse,
+    ) {
+        self.proxy.add_via(&mut response.headers, &response.version);
+    }
+}
+
+/// Settles which authority a request is for, so that every later stage and
+/// the origin see the same one: origin-form targets get an absolute URI
+/// built from Host, and an absolute URI that disagrees with Host is
+/// resolved according to the `HostMismatch` policy.
+struct EffectiveTarget {
+    policy: HostMismatchPolicy,
+}
+
+#[async_trait]
+impl RequestInterceptor for EffectiveTarget {
+    fn name(&self) -> &str {
+        "effective target"
+    }
+
+    async fn on_request(
+        &self,
+        ctx: &RequestContext,
+        request: &mut HttpRequest,
+    ) -> ProxyResult<Verdict> {
+        if request.method == "CONNECT" {
+            return Ok(Verdict::Continue);
+        }
+
+        if request.uri.starts_with('/') {
+            let host = match request.headers.get("host") {
+                Some(host) => host,
+                None => {
+                    return Ok(Verdict::Respond(
+                        LocalResponse::error_page(
+                            400,
+                            "Bad Request",
+                            "<p>The request names neither a host nor an absolute URI.</p>",
+                        )
+                        .with_error(ProxyError::InvalidRequest(
+                            "No Host header for relative URL".to_string(),
+                        )),
+                    ));
+                }
+            };
+            request.uri = format!("http://{}{}", host, request.uri);
+            return Ok(Verdict::Continue);
+        }
+
+        let url = match url::Url::parse(&request.uri) {
+            Ok(url) => url,
+            Err(_) => return Ok(Verdict::Continue),
+        };
+        let authority = match (url.host_str(), url.port()) {
+            (Some(host), Some(port)) => format!("{}:{}", host, port),
+            (Some(host), None) => host.to_string(),
+            (None, _) => return Ok(Verdict::Continue),
+        };
+
+        let host = match request.headers.get("host") {
+            Some(host) => host.to_string(),
+            None => {
+                request.headers.insert("Host", authority);
+                return Ok(Verdict::Continue);
+            }
+        };
+        if same_authority(&url, &host) {
+            return Ok(Verdict::Continue);
+        }
+
+        match self.policy {
+            HostMismatchPolicy::PreferUri => {
+                debug!(
+                    "Replacing Host {} with {} from the request URI",
+                    host, authority
+                );
+                request.headers.insert("Host", authority);
+            }
+            HostMismatchPolicy::PreferHost => {
+                debug!("Retargeting {} to Host {}", request.uri, host);
+                request.uri = format!("{}://{}{}", url.scheme(), host, &origin_form(&request.uri));
+            }
+            HostMismatchPolicy::Reject => {
+                let reason = format!("Host {} does not match request URI {}", host, request.uri);
+                warn!("Rejecting request from {}: {}", ctx.client_addr, reason);
+                return Ok(Verdict::Respond(
+                    LocalResponse::error_page(400, "Bad Request", "")
+                        .with_error(ProxyError::InvalidRequest(reason)),
+                ));
+            }
+        }
+        Ok(Verdict::Continue)
+    }
+}
+
+/// Whether a Host header value names the same host and port as `url`,
+/// allowing for an omitted default port.
+fn same_authority(url: &url::Url, host: &str) -> bool {
+    match url::Url::parse(&format!("{}://{}/", url.scheme(), host)) {
+        Ok(other) => {
+            other.host_str() == url.host_str()
+                && other.port_or_known_default() == url.port_or_known_default()
+        }
+        Err(_) => false,
+    }
+}
+
+/// Proxy Basic authentication.
+struct Authentication;
+
+#[async_trait]
+impl RequestInterceptor for Authentication {
+    fn name(&self) -> &str {
+        "authentication"
+    }
+
+    async fn on_request(
+        &self,
+        ctx: &RequestContext,
+        request: &mut HttpRequest,
+    ) -> ProxyResult<Verdict> {
+        if ctx.intercepted {
+            return Ok(Verdict::Continue);
+        }
+        let credentials = request.headers.get("proxy-authorization");
+        let auth = &ctx.state.authenticator;
+        let authenticated = auth.authenticate(request);
+        if let (Some(credentials), Ok(false) | Err(_)) = (credentials, &authenticated) {
+            let user = basic_auth_username(credentials).unwrap_or_default();
+            ctx.state.denial_log.record(
+                ctx.client_addr.ip(),
+                Denial::AuthFailure,
+                &[("user", &user), ("url", &request.uri)],
+            );
+        }
+        if authenticated? {
+            return Ok(Verdict::Continue);
+        }
+
+        let challenge = format!("Basic realm=\"{}\"", auth.get_realm());
+        let mut response = LocalResponse::error_page(407, "Proxy Authentication Required", "")
+            .with_header("Proxy-Authenticate", &challenge)
+            .with_error(ProxyError::AuthenticationFailed);
+        // Only count attempts that sent credentials, not initial challenges
+        if request.headers.contains_key("proxy-authorization") {
+            response.tarpit = ctx.state.tarpit.record_auth_failure(ctx.client_addr.ip());
+        }
+        Ok(Verdict::Respond(response))
+    }
+}
+
+/// Restricts the ports CONNECT requests may reach (ConnectPort).
+struct ConnectPortCheck {
+    ports: ConnectPorts,
+    /// Whether Proxy-Authorization was checked, so its user can be trusted.
+    authenticated: bool,
+}
+
+#[async_trait]
+impl RequestInterceptor for ConnectPortCheck {
+    fn name(&self) -> &str {
+        "connect ports"
+    }
+
+    async fn on_request(
+        &self,
+        ctx: &RequestContext,
+        request: &mut HttpRequest,
+    ) -> ProxyResult<Verdict> {
+        if request.method != "CONNECT" {
+            return Ok(Verdict::Continue);
+        }
+        // Malformed targets are refused when the tunnel is opened
+        let port = match parse_host_port(&request.uri) {
+            Ok((_, port)) => port,
+            Err(_) => return Ok(Verdict::Continue),
+        };
+        let user = ctx.user(request).filter(|_| self.authenticated);
+        if self
+            .ports
+            .allows(port, ctx.client_addr.ip(), user.as_deref())
+        {
+            return Ok(Verdict::Continue);
+        }
+
+        warn!("CONNECT to port {} not allowed", port);
+        Ok(Verdict::Respond(
+            LocalResponse::error_page(403, "Port not allowed", "").with_error(
+                ProxyError::AccessDenied(format!("CONNECT to port {} is not allowed", port)),
+            ),
+        ))
+    }
+}
+
+/// Serves the statistics page for requests to the StatHost.
+struct StatsPage {
+    stat_host: String,
+}
+
+#[async_trait]
+impl RequestInterceptor for StatsPage {
+    fn name(&self) -> &str {
+        "statistics page"
+    }
+
+    async fn on_request(
+        &self,
+        ctx: &RequestContext,
+        request: &mut HttpRequest,
+    ) -> ProxyResult<Verdict> {
+        let host_header = request.headers.get("host").unwrap_or(&request.uri);
+        if !host_header.contains(&self.stat_host) {
+            return Ok(Verdict::Continue);
+        }
+
+        debug!("Handling statistics request");
+        let wants_json = origin_form(&request.uri).split('?').next() == Some("/stats.json")
+            || request
+                .headers
+                .get("accept")
+                .is_some_and(|accept| accept.contains("application/json"));
+        if wants_json {
+            let stats_json = ctx.state.stats_snapshot().await.to_json();
+            let mut response = LocalResponse::html(200, "OK", stats_json);
+            response.headers.insert("Content-Type", "application/json");
+            response.headers.insert("Cache-Control", "no-cache");
+            return Ok(Verdict::Respond(response));
+        }
+
+        let config = &ctx.state.config;
+        let admin_url = config.admin_port.map(|port| {
+            let host = match ctx.local_addr {
+                Some(local_addr) if config.admin_listen.is_unspecified() => local_addr.ip(),
+                _ => config.admin_listen,
+            };
+            format!("http://{}", SocketAddr::new(host, port))
+        });
+        let mut sections = ctx.state.connections.to_html(admin_url.as_deref());
+        if ctx.state.reverse_proxy.is_enabled() {
+            sections.push_str(&ctx.state.reverse_proxy.to_html());
+        }
+        if ctx.state.dns_cache.is_enabled() {
+            sections.push_str(&ctx.state.dns_cache.to_html());
+        }
+        let stats_html = ctx
+            .state
+            .stats_snapshot()
+            .await
+            .to_html_with_sections(&sections);
+
+        let mut response = LocalResponse::html(200, "OK", stats_html);
+        response
+            .headers
+            .insert("Content-Type", "text/html; charset=utf-8");
+        response.headers.insert("Cache-Control", "no-cache");
+        Ok(Verdict::Respond(response))
+    }
+}
+
+/// Redirect rules, answered without contacting the origin.
+struct Redirects {
+    proxy: Arc<ProxyLogic>,
+}
+
+#[async_trait]
+impl RequestInterceptor for Redirects {
+    fn name(&self) -> &str {
+        "redirect"
+    }
+
+    async fn on_request(
+        &self,
+        _ctx: &RequestContext,
+        request: &mut HttpRequest,
+    ) -> ProxyResult<Verdict> {
+        if request.method == "CONNECT" {
+            return Ok(Verdict::Continue);
+        }
+
+        match self.proxy.redirect_for(&request.uri) {
+            // Captured parts of the target must not start new header lines
+            Some((_, location)) if location.chars().any(|c| c.is_ascii_control()) => {
+                warn!("Not redirecting {}: invalid Location", request.uri);
+                Ok(Verdict::Respond(
+                    LocalResponse::error_page(400, "Bad Request", "").with_error(
+                        ProxyError::InvalidRequest(format!(
+                            "Redirect of {} has control characters",
+                            request.uri
+                        )),
+                    ),
+                ))
+            }
+            Some((status, location)) => {
+                debug!("Redirecting {} to {} ({})", request.uri, location, status);
+                Ok(Verdict::Respond(LocalResponse::redirect(status, &location)))
+            }
+            None => Ok(Verdict::Continue),
+        }
+    }
+}
+
+/// URL rewrite rules, applied before the target is resolved.
+struct UrlRewrite {
+    proxy: Arc<ProxyLogic>,
+}
+
+#[async_trait]
+impl RequestInterceptor for UrlRewrite {
+    fn name(&self) -> &str {
+        "URL rewrite"
+    }
+
+    async fn on_request(
+        &self,
+        _ctx: &RequestContext,
+        request: &mut HttpRequest,
+    ) -> ProxyResult<Verdict> {
+        if let Some(rewritten) = self.proxy.rewrite_url(&request.uri) {
+            debug!("Rewriting URL {} -> {}", request.uri, rewritten);
+            request.uri = rewritten;
+
+            if let Ok(url) = url::Url::parse(&request.uri) {
+                if let Some(host) = url.host_str() {
+                    let host = match url.port() {
+                        Some(port) => format!("{}:{}", host, port),
+                        None => host.to_string(),
+                    };
+                    request.headers.insert("Host", host);
+                }
+            }
+        }
+        Ok(Verdict::Continue)
+    }
+}
+
+/// URL filter rules, by client for FilterGroup.
+struct UrlFilter {
+    /// Whether Proxy-Authorization was checked, so its user can be trusted.
+    authenticated: bool,
+}
+
+#[async_trait]
+impl RequestInterceptor for UrlFilter {
+    fn name(&self) -> &str {
+        "filter"
+    }
+
+    async fn on_request(
+        &self,
+        ctx: &RequestContext,
+        request: &mut HttpRequest,
+    ) -> ProxyResult<Verdict> {
+        let user = ctx.user(request).filter(|_| self.authenticated);
+        let allowed = ctx.state.filter.read().unwrap().is_allowed(
+            &request.uri,
+            ctx.client_addr.ip(),
+            user.as_deref(),
+        )?;
+        if allowed {
+            return Ok(Verdict::Continue);
+        }
+
+        warn!("Request blocked by filter: {}", request.uri);
+        ctx.state.denial_log.record(
+            ctx.client_addr.ip(),
+            Denial::Filtered,
+            &[("url", &request.uri)],
+        );
+        Ok(Verdict::Respond(
+            LocalResponse::error_page(403, "Forbidden by filter", "")
+                .with_error(ProxyError::FilterBlocked(request.uri.clone())),
+        ))
+    }
+}
+
+/// Per-destination request rate limits protecting origins.
+struct DestinationRateLimit;
+
+#[async_trait]
+impl RequestInterceptor for DestinationRateLimit {
+    fn name(&self) -> &str {
+        "destination rate limit"
+    }
+
+    async fn on_request(
+        &self,
+        ctx: &RequestContext,
+        request: &mut HttpRequest,
+    ) -> ProxyResult<Verdict> {
+        let limits = &ctx.state.destination_limits;
+        if !limits.is_enabled() {
+            return Ok(Verdict::Continue);
+        }
+
+        let host = match request_host(request) {
+            Some(host) => host,
+            None => return Ok(Verdict::Continue),
+        };
+        let retry_after = match limits.check(&host) {
+            Ok(()) => return Ok(Verdict::Continue),
+            Err(retry_after) => retry_after,
+        };
+
+        warn!(
+            "Request rate limit for {} exceeded by {}",
+            host, ctx.client_addr
+        );
+        ctx.state.counters.add(Counter::RequestsDenied, 1);
+        Ok(Verdict::Respond(
+            LocalResponse::error_page(429, "Too Many Requests", "")
+                .with_header("Retry-After", &retry_after.as_secs().max(1).to_string())
+                .with_error(ProxyError::RateLimited(host)),
+        ))
+    }
+}
+
+/// MaxRequestBodySize, rejecting uploads with too large a Content-Length
+/// before anything is sent upstream. Chunked bodies are counted as they
+/// are read instead.
+struct BodySizeLimit {
+    limit: u64,
+}
+
+#[async_trait]
+impl RequestInterceptor for BodySizeLimit {
+    fn name(&self) -> &str {
+        "request body size limit"
+    }
+
+    async fn on_request(
+        &self,
+        ctx: &RequestContext,
+        request: &mut HttpRequest,
+    ) -> ProxyResult<Verdict> {
+        let length = match request.headers.get("content-length") {
+            Some(length) => length,
+            None => return Ok(Verdict::Continue),
+        };
+
+        let length: u64 = match length.trim().parse() {
+            Ok(length) => length,
+            Err(_) => {
+                return Ok(Verdict::Respond(
+                    LocalResponse::error_page(400, "Bad Request", "").with_error(
+                        ProxyError::InvalidRequest(format!("Invalid Content-Length: {}", length)),
+                    ),
+                ))
+            }
+        };
+
+        if length <= self.limit {
+            return Ok(Verdict::Continue);
+        }
+
+        warn!(
+            "Request body of {} bytes from {} exceeds limit of {} bytes",
+            length, ctx.client_addr, self.limit
+        );
+        Ok(Verdict::Respond(
+            LocalResponse::error_page(413, "Payload Too Large", "").with_error(
+                ProxyError::PayloadTooLarge(format!(
+                    "{} bytes exceeds limit of {} bytes",
+                    length, self.limit
+                )),
+            ),
+        ))
+    }
+}
+
+/// Header rewrite rules, Anonymous, Via, the X-Tinyproxy header, AddHeader,
+/// ForwardedHeaders and the ident user (X-Forwarded-User) for forwarded
+/// requests. The client's proxy
+/// credentials are meant for this proxy and are not passed on.
+struct HeaderRewrite {
+    proxy: Arc<ProxyLogic>,
+}
+
+#[async_trait]
+impl RequestInterceptor for HeaderRewrite {
+    fn name(&self) -> &str {
+        "header rewrite"
+    }
+
+    async fn on_request(
+        &self,
+        ctx: &RequestContext,
+        request: &mut HttpRequest,
+    ) -> ProxyResult<Verdict> {
+        if request.method != "CONNECT" {
+            request.headers.remove("Proxy-Authorization");
+            self.proxy.process_headers(
+                &mut request.headers,
+                &request.version,
+                &ctx.client_addr.ip(),
+            );
+            self.proxy.add_forwarded(
+                &mut request.headers,
+                &request.uri,
+                &ctx.client_addr.ip(),
+                ctx.forwarded_by.as_ref(),
+            );
+            if ctx.state.config.ident_lookup {
+                // Only the proxy's own lookup may name the user
+                request.headers.remove("X-Forwarded-User");
+                if let Some(user) = &ctx.ident {
+                    request.headers.insert("X-Forwarded-User", user.as_str());
+                }
+            }
+        }
+        Ok(Verdict::Continue)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncReadExt;
+
+    struct TagRequest;
+
+    #[async_trait]
+    impl RequestInterceptor for TagRequest {
+        fn name(&self) -> &str {
+            "tag"
+        }
+
+        async fn on_request(
+            &self,
+            _ctx: &RequestContext,
+            request: &mut HttpRequest,
+        ) -> ProxyResult<Verdict> {
+            if request.uri.contains("/private") {
+                return Ok(Verdict::Respond(LocalResponse::error_page(
+                    451,
+                    "Unavailable For Legal Reasons",
+                    "",
+                )));
+            }
+            request.headers.insert("X-Tagged", "yes");
+            Ok(Verdict::Continue)
+        }
+    }
+
+    struct TagResponse;
+
+    impl ResponseInterceptor for TagResponse {
+        fn name(&self) -> &str {
+            "tag"
+        }
+
+        fn on_response(
+            &self,
+            _ctx: &RequestContext,
+            request: &HttpRequest,
+            response: &mut HttpResponse,
+        ) {
+            response.headers.insert("X-Requested", request.uri.as_str());
+            response.headers.remove("server");
+        }
+    }
+
+    fn context(config: &str, client: &str, custom: Interceptors) -> RequestContext {
+        let config = Arc::new(Config::parse_config(config).unwrap());
+        RequestContext {
+            connection_id: 1,
+            client_addr: client.parse().unwrap(),
+            local_addr: None,
+            forwarded_by: None,
+            ident: None,
+            reverse_routed: Arc::default(),
+            intercepted: false,
+            tunnel_user: None,
+            state: Arc::new(ServerState::with_interceptors(config, custom)),
+        }
+    }
+
+    fn request(uri: &str) -> HttpRequest {
+        HttpRequest {
+            method: "GET".to_string(),
+            uri: uri.to_string(),
+            version: "1.1".to_string(),
+            headers: Headers::new(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_request_chain() {
+        let mut custom = Interceptors::new();
+        custom.add_request(TagRequest);
+        let ctx = context(
+            "Allow 192.0.2.0/24\nRedirect 301 ^http://old\\.example/(.*) http://new.example/$1",
+            "192.0.2.1:40000",
+            custom,
+        );
+        let chain = &ctx.state.interceptors;
+
+        let mut forwarded = request("http://www.example/");
+        assert!(matches!(
+            chain.on_request(&ctx, &mut forwarded).await.unwrap(),
+            Verdict::Continue
+        ));
+        assert_eq!(forwarded.headers.get("x-tagged"), Some("yes"));
+
+        let mut blocked = request("http://www.example/private");
+        match chain.on_request(&ctx, &mut blocked).await.unwrap() {
+            Verdict::Respond(response) => assert_eq!(response.status, 451),
+            Verdict::Continue => panic!("request was not answered"),
+        }
+
+        // Built-in stages run before custom ones
+        let mut redirected = request("http://old.example/page");
+        match chain.on_request(&ctx, &mut redirected).await.unwrap() {
+            Verdict::Respond(response) => {
+                assert_eq!(response.status, 301);
+                assert!(String::from_utf8(response.to_bytes("1.1", false))
+                    .unwrap()
+                    .contains("Location: http://new.example/page\r\n"));
+            }
+            Verdict::Continue => panic!("request was not redirected"),
+        }
+        assert!(!redirected.headers.contains_key("x-tagged"));
+
+        let ctx = context(
+            "Allow 192.0.2.0/24",
+            "198.51.100.1:40000",
+            Interceptors::new(),
+        );
+        match ctx
+            .state
+            .interceptors
+            .on_request(&ctx, &mut request("/"))
+            .await
+            .unwrap()
+        {
+            Verdict::Respond(response) => {
+                assert_eq!(response.status, 403);
+                assert!(matches!(response.error, Some(ProxyError::AccessDenied(_))));
+            }
+            Verdict::Continue => panic!("access was not denied"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_redirect_location() {
+        let ctx = context(
+            "Redirect 302 ^http://old\\.example/(.*) http://new.example/$1",
+            "192.0.2.1:40000",
+            Interceptors::new(),
+        );
+        let chain = &ctx.state.interceptors;
+        let respond = |verdict| match verdict {
+            Verdict::Respond(response) => response,
+            Verdict::Continue => panic!("request was not answered"),
+        };
+
+        // Captured parts of the target are escaped in the page
+        let response = respond(
+            chain
+                .on_request(&ctx, &mut request("http://old.example/\"><script>"))
+                .await
+                .unwrap(),
+        );
+        assert_eq!(response.status, 302);
+        assert!(response
+            .body
+            .contains("<a href=\"http://new.example/&quot;&gt;&lt;script&gt;\">"));
+        assert!(!response.body.contains("<script>"));
+
+        // and cannot add header lines
+        let response = respond(
+            chain
+                .on_request(&ctx, &mut request("http://old.example/\r\nSet-Cookie: a=b"))
+                .await
+                .unwrap(),
+        );
+        assert_eq!(response.status, 400);
+        assert!(response.headers.get("location").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_stats_page() {
+        let ctx = context(
+            "StatHost stats.test",
+            "127.0.0.1:40000",
+            Interceptors::new(),
+        );
+        let chain = &ctx.state.interceptors;
+        let respond = |verdict| match verdict {
+            Verdict::Respond(response) => response,
+            Verdict::Continue => panic!("statistics request was forwarded"),
+        };
+
+        let html = respond(
+            chain
+                .on_request(&ctx, &mut request("http://stats.test/"))
+                .await
+                .unwrap(),
+        );
+        assert_eq!(
+            html.headers.get("content-type"),
+            Some("text/html; charset=utf-8")
+        );
+
+        let json = respond(
+            chain
+                .on_request(&ctx, &mut request("http://stats.test/stats.json"))
+                .await
+                .unwrap(),
+        );
+        assert_eq!(json.headers.get("content-type"), Some("application/json"));
+        let document: serde_json::Value = serde_json::from_str(&json.body).unwrap();
+        assert!(document.is_object());
+
+        let mut accepting = request("http://stats.test/");
+        accepting.headers.insert("Accept", "application/json");
+        let json = respond(chain.on_request(&ctx, &mut accepting).await.unwrap());
+        assert_eq!(json.headers.get("content-type"), Some("application/json"));
+    }
+
+    #[tokio::test]
+    async fn test_connect_ports() {
+        let ctx = context(
+            "BasicAuth admin:secret\nConnectPort 443\nConnectPort 22 user=admin",
+            "192.0.2.7:40000",
+            Interceptors::new(),
+        );
+        let connect = |target: &str, credentials: &str| {
+            let mut request = request(target);
+            request.method = "CONNECT".to_string();
+            request.headers.insert("Proxy-Authorization", credentials);
+            request
+        };
+
+        let admin = "Basic YWRtaW46c2VjcmV0";
+        for (target, allowed) in [("example.com:443", true), ("example.com:22", true)] {
+            let verdict = ctx
+                .state
+                .interceptors
+                .on_request(&ctx, &mut connect(target, admin))
+                .await
+                .unwrap();
+            assert_eq!(matches!(verdict, Verdict::Continue), allowed);
+        }
+
+        let ctx = context(
+            "ConnectPort 22 user=admin",
+            "192.0.2.7:40000",
+            Interceptors::new(),
+        );
+        // Without BasicAuth the claimed user is not trusted
+        match ctx
+            .state
+            .interceptors
+            .on_request(&ctx, &mut connect("example.com:22", admin))
+            .await
+            .unwrap()
+        {
+            Verdict::Respond(response) => {
+                assert_eq!(response.status, 403);
+                assert!(matches!(response.error, Some(ProxyError::AccessDenied(_))));
+            }
+            Verdict::Continue => panic!("port was not refused"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_client_identity_headers() {
+        let ctx = context("XTinyproxy Yes", "192.0.2.7:40000", Interceptors::new());
+        let mut forwarded = request("http://www.example/");
+        forwarded.headers.insert("X-Tinyproxy", "10.0.0.1");
+        ctx.state
+            .interceptors
+            .on_request(&ctx, &mut forwarded)
+            .await
+            .unwrap();
+        assert_eq!(forwarded.headers.get("x-tinyproxy"), Some("192.0.2.7"));
+
+        // Proxy credentials stay with this proxy
+        let ctx = context("", "192.0.2.7:40000", Interceptors::new());
+        let mut forwarded = request("http://www.example/");
+        forwarded
+            .headers
+            .insert("Proxy-Authorization", "Basic Ym9iOmh1bnRlcjI=");
+        ctx.state
+            .interceptors
+            .on_request(&ctx, &mut forwarded)
+            .await
+            .unwrap();
+        assert!(!forwarded.headers.contains_key("x-tinyproxy"));
+        assert!(!forwarded.headers.contains_key("proxy-authorization"));
+
+        // The ident user replaces one claimed by the client
+        let mut ctx = context("IdentLookup Yes", "192.0.2.7:40000", Interceptors::new());
+        ctx.ident = Some("alice".to_string());
+        let mut forwarded = request("http://www.example/");
+        forwarded.headers.insert("X-Forwarded-User", "root");
+        ctx.state
+            .interceptors
+            .on_request(&ctx, &mut forwarded)
+            .await
+            .unwrap();
+        assert_eq!(forwarded.headers.get("x-forwarded-user"), Some("alice"));
+    }
+
+    #[test]
+    fn test_validate_framing() {
+        let framed = |headers: &[(&str, &str)]| {
+            let mut request = request("/upload");
+            request.method = "POST".to_string();
+            request.headers = headers.iter().copied().collect();
+            validate_framing(&mut request).map(|()| request.headers)
+        };
+
+        assert!(framed(&[("Content-Length", "5")]).is_ok());
+        assert!(framed(&[("Transfer-Encoding", "gzip, chunked")]).is_ok());
+        assert_eq!(
+            framed(&[("Content-Length", "5"), ("content-length", "5")])
+                .unwrap()
+                .to_lines(),
+            "Content-Length: 5\r\n"
+        );
+
+        assert!(framed(&[("Content-Length", "5"), ("Transfer-Encoding", "chunked")]).is_err());
+        assert!(framed(&[("Content-Length", "5, 6")]).is_err());
+        assert!(framed(&[("Content-Length", "-1")]).is_err());
+        assert!(framed(&[("Transfer-Encoding", "chunked, gzip")]).is_err());
+        assert!(framed(&[
+            ("Transfer-Encoding", "chunked"),
+            ("Transfer-Encoding", "chunked")
+        ])
+        .is_err());
+        assert!(framed(&[("Transfer-Encoding", "")]).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_effective_target() {
+        let resolve = |policy: &str, uri: &str, host: Option<&str>| {
+            let ctx = context(
+                &format!("HostMismatch {}", policy),
+                "127.0.0.1:40000",
+                Interceptors::new(),
+            );
+            let mut request = request(uri);
+            if let Some(host) = host {
+                request.headers.insert("Host", host);
+            }
+            async move {
+                let verdict = EffectiveTarget {
+                    policy: ctx.state.config.host_mismatch,
+                }
+                .on_request(&ctx, &mut request)
+                .await
+                .unwrap();
+                (verdict, request)
+            }
+        };
+
+        let (_, request) = resolve("uri", "/a?b", Some("www.example:8080")).await;
+        assert_eq!(request.uri, "http://www.example:8080/a?b");
+
+        let (_, request) = resolve("uri", "http://www.example/a", None).await;
+        assert_eq!(request.headers.get("host"), Some("www.example"));
+
+        // A default port matches its omission
+        let (_, request) = resolve("reject", "http://www.example/a", Some("WWW.example:80")).await;
+        assert_eq!(request.headers.get("host"), Some("WWW.example:80"));
+
+        let (_, request) =
+            resolve("uri", "http://allowed.example/a", Some("blocked.example")).await;
+        assert_eq!(request.uri, "http://allowed.example/a");
+        assert_eq!(request.headers.get("host"), Some("allowed.example"));
+
+        let (_, request) =
+            resolve("host", "http://allowed.example/a", Some("blocked.example")).await;
+        assert_eq!(request.uri, "http://blocked.example/a");
+
+        match resolve(
+            "reject",
+            "http://allowed.example/a",
+            Some("blocked.example"),
+        )
+        .await
+        .0
+        {
+            Verdict::Respond(response) => {
+                assert_eq!(response.status, 400);
+                assert!(matches!(
+                    response.error,
+                    Some(ProxyError::InvalidRequest(_))
+                ));
+            }
+            Verdict::Continue => panic!("mismatched Host was not rejected"),
+        }
+        assert!(Config::parse_config("HostMismatch either").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_intercepted_response() {
+        let mut interceptors = Interceptors::new();
+        interceptors.add_response(TagResponse);
+        let ctx = context("", "192.0.2.1:40000", interceptors.clone());
+
+        // Bytes past the end of the body are not relayed
+        let origin: &[u8] = b"HTTP/1.1 100 Continue\r\n\r\n\
+                              HTTP/1.1 200 OK\r\nServer: origin\r\nContent-Length: 4\r\n\r\nbody+junk";
+        let (prefix, rest) = origin.split_at(10);
+        let mut reader = InterceptedResponse::new(
+            rest,
+            BytesMut::from(prefix),
+            interceptors.clone(),
+            ctx.clone(),
+            request("/index.html"),
+        )
+        .with_keep_alive(true);
+        let mut relayed = Vec::new();
+        reader.read_to_end(&mut relayed).await.unwrap();
+
+        assert_eq!(
+            relayed,
+            b"HTTP/1.1 100 Continue\r\n\r\n\
+              HTTP/1.1 200 OK\r\nContent-Length: 4\r\nX-Requested: /index.html\r\n\
+              Keep-Alive: timeout=15\r\n\r\nbody"
+        );
+        assert!(reader.keeps_connection());
+        assert!(!reader.reuses_origin());
+
+        // The origin's connection can take another request unless it asks to
+        // close it
+        for (origin, reusable) in [
+            (
+                &b"HTTP/1.1 200 OK\r\nContent-Length: 4\r\n\r\nbody"[..],
+                true,
+            ),
+            (
+                b"HTTP/1.1 200 OK\r\nConnection: close\r\nContent-Length: 4\r\n\r\nbody",
+                false,
+            ),
+            (b"HTTP/1.0 200 OK\r\nContent-Length: 4\r\n\r\nbody", false),
+        ] {
+            let mut reader = InterceptedResponse::new(
+                origin,
+                BytesMut::new(),
+                interceptors.clone(),
+                ctx.clone(),
+                request("/index.html"),
+            );
+            reader.read_to_end(&mut Vec::new()).await.unwrap();
+            assert_eq!(reader.reuses_origin(), reusable);
+        }
+
+        // Responses ending when the origin closes end the client's connection
+        let origin: &[u8] = b"HTTP/1.1 200 OK\r\nConnection: keep-alive\r\n\r\nbody";
+        let mut reader = InterceptedResponse::new(
+            origin,
+            BytesMut::new(),
+            interceptors,
+            ctx,
+            request("/index.html"),
+        )
+        .with_keep_alive(true);
+        let mut relayed = Vec::new();
+        reader.read_to_end(&mut relayed).await.unwrap();
+
+        assert_eq!(
+            relayed,
+            b"HTTP/1.1 200 OK\r\nX-Requested: /index.html\r\nConnection: close\r\n\r\nbody"
+        );
+        assert!(!reader.keeps_connection());
+    }
+}
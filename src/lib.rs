@@ -0,0 +1,45 @@
+// Tests set up configurations field by field
+#![cfg_attr(test, allow(clippy::field_reassign_with_default))]
+
+pub mod access_log;
+pub mod acl;
+pub mod acl_sync;
+pub mod admin;
+pub mod auth;
+pub mod circuit;
+pub mod config;
+pub mod connect_ports;
+pub mod connection;
+pub mod connector;
+pub mod denial;
+pub mod dns;
+pub mod error;
+pub mod error_pages;
+pub mod filter;
+pub mod framing;
+pub mod gzip;
+pub mod headers;
+pub mod ident;
+pub mod interceptor;
+pub mod logging;
+pub mod mirror;
+#[cfg(feature = "rustls")]
+pub mod mitm;
+pub mod pool;
+#[cfg(unix)]
+pub mod privileges;
+pub mod proxy;
+pub mod proxy_protocol;
+pub mod ratelimit;
+pub mod record;
+pub mod registry;
+pub mod reverse;
+pub mod server;
+pub mod sni;
+pub mod state;
+pub mod stats;
+pub mod tarpit;
+pub mod throttle;
+#[cfg(any(feature = "rustls", feature = "native-tls"))]
+pub mod tls;
+pub mod utils;
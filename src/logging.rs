@@ -0,0 +1,168 @@
+use crate::config::Config;
+use chrono::{SecondsFormat, Utc};
+use log::kv::{self, Key, Value, VisitSource, VisitValue};
+use log::{LevelFilter, Record};
+use serde_json::{Map, Value as Json};
+use std::io::Write;
+
+/// Set up logging to standard error at the LogLevel, or debug level with
+/// Debug set. The level can be changed later with [`set_level`]. With
+/// `LogFormat json` every record is written as a JSON object; see
+/// [`format_json`].
+pub fn init(config: &Config) {
+    let level = if config.debug {
+        LevelFilter::Debug
+    } else {
+        parse_level(&config.log_level).unwrap_or_else(|| {
+            eprintln!("Unknown LogLevel {:?}, using Info", config.log_level);
+            LevelFilter::Info
+        })
+    };
+
+    // Everything passes the logger's own filter, so that the global
+    // maximum alone decides, and can be raised at runtime
+    let mut builder = env_logger::Builder::from_default_env();
+    builder.filter_level(LevelFilter::Trace);
+    if config.log_format == "json" {
+        builder.format(|buf, record| writeln!(buf, "{}", format_json(record)));
+    }
+    builder.init();
+    set_level(level);
+}
+
+/// A level by tinyproxy's LogLevel names (Critical, Error, Warning,
+/// Notice, Connect, Info, Debug) or Rust's (error, warn, info, debug,
+/// trace, off), ignoring case.
+pub fn parse_level(name: &str) -> Option<LevelFilter> {
+    match name.trim_matches('"').to_lowercase().as_str() {
+        "critical" | "error" => Some(LevelFilter::Error),
+        "warning" | "warn" => Some(LevelFilter::Warn),
+        "notice" | "connect" | "info" => Some(LevelFilter::Info),
+        "debug" => Some(LevelFilter::Debug),
+        "trace" => Some(LevelFilter::Trace),
+        "off" => Some(LevelFilter::Off),
+        _ => None,
+    }
+}
+
+pub fn level() -> LevelFilter {
+    log::max_level()
+}
+
+pub fn set_level(level: LevelFilter) {
+    log::set_max_level(level);
+}
+
+/// A log record as a JSON object with `timestamp`, `level`, `target` and
+/// `message`, plus the record's key-value fields, such as `client_ip`,
+/// `method`, `host`, `status`, `bytes` and `duration_ms` for requests.
+pub fn format_json(record: &Record) -> String {
+    let mut object = Map::new();
+    object.insert(
+        "timestamp".to_string(),
+        Utc::now()
+            .to_rfc3339_opts(SecondsFormat::Millis, true)
+            .into(),
+    );
+    object.insert(
+        "level".to_string(),
+        record.level().as_str().to_lowercase().into(),
+    );
+    object.insert("target".to_string(), record.target().into());
+    object.insert("message".to_string(), record.args().to_string().into());
+    let _ = record.key_values().visit(&mut Fields(&mut object));
+    Json::Object(object).to_string()
+}
+
+struct Fields<'a>(&'a mut Map<String, Json>);
+
+impl<'kvs> VisitSource<'kvs> for Fields<'_> {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), kv::Error> {
+        let mut field = Field(Json::Null);
+        value.visit(&mut field)?;
+        self.0.insert(key.to_string(), field.0);
+        Ok(())
+    }
+}
+
+/// A key-value field converted to JSON: numbers, booleans and null as
+/// such, anything else as its text.
+struct Field(Json);
+
+impl<'v> VisitValue<'v> for Field {
+    fn visit_any(&mut self, value: Value) -> Result<(), kv::Error> {
+        self.0 = value.to_string().into();
+        Ok(())
+    }
+
+    fn visit_null(&mut self) -> Result<(), kv::Error> {
+        self.0 = Json::Null;
+        Ok(())
+    }
+
+    fn visit_u64(&mut self, value: u64) -> Result<(), kv::Error> {
+        self.0 = value.into();
+        Ok(())
+    }
+
+    fn visit_i64(&mut self, value: i64) -> Result<(), kv::Error> {
+        self.0 = value.into();
+        Ok(())
+    }
+
+    fn visit_f64(&mut self, value: f64) -> Result<(), kv::Error> {
+        self.0 = value.into();
+        Ok(())
+    }
+
+    fn visit_bool(&mut self, value: bool) -> Result<(), kv::Error> {
+        self.0 = value.into();
+        Ok(())
+    }
+
+    fn visit_str(&mut self, value: &str) -> Result<(), kv::Error> {
+        self.0 = value.into();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use log::Level;
+
+    #[test]
+    fn test_parse_level() {
+        assert_eq!(parse_level("Critical"), Some(LevelFilter::Error));
+        assert_eq!(parse_level("\"Warning\""), Some(LevelFilter::Warn));
+        assert_eq!(parse_level("Connect"), Some(LevelFilter::Info));
+        assert_eq!(parse_level("DEBUG"), Some(LevelFilter::Debug));
+        assert_eq!(parse_level("verbose"), None);
+    }
+
+    #[test]
+    fn test_format_json() {
+        let fields: &[(&str, Value)] = &[
+            ("client_ip", Value::from_display(&"192.0.2.7")),
+            ("status", Value::from(200u16)),
+            ("host", Value::null()),
+            ("cached", Value::from(false)),
+        ];
+        let record = Record::builder()
+            .args(format_args!("GET \"/\" done"))
+            .level(Level::Warn)
+            .target("tinyproxy_rust::connection")
+            .key_values(&fields)
+            .build();
+
+        let line: Json = serde_json::from_str(&format_json(&record)).unwrap();
+        assert_eq!(line["level"], "warn");
+        assert_eq!(line["target"], "tinyproxy_rust::connection");
+        assert_eq!(line["message"], "GET \"/\" done");
+        assert_eq!(line["client_ip"], "192.0.2.7");
+        assert_eq!(line["status"], 200);
+        assert!(line["host"].is_null());
+        assert_eq!(line["cached"], false);
+        assert!(line["timestamp"].as_str().unwrap().ends_with('Z'));
+    }
+}
@@ -1,34 +1,23 @@
 use anyhow::Result;
+use clap::parser::ValueSource;
 use clap::{Arg, Command};
 use log::{error, info};
 use std::process;
 use std::sync::Arc;
+use tokio::runtime::{self, Runtime};
 use tokio::signal;
 
-mod acl;
-mod auth;
-mod config;
-mod connection;
-mod error;
-mod filter;
-mod proxy;
-mod server;
-mod stats;
-mod utils;
-
-use config::Config;
-use server::ProxyServer;
-
-#[tokio::main]
-async fn main() -> Result<()> {
-    // Initialize logger
-    env_logger::Builder::from_default_env()
-        .filter_level(log::LevelFilter::Info)
-        .init();
+use tinyproxy_rust::config::Config;
+use tinyproxy_rust::filter::Filter;
+use tinyproxy_rust::logging;
+use tinyproxy_rust::server::ProxyServer;
 
+fn main() -> Result<()> {
     // Parse command line arguments
     let matches = Command::new("tinyproxy-rust")
         .version(env!("CARGO_PKG_VERSION"))
+        // -v/--version below prints the longer banner
+        .disable_version_flag(true)
         .about("A fast lightweight HTTP/HTTPS proxy daemon implemented in Rust")
         .arg(
             Arg::new("config")
@@ -52,12 +41,55 @@ async fn main() -> Result<()> {
                 .help("Display version information")
                 .action(clap::ArgAction::SetTrue),
         )
+        .arg(
+            Arg::new("test-serve")
+                .long("test-serve")
+                .value_name("PORT")
+                .help("Try the configuration on PORT next to a running instance")
+                .value_parser(clap::value_parser!(u16)),
+        )
         .arg(
             Arg::new("debug")
                 .long("debug")
                 .help("Enable debug mode")
                 .action(clap::ArgAction::SetTrue),
         )
+        .arg(
+            Arg::new("worker-threads")
+                .long("worker-threads")
+                .value_name("N")
+                .help("Threads serving connections, 0 for one per CPU core")
+                .value_parser(clap::value_parser!(usize)),
+        )
+        .arg(
+            Arg::new("max-blocking-threads")
+                .long("max-blocking-threads")
+                .value_name("N")
+                .help("Most threads for blocking work such as file access")
+                .value_parser(clap::value_parser!(u64).range(1..)),
+        )
+        .subcommand(
+            Command::new("validate-filter")
+                .about("Check a filter file and print statistics about its rules")
+                .arg(
+                    Arg::new("file")
+                        .value_name("FILE")
+                        .help("Filter file to check")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("extended")
+                        .long("extended")
+                        .help("Treat rules as regular expressions, as FilterExtended does")
+                        .action(clap::ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("case-sensitive")
+                        .long("case-sensitive")
+                        .help("Match case-sensitively, as FilterCaseSensitive does")
+                        .action(clap::ArgAction::SetTrue),
+                ),
+        )
         .get_matches();
 
     if matches.get_flag("version") {
@@ -66,47 +98,97 @@ async fn main() -> Result<()> {
         return Ok(());
     }
 
+    if let Some(args) = matches.subcommand_matches("validate-filter") {
+        // Use the filter settings of the configuration if one was given
+        let mut config = if matches.value_source("config") == Some(ValueSource::CommandLine) {
+            let config_file = matches.get_one::<String>("config").unwrap();
+            Config::from_file(config_file).unwrap_or_else(|e| {
+                eprintln!("Failed to load configuration from {}: {}", config_file, e);
+                process::exit(1);
+            })
+        } else {
+            Config::default()
+        };
+        logging::init(&config);
+        config.filter_extended |= args.get_flag("extended");
+        config.filter_casesensitive |= args.get_flag("case-sensitive");
+
+        let file = args.get_one::<String>("file").unwrap();
+        process::exit(validate_filter(&config, file));
+    }
+
     // Load configuration
     let config_file = matches.get_one::<String>("config").unwrap();
     let mut config = match Config::from_file(config_file) {
         Ok(config) => config,
         Err(e) => {
+            logging::init(&Config::default());
             error!("Failed to load configuration from {}: {}", config_file, e);
             process::exit(1);
         }
     };
 
-    // Override debug mode if specified
+    // Canary instances run in the foreground on their own port
+    let canary_port = matches.get_one::<u16>("test-serve").copied();
+    if let Some(port) = canary_port {
+        config = config.into_canary(port);
+    }
+
+    // Override debug mode and threads if specified
     if matches.get_flag("debug") {
         config.debug = true;
     }
-
-    // Set up logging level based on debug mode
-    if config.debug {
-        env_logger::Builder::from_default_env()
-            .filter_level(log::LevelFilter::Debug)
-            .init();
+    if let Some(&threads) = matches.get_one::<usize>("worker-threads") {
+        config.worker_threads = threads;
+    }
+    if let Some(&threads) = matches.get_one::<u64>("max-blocking-threads") {
+        config.max_blocking_threads = threads as usize;
     }
 
+    // Logging follows Debug and LogFormat
+    logging::init(&config);
+
     info!("Starting tinyproxy-rust v{}", env!("CARGO_PKG_VERSION"));
     info!("Configuration loaded from: {}", config_file);
+    if let Some(port) = canary_port {
+        info!("Test-serving the configuration on port {}", port);
+    }
 
     // If daemon mode is requested, daemonize the process
-    if matches.get_flag("daemon") || config.daemon {
+    if canary_port.is_none() && (matches.get_flag("daemon") || config.daemon) {
         info!("Running in daemon mode");
         daemonize()?;
     }
 
+    // The runtime starts after forking, which would lose its threads
+    let runtime = build_runtime(&config)?;
+    runtime.block_on(serve(Arc::new(config)))
+}
+
+/// A runtime with the configured worker and blocking threads.
+fn build_runtime(config: &Config) -> Result<Runtime> {
+    let mut builder = runtime::Builder::new_multi_thread();
+    if config.worker_threads > 0 {
+        builder.worker_threads(config.worker_threads);
+    }
+    builder
+        .max_blocking_threads(config.max_blocking_threads)
+        .enable_all()
+        .build()
+        .map_err(|e| anyhow::anyhow!("Failed to start runtime: {}", e))
+}
+
+/// Run the proxy server until it is shut down.
+async fn serve(config: Arc<Config>) -> Result<()> {
     // Create and start the proxy server
-    let config = Arc::new(config);
     let server = ProxyServer::new(config.clone()).await?;
 
     // Set up signal handling
     let server_clone = server.clone();
     tokio::spawn(async move {
-        match signal::ctrl_c().await {
+        match shutdown_signal().await {
             Ok(()) => {
-                info!("Received interrupt signal, shutting down gracefully...");
+                info!("Received shutdown signal, shutting down gracefully...");
                 server_clone.shutdown().await;
             }
             Err(err) => {
@@ -129,6 +211,40 @@ async fn main() -> Result<()> {
     Ok(())
 }
 
+/// Print a report on a filter file, returning the process exit code.
+fn validate_filter(config: &Config, file: &str) -> i32 {
+    let report = match Filter::validate_file(config, file) {
+        Ok(report) => report,
+        Err(e) => {
+            eprintln!("{}", e);
+            return 1;
+        }
+    };
+
+    for invalid in &report.invalid {
+        println!(
+            "{}:{}: invalid regex {:?}: {}",
+            file, invalid.line, invalid.pattern, invalid.error
+        );
+    }
+    println!(
+        "{}: {} rules ({} exact, {} domain, {} host, {} regex), {} invalid",
+        file,
+        report.rule_count(),
+        report.exact,
+        report.domain,
+        report.host,
+        report.regex,
+        report.invalid.len()
+    );
+
+    if report.invalid.is_empty() {
+        0
+    } else {
+        1
+    }
+}
+
 #[cfg(unix)]
 fn daemonize() -> Result<()> {
     #[allow(unused_imports)]
@@ -189,3 +305,20 @@ fn daemonize() -> Result<()> {
     warn!("Daemon mode is not supported on this platform");
     Ok(())
 }
+
+/// Wait for an interrupt, or for SIGTERM as sent by service managers.
+async fn shutdown_signal() -> std::io::Result<()> {
+    #[cfg(unix)]
+    {
+        use signal::unix::{signal, SignalKind};
+
+        let mut terminate = signal(SignalKind::terminate())?;
+        tokio::select! {
+            result = signal::ctrl_c() => result,
+            _ = terminate.recv() => Ok(()),
+        }
+    }
+
+    #[cfg(not(unix))]
+    signal::ctrl_c().await
+}
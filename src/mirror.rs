@@ -0,0 +1,140 @@
+use crate::config::Config;
+use crate::connection::reconstruct_http_request;
+use crate::connector::Connector;
+use crate::utils::HttpRequest;
+use log::{debug, warn};
+use regex::Regex;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::time::timeout;
+
+/// How long a mirrored exchange may take before it is abandoned.
+const MIRROR_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Mirror rules sending copies of matching requests to secondary backends
+/// ("shadow traffic"). Their responses are read and discarded, and never
+/// affect the client.
+pub struct Mirrors {
+    rules: Vec<MirrorRule>,
+}
+
+struct MirrorRule {
+    pattern: Regex,
+    host: String,
+    port: u16,
+}
+
+impl Mirrors {
+    pub fn new(config: &Config) -> Self {
+        let mut rules = Vec::new();
+
+        for rule in &config.mirrors {
+            match Regex::new(&rule.pattern) {
+                Ok(pattern) => rules.push(MirrorRule {
+                    pattern,
+                    host: rule.host.clone(),
+                    port: rule.port,
+                }),
+                Err(e) => warn!("Invalid mirror pattern {}: {}", rule.pattern, e),
+            }
+        }
+
+        Self { rules }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// Send a copy of `request` and its complete `body` to the backend of
+    /// every rule matching the request URI, in the background. Returns the
+    /// number of copies sent.
+    pub fn mirror(
+        &self,
+        connector: &Arc<dyn Connector>,
+        request: &HttpRequest,
+        body: &[u8],
+    ) -> usize {
+        let mut copy = request.clone();
+        copy.headers.insert("Connection", "close");
+        let mut data = reconstruct_http_request(&copy);
+        data.extend_from_slice(body);
+        let data: Arc<[u8]> = data.into();
+
+        let mut sent = 0;
+        for rule in self
+            .rules
+            .iter()
+            .filter(|rule| rule.pattern.is_match(&request.uri))
+        {
+            let connector = connector.clone();
+            let data = data.clone();
+            let host = rule.host.clone();
+            let port = rule.port;
+            let description = format!("{} {} to {}:{}", request.method, request.uri, host, port);
+
+            tokio::spawn(async move {
+                let exchange = async {
+                    let mut stream = connector
+                        .connect(&host, port)
+                        .await
+                        .map_err(|e| std::io::Error::other(e.to_string()))?;
+                    stream.write_all(&data).await?;
+                    tokio::io::copy(&mut stream, &mut tokio::io::sink()).await
+                };
+                match timeout(MIRROR_TIMEOUT, exchange).await {
+                    Ok(Ok(_)) => debug!("Mirrored {}", description),
+                    Ok(Err(e)) => debug!("Mirroring {} failed: {}", description, e),
+                    Err(_) => debug!("Mirroring {} timed out", description),
+                }
+            });
+            sent += 1;
+        }
+        sent
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::connector::DirectConnector;
+    use crate::utils::parse_http_request;
+    use tokio::io::AsyncReadExt;
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn test_mirror() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let config = Config::parse_config(&format!(
+            "Mirror \"^http://api\\.example/\" 127.0.0.1:{}",
+            port
+        ))
+        .unwrap();
+        let mirrors = Mirrors::new(&config);
+        let connector: Arc<dyn Connector> = Arc::new(DirectConnector::new(Duration::from_secs(5)));
+
+        let request = parse_http_request(
+            b"POST http://api.example/items HTTP/1.1\r\nHost: api.example\r\nContent-Length: 4\r\n\r\n",
+        )
+        .unwrap();
+        assert_eq!(mirrors.mirror(&connector, &request, b"data"), 1);
+
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut received = Vec::new();
+        while !received.ends_with(b"data") {
+            let mut buf = [0u8; 1024];
+            let n = stream.read(&mut buf).await.unwrap();
+            assert!(n > 0, "mirror closed early");
+            received.extend_from_slice(&buf[..n]);
+        }
+        assert_eq!(
+            received,
+            b"POST /items HTTP/1.1\r\nHost: api.example\r\nContent-Length: 4\r\nConnection: close\r\n\r\ndata"
+        );
+
+        let other = parse_http_request(b"GET http://www.example/ HTTP/1.1\r\n\r\n").unwrap();
+        assert_eq!(mirrors.mirror(&connector, &other, b""), 0);
+    }
+}
@@ -0,0 +1,594 @@
+use crate::config::Config;
+use crate::connector::BoxedStream;
+use crate::utils::wildcard_match;
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use chrono::{DateTime, Datelike, Duration, Utc};
+use log::{debug, info, warn};
+use lru::LruCache;
+use rcgen::{
+    date_time_ymd, BasicConstraints, CertificateParams, DnType, ExtendedKeyUsagePurpose, IsCa,
+    Issuer, KeyPair, KeyUsagePurpose, SerialNumber,
+};
+use ring::rand::{SecureRandom, SystemRandom};
+use std::io;
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use tokio_rustls::rustls::crypto::ring::default_provider;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer};
+use tokio_rustls::rustls::server::Acceptor;
+use tokio_rustls::rustls::sign::CertifiedKey;
+use tokio_rustls::rustls::ServerConfig;
+use tokio_rustls::LazyConfigAcceptor;
+
+/// Days the interception CA is valid.
+const CA_DAYS: i64 = 3650;
+
+/// Days certificates made for servers are valid, from a day before they
+/// are made to allow for clock skew.
+const LEAF_DAYS: i64 = 365;
+
+/// Server certificates kept; the least recently used goes when it is full.
+const MAX_CACHED: usize = 1000;
+
+/// Days a certificate saved in TlsInterceptCacheDir is used, leaving a
+/// month before it expires.
+const SAVED_DAYS: u64 = LEAF_DAYS as u64 - 30;
+
+/// HTTPS inspection (TlsIntercept): CONNECT tunnels are answered with a
+/// certificate for the server the client asks for, made on the fly and
+/// signed by a local CA the clients trust. The decrypted requests then go
+/// through the normal pipeline and are encrypted again towards the origin.
+/// The CA is loaded from TlsInterceptCa, or created there on first use,
+/// and loaded again on reload; tunnels to NoTlsIntercept servers are
+/// relayed as they are. Certificates made for servers are kept in memory
+/// and, with TlsInterceptCacheDir, on disk for later runs.
+pub struct TlsInterception {
+    /// The TlsInterceptCa files, if interception is on.
+    ca_files: Option<(String, String)>,
+    cache_dir: Option<String>,
+    ca: RwLock<Option<Arc<CertificateAuthority>>>,
+    exclude: Vec<String>,
+    configs: Mutex<LruCache<String, Arc<ServerConfig>>>,
+    rng: SystemRandom,
+}
+
+/// The interception CA, and the key of every server certificate it
+/// signs, made at startup or kept in TlsInterceptCacheDir.
+struct CertificateAuthority {
+    cert: Vec<u8>,
+    issuer: Issuer<'static, KeyPair>,
+    leaf_key: KeyPair,
+}
+
+impl TlsInterception {
+    pub fn new(config: &Config) -> Self {
+        let ca_files = match &config.tls_intercept_ca {
+            _ if !config.tls_intercept => None,
+            Some(files) => Some(files.clone()),
+            None => {
+                warn!("TLS interception disabled: TlsInterceptCa is not set");
+                None
+            }
+        };
+        let cache_dir = config.tls_intercept_cache_dir.clone();
+        let ca = ca_files.as_ref().and_then(|(cert, key)| {
+            CertificateAuthority::load_or_create(cert, key, cache_dir.as_deref())
+                .map_err(|e| warn!("TLS interception disabled: {}", e))
+                .ok()
+        });
+        Self {
+            ca_files,
+            cache_dir,
+            ca: RwLock::new(ca.map(Arc::new)),
+            exclude: config.no_tls_intercept.clone(),
+            configs: Mutex::new(LruCache::new(NonZeroUsize::new(MAX_CACHED).unwrap())),
+            rng: SystemRandom::new(),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.ca.read().unwrap().is_some()
+    }
+
+    /// Whether TlsIntercept is on with a TlsInterceptCa to load, even if
+    /// loading it failed.
+    pub fn is_configured(&self) -> bool {
+        self.ca_files.is_some()
+    }
+
+    /// Load the CA files again, dropping every certificate made with the
+    /// previous CA. The current CA stays if they cannot be loaded.
+    pub fn reload(&self) -> Result<(), String> {
+        let (cert, key) = self
+            .ca_files
+            .as_ref()
+            .ok_or("TLS interception is disabled")?;
+        let ca = CertificateAuthority::load_or_create(cert, key, self.cache_dir.as_deref())?;
+        let mut configs = self.configs.lock().unwrap();
+        *self.ca.write().unwrap() = Some(Arc::new(ca));
+        configs.clear();
+        info!("Loaded TLS interception CA {}", cert);
+        Ok(())
+    }
+
+    /// Reload the CA whenever the process gets SIGHUP.
+    #[cfg(unix)]
+    pub async fn reload_on_hangup(&self) {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut hangups = match signal(SignalKind::hangup()) {
+            Ok(hangups) => hangups,
+            Err(e) => {
+                warn!("Unable to listen for SIGHUP: {}", e);
+                return;
+            }
+        };
+        while hangups.recv().await.is_some() {
+            if let Err(e) = self.reload() {
+                warn!("Keeping the current TLS interception CA: {}", e);
+            }
+        }
+    }
+
+    /// Whether tunnels to `host` are decrypted.
+    pub fn intercepts(&self, host: &str) -> bool {
+        let host = host.to_lowercase();
+        self.is_enabled()
+            && !self
+                .exclude
+                .iter()
+                .any(|pattern| wildcard_match(pattern, &host))
+    }
+
+    /// Take over the TLS handshake a client starts in a tunnel to `host`,
+    /// presenting a certificate for the server name it asks for, or `host`
+    /// without one.
+    pub async fn accept(&self, stream: BoxedStream, host: &str) -> io::Result<BoxedStream> {
+        let start = LazyConfigAcceptor::new(Acceptor::default(), stream).await?;
+        let server_name = start
+            .client_hello()
+            .server_name()
+            .map_or_else(|| host.to_lowercase(), str::to_lowercase);
+        let config = self.server_config(&server_name).map_err(io::Error::other)?;
+        let stream = start.into_stream(config).await?;
+        Ok(Box::new(stream))
+    }
+
+    /// TLS settings presenting a certificate for `server_name`.
+    fn server_config(&self, server_name: &str) -> Result<Arc<ServerConfig>, String> {
+        if let Some(config) = self.configs.lock().unwrap().get(server_name) {
+            return Ok(config.clone());
+        }
+        let ca = self
+            .ca
+            .read()
+            .unwrap()
+            .clone()
+            .ok_or("TLS interception is disabled")?;
+
+        let path = self.saved_path(server_name);
+        let saved = path
+            .as_deref()
+            .and_then(|path| ca.load_saved(path))
+            .and_then(|cert| ca.server_config(cert).ok());
+        let config = match saved {
+            Some(config) => {
+                debug!("Loaded the certificate for {} from disk", server_name);
+                config
+            }
+            None => {
+                debug!("Making a certificate for {}", server_name);
+                let cert = ca.sign_server(server_name, &self.rng)?;
+                if let Some(path) = &path {
+                    if let Err(e) = ca.save(path, &cert) {
+                        warn!("Cannot save {}: {}", path.display(), e);
+                    }
+                }
+                ca.server_config(cert)?
+            }
+        };
+        let config = Arc::new(config);
+
+        // Unless the CA was reloaded meanwhile
+        let mut configs = self.configs.lock().unwrap();
+        if matches!(&*self.ca.read().unwrap(), Some(current) if Arc::ptr_eq(current, &ca)) {
+            configs.put(server_name.to_string(), config.clone());
+        }
+        Ok(config)
+    }
+
+    /// Where the certificate for `server_name` is saved, if anywhere.
+    fn saved_path(&self, server_name: &str) -> Option<PathBuf> {
+        let dir = self.cache_dir.as_ref()?;
+        let valid = !server_name.starts_with('.')
+            && server_name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || "-.:[]".contains(c));
+        valid.then(|| Path::new(dir).join(format!("{}.pem", server_name)))
+    }
+}
+
+impl CertificateAuthority {
+    /// The CA in `cert_path` and `key_path`, made and saved there if
+    /// neither file exists yet. The key for servers is kept in `cache_dir`
+    /// if given.
+    fn load_or_create(
+        cert_path: &str,
+        key_path: &str,
+        cache_dir: Option<&str>,
+    ) -> Result<Self, String> {
+        let (cert, key) = match (Path::new(cert_path).exists(), Path::new(key_path).exists()) {
+            (true, true) => {
+                let read = |path| {
+                    std::fs::read_to_string(path)
+                        .map_err(|e| format!("cannot read {}: {}", path, e))
+                };
+                let cert = pem_decode(&read(cert_path)?, "CERTIFICATE")
+                    .ok_or_else(|| format!("{} holds no PEM certificate", cert_path))?;
+                let key = KeyPair::from_pem(&read(key_path)?).map_err(|e| {
+                    format!(
+                        "{} holds no PKCS#8 private key (convert it with openssl pkcs8 -topk8 -nocrypt): {}",
+                        key_path, e
+                    )
+                })?;
+                CertifiedKey::from_der(
+                    vec![CertificateDer::from(cert.clone())],
+                    PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(key.serialize_der())),
+                    &default_provider(),
+                )
+                .map_err(|_| {
+                    format!("certificate {} does not match key {}", cert_path, key_path)
+                })?;
+                (cert, key)
+            }
+            (false, false) => {
+                let key = generate_key()?;
+                let mut params = CertificateParams::default();
+                params.distinguished_name.push(
+                    DnType::CommonName,
+                    format!("{} interception CA", env!("CARGO_PKG_NAME")),
+                );
+                params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+                params.key_usages = vec![KeyUsagePurpose::KeyCertSign, KeyUsagePurpose::CrlSign];
+                set_validity(&mut params, CA_DAYS);
+                let cert = params.self_signed(&key).map_err(|e| e.to_string())?;
+                write_private(key_path, &key.serialize_pem())
+                    .map_err(|e| format!("cannot write {}: {}", key_path, e))?;
+                std::fs::write(cert_path, cert.pem())
+                    .map_err(|e| format!("cannot write {}: {}", cert_path, e))?;
+                info!(
+                    "Created TLS interception CA {}; clients must trust it",
+                    cert_path
+                );
+                (cert.der().to_vec(), key)
+            }
+            _ => return Err(format!("only one of {} and {} exists", cert_path, key_path)),
+        };
+        let issuer = Issuer::from_ca_cert_der(&CertificateDer::from(cert.as_slice()), key)
+            .map_err(|e| format!("cannot parse certificate {}: {}", cert_path, e))?;
+
+        let leaf_key = match cache_dir {
+            Some(dir) => {
+                std::fs::create_dir_all(dir)
+                    .map_err(|e| format!("cannot create {}: {}", dir, e))?;
+                load_or_create_key(&Path::new(dir).join("leaf.key"))?
+            }
+            None => generate_key()?,
+        };
+
+        Ok(Self {
+            cert,
+            issuer,
+            leaf_key,
+        })
+    }
+
+    /// TLS settings presenting `cert`, signed by this CA.
+    fn server_config(&self, cert: Vec<u8>) -> Result<ServerConfig, String> {
+        let mut config = ServerConfig::builder_with_provider(Arc::new(default_provider()))
+            .with_safe_default_protocol_versions()
+            .map_err(|e| e.to_string())?
+            .with_no_client_auth()
+            .with_single_cert(
+                vec![
+                    CertificateDer::from(cert),
+                    CertificateDer::from(self.cert.clone()),
+                ],
+                PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(self.leaf_key.serialize_der())),
+            )
+            .map_err(|e| e.to_string())?;
+        // Requests inside are read as HTTP/1.1
+        config.alpn_protocols = vec![b"http/1.1".to_vec()];
+        Ok(config)
+    }
+
+    /// The server certificate saved in `path`, if this CA signed it and it
+    /// is recent enough.
+    fn load_saved(&self, path: &Path) -> Option<Vec<u8>> {
+        let age = std::fs::metadata(path)
+            .ok()?
+            .modified()
+            .ok()?
+            .elapsed()
+            .ok()?;
+        if age.as_secs() > SAVED_DAYS * 24 * 60 * 60 {
+            return None;
+        }
+        let text = std::fs::read_to_string(path).ok()?;
+        let end = "-----END CERTIFICATE-----";
+        let (cert, issuer) = text.split_once(end)?;
+        let cert = pem_decode(&format!("{}{}", cert, end), "CERTIFICATE")?;
+        (pem_decode(issuer, "CERTIFICATE")? == self.cert).then_some(cert)
+    }
+
+    /// Save the server certificate `cert` in `path`, followed by this CA's.
+    fn save(&self, path: &Path, cert: &[u8]) -> io::Result<()> {
+        let partial = path.with_extension("pem.partial");
+        std::fs::write(
+            &partial,
+            pem_encode(cert, "CERTIFICATE") + &pem_encode(&self.cert, "CERTIFICATE"),
+        )?;
+        std::fs::rename(partial, path)
+    }
+
+    /// A certificate for `server_name`, a host name or address.
+    fn sign_server(&self, server_name: &str, rng: &SystemRandom) -> Result<Vec<u8>, String> {
+        let alt_name = server_name.trim_matches(['[', ']']).to_string();
+        let mut params = CertificateParams::new(vec![alt_name]).map_err(|e| e.to_string())?;
+        params
+            .distinguished_name
+            .push(DnType::CommonName, server_name);
+        params.key_usages = vec![KeyUsagePurpose::DigitalSignature];
+        params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ServerAuth];
+        params.use_authority_key_identifier_extension = true;
+        params.serial_number = Some(random_serial(rng)?);
+        set_validity(&mut params, LEAF_DAYS);
+        let cert = params
+            .signed_by(&self.leaf_key, &self.issuer)
+            .map_err(|e| e.to_string())?;
+        Ok(cert.der().to_vec())
+    }
+}
+
+/// A new P-256 key.
+fn generate_key() -> Result<KeyPair, String> {
+    KeyPair::generate().map_err(|e| format!("cannot generate a key: {}", e))
+}
+
+/// The key in `path`, made and saved there if it does not exist yet.
+fn load_or_create_key(path: &Path) -> Result<KeyPair, String> {
+    if path.exists() {
+        let pem = std::fs::read_to_string(path)
+            .map_err(|e| format!("cannot read {}: {}", path.display(), e))?;
+        return KeyPair::from_pem(&pem)
+            .map_err(|e| format!("{} holds no PKCS#8 private key: {}", path.display(), e));
+    }
+    let key = generate_key()?;
+    write_private(&path.display().to_string(), &key.serialize_pem())
+        .map_err(|e| format!("cannot write {}: {}", path.display(), e))?;
+    Ok(key)
+}
+
+/// A random serial number, so that no two certificates the CA signs
+/// share one even though they share a key.
+fn random_serial(rng: &SystemRandom) -> Result<SerialNumber, String> {
+    let mut serial = [0u8; 16];
+    rng.fill(&mut serial).map_err(|_| "no random serial")?;
+    serial[0] &= 0x7f;
+    Ok(SerialNumber::from_slice(&serial))
+}
+
+/// Make `params` valid for `days`, from a day before now.
+fn set_validity(params: &mut CertificateParams, days: i64) {
+    let day =
+        |time: DateTime<Utc>| date_time_ymd(time.year(), time.month() as u8, time.day() as u8);
+    let now = Utc::now();
+    params.not_before = day(now - Duration::days(1));
+    params.not_after = day(now + Duration::days(days));
+}
+
+pub(crate) fn pem_decode(text: &str, label: &str) -> Option<Vec<u8>> {
+    let begin = format!("-----BEGIN {}-----", label);
+    let end = format!("-----END {}-----", label);
+    let start = text.find(&begin)? + begin.len();
+    let length = text[start..].find(&end)?;
+    let base64: String = text[start..start + length]
+        .chars()
+        .filter(|c| !c.is_whitespace())
+        .collect();
+    STANDARD.decode(base64).ok()
+}
+
+fn pem_encode(der: &[u8], label: &str) -> String {
+    let base64 = STANDARD.encode(der);
+    let mut pem = format!("-----BEGIN {}-----\n", label);
+    for line in base64.as_bytes().chunks(64) {
+        pem.push_str(&String::from_utf8_lossy(line));
+        pem.push('\n');
+    }
+    pem + &format!("-----END {}-----\n", label)
+}
+
+/// Write a file only its owner can read.
+fn write_private(path: &str, contents: &str) -> io::Result<()> {
+    use std::io::Write;
+
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options.open(path)?.write_all(contents.as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio_rustls::rustls::pki_types::ServerName;
+    use tokio_rustls::rustls::{ClientConfig, RootCertStore};
+
+    #[tokio::test]
+    async fn test_interception() {
+        let dir = tempfile::tempdir().unwrap();
+        let cert_path = dir.path().join("ca.pem");
+        let key_path = dir.path().join("ca.key");
+        let config = Config::parse_config(&format!(
+            "TlsIntercept Yes\nTlsInterceptCa \"{}\" \"{}\"\nNoTlsIntercept *.bank.example",
+            cert_path.display(),
+            key_path.display()
+        ))
+        .unwrap();
+
+        let interception = TlsInterception::new(&config);
+        assert!(interception.is_enabled());
+        assert!(interception.intercepts("Example.com"));
+        assert!(!interception.intercepts("www.bank.example"));
+
+        // Made on first use, the CA is loaded again later
+        let ca = std::fs::read_to_string(&cert_path).unwrap();
+        let reloaded = TlsInterception::new(&config);
+        assert_eq!(
+            reloaded.ca.read().unwrap().as_ref().unwrap().cert,
+            pem_decode(&ca, "CERTIFICATE").unwrap()
+        );
+        std::fs::remove_file(&key_path).unwrap();
+        assert!(!TlsInterception::new(&config).is_enabled());
+
+        // Clients trusting the CA accept the certificates made for servers
+        let mut roots = RootCertStore::empty();
+        roots
+            .add(CertificateDer::from(
+                pem_decode(&ca, "CERTIFICATE").unwrap(),
+            ))
+            .unwrap();
+        let client_config = ClientConfig::builder_with_provider(Arc::new(default_provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let connector = tokio_rustls::TlsConnector::from(Arc::new(client_config));
+
+        for (server_name, host) in [
+            ("www.example.com", "ignored.test"),
+            ("192.0.2.7", "192.0.2.7"),
+        ] {
+            let (client, proxy) = tokio::io::duplex(16384);
+            let server = async {
+                let mut stream = interception.accept(Box::new(proxy), host).await.unwrap();
+                let mut request = [0u8; 4];
+                stream.read_exact(&mut request).await.unwrap();
+                stream.write_all(b"pong").await.unwrap();
+                stream.flush().await.unwrap();
+                request
+            };
+            let name = ServerName::try_from(server_name.to_string()).unwrap();
+            let client = async {
+                let mut stream = connector.connect(name, client).await.unwrap();
+                stream.write_all(b"ping").await.unwrap();
+                let mut response = [0u8; 4];
+                stream.read_exact(&mut response).await.unwrap();
+                response
+            };
+            let (request, response) = tokio::join!(server, client);
+            assert_eq!((&request, &response), (b"ping", b"pong"));
+        }
+        assert_eq!(interception.configs.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_certificate_cache() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config::parse_config(&format!(
+            "TlsIntercept Yes\nTlsInterceptCa \"{0}/ca.pem\" \"{0}/ca.key\"",
+            dir.path().display()
+        ))
+        .unwrap();
+        let interception = TlsInterception::new(&config);
+        *interception.configs.lock().unwrap() = LruCache::new(NonZeroUsize::new(2).unwrap());
+
+        // The least recently used certificate is the one made again
+        let first = interception.server_config("a.example").unwrap();
+        interception.server_config("b.example").unwrap();
+        let again = interception.server_config("a.example").unwrap();
+        assert!(Arc::ptr_eq(&first, &again));
+        interception.server_config("c.example").unwrap();
+        let configs = interception.configs.lock().unwrap();
+        assert!(configs.contains("a.example"));
+        assert!(!configs.contains("b.example"));
+        assert_eq!(configs.len(), 2);
+    }
+
+    #[test]
+    fn test_reload() {
+        let dir = tempfile::tempdir().unwrap();
+        let cert_path = dir.path().join("ca.pem");
+        let key_path = dir.path().join("ca.key");
+        let config = Config::parse_config(&format!(
+            "TlsIntercept Yes\nTlsInterceptCa \"{}\" \"{}\"",
+            cert_path.display(),
+            key_path.display()
+        ))
+        .unwrap();
+        let interception = TlsInterception::new(&config);
+        let ca = || {
+            interception
+                .ca
+                .read()
+                .unwrap()
+                .as_ref()
+                .unwrap()
+                .cert
+                .clone()
+        };
+        let first = ca();
+        interception.server_config("a.example").unwrap();
+
+        // A broken CA is not taken
+        std::fs::write(&cert_path, "not a certificate").unwrap();
+        assert!(interception.reload().is_err());
+        assert_eq!(ca(), first);
+        assert_eq!(interception.configs.lock().unwrap().len(), 1);
+
+        // A new one replaces the certificates made with the old
+        std::fs::remove_file(&cert_path).unwrap();
+        std::fs::remove_file(&key_path).unwrap();
+        interception.reload().unwrap();
+        assert_ne!(ca(), first);
+        assert!(interception.configs.lock().unwrap().is_empty());
+        interception.server_config("a.example").unwrap();
+        assert_eq!(interception.configs.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_saved_certificates() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config::parse_config(&format!(
+            "TlsIntercept Yes\nTlsInterceptCa \"{0}/ca.pem\" \"{0}/ca.key\"\n\
+             TlsInterceptCacheDir \"{0}/leaves\"",
+            dir.path().display()
+        ))
+        .unwrap();
+        let saved = dir.path().join("leaves/a.example.pem");
+        let read = || std::fs::read_to_string(&saved).unwrap();
+
+        TlsInterception::new(&config)
+            .server_config("a.example")
+            .unwrap();
+        let first = read();
+        assert!(dir.path().join("leaves/leaf.key").exists());
+
+        // Another run presents the saved certificate
+        let interception = TlsInterception::new(&config);
+        interception.server_config("a.example").unwrap();
+        assert_eq!(read(), first);
+        assert!(interception.saved_path("../ca.pem").is_none());
+
+        // Certificates of another CA are made again
+        std::fs::remove_file(dir.path().join("ca.pem")).unwrap();
+        std::fs::remove_file(dir.path().join("ca.key")).unwrap();
+        interception.reload().unwrap();
+        interception.server_config("a.example").unwrap();
+        assert_ne!(read(), first);
+    }
+}
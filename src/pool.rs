@@ -0,0 +1,174 @@
+use crate::config::Config;
+use crate::connector::BoxedStream;
+use futures::task::noop_waker_ref;
+use log::debug;
+use std::collections::HashMap;
+use std::pin::Pin;
+use std::sync::Mutex;
+use std::task::Context;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, ReadBuf};
+
+/// Origin connections left open after a complete exchange, kept for the
+/// next request to the same origin (ConnectionPoolSize). Connections idle
+/// longer than PoolIdleTimeout are closed, and the oldest one makes room
+/// when the pool is full.
+pub struct ConnectionPool {
+    max_idle: usize,
+    idle_timeout: Duration,
+    idle: Mutex<HashMap<String, Vec<IdleConnection>>>,
+}
+
+struct IdleConnection {
+    stream: BoxedStream,
+    since: Instant,
+}
+
+impl ConnectionPool {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            max_idle: config.connection_pool_size,
+            idle_timeout: Duration::from_secs(config.pool_idle_timeout),
+            idle: Mutex::default(),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.max_idle > 0 && !self.idle_timeout.is_zero()
+    }
+
+    /// The most recently used idle connection to `origin` that still looks
+    /// open.
+    pub fn checkout(&self, origin: &str) -> Option<BoxedStream> {
+        let mut idle = self.idle.lock().unwrap();
+        self.expire(&mut idle);
+        let connections = idle.get_mut(origin)?;
+        let mut found = None;
+        while let Some(mut connection) = connections.pop() {
+            if is_quiet(&mut connection.stream) {
+                found = Some(connection.stream);
+                break;
+            }
+            debug!(
+                "Dropping pooled connection to {}: closed by the origin",
+                origin
+            );
+        }
+        if connections.is_empty() {
+            idle.remove(origin);
+        }
+        found
+    }
+
+    /// Keep `stream`, done with its last exchange, for later requests to
+    /// `origin`.
+    pub fn checkin(&self, origin: &str, stream: BoxedStream) {
+        if !self.is_enabled() {
+            return;
+        }
+        let mut idle = self.idle.lock().unwrap();
+        self.expire(&mut idle);
+        if idle.values().map(Vec::len).sum::<usize>() >= self.max_idle {
+            let oldest = idle
+                .iter()
+                .filter_map(|(origin, connections)| Some((connections.first()?.since, origin)))
+                .min()
+                .map(|(_, origin)| origin.clone());
+            if let Some(oldest) = oldest {
+                let connections = idle.get_mut(&oldest).unwrap();
+                connections.remove(0);
+                if connections.is_empty() {
+                    idle.remove(&oldest);
+                }
+            }
+        }
+        idle.entry(origin.to_string())
+            .or_default()
+            .push(IdleConnection {
+                stream,
+                since: Instant::now(),
+            });
+    }
+
+    /// Number of idle connections.
+    pub fn idle_count(&self) -> usize {
+        self.idle.lock().unwrap().values().map(Vec::len).sum()
+    }
+
+    /// Close connections idle for longer than the timeout.
+    fn expire(&self, idle: &mut HashMap<String, Vec<IdleConnection>>) {
+        let now = Instant::now();
+        idle.retain(|_, connections| {
+            connections.retain(|connection| now - connection.since < self.idle_timeout);
+            !connections.is_empty()
+        });
+    }
+}
+
+/// Whether an idle connection has nothing to read. Anything else, be it
+/// the origin closing it, an error or unexpected data, makes it unusable.
+fn is_quiet(stream: &mut BoxedStream) -> bool {
+    let mut byte = [0u8; 1];
+    let mut buf = ReadBuf::new(&mut byte);
+    let mut cx = Context::from_waker(noop_waker_ref());
+    Pin::new(stream).poll_read(&mut cx, &mut buf).is_pending()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn test_connection_pool() {
+        let config = Config::parse_config("ConnectionPoolSize 2").unwrap();
+        let pool = ConnectionPool::new(&config);
+        assert!(pool.is_enabled());
+        assert!(pool.checkout("http://a.test:80").is_none());
+
+        // Connections are handed out again, most recent first
+        let (first, mut first_origin) = duplex(64);
+        let (second, _second_origin) = duplex(64);
+        pool.checkin("http://a.test:80", Box::new(first));
+        pool.checkin("http://a.test:80", Box::new(second));
+        let mut stream = pool.checkout("http://a.test:80").unwrap();
+        stream.write_all(b"second").await.unwrap();
+        assert!(pool.checkout("http://b.test:80").is_none());
+        assert_eq!(pool.idle_count(), 1);
+
+        // Connections the origin closed or wrote to are dropped
+        first_origin.write_all(b"late").await.unwrap();
+        assert!(pool.checkout("http://a.test:80").is_none());
+        let (closed, closed_origin) = duplex(64);
+        drop(closed_origin);
+        pool.checkin("http://a.test:80", Box::new(closed));
+        assert!(pool.checkout("http://a.test:80").is_none());
+
+        // The oldest connection makes room when the pool is full
+        for origin in ["http://a.test:80", "http://b.test:80", "http://c.test:80"] {
+            let (stream, mut peer) = duplex(64);
+            tokio::spawn(async move { peer.read(&mut [0u8; 1]).await });
+            pool.checkin(origin, Box::new(stream));
+        }
+        assert_eq!(pool.idle_count(), 2);
+        assert!(pool.checkout("http://a.test:80").is_none());
+        assert!(pool.checkout("http://c.test:80").is_some());
+    }
+
+    #[tokio::test]
+    async fn test_idle_timeout() {
+        let config = Config::parse_config("PoolIdleTimeout 1").unwrap();
+        let pool = ConnectionPool::new(&config);
+        let (stream, _origin) = duplex(64);
+        pool.checkin("http://a.test:80", Box::new(stream));
+
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+        assert!(pool.checkout("http://a.test:80").is_none());
+        assert_eq!(pool.idle_count(), 0);
+
+        let disabled = ConnectionPool::new(&Config::parse_config("ConnectionPoolSize 0").unwrap());
+        let (stream, _origin) = duplex(64);
+        disabled.checkin("http://a.test:80", Box::new(stream));
+        assert_eq!(disabled.idle_count(), 0);
+    }
+}
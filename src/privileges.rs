@@ -0,0 +1,89 @@
+use crate::config::Config;
+use anyhow::{Context, Result};
+use log::{info, warn};
+use nix::unistd::{self, Gid, Group, Uid, User};
+
+/// Switch to the User and Group from the config once the listeners are
+/// bound. This only happens when running as root; if the switch cannot be
+/// completed the proxy must not keep running as root, so it fails.
+pub fn drop_privileges(config: &Config) -> Result<()> {
+    if !unistd::geteuid().is_root() {
+        if config.user.is_some() || config.group.is_some() {
+            info!("Not running as root, keeping the current user and group");
+        }
+        return Ok(());
+    }
+    let user = match &config.user {
+        Some(user) => resolve_user(user)?,
+        None => {
+            if config.group.is_some() {
+                return Err(anyhow::anyhow!("Group is set without User"));
+            }
+            warn!("Running as root: set User and Group to drop privileges");
+            return Ok(());
+        }
+    };
+    let gid = match &config.group {
+        Some(group) => resolve_group(group)?,
+        None => user.gid,
+    };
+
+    // Supplementary groups first, while still allowed to change them
+    #[cfg(not(any(target_os = "macos", target_os = "ios")))]
+    unistd::setgroups(&[gid]).context("Failed to drop supplementary groups")?;
+    unistd::setgid(gid).with_context(|| format!("Failed to switch to group {}", gid))?;
+    unistd::setuid(user.uid).with_context(|| format!("Failed to switch to user {}", user.name))?;
+
+    // Root must not be recoverable
+    if !user.uid.is_root() && unistd::setuid(Uid::from_raw(0)).is_ok() {
+        return Err(anyhow::anyhow!(
+            "Privileges could be regained after dropping them"
+        ));
+    }
+    info!(
+        "Running as user {} ({}), group {}",
+        user.name, user.uid, gid
+    );
+    Ok(())
+}
+
+/// The account named `user`, or with that numeric UID.
+fn resolve_user(user: &str) -> Result<User> {
+    let found = match user.parse::<u32>() {
+        Ok(uid) => User::from_uid(Uid::from_raw(uid)),
+        Err(_) => User::from_name(user),
+    };
+    found
+        .with_context(|| format!("Failed to look up user {}", user))?
+        .ok_or_else(|| anyhow::anyhow!("Unknown user: {}", user))
+}
+
+/// The GID of the group named `group`, or the number itself.
+fn resolve_group(group: &str) -> Result<Gid> {
+    if let Ok(gid) = group.parse::<u32>() {
+        return Ok(Gid::from_raw(gid));
+    }
+    Group::from_name(group)
+        .with_context(|| format!("Failed to look up group {}", group))?
+        .map(|group| group.gid)
+        .ok_or_else(|| anyhow::anyhow!("Unknown group: {}", group))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve() {
+        assert!(resolve_user("root").unwrap().uid.is_root());
+        assert_eq!(resolve_user("0").unwrap().name, "root");
+        assert!(resolve_user("no-such-user-here")
+            .unwrap_err()
+            .to_string()
+            .contains("Unknown user"));
+
+        assert_eq!(resolve_group("4242").unwrap(), Gid::from_raw(4242));
+        assert_eq!(resolve_group("root").unwrap(), Gid::from_raw(0));
+        assert!(resolve_group("no-such-group-here").is_err());
+    }
+}
@@ -1,20 +1,167 @@
+use crate::acl::IpList;
 use crate::config::Config;
 use crate::error::ProxyResult;
+use crate::headers::Headers;
+use log::{debug, warn};
+use regex::Regex;
+use std::net::IpAddr;
+
+/// Headers Anonymous always lets through, as the request cannot be
+/// delivered, its body framed or its WebSocket handshake completed
+/// without them.
+const MESSAGE_HEADERS: &[&str] = &[
+    "Host",
+    "Content-Length",
+    "Transfer-Encoding",
+    "Connection",
+    "Upgrade",
+    "Sec-WebSocket-Key",
+    "Sec-WebSocket-Version",
+    "Sec-WebSocket-Protocol",
+    "Sec-WebSocket-Extensions",
+];
 
 pub struct ProxyLogic {
     config: std::sync::Arc<Config>,
+    header_rewrites: Vec<HeaderRewriteRule>,
+    url_rewrites: Vec<(Regex, String)>,
+    redirects: Vec<RedirectRule>,
+    /// Name the proxy identifies itself with in Via headers.
+    via_name: String,
+    no_upstream: NoUpstream,
+    /// Client networks of each Upstream line, `None` when it serves all.
+    upstream_clients: Vec<Option<IpList>>,
+}
+
+/// Destinations always reached directly (NoUpstream), with the semantics
+/// of `no_proxy`: a domain also covers its subdomains, a leading dot is
+/// optional, addresses and networks match literal IP hosts and `*`
+/// matches everything.
+struct NoUpstream {
+    all: bool,
+    domains: Vec<String>,
+    networks: IpList,
+}
+
+impl NoUpstream {
+    fn new(entries: &[String]) -> Self {
+        let (networks, domains): (Vec<String>, Vec<String>) = entries
+            .iter()
+            .filter(|entry| *entry != "*")
+            .cloned()
+            .partition(|entry| {
+                let address = entry.split_once('/').map_or(entry.as_str(), |(ip, _)| ip);
+                address.trim_matches(['[', ']']).parse::<IpAddr>().is_ok()
+            });
+
+        Self {
+            all: entries.iter().any(|entry| entry == "*"),
+            domains: domains
+                .into_iter()
+                .map(|domain| domain.trim_start_matches('.').to_string())
+                .collect(),
+            networks: IpList::new(&networks, "NoUpstream"),
+        }
+    }
+
+    fn matches(&self, host: &str) -> bool {
+        if self.all {
+            return true;
+        }
+        let host = host.trim_matches(['[', ']']).trim_end_matches('.');
+        if let Ok(ip) = host.parse::<IpAddr>() {
+            return self.networks.contains(&ip);
+        }
+
+        let host = host.to_lowercase();
+        self.domains.iter().any(|domain| {
+            host.strip_suffix(domain.as_str())
+                .is_some_and(|rest| rest.is_empty() || rest.ends_with('.'))
+        })
+    }
+}
+
+struct RedirectRule {
+    status: u16,
+    pattern: Regex,
+    location: String,
+}
+
+struct HeaderRewriteRule {
+    header: String,
+    pattern: Regex,
+    replacement: Option<String>,
 }
 
 impl ProxyLogic {
     pub fn new(config: std::sync::Arc<Config>) -> Self {
-        Self { config }
+        let mut header_rewrites = Vec::new();
+
+        for rule in &config.header_rewrites {
+            match Regex::new(&rule.pattern) {
+                Ok(pattern) => header_rewrites.push(HeaderRewriteRule {
+                    header: rule.header.to_lowercase(),
+                    pattern,
+                    replacement: rule.replacement.clone(),
+                }),
+                Err(e) => warn!("Invalid header rewrite pattern {}: {}", rule.pattern, e),
+            }
+        }
+
+        let mut url_rewrites = Vec::new();
+
+        for rule in &config.url_rewrites {
+            match Regex::new(&rule.pattern) {
+                Ok(pattern) => url_rewrites.push((pattern, rule.replacement.clone())),
+                Err(e) => warn!("Invalid URL rewrite pattern {}: {}", rule.pattern, e),
+            }
+        }
+
+        let mut redirects = Vec::new();
+
+        for rule in &config.redirects {
+            match Regex::new(&rule.pattern) {
+                Ok(pattern) => redirects.push(RedirectRule {
+                    status: rule.status,
+                    pattern,
+                    location: rule.location.clone(),
+                }),
+                Err(e) => warn!("Invalid redirect pattern {}: {}", rule.pattern, e),
+            }
+        }
+
+        let via_name = config
+            .via_proxy_name
+            .clone()
+            .or_else(hostname)
+            .unwrap_or_else(|| "tinyproxy-rust".to_string());
+
+        let no_upstream = NoUpstream::new(&config.no_upstream);
+        let upstream_clients = config
+            .upstream
+            .iter()
+            .map(|upstream| {
+                (!upstream.clients.is_empty())
+                    .then(|| IpList::new(&upstream.clients, "Upstream client"))
+            })
+            .collect();
+
+        Self {
+            config,
+            header_rewrites,
+            url_rewrites,
+            redirects,
+            via_name,
+            no_upstream,
+            upstream_clients,
+        }
     }
 
     pub async fn handle_http_proxy(
         &self,
         _method: &str,
         _uri: &str,
-        _headers: &std::collections::HashMap<String, String>,
+        _headers: &Headers,
     ) -> ProxyResult<()> {
         // Basic HTTP proxy logic - this is a placeholder for now
         // In a full implementation, this would handle:
@@ -28,58 +175,568 @@ impl ProxyLogic {
         Ok(())
     }
 
-    pub fn should_use_upstream(&self, host: &str) -> Option<&crate::config::UpstreamConfig> {
-        // Check if we should use an upstream proxy for this host
-        for upstream in &self.config.upstream {
-            if let Some(domain) = &upstream.domain {
-                if host.ends_with(domain) {
-                    return Some(upstream);
-                }
-            }
+    /// Upstream proxy for requests from `client` to `host`, if any. As in
+    /// tinyproxy, an Upstream line with a domain serves only that host (or,
+    /// with a leading dot, the domain and its subdomains) and is preferred;
+    /// other hosts use the first line without a domain. Upstream lines
+    /// limited to client networks are skipped for other clients.
+    pub fn should_use_upstream(
+        &self,
+        host: &str,
+        client: IpAddr,
+    ) -> Option<&crate::config::UpstreamConfig> {
+        if self.no_upstream.matches(host) {
+            debug!("Connecting to {} directly (NoUpstream)", host);
+            return None;
         }
 
-        // If no specific upstream is configured, use the first one if available
-        self.config.upstream.first()
+        let host = host.to_lowercase();
+        let candidates = || {
+            self.config
+                .upstream
+                .iter()
+                .zip(&self.upstream_clients)
+                .filter(|(_, clients)| {
+                    clients
+                        .as_ref()
+                        .is_none_or(|clients| clients.contains(&client))
+                })
+                .map(|(upstream, _)| upstream)
+        };
+
+        candidates()
+            .find(|upstream| {
+                upstream.domain.as_deref().is_some_and(|domain| {
+                    let domain = domain.to_lowercase();
+                    match domain.strip_prefix('.') {
+                        Some(parent) => host == parent || host.ends_with(&domain),
+                        None => host == domain,
+                    }
+                })
+            })
+            .or_else(|| candidates().find(|upstream| upstream.domain.is_none()))
     }
 
-    pub fn get_reverse_proxy_target(&self, path: &str) -> Option<&str> {
-        // Check reverse proxy rules
-        for rule in &self.config.reverse_proxy {
-            if path.starts_with(&rule.path) {
-                return Some(&rule.url);
-            }
-        }
-        None
+    /// Rewrite a request target using the first matching URLRewrite rule.
+    /// Returns `None` when no rule matches.
+    pub fn rewrite_url(&self, uri: &str) -> Option<String> {
+        self.url_rewrites
+            .iter()
+            .find(|(pattern, _)| pattern.is_match(uri))
+            .map(|(pattern, replacement)| pattern.replace(uri, replacement.as_str()).into_owned())
+    }
+
+    /// Look up a Redirect rule for the request target, returning the status
+    /// code and the expanded Location the proxy should answer with.
+    pub fn redirect_for(&self, uri: &str) -> Option<(u16, String)> {
+        self.redirects.iter().find_map(|rule| {
+            let captures = rule.pattern.captures(uri)?;
+            let mut location = String::new();
+            captures.expand(&rule.location, &mut location);
+            Some((rule.status, location))
+        })
     }
 
+    /// Prepare the headers of a request received with HTTP `version` for
+    /// forwarding: HeaderRewrite rules, then Anonymous, then the headers
+    /// this proxy adds (Via, X-Tinyproxy and AddHeader).
     pub fn process_headers(
         &self,
-        headers: &mut std::collections::HashMap<String, String>,
+        headers: &mut Headers,
+        version: &str,
         client_ip: &std::net::IpAddr,
     ) {
-        // Remove anonymous headers
-        for header in &self.config.anonymous {
-            headers.remove(&header.to_lowercase());
+        self.rewrite_headers(headers);
+
+        // With Anonymous, only the listed headers and those framing the
+        // message are passed on
+        if !self.config.anonymous.is_empty() {
+            headers.retain_mut(|name, _| {
+                let allowed = |header: &str| header.eq_ignore_ascii_case(name);
+                MESSAGE_HEADERS.iter().any(|header| allowed(header))
+                    || self.config.anonymous.iter().any(|header| allowed(header))
+            });
         }
 
-        // Add Via header if not disabled
-        if !self.config.disable_via_header {
-            let via_value = if let Some(proxy_name) = &self.config.via_proxy_name {
-                format!("1.1 {}", proxy_name)
-            } else {
-                "1.1 tinyproxy-rust".to_string()
-            };
-            headers.insert("via".to_string(), via_value);
+        self.add_via(headers, version);
+
+        self.add_x_tinyproxy(headers, client_ip);
+
+        // Add custom headers
+        for (name, value) in &self.config.add_headers {
+            headers.insert(name.as_str(), value.as_str());
+        }
+    }
+
+    /// Record this proxy in the Via header of a message received with HTTP
+    /// `version`, after the intermediaries already listed (RFC 9110
+    /// section 7.6.3). Does nothing if DisableViaHeader is set.
+    pub fn add_via(&self, headers: &mut Headers, version: &str) {
+        if self.config.disable_via_header {
+            return;
         }
 
-        // Add X-Tinyproxy header if enabled
+        let entry = format!("{} {}", version, self.via_name);
+        let via = match headers.get_combined("via") {
+            Some(existing) => format!("{}, {}", existing, entry),
+            None => entry,
+        };
+        headers.insert("Via", via);
+    }
+
+    /// Tell the origin the client's address in an X-Tinyproxy header if
+    /// XTinyproxy is enabled, replacing any value the client sent.
+    pub fn add_x_tinyproxy(&self, headers: &mut Headers, client_ip: &std::net::IpAddr) {
         if self.config.x_tinyproxy {
-            headers.insert("x-tinyproxy".to_string(), client_ip.to_string());
+            headers.insert("X-Tinyproxy", client_ip.to_string());
         }
+    }
 
-        // Add custom headers
-        for (name, value) in &self.config.add_headers {
-            headers.insert(name.to_lowercase(), value.clone());
+    /// Tell the origin about the client in the configured ForwardedHeaders.
+    /// `forwarded_by` is the trusted proxy the request came through: its
+    /// forwarding headers are kept, the chains extended with its address.
+    /// Those sent by anyone else are replaced.
+    pub fn add_forwarded(
+        &self,
+        headers: &mut Headers,
+        uri: &str,
+        client_ip: &IpAddr,
+        forwarded_by: Option<&IpAddr>,
+    ) {
+        let enabled = &self.config.forwarded_headers;
+        let trusted = forwarded_by.is_some();
+        let node = forwarded_by.unwrap_or(client_ip);
+        let proto = if uri.starts_with("https://") {
+            "https"
+        } else {
+            "http"
+        };
+        let host = headers.get("host").map(str::to_string).or_else(|| {
+            let authority = uri.split_once("://")?.1;
+            Some(authority.split(['/', '?']).next()?.to_string())
+        });
+
+        let mut extend = |name: &str, entry: String| {
+            let value = match headers.get_combined(name).filter(|_| trusted) {
+                Some(existing) => format!("{}, {}", existing, entry),
+                None => entry,
+            };
+            headers.insert(name, value);
+        };
+        if enabled.x_forwarded_for {
+            extend("X-Forwarded-For", node.to_string());
+        }
+        if enabled.forwarded {
+            let node = match node {
+                IpAddr::V4(ip) => ip.to_string(),
+                IpAddr::V6(ip) => format!("\"[{}]\"", ip),
+            };
+            let mut element = format!("for={};proto={}", node, proto);
+            if let Some(host) = &host {
+                element.push_str(";host=");
+                element.push_str(&forwarded_value(host));
+            }
+            extend("Forwarded", element);
+        }
+
+        let mut set = |name: &str, value: &str| {
+            if !(trusted && headers.contains_key(name)) {
+                headers.insert(name, value);
+            }
+        };
+        if enabled.x_forwarded_proto {
+            set("X-Forwarded-Proto", proto);
+        }
+        if let Some(host) = host.filter(|_| enabled.x_forwarded_host) {
+            set("X-Forwarded-Host", &host);
         }
     }
+
+    /// Apply the configured HeaderRewrite rules: matching values are
+    /// rewritten with the rule's replacement (capture groups allowed), or
+    /// the header is dropped when the rule has no replacement.
+    pub fn rewrite_headers(&self, headers: &mut Headers) {
+        for rule in &self.header_rewrites {
+            // Repeated fields are rewritten one by one
+            headers.retain_mut(|name, value| {
+                if !name.eq_ignore_ascii_case(&rule.header) || !rule.pattern.is_match(value) {
+                    return true;
+                }
+
+                match &rule.replacement {
+                    Some(replacement) => {
+                        let rewritten = rule
+                            .pattern
+                            .replace_all(value, replacement.as_str())
+                            .into_owned();
+                        debug!("Rewriting header {}: {} -> {}", name, value, rewritten);
+                        *value = rewritten;
+                        true
+                    }
+                    None => {
+                        debug!("Removing header {} by rewrite rule", name);
+                        false
+                    }
+                }
+            });
+        }
+    }
+}
+
+/// This machine's host name.
+fn hostname() -> Option<String> {
+    let mut buf = [0u8; 256];
+    // SAFETY: the buffer is valid for its length, which gethostname bounds
+    let result = unsafe { libc::gethostname(buf.as_mut_ptr().cast(), buf.len()) };
+    if result != 0 {
+        return None;
+    }
+    let len = buf.iter().position(|&byte| byte == 0).unwrap_or(buf.len());
+    let name = String::from_utf8_lossy(&buf[..len]).into_owned();
+    (!name.is_empty()).then_some(name)
+}
+
+/// A Forwarded parameter value: a token, or else a quoted string.
+fn forwarded_value(value: &str) -> String {
+    let is_token = !value.is_empty()
+        && value
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "!#$%&'*+-.^_`|~".contains(c));
+    if is_token {
+        value.to_string()
+    } else {
+        format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_header_rewrite() {
+        let config = Config::parse_config(
+            r#"
+HeaderRewrite User-Agent "^curl/(.*)$" "Mozilla/5.0 (curl $1)"
+HeaderRewrite X-Internal-Token ".*"
+HeaderRewrite Cookie "tracking=[^;]*;?\s*" ""
+"#,
+        )
+        .unwrap();
+        let proxy = ProxyLogic::new(Arc::new(config));
+
+        let mut headers: Headers = [
+            ("User-Agent", "curl/8.0"),
+            ("X-Internal-Token", "secret"),
+            ("Cookie", "tracking=abc; session=1"),
+            ("Host", "example.com"),
+        ]
+        .into_iter()
+        .collect();
+
+        proxy.rewrite_headers(&mut headers);
+
+        assert_eq!(
+            headers.to_lines(),
+            "User-Agent: Mozilla/5.0 (curl 8.0)\r\nCookie: session=1\r\nHost: example.com\r\n"
+        );
+    }
+
+    #[test]
+    fn test_no_upstream() {
+        let config = Config::parse_config(
+            "Upstream http:parent.test:3128\n\
+             NoUpstream localhost, .internal.example 10.0.0.0/8\n\
+             NoUpstream ::1 Intranet",
+        )
+        .unwrap();
+        let proxy = ProxyLogic::new(Arc::new(config));
+        let client: IpAddr = "192.0.2.7".parse().unwrap();
+        let direct = |host| proxy.should_use_upstream(host, client).is_none();
+
+        assert!(direct("localhost"));
+        assert!(direct("internal.example"));
+        assert!(direct("wiki.Internal.example"));
+        assert!(!direct("notinternal.example"));
+        assert!(direct("10.1.2.3"));
+        assert!(!direct("11.1.2.3"));
+        assert!(direct("[::1]"));
+        assert!(direct("intranet"));
+        assert!(!direct("example.com"));
+
+        let config = Config::parse_config("Upstream http:parent.test:3128\nNoUpstream *").unwrap();
+        let proxy = ProxyLogic::new(Arc::new(config));
+        assert!(proxy.should_use_upstream("example.com", client).is_none());
+    }
+
+    #[test]
+    fn test_upstream_by_client() {
+        let config = Config::parse_config(
+            "Upstream http:branch.test:3128 client=10.1.0.0/16 client=fd00::/8\n\
+             Upstream http:partner.test:3128 .partner.example client=10.2.0.0/16",
+        )
+        .unwrap();
+        let proxy = ProxyLogic::new(Arc::new(config));
+        let upstream = |host, client: &str| {
+            proxy
+                .should_use_upstream(host, client.parse().unwrap())
+                .map(|upstream| upstream.host.as_str())
+        };
+
+        assert_eq!(upstream("example.com", "10.1.2.3"), Some("branch.test"));
+        assert_eq!(upstream("example.com", "fd00::7"), Some("branch.test"));
+        assert_eq!(
+            upstream("www.partner.example", "10.2.0.1"),
+            Some("partner.test")
+        );
+        // Everyone else connects directly
+        assert_eq!(upstream("example.com", "192.0.2.7"), None);
+        assert_eq!(upstream("www.partner.example", "192.0.2.7"), None);
+    }
+
+    #[test]
+    fn test_upstream_by_domain() {
+        let config = Config::parse_config(
+            "Upstream http:intranet.test:3128 .corp.example\n\
+             Upstream http:default.test:3128\n\
+             Upstream http:partner.test:3128 partner.example",
+        )
+        .unwrap();
+        let proxy = ProxyLogic::new(Arc::new(config));
+        let client: IpAddr = "192.0.2.7".parse().unwrap();
+        let upstream = |host| {
+            proxy
+                .should_use_upstream(host, client)
+                .map(|upstream| upstream.host.as_str())
+        };
+
+        assert_eq!(upstream("wiki.Corp.example"), Some("intranet.test"));
+        assert_eq!(upstream("corp.example"), Some("intranet.test"));
+        assert_eq!(upstream("partner.example"), Some("partner.test"));
+        // Without a leading dot only the host itself matches
+        assert_eq!(upstream("www.partner.example"), Some("default.test"));
+        assert_eq!(upstream("notcorp.example"), Some("default.test"));
+
+        // Lines with a domain never serve other hosts
+        let config =
+            Config::parse_config("Upstream http:intranet.test:3128 .corp.example").unwrap();
+        let proxy = ProxyLogic::new(Arc::new(config));
+        assert!(proxy.should_use_upstream("example.com", client).is_none());
+    }
+
+    #[test]
+    fn test_header_rewrite_no_match() {
+        let config =
+            Config::parse_config(r#"HeaderRewrite User-Agent "^curl/" "Mozilla/5.0""#).unwrap();
+        let proxy = ProxyLogic::new(Arc::new(config));
+
+        let mut headers: Headers = [("User-Agent", "Wget/1.21")].into_iter().collect();
+
+        proxy.rewrite_headers(&mut headers);
+
+        assert_eq!(headers.get("user-agent"), Some("Wget/1.21"));
+    }
+
+    #[test]
+    fn test_header_rewrite_repeated_fields() {
+        let config = Config::parse_config(
+            r#"
+HeaderRewrite Cookie "^tracking=.*$"
+HeaderRewrite Via "^1\.0 (.*)$" "1.1 $1"
+"#,
+        )
+        .unwrap();
+        let proxy = ProxyLogic::new(Arc::new(config));
+
+        let mut headers: Headers = [
+            ("Cookie", "session=1"),
+            ("Via", "1.0 gateway"),
+            ("Cookie", "tracking=abc"),
+            ("Via", "1.1 cache"),
+        ]
+        .into_iter()
+        .collect();
+
+        proxy.rewrite_headers(&mut headers);
+
+        assert_eq!(
+            headers.to_lines(),
+            "Cookie: session=1\r\nVia: 1.1 gateway\r\nVia: 1.1 cache\r\n"
+        );
+    }
+
+    #[test]
+    fn test_via() {
+        let config = Config::parse_config("ViaProxyName \"edge-1\"").unwrap();
+        let proxy = ProxyLogic::new(Arc::new(config));
+
+        let mut headers = Headers::new();
+        proxy.add_via(&mut headers, "1.0");
+        assert_eq!(headers.get("via"), Some("1.0 edge-1"));
+
+        let mut headers: Headers = [
+            ("Via", "1.1 cache"),
+            ("Host", "example.com"),
+            ("via", "2 cdn"),
+        ]
+        .into_iter()
+        .collect();
+        proxy.add_via(&mut headers, "1.1");
+        assert_eq!(
+            headers.to_lines(),
+            "Via: 1.1 cache, 2 cdn, 1.1 edge-1\r\nHost: example.com\r\n"
+        );
+
+        let config = Config::parse_config("DisableViaHeader Yes").unwrap();
+        let proxy = ProxyLogic::new(Arc::new(config));
+        let mut headers = Headers::new();
+        proxy.add_via(&mut headers, "1.1");
+        assert!(headers.is_empty());
+    }
+
+    #[test]
+    fn test_process_headers() {
+        let config = Config::parse_config(
+            "Anonymous \"Host\" \"Cookie\"\n\
+             Anonymous Accept\n\
+             XTinyproxy Yes\n\
+             ViaProxyName edge\n\
+             AddHeader \"X-Proxy-Pool\" \"blue green\"",
+        )
+        .unwrap();
+        let proxy = ProxyLogic::new(Arc::new(config));
+
+        let mut headers: Headers = [
+            ("Host", "example.com"),
+            ("User-Agent", "curl/8.5.0"),
+            ("cookie", "session=1"),
+            ("Content-Length", "5"),
+            ("Referer", "http://example.org/"),
+            ("X-Proxy-Pool", "client"),
+            ("Sec-WebSocket-Key", "dGhlIHNhbXBsZSBub25jZQ=="),
+        ]
+        .into_iter()
+        .collect();
+        proxy.process_headers(&mut headers, "1.0", &"192.0.2.7".parse().unwrap());
+        assert_eq!(
+            headers.to_lines(),
+            "Host: example.com\r\ncookie: session=1\r\nContent-Length: 5\r\n\
+             Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nVia: 1.0 edge\r\nX-Tinyproxy: 192.0.2.7\r\nX-Proxy-Pool: blue green\r\n"
+        );
+
+        assert!(Config::parse_config("AddHeader X-Only-Name").is_err());
+    }
+
+    #[test]
+    fn test_forwarded_headers() {
+        let config = Config::parse_config("ForwardedHeaders all").unwrap();
+        let proxy = ProxyLogic::new(Arc::new(config));
+        let client: IpAddr = "192.0.2.7".parse().unwrap();
+        let balancer: IpAddr = "10.0.0.2".parse().unwrap();
+        let spoofed = || -> Headers {
+            [
+                ("Host", "example.com:8080"),
+                ("X-Forwarded-For", "203.0.113.9"),
+                ("X-Forwarded-Proto", "https"),
+                ("Forwarded", "for=203.0.113.9"),
+            ]
+            .into_iter()
+            .collect()
+        };
+
+        // Values sent by a client are replaced
+        let mut headers = spoofed();
+        proxy.add_forwarded(&mut headers, "http://example.com:8080/", &client, None);
+        assert_eq!(headers.get("x-forwarded-for"), Some("192.0.2.7"));
+        assert_eq!(headers.get("x-forwarded-proto"), Some("http"));
+        assert_eq!(headers.get("x-forwarded-host"), Some("example.com:8080"));
+        assert_eq!(
+            headers.get("forwarded"),
+            Some("for=192.0.2.7;proto=http;host=\"example.com:8080\"")
+        );
+
+        // Those of a trusted proxy are extended with its address
+        let mut headers = spoofed();
+        let forwarded_by = Some(&balancer);
+        proxy.add_forwarded(&mut headers, "/", &client, forwarded_by);
+        assert_eq!(
+            headers.get("x-forwarded-for"),
+            Some("203.0.113.9, 10.0.0.2")
+        );
+        assert_eq!(headers.get("x-forwarded-proto"), Some("https"));
+        assert_eq!(
+            headers.get("forwarded"),
+            Some("for=203.0.113.9, for=10.0.0.2;proto=http;host=\"example.com:8080\"")
+        );
+
+        let v6: IpAddr = "2001:db8::1".parse().unwrap();
+        let mut headers = Headers::new();
+        proxy.add_forwarded(&mut headers, "https://example.com/", &v6, None);
+        assert_eq!(
+            headers.get("forwarded"),
+            Some("for=\"[2001:db8::1]\";proto=https;host=example.com")
+        );
+
+        // Nothing is added unless configured
+        let proxy = ProxyLogic::new(Arc::new(Config::default()));
+        let mut headers = spoofed();
+        proxy.add_forwarded(&mut headers, "/", &client, None);
+        assert_eq!(headers.get("x-forwarded-for"), Some("203.0.113.9"));
+        assert!(!headers.contains_key("x-forwarded-host"));
+
+        let config = Config::parse_config("ForwardedHeaders X-Forwarded-For Forwarded").unwrap();
+        assert!(config.forwarded_headers.forwarded && !config.forwarded_headers.x_forwarded_host);
+        assert!(Config::parse_config("ForwardedHeaders X-Real-IP").is_err());
+    }
+
+    #[test]
+    fn test_url_rewrite() {
+        let config = Config::parse_config(
+            r#"
+URLRewrite "^http://legacy\.example\.com/(.*)$" "http://www.example.com/old/$1"
+URLRewrite "^http://legacy\." "http://unreachable."
+"#,
+        )
+        .unwrap();
+        let proxy = ProxyLogic::new(Arc::new(config));
+
+        assert_eq!(
+            proxy.rewrite_url("http://legacy.example.com/a/b?c=d"),
+            Some("http://www.example.com/old/a/b?c=d".to_string())
+        );
+        assert_eq!(proxy.rewrite_url("http://other.example.com/"), None);
+        assert!(Config::parse_config(r#"URLRewrite "^http://x""#).is_err());
+    }
+
+    #[test]
+    fn test_redirect() {
+        let config = Config::parse_config(
+            r#"
+Redirect 301 "^http://(intranet\.example\.com/.*)$" "https://$1"
+Redirect "^http://ads\." "http://info.example.com/blocked.html"
+"#,
+        )
+        .unwrap();
+        let proxy = ProxyLogic::new(Arc::new(config));
+
+        assert_eq!(
+            proxy.redirect_for("http://intranet.example.com/wiki?page=1"),
+            Some((301, "https://intranet.example.com/wiki?page=1".to_string()))
+        );
+        assert_eq!(
+            proxy.redirect_for("http://ads.tracker.net/pixel.gif"),
+            Some((302, "http://info.example.com/blocked.html".to_string()))
+        );
+        assert_eq!(proxy.redirect_for("http://www.example.com/"), None);
+
+        assert!(Config::parse_config(r#"Redirect 200 "^x" "y""#).is_err());
+        assert!(Config::parse_config(r#"Redirect "^x""#).is_err());
+    }
+
+    #[test]
+    fn test_header_rewrite_invalid_pattern() {
+        assert!(Config::parse_config(r#"HeaderRewrite User-Agent "(" "x""#).is_err());
+        assert!(Config::parse_config("HeaderRewrite User-Agent").is_err());
+    }
 }
@@ -0,0 +1,183 @@
+use crate::error::{ProxyError, ProxyResult};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+const V1_PREFIX: &[u8] = b"PROXY ";
+const V1_MAX_LENGTH: usize = 107;
+const V2_SIGNATURE: &[u8] = b"\r\n\r\n\0\r\nQUIT\n";
+const V2_HEADER_LENGTH: usize = 16;
+
+/// A parsed PROXY protocol header.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProxyHeader {
+    /// Number of bytes the header occupied at the start of the stream.
+    pub length: usize,
+    /// Original client address, or `None` for LOCAL/UNKNOWN connections
+    /// (health checks from the load balancer itself).
+    pub source: Option<SocketAddr>,
+}
+
+/// Try to parse a PROXY protocol (v1 or v2) header from the start of `data`.
+///
+/// Returns `Ok(None)` if more data is needed to decide, and an error if the
+/// data cannot be a PROXY header at all.
+pub fn parse_proxy_header(data: &[u8]) -> ProxyResult<Option<ProxyHeader>> {
+    let probe = data.len().min(V2_SIGNATURE.len());
+    if data[..probe] == V2_SIGNATURE[..probe] {
+        if data.len() < V2_HEADER_LENGTH {
+            return Ok(None);
+        }
+        return parse_v2(data);
+    }
+
+    let probe = data.len().min(V1_PREFIX.len());
+    if data[..probe] == V1_PREFIX[..probe] {
+        if data.len() < V1_PREFIX.len() {
+            return Ok(None);
+        }
+        return parse_v1(data);
+    }
+
+    Err(ProxyError::Protocol(
+        "Expected PROXY protocol header".to_string(),
+    ))
+}
+
+fn parse_v1(data: &[u8]) -> ProxyResult<Option<ProxyHeader>> {
+    let end = match data.windows(2).position(|w| w == b"\r\n") {
+        Some(pos) => pos,
+        None if data.len() >= V1_MAX_LENGTH => {
+            return Err(ProxyError::Protocol("PROXY v1 header too long".to_string()));
+        }
+        None => return Ok(None),
+    };
+
+    let line = std::str::from_utf8(&data[..end])
+        .map_err(|_| ProxyError::Protocol("PROXY v1 header is not ASCII".to_string()))?;
+    let parts: Vec<&str> = line.split(' ').collect();
+
+    let source = match parts.get(1).copied() {
+        Some("UNKNOWN") => None,
+        Some("TCP4") | Some("TCP6") if parts.len() == 6 => {
+            let ip: IpAddr = parts[2].parse().map_err(|_| {
+                ProxyError::Protocol(format!("Invalid PROXY source address: {}", parts[2]))
+            })?;
+            let port: u16 = parts[4].parse().map_err(|_| {
+                ProxyError::Protocol(format!("Invalid PROXY source port: {}", parts[4]))
+            })?;
+            Some(SocketAddr::new(ip, port))
+        }
+        _ => {
+            return Err(ProxyError::Protocol(format!(
+                "Invalid PROXY v1 header: {}",
+                line
+            )));
+        }
+    };
+
+    Ok(Some(ProxyHeader {
+        length: end + 2,
+        source,
+    }))
+}
+
+fn parse_v2(data: &[u8]) -> ProxyResult<Option<ProxyHeader>> {
+    let version_command = data[12];
+    if version_command >> 4 != 2 {
+        return Err(ProxyError::Protocol(format!(
+            "Unsupported PROXY protocol version: {}",
+            version_command >> 4
+        )));
+    }
+
+    let address_length = u16::from_be_bytes([data[14], data[15]]) as usize;
+    let length = V2_HEADER_LENGTH + address_length;
+    if data.len() < length {
+        return Ok(None);
+    }
+
+    // LOCAL command: the connection was made by the proxy itself
+    if version_command & 0x0f == 0 {
+        return Ok(Some(ProxyHeader {
+            length,
+            source: None,
+        }));
+    }
+
+    let addresses = &data[V2_HEADER_LENGTH..length];
+    let source = match data[13] >> 4 {
+        // AF_INET
+        1 if addresses.len() >= 12 => {
+            let ip = Ipv4Addr::new(addresses[0], addresses[1], addresses[2], addresses[3]);
+            let port = u16::from_be_bytes([addresses[8], addresses[9]]);
+            Some(SocketAddr::new(IpAddr::V4(ip), port))
+        }
+        // AF_INET6
+        2 if addresses.len() >= 36 => {
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(&addresses[..16]);
+            let port = u16::from_be_bytes([addresses[32], addresses[33]]);
+            Some(SocketAddr::new(IpAddr::V6(Ipv6Addr::from(octets)), port))
+        }
+        // AF_UNSPEC, AF_UNIX or truncated address block
+        _ => None,
+    };
+
+    Ok(Some(ProxyHeader { length, source }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_v1() {
+        let data = b"PROXY TCP4 203.0.113.7 10.0.0.1 51234 8888\r\nGET / HTTP/1.1\r\n";
+        let header = parse_proxy_header(data).unwrap().unwrap();
+
+        assert_eq!(header.length, 44);
+        assert_eq!(header.source, Some("203.0.113.7:51234".parse().unwrap()));
+
+        let data = b"PROXY TCP6 2001:db8::1 2001:db8::2 4711 443\r\n";
+        let header = parse_proxy_header(data).unwrap().unwrap();
+        assert_eq!(header.source, Some("[2001:db8::1]:4711".parse().unwrap()));
+
+        let header = parse_proxy_header(b"PROXY UNKNOWN\r\n").unwrap().unwrap();
+        assert_eq!(header.source, None);
+    }
+
+    #[test]
+    fn test_parse_incomplete() {
+        assert_eq!(parse_proxy_header(b"PRO").unwrap(), None);
+        assert_eq!(parse_proxy_header(b"PROXY TCP4 203.0").unwrap(), None);
+        assert_eq!(parse_proxy_header(&V2_SIGNATURE[..5]).unwrap(), None);
+    }
+
+    #[test]
+    fn test_parse_invalid() {
+        assert!(parse_proxy_header(b"GET / HTTP/1.1\r\n").is_err());
+        assert!(parse_proxy_header(b"PROXY TCP4 bogus 10.0.0.1 1 2\r\n").is_err());
+    }
+
+    #[test]
+    fn test_parse_v2() {
+        let mut data = V2_SIGNATURE.to_vec();
+        data.extend_from_slice(&[0x21, 0x11, 0x00, 0x0c]);
+        data.extend_from_slice(&[203, 0, 113, 7, 10, 0, 0, 1]);
+        data.extend_from_slice(&51234u16.to_be_bytes());
+        data.extend_from_slice(&8888u16.to_be_bytes());
+        data.extend_from_slice(b"GET / HTTP/1.1\r\n");
+
+        let header = parse_proxy_header(&data).unwrap().unwrap();
+        assert_eq!(header.length, 28);
+        assert_eq!(header.source, Some("203.0.113.7:51234".parse().unwrap()));
+
+        // Truncated address block
+        assert_eq!(parse_proxy_header(&data[..20]).unwrap(), None);
+
+        // LOCAL command carries no address
+        let mut local = V2_SIGNATURE.to_vec();
+        local.extend_from_slice(&[0x20, 0x00, 0x00, 0x00]);
+        let header = parse_proxy_header(&local).unwrap().unwrap();
+        assert_eq!(header.source, None);
+    }
+}
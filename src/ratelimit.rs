@@ -0,0 +1,379 @@
+use crate::config::{Config, DestinationConnectionLimitConfig, DestinationRateLimitConfig};
+use log::debug;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+
+/// Upper bound on tracked destination buckets before idle ones are pruned.
+const MAX_TRACKED_DESTINATIONS: usize = 10_000;
+
+/// Request rate limits keyed by destination host, protecting origins
+/// from being hammered through the proxy. Every host matching a rule gets
+/// its own token bucket, shared by all clients.
+pub struct DestinationRateLimits {
+    rules: Vec<DestinationRateLimitConfig>,
+    buckets: Mutex<HashMap<String, RequestBucket>>,
+}
+
+struct RequestBucket {
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl RequestBucket {
+    fn new(rate: f64, now: Instant) -> Self {
+        let burst = rate.max(1.0);
+        Self {
+            rate,
+            burst,
+            tokens: burst,
+            last_refill: now,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last_refill);
+        self.tokens = (self.tokens + elapsed.as_secs_f64() * self.rate).min(self.burst);
+        self.last_refill = now;
+    }
+
+    fn try_acquire(&mut self, now: Instant) -> Result<(), Duration> {
+        self.refill(now);
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - self.tokens) / self.rate))
+        }
+    }
+
+    fn is_full(&mut self, now: Instant) -> bool {
+        self.refill(now);
+        self.tokens >= self.burst
+    }
+}
+
+impl DestinationRateLimits {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            rules: config.destination_rate_limits.clone(),
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.rules.is_empty()
+    }
+
+    /// Account one request to `host`. Returns how long the client should
+    /// wait before retrying when the destination's limit is exceeded.
+    pub fn check(&self, host: &str) -> Result<(), Duration> {
+        self.check_at(host, Instant::now())
+    }
+
+    fn check_at(&self, host: &str, now: Instant) -> Result<(), Duration> {
+        let host = host.to_lowercase();
+        let rule = match self
+            .rules
+            .iter()
+            .find(|rule| host_matches(&rule.host, &host))
+        {
+            Some(rule) => rule,
+            None => return Ok(()),
+        };
+
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= MAX_TRACKED_DESTINATIONS && !buckets.contains_key(&host) {
+            buckets.retain(|_, bucket| !bucket.is_full(now));
+        }
+
+        let result = buckets
+            .entry(host.clone())
+            .or_insert_with(|| RequestBucket::new(rule.requests_per_second, now))
+            .try_acquire(now);
+
+        if result.is_err() {
+            debug!("Request rate limit for {} exceeded", host);
+        }
+        result
+    }
+}
+
+/// Caps on simultaneous upstream connections to a destination host, so
+/// fragile origins are not overwhelmed through the proxy. Every host
+/// matching a rule has its own cap; requests over it wait up to the rule's
+/// queue timeout for one of the host's connections to close.
+pub struct DestinationConnectionLimits {
+    rules: Vec<DestinationConnectionLimitConfig>,
+    open: Arc<OpenConnections>,
+}
+
+#[derive(Default)]
+struct OpenConnections {
+    counts: Mutex<HashMap<String, usize>>,
+    closed: Notify,
+}
+
+/// One upstream connection counted against its destination's cap until
+/// dropped.
+pub struct DestinationPermit {
+    open: Arc<OpenConnections>,
+    host: Option<String>,
+}
+
+impl DestinationConnectionLimits {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            rules: config.destination_connection_limits.clone(),
+            open: Arc::new(OpenConnections::default()),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.rules.is_empty()
+    }
+
+    /// Count a new connection to `host`, waiting for a free one if needed.
+    /// Returns `None` if the host stayed at its cap for the whole wait.
+    pub async fn acquire(&self, host: &str) -> Option<DestinationPermit> {
+        let host = host.to_lowercase();
+        let rule = match self
+            .rules
+            .iter()
+            .find(|rule| host_matches(&rule.host, &host))
+        {
+            Some(rule) => rule,
+            None => {
+                return Some(DestinationPermit {
+                    open: self.open.clone(),
+                    host: None,
+                })
+            }
+        };
+
+        let deadline = tokio::time::Instant::now() + Duration::from_secs(rule.queue_timeout);
+        loop {
+            // Listen before checking, so a close in between is not missed
+            let closed = self.open.closed.notified();
+            tokio::pin!(closed);
+            closed.as_mut().enable();
+
+            {
+                let mut counts = self.open.counts.lock().unwrap();
+                let count = counts.entry(host.clone()).or_insert(0);
+                if *count < rule.max_connections {
+                    *count += 1;
+                    return Some(DestinationPermit {
+                        open: self.open.clone(),
+                        host: Some(host),
+                    });
+                }
+            }
+
+            if tokio::time::timeout_at(deadline, closed).await.is_err() {
+                debug!("Connection limit for {} reached", host);
+                return None;
+            }
+        }
+    }
+
+    /// Open connections to `host` counted against a cap.
+    pub fn open_connections(&self, host: &str) -> usize {
+        let counts = self.open.counts.lock().unwrap();
+        counts.get(&host.to_lowercase()).copied().unwrap_or(0)
+    }
+}
+
+impl Drop for DestinationPermit {
+    fn drop(&mut self) {
+        let Some(host) = &self.host else {
+            return;
+        };
+        let mut counts = self.open.counts.lock().unwrap();
+        if let Some(count) = counts.get_mut(host) {
+            *count -= 1;
+            if *count == 0 {
+                counts.remove(host);
+            }
+        }
+        self.open.closed.notify_waiters();
+    }
+}
+
+/// Cap on simultaneous connections from one client address
+/// (MaxClientsPerIP), so a single client cannot take every MaxClients
+/// slot. Clients are counted by the address they connect from.
+pub struct ClientConnectionLimits {
+    max_per_ip: usize,
+    counts: Arc<Mutex<HashMap<IpAddr, usize>>>,
+}
+
+/// One client connection counted against its address's cap until dropped.
+pub struct ClientPermit {
+    counts: Arc<Mutex<HashMap<IpAddr, usize>>>,
+    ip: Option<IpAddr>,
+}
+
+impl ClientConnectionLimits {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            max_per_ip: config.max_clients_per_ip,
+            counts: Arc::default(),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.max_per_ip > 0
+    }
+
+    /// Count a new connection from `ip`, or `None` if it is at its cap.
+    pub fn try_acquire(&self, ip: IpAddr) -> Option<ClientPermit> {
+        if !self.is_enabled() {
+            return Some(ClientPermit {
+                counts: self.counts.clone(),
+                ip: None,
+            });
+        }
+
+        let mut counts = self.counts.lock().unwrap();
+        let count = counts.entry(ip).or_insert(0);
+        if *count >= self.max_per_ip {
+            return None;
+        }
+        *count += 1;
+        Some(ClientPermit {
+            counts: self.counts.clone(),
+            ip: Some(ip),
+        })
+    }
+
+    pub fn open_connections(&self, ip: IpAddr) -> usize {
+        self.counts.lock().unwrap().get(&ip).copied().unwrap_or(0)
+    }
+}
+
+impl Drop for ClientPermit {
+    fn drop(&mut self) {
+        let Some(ip) = self.ip else {
+            return;
+        };
+        let mut counts = self.counts.lock().unwrap();
+        if let Some(count) = counts.get_mut(&ip) {
+            *count -= 1;
+            if *count == 0 {
+                counts.remove(&ip);
+            }
+        }
+    }
+}
+
+/// Match a host against a rule: `.example.com` matches the domain and all
+/// subdomains, anything else must match exactly.
+fn host_matches(pattern: &str, host: &str) -> bool {
+    match pattern.strip_prefix('.') {
+        Some(domain) => host == domain || host.ends_with(pattern),
+        None => host == pattern,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_host_matches() {
+        assert!(host_matches("api.example.com", "api.example.com"));
+        assert!(!host_matches("api.example.com", "www.example.com"));
+        assert!(host_matches(".example.com", "example.com"));
+        assert!(host_matches(".example.com", "a.b.example.com"));
+        assert!(!host_matches(".example.com", "badexample.com"));
+    }
+
+    #[test]
+    fn test_destination_rate_limit() {
+        let config = Config::parse_config("DestinationRateLimit .internal.example 2/s").unwrap();
+        let limits = DestinationRateLimits::new(&config);
+        let start = Instant::now();
+
+        assert!(limits.is_enabled());
+        assert!(limits.check_at("api.internal.example", start).is_ok());
+        assert!(limits.check_at("api.internal.example", start).is_ok());
+        let retry = limits.check_at("API.internal.example", start).unwrap_err();
+        assert!(retry <= Duration::from_millis(500));
+
+        // Each destination host has its own bucket
+        assert!(limits.check_at("db.internal.example", start).is_ok());
+
+        // Unmatched hosts are never limited
+        for _ in 0..10 {
+            assert!(limits.check_at("www.example.com", start).is_ok());
+        }
+
+        let later = start + Duration::from_millis(500);
+        assert!(limits.check_at("api.internal.example", later).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_destination_connection_limit() {
+        let config = Config::parse_config(
+            "DestinationConnectionLimit .fragile.example 2\n\
+             DestinationConnectionLimit queued.example 1 5",
+        )
+        .unwrap();
+        let limits = Arc::new(DestinationConnectionLimits::new(&config));
+        assert!(limits.is_enabled());
+
+        let first = limits.acquire("a.fragile.example").await.unwrap();
+        let _second = limits.acquire("A.Fragile.example").await.unwrap();
+        assert!(limits.acquire("a.fragile.example").await.is_none());
+        assert_eq!(limits.open_connections("a.fragile.example"), 2);
+        // Each host has its own cap
+        assert!(limits.acquire("b.fragile.example").await.is_some());
+        drop(first);
+        assert!(limits.acquire("a.fragile.example").await.is_some());
+
+        // Unmatched hosts are never limited
+        let mut unlimited = Vec::new();
+        for _ in 0..10 {
+            unlimited.push(limits.acquire("www.example.com").await.unwrap());
+        }
+        assert_eq!(limits.open_connections("www.example.com"), 0);
+
+        // Requests wait for a connection to close
+        let held = limits.acquire("queued.example").await.unwrap();
+        let waiter = tokio::spawn({
+            let limits = limits.clone();
+            async move { limits.acquire("queued.example").await.is_some() }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        drop(held);
+        assert!(waiter.await.unwrap());
+        assert_eq!(limits.open_connections("queued.example"), 0);
+    }
+
+    #[test]
+    fn test_client_connection_limit() {
+        let config = Config::parse_config("MaxClientsPerIP 2").unwrap();
+        let limits = ClientConnectionLimits::new(&config);
+        let client: IpAddr = "192.0.2.7".parse().unwrap();
+        let other: IpAddr = "192.0.2.8".parse().unwrap();
+
+        let first = limits.try_acquire(client).unwrap();
+        let _second = limits.try_acquire(client).unwrap();
+        assert!(limits.try_acquire(client).is_none());
+        assert!(limits.try_acquire(other).is_some());
+        assert_eq!(limits.open_connections(client), 2);
+
+        drop(first);
+        assert!(limits.try_acquire(client).is_some());
+
+        let unlimited = ClientConnectionLimits::new(&Config::default());
+        let permits: Vec<_> = (0..10).map(|_| unlimited.try_acquire(client)).collect();
+        assert!(permits.iter().all(Option::is_some));
+        assert_eq!(unlimited.open_connections(client), 0);
+    }
+}
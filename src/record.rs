@@ -0,0 +1,285 @@
+use crate::connector::{BoxedStream, ConnectFailure, Connector};
+use crate::interceptor::LocalResponse;
+use crate::utils::{html_escape, origin_form, parse_http_request, HeadScanner, HttpRequest};
+use async_trait::async_trait;
+use log::{debug, warn};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+
+/// Requests whose head is larger than this are not recorded.
+const MAX_REQUEST_HEAD: usize = 65536;
+
+/// Responses larger than this are not recorded.
+const MAX_RECORDING: usize = 16 * 1024 * 1024;
+
+/// Connector saving every response received through `inner` under a
+/// directory, for `ReplayConnector` to answer from later.
+pub struct RecordingConnector {
+    inner: Arc<dyn Connector>,
+    dir: PathBuf,
+}
+
+impl RecordingConnector {
+    pub fn new(inner: Arc<dyn Connector>, dir: impl AsRef<Path>) -> Self {
+        Self {
+            inner,
+            dir: dir.as_ref().to_path_buf(),
+        }
+    }
+}
+
+#[async_trait]
+impl Connector for RecordingConnector {
+    async fn connect(&self, host: &str, port: u16) -> Result<BoxedStream, ConnectFailure> {
+        let stream = self.inner.connect(host, port).await?;
+        Ok(Box::new(Recorder {
+            inner: stream,
+            target: format!("{}:{}", host, port),
+            dir: self.dir.clone(),
+            request: Vec::new(),
+            scanner: HeadScanner::new(),
+            key: None,
+            response: Vec::new(),
+            discarded: false,
+        }))
+    }
+}
+
+/// Stream that passes everything through, keeping a copy of the request
+/// head and the response. The recording is saved when it is dropped.
+struct Recorder {
+    inner: BoxedStream,
+    target: String,
+    dir: PathBuf,
+    request: Vec<u8>,
+    scanner: HeadScanner,
+    key: Option<String>,
+    response: Vec<u8>,
+    /// Set when the exchange is not HTTP or too large to record.
+    discarded: bool,
+}
+
+impl Recorder {
+    fn record_request(&mut self, data: &[u8]) {
+        if self.key.is_some() || self.discarded {
+            return;
+        }
+
+        self.request.extend_from_slice(data);
+        if let Some(end) = self.scanner.find(&self.request) {
+            match parse_http_request(&self.request[..end + 4]) {
+                Ok(request) => self.key = Some(recording_key(&self.target, &request)),
+                Err(_) => self.discarded = true,
+            }
+            self.request = Vec::new();
+        } else if self.request.len() > MAX_REQUEST_HEAD {
+            self.discarded = true;
+        }
+    }
+
+    fn record_response(&mut self, data: &[u8]) {
+        if self.key.is_none() || self.discarded {
+            return;
+        }
+
+        if self.response.len() + data.len() > MAX_RECORDING {
+            debug!("Not recording response from {}: too large", self.target);
+            self.discarded = true;
+            self.response = Vec::new();
+        } else {
+            self.response.extend_from_slice(data);
+        }
+    }
+}
+
+impl AsyncRead for Recorder {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let filled = buf.filled().len();
+        let result = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = result {
+            self.record_response(&buf.filled()[filled..]);
+        }
+        result
+    }
+}
+
+impl AsyncWrite for Recorder {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let result = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(written)) = result {
+            self.record_request(&buf[..written]);
+        }
+        result
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+impl Drop for Recorder {
+    fn drop(&mut self) {
+        let key = match &self.key {
+            Some(key) if !self.discarded && !self.response.is_empty() => key,
+            _ => return,
+        };
+
+        let path = recording_path(&self.dir, key);
+        let result =
+            std::fs::create_dir_all(&self.dir).and_then(|()| std::fs::write(&path, &self.response));
+        match result {
+            Ok(()) => debug!("Recorded {} as {}", key, path.display()),
+            Err(e) => warn!("Failed to record {} as {}: {}", key, path.display(), e),
+        }
+    }
+}
+
+/// Connector answering requests from the recordings of a
+/// `RecordingConnector`, without any network access.
+pub struct ReplayConnector {
+    dir: PathBuf,
+}
+
+impl ReplayConnector {
+    pub fn new(dir: impl AsRef<Path>) -> Self {
+        Self {
+            dir: dir.as_ref().to_path_buf(),
+        }
+    }
+}
+
+#[async_trait]
+impl Connector for ReplayConnector {
+    async fn connect(&self, host: &str, port: u16) -> Result<BoxedStream, ConnectFailure> {
+        let (proxy_side, origin) = tokio::io::duplex(65536);
+        tokio::spawn(replay(
+            origin,
+            format!("{}:{}", host, port),
+            self.dir.clone(),
+        ));
+        Ok(Box::new(proxy_side))
+    }
+}
+
+/// Play the origin's part of an exchange: read the request head and
+/// answer with its recording.
+async fn replay(mut origin: tokio::io::DuplexStream, target: String, dir: PathBuf) {
+    let mut request = Vec::new();
+    let mut scanner = HeadScanner::new();
+    let mut buf = [0u8; 8192];
+
+    let head_end = loop {
+        if let Some(end) = scanner.find(&request) {
+            break end;
+        }
+        match origin.read(&mut buf).await {
+            Ok(n) if n > 0 && request.len() < MAX_REQUEST_HEAD => {
+                request.extend_from_slice(&buf[..n])
+            }
+            _ => return,
+        }
+    };
+    let key = match parse_http_request(&request[..head_end + 4]) {
+        Ok(request) => recording_key(&target, &request),
+        Err(_) => return,
+    };
+
+    let response = match tokio::fs::read(recording_path(&dir, &key)).await {
+        Ok(response) => {
+            debug!("Replaying {}", key);
+            response
+        }
+        Err(e) => {
+            warn!("No recording for {}: {}", key, e);
+            let detail = format!("<p>No recorded response for {}</p>", html_escape(&key));
+            LocalResponse::error_page(502, "Bad Gateway", &detail).to_bytes("1.1", false)
+        }
+    };
+
+    if origin.write_all(&response).await.is_ok() && origin.shutdown().await.is_ok() {
+        // Accept the rest of the request so the proxy can finish sending it
+        let _ = tokio::io::copy(&mut origin, &mut tokio::io::sink()).await;
+    }
+}
+
+/// Identifies an exchange by method, target authority and path.
+fn recording_key(target: &str, request: &HttpRequest) -> String {
+    format!("{} {}{}", request.method, target, origin_form(&request.uri))
+}
+
+/// File holding the recording for `key`, named by a stable hash of it.
+fn recording_path(dir: &Path, key: &str) -> PathBuf {
+    // FNV-1a, which unlike std's hasher is the same in every build
+    let hash = key.bytes().fold(0xcbf29ce484222325u64, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    });
+    dir.join(format!("{:016x}.http", hash))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Origin answering every request with a fixed response.
+    struct FixedOrigin;
+
+    #[async_trait]
+    impl Connector for FixedOrigin {
+        async fn connect(&self, _host: &str, _port: u16) -> Result<BoxedStream, ConnectFailure> {
+            let (proxy_side, mut origin) = tokio::io::duplex(4096);
+            tokio::spawn(async move {
+                let mut buf = [0u8; 1024];
+                let _ = origin.read(&mut buf).await.unwrap();
+                origin
+                    .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 8\r\n\r\nrecorded")
+                    .await
+                    .unwrap();
+            });
+            Ok(Box::new(proxy_side))
+        }
+    }
+
+    async fn exchange(connector: &dyn Connector, request: &[u8]) -> Vec<u8> {
+        let mut stream = connector.connect("origin.test", 80).await.unwrap();
+        stream.write_all(request).await.unwrap();
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).await.unwrap();
+        response
+    }
+
+    #[tokio::test]
+    async fn test_record_and_replay() {
+        let dir = tempfile::tempdir().unwrap();
+        let request = b"GET /page?q=1 HTTP/1.1\r\nHost: origin.test\r\n\r\n";
+
+        let recorder = RecordingConnector::new(Arc::new(FixedOrigin), dir.path());
+        let recorded = exchange(&recorder, request).await;
+        assert!(recorded.ends_with(b"recorded"));
+
+        let replayer = ReplayConnector::new(dir.path());
+        assert_eq!(exchange(&replayer, request).await, recorded);
+
+        let missing = exchange(
+            &replayer,
+            b"GET /other HTTP/1.1\r\nHost: origin.test\r\n\r\n",
+        )
+        .await;
+        assert!(missing.starts_with(b"HTTP/1.1 502 Bad Gateway\r\n"));
+    }
+}
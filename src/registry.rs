@@ -0,0 +1,364 @@
+use crate::utils::{format_bytes, html_escape};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{ready, Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::sync::Notify;
+use tokio_util::sync::CancellationToken;
+
+/// Registry of the client connections currently being served, so they can
+/// be listed and closed at runtime, and drained on shutdown.
+#[derive(Default)]
+pub struct ConnectionRegistry {
+    next_id: AtomicU64,
+    connections: Mutex<HashMap<u64, ConnectionEntry>>,
+    draining: CancellationToken,
+    emptied: Notify,
+}
+
+struct ConnectionEntry {
+    client_addr: SocketAddr,
+    target: Option<String>,
+    started: DateTime<Utc>,
+    traffic: Arc<Traffic>,
+    cancel: CancellationToken,
+}
+
+/// Bytes a client connection has moved so far, counted as they pass.
+#[derive(Debug, Default)]
+pub struct Traffic {
+    /// Bytes read from the client.
+    pub received: AtomicU64,
+    /// Bytes written to the client.
+    pub sent: AtomicU64,
+}
+
+/// Snapshot of an active connection.
+#[derive(Debug, Clone, Serialize)]
+pub struct ConnectionInfo {
+    pub id: u64,
+    pub client_addr: SocketAddr,
+    pub target: Option<String>,
+    pub started: DateTime<Utc>,
+    pub duration_secs: i64,
+    pub bytes_received: u64,
+    pub bytes_sent: u64,
+}
+
+/// Membership of a connection in the registry; removes it when dropped.
+pub struct Registration {
+    registry: Arc<ConnectionRegistry>,
+    id: u64,
+    traffic: Arc<Traffic>,
+    cancel: CancellationToken,
+}
+
+impl ConnectionRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn register(self: &Arc<Self>, client_addr: SocketAddr) -> Registration {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+        let cancel = CancellationToken::new();
+        let traffic = Arc::new(Traffic::default());
+
+        self.connections.lock().unwrap().insert(
+            id,
+            ConnectionEntry {
+                client_addr,
+                target: None,
+                started: Utc::now(),
+                traffic: traffic.clone(),
+                cancel: cancel.clone(),
+            },
+        );
+
+        Registration {
+            registry: self.clone(),
+            id,
+            traffic,
+            cancel,
+        }
+    }
+
+    pub fn set_client(&self, id: u64, client_addr: SocketAddr) {
+        if let Some(entry) = self.connections.lock().unwrap().get_mut(&id) {
+            entry.client_addr = client_addr;
+        }
+    }
+
+    pub fn set_target(&self, id: u64, target: &str) {
+        if let Some(entry) = self.connections.lock().unwrap().get_mut(&id) {
+            entry.target = Some(target.to_string());
+        }
+    }
+
+    /// Close a connection. Returns false if there is no such connection.
+    pub fn close(&self, id: u64) -> bool {
+        match self.connections.lock().unwrap().get(&id) {
+            Some(entry) => {
+                entry.cancel.cancel();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Close every connection.
+    pub fn close_all(&self) {
+        for entry in self.connections.lock().unwrap().values() {
+            entry.cancel.cancel();
+        }
+    }
+
+    /// Ask connections to finish: idle ones close, busy ones close once
+    /// their current request is done.
+    pub fn drain(&self) {
+        self.draining.cancel();
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.draining.is_cancelled()
+    }
+
+    /// Completes once the registry is draining.
+    pub async fn draining(&self) {
+        self.draining.cancelled().await
+    }
+
+    /// Completes once no connection is left.
+    pub async fn wait_closed(&self) {
+        loop {
+            // Listen before checking, so a close in between is not missed
+            let emptied = self.emptied.notified();
+            tokio::pin!(emptied);
+            emptied.as_mut().enable();
+            if self.count() == 0 {
+                return;
+            }
+            emptied.await;
+        }
+    }
+
+    /// Number of active connections.
+    pub fn count(&self) -> usize {
+        self.connections.lock().unwrap().len()
+    }
+
+    pub fn list(&self) -> Vec<ConnectionInfo> {
+        let now = Utc::now();
+        let mut connections: Vec<ConnectionInfo> = self
+            .connections
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(id, entry)| ConnectionInfo {
+                id: *id,
+                client_addr: entry.client_addr,
+                target: entry.target.clone(),
+                started: entry.started,
+                duration_secs: (now - entry.started).num_seconds(),
+                bytes_received: entry.traffic.received.load(Ordering::Relaxed),
+                bytes_sent: entry.traffic.sent.load(Ordering::Relaxed),
+            })
+            .collect();
+        connections.sort_by_key(|connection| connection.id);
+        connections
+    }
+
+    /// Statistics page section listing the active connections. With an
+    /// admin API base URL, each row gets a button to close the connection.
+    pub fn to_html(&self, admin_url: Option<&str>) -> String {
+        let rows: String = self
+            .list()
+            .iter()
+            .map(|connection| {
+                let close = admin_url
+                    .map(|url| {
+                        format!(
+                            "<form method=\"post\" action=\"{}/connections/{}/close\">\
+                             <button type=\"submit\">Close</button></form>",
+                            html_escape(url),
+                            connection.id
+                        )
+                    })
+                    .unwrap_or_default();
+                format!(
+                    "            <tr><td>{}</td><td>{}</td><td>{}</td><td class=\"value\">{}s</td><td class=\"value\">{}</td><td class=\"value\">{}</td><td>{}</td></tr>\n",
+                    connection.id,
+                    connection.client_addr,
+                    html_escape(connection.target.as_deref().unwrap_or("-")),
+                    connection.duration_secs,
+                    format_bytes(connection.bytes_received),
+                    format_bytes(connection.bytes_sent),
+                    close
+                )
+            })
+            .collect();
+
+        format!(
+            r#"    <div class="section">
+        <h2>Active Connections</h2>
+        <table>
+            <tr><th>ID</th><th>Client</th><th>Target</th><th>Duration</th><th>Received</th><th>Sent</th><th></th></tr>
+{}        </table>
+    </div>
+"#,
+            rows
+        )
+    }
+}
+
+impl Registration {
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Wrap the client's stream so that the registry sees its traffic.
+    pub fn meter<S>(&self, stream: S) -> Metered<S> {
+        Metered {
+            inner: stream,
+            traffic: self.traffic.clone(),
+        }
+    }
+
+    /// Completes once the connection has been closed through the registry.
+    pub async fn closed(&self) {
+        self.cancel.cancelled().await
+    }
+}
+
+/// A client stream counting its bytes into the connection's [`Traffic`].
+pub struct Metered<S> {
+    inner: S,
+    traffic: Arc<Traffic>,
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Metered<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        ready!(Pin::new(&mut self.inner).poll_read(cx, buf))?;
+        let read = (buf.filled().len() - before) as u64;
+        self.traffic.received.fetch_add(read, Ordering::Relaxed);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Metered<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        data: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let written = ready!(Pin::new(&mut self.inner).poll_write(cx, data))?;
+        self.traffic
+            .sent
+            .fetch_add(written as u64, Ordering::Relaxed);
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        let mut connections = self.registry.connections.lock().unwrap();
+        connections.remove(&self.id);
+        if connections.is_empty() {
+            self.registry.emptied.notify_waiters();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_connection_registry() {
+        let registry = Arc::new(ConnectionRegistry::new());
+        let client: SocketAddr = "192.0.2.1:40000".parse().unwrap();
+
+        let first = registry.register(client);
+        let second = registry.register(client);
+        registry.set_target(second.id(), "example.com:443");
+
+        let connections = registry.list();
+        assert_eq!(connections.len(), 2);
+        assert_eq!(connections[1].target.as_deref(), Some("example.com:443"));
+        assert!(registry
+            .to_html(Some("http://127.0.0.1:8081"))
+            .contains("127.0.0.1:8081/connections/2/close"));
+
+        assert!(registry.close(first.id()));
+        first.closed().await;
+
+        drop(first);
+        assert!(!registry.close(1));
+        assert_eq!(registry.list().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_drain() {
+        let registry = Arc::new(ConnectionRegistry::new());
+        let client: SocketAddr = "192.0.2.1:40000".parse().unwrap();
+        registry.wait_closed().await;
+
+        let idle = registry.register(client);
+        let busy = registry.register(client);
+        assert!(!registry.is_draining());
+        registry.drain();
+        registry.draining().await;
+
+        let waiter = tokio::spawn({
+            let registry = registry.clone();
+            async move { registry.wait_closed().await }
+        });
+        drop(idle);
+        tokio::task::yield_now().await;
+        assert!(!waiter.is_finished());
+
+        // Connections still busy at the timeout are closed
+        registry.close_all();
+        busy.closed().await;
+        drop(busy);
+        waiter.await.unwrap();
+        assert_eq!(registry.count(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_metered_traffic() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let registry = Arc::new(ConnectionRegistry::new());
+        let registration = registry.register("192.0.2.1:40000".parse().unwrap());
+        let (mut client, proxy) = tokio::io::duplex(64);
+        let mut proxy = registration.meter(proxy);
+
+        client.write_all(b"GET / HTTP/1.1\r\n").await.unwrap();
+        let mut request = [0u8; 16];
+        proxy.read_exact(&mut request).await.unwrap();
+        proxy.write_all(b"HTTP/1.1 200 OK").await.unwrap();
+
+        let connection = &registry.list()[0];
+        assert_eq!(connection.bytes_received, 16);
+        assert_eq!(connection.bytes_sent, 15);
+    }
+}
@@ -0,0 +1,626 @@
+use crate::config::{BalanceStrategy, Config, HealthCheckConfig};
+use crate::connection::request_host;
+use crate::connector::Connector;
+use crate::utils::{html_escape, parse_http_response, wildcard_match, HeadScanner, HttpRequest};
+use futures::future::join_all;
+use log::{info, warn};
+use serde::Serialize;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::time::timeout;
+
+/// Longest time a health check probe may take.
+const MAX_PROBE_TIME: Duration = Duration::from_secs(5);
+
+/// ReversePath routing: requests for a local path prefix are sent to one
+/// of the backends configured for it, optionally keeping each client on
+/// the same backend with a cookie. Rules may be limited to requests whose
+/// Host header matches a pattern, and those are tried before rules for
+/// any host. Backends failing their health checks are left out until they
+/// pass again.
+pub struct ReverseProxy {
+    rules: Vec<ReverseRule>,
+    sticky_cookie: Option<String>,
+    health_check: Option<HealthCheckConfig>,
+}
+
+/// Outcome of routing a request with the ReversePath rules.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Route {
+    /// No ReversePath prefix matches the request.
+    NoMatch,
+    /// The request was sent to a backend.
+    Backend,
+    /// Every backend for the matching path is down.
+    Unavailable,
+}
+
+struct ReverseRule {
+    host: Option<String>,
+    path: String,
+    backends: Vec<Backend>,
+    balance: BalanceStrategy,
+    next: AtomicUsize,
+}
+
+struct Backend {
+    url: String,
+    /// Host header value for requests to this backend.
+    authority: String,
+    host: String,
+    port: u16,
+    weight: u32,
+    state: Arc<BackendState>,
+}
+
+#[derive(Default)]
+struct BackendState {
+    active: AtomicUsize,
+    requests: AtomicU64,
+    /// Set while the backend fails its health checks.
+    down: AtomicBool,
+}
+
+/// Snapshot of a reverse proxy backend's load.
+#[derive(Debug, Clone, Serialize)]
+pub struct BackendStatus {
+    pub host: Option<String>,
+    pub path: String,
+    pub url: String,
+    pub weight: u32,
+    pub healthy: bool,
+    pub active: usize,
+    pub requests: u64,
+}
+
+/// A request in progress on a backend, counted for least-connections
+/// balancing until dropped.
+pub struct BackendGuard {
+    state: Arc<BackendState>,
+}
+
+impl Drop for BackendGuard {
+    fn drop(&mut self) {
+        self.state.active.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl ReverseProxy {
+    pub fn new(config: &Config) -> Self {
+        let rules = config
+            .reverse_proxy
+            .iter()
+            .map(|rule| ReverseRule {
+                host: rule.host.clone(),
+                path: rule.path.clone(),
+                backends: rule
+                    .backends
+                    .iter()
+                    .map(|backend| {
+                        // ReversePath only accepts http URLs with a host
+                        let url = url::Url::parse(&backend.url).ok();
+                        Backend {
+                            url: backend.url.clone(),
+                            authority: authority(&backend.url),
+                            host: url
+                                .as_ref()
+                                .and_then(|url| url.host_str())
+                                .unwrap_or_default()
+                                .to_string(),
+                            port: url
+                                .and_then(|url| url.port_or_known_default())
+                                .unwrap_or(80),
+                            weight: backend.weight,
+                            state: Arc::default(),
+                        }
+                    })
+                    .collect(),
+                balance: rule.balance,
+                next: AtomicUsize::new(0),
+            })
+            .collect();
+
+        Self {
+            rules,
+            sticky_cookie: config.reverse_sticky_cookie.clone(),
+            health_check: config.reverse_health_check.clone(),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.rules.is_empty()
+    }
+
+    /// Send an origin-form request for a ReversePath prefix to one of the
+    /// path's healthy backends, rewriting its target and Host header.
+    pub fn route(&self, request: &mut HttpRequest) -> Route {
+        if !request.uri.starts_with('/') {
+            return Route::NoMatch;
+        }
+        let host = request_host(request).map(|host| host.to_lowercase());
+        let for_host = |rule: &&ReverseRule| match (&rule.host, &host) {
+            (Some(pattern), Some(host)) => wildcard_match(pattern, host),
+            _ => false,
+        };
+        let rule = match self
+            .rules
+            .iter()
+            .filter(for_host)
+            .chain(self.rules.iter().filter(|rule| rule.host.is_none()))
+            .find(|rule| request.uri.starts_with(&rule.path))
+        {
+            Some(rule) => rule,
+            None => return Route::NoMatch,
+        };
+
+        let pinned = self
+            .pinned_backend(request)
+            .filter(|&index| index < rule.backends.len() && rule.backends[index].is_up());
+        let index = match pinned.or_else(|| rule.choose()) {
+            Some(index) => index,
+            None => return Route::Unavailable,
+        };
+        let backend = &rule.backends[index];
+        backend.state.requests.fetch_add(1, Ordering::Relaxed);
+
+        request.uri = join(&backend.url, &request.uri[rule.path.len()..]);
+        request.headers.insert("Host", backend.authority.as_str());
+        Route::Backend
+    }
+
+    /// How long clients should wait when every backend of a path is down.
+    pub fn retry_after(&self) -> Duration {
+        self.health_check
+            .as_ref()
+            .map_or(Duration::from_secs(10), |check| {
+                Duration::from_secs(check.interval)
+            })
+    }
+
+    /// Check the health of every backend periodically, forever. Returns
+    /// at once if no health check is configured.
+    pub async fn run_health_checks(&self, connector: &dyn Connector) {
+        let check = match &self.health_check {
+            Some(check) if self.is_enabled() => check,
+            _ => return,
+        };
+
+        let mut interval = tokio::time::interval(Duration::from_secs(check.interval));
+        loop {
+            interval.tick().await;
+            self.check_health(connector).await;
+        }
+    }
+
+    /// Probe every backend once, taking failing ones out of rotation and
+    /// passing ones back in.
+    pub async fn check_health(&self, connector: &dyn Connector) {
+        let check = match &self.health_check {
+            Some(check) => check,
+            None => return,
+        };
+        let backends = self.rules.iter().flat_map(|rule| &rule.backends);
+
+        join_all(backends.map(|backend| async move {
+            let result = probe(backend, check, connector).await;
+            let was_down = backend.state.down.swap(result.is_err(), Ordering::Relaxed);
+            match result {
+                Err(reason) if !was_down => {
+                    warn!("Backend {} is down: {}", backend.url, reason)
+                }
+                Ok(()) if was_down => info!("Backend {} is up again", backend.url),
+                _ => {}
+            }
+        }))
+        .await;
+    }
+
+    /// Count a request to the backend serving `uri` as in progress until
+    /// the returned guard is dropped.
+    pub fn track(&self, uri: &str) -> Option<BackendGuard> {
+        let backend = self
+            .rules
+            .iter()
+            .flat_map(|rule| &rule.backends)
+            .find(|backend| serves(&backend.url, uri))?;
+        backend.state.active.fetch_add(1, Ordering::Relaxed);
+        Some(BackendGuard {
+            state: backend.state.clone(),
+        })
+    }
+
+    /// Load of every backend, in configuration order.
+    pub fn backends(&self) -> Vec<BackendStatus> {
+        self.rules
+            .iter()
+            .flat_map(|rule| {
+                rule.backends.iter().map(|backend| BackendStatus {
+                    host: rule.host.clone(),
+                    path: rule.path.clone(),
+                    url: backend.url.clone(),
+                    weight: backend.weight,
+                    healthy: backend.is_up(),
+                    active: backend.state.active.load(Ordering::Relaxed),
+                    requests: backend.state.requests.load(Ordering::Relaxed),
+                })
+            })
+            .collect()
+    }
+
+    /// Statistics page section listing the backends and their load.
+    pub fn to_html(&self) -> String {
+        let rows: String = self
+            .backends()
+            .iter()
+            .map(|backend| {
+                format!(
+                    "            <tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td class=\"value\">{}</td><td class=\"value\">{}</td></tr>\n",
+                    html_escape(&format!(
+                        "{}{}",
+                        backend.host.as_deref().unwrap_or(""),
+                        backend.path
+                    )),
+                    html_escape(&backend.url),
+                    if backend.healthy { "up" } else { "down" },
+                    backend.weight,
+                    backend.active,
+                    backend.requests
+                )
+            })
+            .collect();
+
+        format!(
+            r#"    <div class="section">
+        <h2>Reverse Proxy Backends</h2>
+        <table>
+            <tr><th>Path</th><th>Backend</th><th>Status</th><th>Weight</th><th>Active</th><th>Requests</th></tr>
+{}        </table>
+    </div>
+"#,
+            rows
+        )
+    }
+
+    /// Set-Cookie value pinning the client to the backend that served
+    /// `request`, when sticky sessions are enabled and the client is not
+    /// pinned to that backend already.
+    pub fn sticky_cookie_for(&self, request: &HttpRequest) -> Option<String> {
+        let name = self.sticky_cookie.as_ref()?;
+        let (rule, index) = self
+            .rules
+            .iter()
+            .filter(|rule| rule.backends.len() > 1)
+            .find_map(|rule| {
+                let index = rule
+                    .backends
+                    .iter()
+                    .position(|backend| serves(&backend.url, &request.uri))?;
+                Some((rule, index))
+            })?;
+
+        if self.pinned_backend(request) == Some(index) {
+            return None;
+        }
+        Some(format!("{}={}; Path={}; HttpOnly", name, index, rule.path))
+    }
+
+    /// Backend index carried in the request's sticky session cookie.
+    fn pinned_backend(&self, request: &HttpRequest) -> Option<usize> {
+        let name = self.sticky_cookie.as_ref()?;
+        request
+            .headers
+            .get_all("cookie")
+            .flat_map(|cookies| cookies.split(';'))
+            .filter_map(|cookie| cookie.trim().split_once('='))
+            .find(|(cookie, _)| cookie == name)
+            .and_then(|(_, value)| value.parse().ok())
+    }
+}
+
+impl Backend {
+    fn is_up(&self) -> bool {
+        !self.state.down.load(Ordering::Relaxed)
+    }
+}
+
+impl ReverseRule {
+    /// Index of a healthy backend for a request without a sticky session.
+    fn choose(&self) -> Option<usize> {
+        let count = self.backends.len();
+        let turn = self.next.fetch_add(1, Ordering::Relaxed);
+        // Healthy backends, starting at a rotating position
+        let mut candidates = (0..count)
+            .map(|offset| (turn + offset) % count)
+            .filter(|&index| self.backends[index].is_up());
+
+        match self.balance {
+            BalanceStrategy::RoundRobin => candidates.next(),
+            BalanceStrategy::LeastConnections => candidates
+                .min_by_key(|&index| self.backends[index].state.active.load(Ordering::Relaxed)),
+            BalanceStrategy::Weighted => {
+                let up = |backend: &&Backend| backend.is_up();
+                let total: usize = self
+                    .backends
+                    .iter()
+                    .filter(up)
+                    .map(|backend| backend.weight as usize)
+                    .sum();
+                if total == 0 {
+                    return None;
+                }
+                let mut position = turn % total;
+                self.backends.iter().position(|backend| {
+                    if !backend.is_up() {
+                        return false;
+                    }
+                    let weight = backend.weight as usize;
+                    if position < weight {
+                        return true;
+                    }
+                    position -= weight;
+                    false
+                })
+            }
+        }
+    }
+}
+
+/// Request the health check path from a backend and compare the status.
+async fn probe(
+    backend: &Backend,
+    check: &HealthCheckConfig,
+    connector: &dyn Connector,
+) -> Result<(), String> {
+    let exchange = async {
+        let mut stream = connector
+            .connect(&backend.host, backend.port)
+            .await
+            .map_err(|e| e.to_string())?;
+        let request = format!(
+            "GET {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: tinyproxy-rust health check\r\nConnection: close\r\n\r\n",
+            check.path, backend.authority
+        );
+        stream
+            .write_all(request.as_bytes())
+            .await
+            .map_err(|e| e.to_string())?;
+
+        let mut head = Vec::new();
+        let mut scanner = HeadScanner::new();
+        let mut buf = [0u8; 4096];
+        let end = loop {
+            if let Some(end) = scanner.find(&head) {
+                break end;
+            }
+            match stream.read(&mut buf).await {
+                Ok(0) => return Err("connection closed before a response".to_string()),
+                Ok(n) if head.len() < 65536 => head.extend_from_slice(&buf[..n]),
+                Ok(_) => return Err("response head too large".to_string()),
+                Err(e) => return Err(e.to_string()),
+            }
+        };
+        let response = parse_http_response(&head[..end + 4]).map_err(|e| e.to_string())?;
+        if response.status != check.status {
+            return Err(format!(
+                "{} answered {} instead of {}",
+                check.path, response.status, check.status
+            ));
+        }
+        Ok(())
+    };
+
+    let limit = Duration::from_secs(check.interval).min(MAX_PROBE_TIME);
+    timeout(limit, exchange)
+        .await
+        .unwrap_or_else(|_| Err("no answer in time".to_string()))
+}
+
+/// Host and port of a backend URL, as sent in the Host header.
+fn authority(url: &str) -> String {
+    let after_scheme = url.split_once("://").map_or(url, |(_, rest)| rest);
+    let end = after_scheme.find(['/', '?']).unwrap_or(after_scheme.len());
+    after_scheme[..end].to_string()
+}
+
+/// Append the part of a request path after the ReversePath prefix to a
+/// backend URL, with exactly one slash between them.
+fn join(base: &str, rest: &str) -> String {
+    match (base.ends_with('/'), rest.starts_with('/')) {
+        (true, true) => format!("{}{}", base, &rest[1..]),
+        (false, false) if !rest.is_empty() && !rest.starts_with('?') => {
+            format!("{}/{}", base, rest)
+        }
+        _ => format!("{}{}", base, rest),
+    }
+}
+
+/// Whether `uri` is a target under the backend URL `base`.
+fn serves(base: &str, uri: &str) -> bool {
+    match uri.strip_prefix(base.trim_end_matches('/')) {
+        Some(rest) => rest.is_empty() || rest.starts_with(['/', '?']),
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::connector::{BoxedStream, ConnectFailure};
+    use crate::headers::Headers;
+    use async_trait::async_trait;
+
+    fn request(uri: &str, cookie: Option<&str>) -> HttpRequest {
+        let mut headers: Headers = [("Host", "proxy.example")].into_iter().collect();
+        if let Some(cookie) = cookie {
+            headers.append("Cookie", cookie);
+        }
+        HttpRequest {
+            method: "GET".to_string(),
+            uri: uri.to_string(),
+            version: "1.1".to_string(),
+            headers,
+        }
+    }
+
+    #[test]
+    fn test_reverse_routing() {
+        let config = Config::parse_config(
+            "ReversePath \"/app/\" \"http://a.internal:8080/\" \"http://b.internal/app\"\n\
+             ReversePath \"/docs\" \"http://docs.internal/\"\n\
+             ReverseStickyCookie backend",
+        )
+        .unwrap();
+        let reverse = ReverseProxy::new(&config);
+
+        let mut first = request("/app/list?page=2", None);
+        assert_eq!(reverse.route(&mut first), Route::Backend);
+        assert_eq!(first.uri, "http://a.internal:8080/list?page=2");
+        assert_eq!(first.headers.get("host"), Some("a.internal:8080"));
+        assert_eq!(
+            reverse.sticky_cookie_for(&first).as_deref(),
+            Some("backend=0; Path=/app/; HttpOnly")
+        );
+
+        // Round robin without a cookie
+        let mut second = request("/app/list", None);
+        assert_eq!(reverse.route(&mut second), Route::Backend);
+        assert_eq!(second.uri, "http://b.internal/app/list");
+
+        // The cookie keeps a client on its backend
+        let mut pinned = request("/app/", Some("theme=dark; backend=1"));
+        assert_eq!(reverse.route(&mut pinned), Route::Backend);
+        assert_eq!(pinned.uri, "http://b.internal/app");
+        assert_eq!(reverse.sticky_cookie_for(&pinned), None);
+
+        // A single backend needs no cookie
+        let mut docs = request("/docs/guide", None);
+        assert_eq!(reverse.route(&mut docs), Route::Backend);
+        assert_eq!(docs.uri, "http://docs.internal/guide");
+        assert_eq!(reverse.sticky_cookie_for(&docs), None);
+
+        assert_eq!(reverse.route(&mut request("/other", None)), Route::NoMatch);
+        assert_eq!(
+            reverse.route(&mut request("http://a.internal:8080/", None)),
+            Route::NoMatch
+        );
+    }
+
+    #[test]
+    fn test_virtual_host_routing() {
+        let config = Config::parse_config(
+            "ReversePath \"/\" \"http://default.internal/\"\n\
+             ReversePath \"/\" \"http://apps.internal/\" host=*.apps.example.com\n\
+             ReversePath \"/api/\" \"http://api.internal/\" host=Portal.example.com\n\
+             ReversePath \"/\" \"http://portal.internal/\" host=portal.example.com",
+        )
+        .unwrap();
+        let reverse = ReverseProxy::new(&config);
+        let route = |host: &str, path: &str| {
+            let mut request = request(path, None);
+            request.headers.insert("Host", host);
+            assert_eq!(reverse.route(&mut request), Route::Backend);
+            request.uri
+        };
+
+        assert_eq!(
+            route("wiki.apps.example.com", "/page"),
+            "http://apps.internal/page"
+        );
+        assert_eq!(
+            route("portal.example.com:8080", "/api/users"),
+            "http://api.internal/users"
+        );
+        assert_eq!(
+            route("PORTAL.example.com", "/login"),
+            "http://portal.internal/login"
+        );
+        assert_eq!(route("apps.example.com", "/"), "http://default.internal/");
+        assert_eq!(
+            reverse.backends()[1].host.as_deref(),
+            Some("*.apps.example.com")
+        );
+    }
+
+    /// Backends answering health checks with 200 if their host name
+    /// starts with "up", and 503 otherwise.
+    struct HealthConnector;
+
+    #[async_trait]
+    impl Connector for HealthConnector {
+        async fn connect(&self, host: &str, _port: u16) -> Result<BoxedStream, ConnectFailure> {
+            let (proxy_side, mut backend) = tokio::io::duplex(4096);
+            let status = if host.starts_with("up") {
+                "200 OK"
+            } else {
+                "503 Service Unavailable"
+            };
+            tokio::spawn(async move {
+                let mut buf = [0u8; 1024];
+                let _ = backend.read(&mut buf).await.unwrap();
+                let response = format!("HTTP/1.1 {}\r\nContent-Length: 0\r\n\r\n", status);
+                backend.write_all(response.as_bytes()).await.unwrap();
+            });
+            Ok(Box::new(proxy_side))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_health_checks() {
+        let config = Config::parse_config(
+            "ReversePath \"/app/\" \"http://up.internal/\" \"http://sick.internal/\"\n\
+             ReversePath \"/old/\" \"http://sick.internal/\"\n\
+             ReverseHealthCheck /healthz 200 30",
+        )
+        .unwrap();
+        let reverse = ReverseProxy::new(&config);
+        reverse.check_health(&HealthConnector).await;
+
+        for _ in 0..3 {
+            let mut app = request("/app/", None);
+            assert_eq!(reverse.route(&mut app), Route::Backend);
+            assert_eq!(app.uri, "http://up.internal/");
+        }
+        assert_eq!(
+            reverse.route(&mut request("/old/", None)),
+            Route::Unavailable
+        );
+        assert_eq!(reverse.retry_after(), Duration::from_secs(30));
+
+        let healthy: Vec<bool> = reverse.backends().iter().map(|b| b.healthy).collect();
+        assert_eq!(healthy, [true, false, false]);
+    }
+
+    #[test]
+    fn test_balance_strategies() {
+        let pick = |reverse: &ReverseProxy| {
+            let mut request = request("/", None);
+            assert_eq!(reverse.route(&mut request), Route::Backend);
+            request.headers.get("host").unwrap().to_string()
+        };
+
+        let weighted = ReverseProxy::new(
+            &Config::parse_config(
+                "ReversePath \"/\" \"http://a/\" weight=3 \"http://b/\" balance=weighted",
+            )
+            .unwrap(),
+        );
+        let picks: Vec<String> = (0..8).map(|_| pick(&weighted)).collect();
+        assert_eq!(picks, ["a", "a", "a", "b", "a", "a", "a", "b"]);
+
+        let least = ReverseProxy::new(
+            &Config::parse_config(
+                "ReversePath \"/\" \"http://a/\" \"http://b/\" balance=least-connections",
+            )
+            .unwrap(),
+        );
+        let busy = least.track("http://a/slow").unwrap();
+        assert_eq!(pick(&least), "b");
+        assert_eq!(pick(&least), "b");
+        drop(busy);
+        let status = least.backends();
+        assert_eq!((status[0].active, status[0].requests), (0, 0));
+        assert_eq!((status[1].active, status[1].requests), (0, 2));
+    }
+}
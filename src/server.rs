@@ -1,36 +1,55 @@
 use crate::config::Config;
 use anyhow::Result;
 use log::{debug, error, info, warn};
+use socket2::SockRef;
+use std::net::SocketAddr;
 use std::sync::Arc;
 use std::time::Instant;
 use tokio::net::TcpListener;
-use tokio::sync::{mpsc, RwLock, Semaphore};
-use tokio::time::Duration;
+use tokio::sync::{mpsc, OwnedSemaphorePermit};
+use tokio::time::{timeout, Duration};
 
+use crate::admin::AdminServer;
 use crate::connection::ConnectionHandler;
-use crate::stats::Stats;
+use crate::connector::SocketTuning;
+use crate::interceptor::Interceptors;
+use crate::state::ServerState;
+use crate::stats::{Counter, Stats};
 
 #[derive(Clone)]
 pub struct ProxyServer {
     config: Arc<Config>,
-    stats: Arc<RwLock<Stats>>,
     shutdown_tx: mpsc::Sender<()>,
     shutdown_rx: Arc<tokio::sync::Mutex<mpsc::Receiver<()>>>,
-    connection_semaphore: Arc<Semaphore>,
+    state: Arc<ServerState>,
 }
 
 impl ProxyServer {
     pub async fn new(config: Arc<Config>) -> Result<Self> {
+        Self::with_interceptors(config, Interceptors::new()).await
+    }
+
+    /// Create a server that applies custom interceptors to every request
+    /// and response, after the built-in policy stages.
+    pub async fn with_interceptors(
+        config: Arc<Config>,
+        interceptors: Interceptors,
+    ) -> Result<Self> {
+        Self::with_state(ServerState::with_interceptors(config, interceptors)).await
+    }
+
+    /// Create a server from prepared state, e.g. with a custom connector
+    /// for reaching upstream targets.
+    pub async fn with_state(state: ServerState) -> Result<Self> {
         let (shutdown_tx, shutdown_rx) = mpsc::channel(1);
-        let stats = Arc::new(RwLock::new(Stats::new()));
-        let connection_semaphore = Arc::new(Semaphore::new(config.max_clients));
+        let config = state.config.clone();
+        let state = Arc::new(state);
 
         Ok(Self {
             config,
-            stats,
             shutdown_tx,
             shutdown_rx: Arc::new(tokio::sync::Mutex::new(shutdown_rx)),
-            connection_semaphore,
+            state,
         })
     }
 
@@ -45,10 +64,11 @@ impl ProxyServer {
                     info!("Listening on {}", addr);
                     listeners.push(listener);
                 }
-                Err(e) => {
+                Err(e) if self.config.require_all_listeners => {
                     error!("Failed to bind to {}: {}", addr, e);
                     return Err(e.into());
                 }
+                Err(e) => warn!("Failed to bind to {}, continuing without it: {}", addr, e),
             }
         }
 
@@ -59,6 +79,60 @@ impl ProxyServer {
         // Start the accept loop for each listener
         let mut tasks = Vec::new();
 
+        if self.config.admin_port.is_some() {
+            let admin = AdminServer::bind(self.state.clone())?;
+            info!("Admin API listening on {}", admin.local_addr()?);
+            tasks.push(tokio::spawn(admin.run()));
+        }
+
+        // Every port is bound; stop running as root
+        #[cfg(unix)]
+        crate::privileges::drop_privileges(&self.config)?;
+
+        if self.config.reverse_health_check.is_some() {
+            let state = self.state.clone();
+            tasks.push(tokio::spawn(async move {
+                state
+                    .reverse_proxy
+                    .run_health_checks(state.connector.as_ref())
+                    .await
+            }));
+        }
+
+        if self.state.remote_access_list.is_enabled() {
+            let state = self.state.clone();
+            tasks.push(tokio::spawn(async move {
+                state
+                    .remote_access_list
+                    .run(state.connector.clone(), &state.acl)
+                    .await
+            }));
+        }
+
+        #[cfg(unix)]
+        if self.state.authenticator.has_user_file() {
+            let state = self.state.clone();
+            tasks.push(tokio::spawn(async move {
+                state.authenticator.reload_on_hangup().await
+            }));
+        }
+
+        #[cfg(unix)]
+        if self.state.error_pages.is_enabled() {
+            let state = self.state.clone();
+            tasks.push(tokio::spawn(async move {
+                state.error_pages.reload_on_hangup().await
+            }));
+        }
+
+        #[cfg(all(unix, feature = "rustls"))]
+        if self.state.tls_interception.is_configured() {
+            let state = self.state.clone();
+            tasks.push(tokio::spawn(async move {
+                state.tls_interception.reload_on_hangup().await
+            }));
+        }
+
         for listener in listeners {
             let server = self.clone();
             let task = tokio::spawn(async move {
@@ -73,53 +147,104 @@ impl ProxyServer {
 
         info!("Shutdown signal received, waiting for connections to close...");
 
-        // Cancel all accept loops
+        // Stop accepting; the listeners close with their accept loops
         for task in tasks {
             task.abort();
         }
 
-        // Wait a bit for existing connections to finish
-        tokio::time::sleep(Duration::from_secs(5)).await;
+        // Let connections finish what they are doing, up to the timeout
+        let connections = &self.state.connections;
+        connections.drain();
+        let grace = Duration::from_secs(self.config.shutdown_timeout);
+        if tokio::time::timeout(grace, connections.wait_closed())
+            .await
+            .is_err()
+        {
+            warn!(
+                "Closing {} connections still open after {}s",
+                connections.count(),
+                grace.as_secs()
+            );
+            connections.close_all();
+            let _ = tokio::time::timeout(Duration::from_secs(1), connections.wait_closed()).await;
+        }
 
         info!("Server shutdown complete");
         Ok(())
     }
 
     async fn accept_loop(&self, listener: TcpListener) {
+        let tuning = SocketTuning::new(&self.config);
         loop {
             match listener.accept().await {
                 Ok((stream, addr)) => {
                     debug!("New connection from {}", addr);
+                    if tuning.is_enabled() {
+                        if let Err(e) = tuning.apply(SockRef::from(&stream)) {
+                            warn!("Failed to tune socket from {}: {}", addr, e);
+                        }
+                    }
 
-                    // Check if we can accept more connections
-                    let permit = match self.connection_semaphore.clone().try_acquire_owned() {
-                        Ok(permit) => permit,
-                        Err(_) => {
-                            warn!(
-                                "Connection limit reached, rejecting connection from {}",
-                                addr
-                            );
+                    let Some(client_permit) = self.state.client_limits.try_acquire(addr.ip())
+                    else {
+                        warn!(
+                            "Per-client connection limit reached, rejecting connection from {}",
+                            addr
+                        );
+                        let Some(overflow) = self.overflow_permit(addr) else {
                             continue;
-                        }
+                        };
+                        let handler = ConnectionHandler::new(stream, addr, self.state.clone());
+                        tokio::spawn(async move {
+                            if let Err(e) = handler
+                                .reject("too many connections from your address")
+                                .await
+                            {
+                                debug!("Failed to reject connection from {}: {}", addr, e);
+                            }
+                            drop(overflow);
+                        });
+                        continue;
                     };
 
-                    // Update connection stats
-                    {
-                        let mut stats = self.stats.write().await;
-                        stats.connections_opened += 1;
-                        stats.active_connections += 1;
-                    }
+                    // Connections finding every slot taken wait for one
+                    // without holding up others, as many as there are
+                    // overflow slots
+                    let slots = self.state.connection_slots.clone();
+                    let (slot, overflow) = match slots.clone().try_acquire_owned() {
+                        Ok(permit) => (Some(permit), None),
+                        Err(_) => match self.overflow_permit(addr) {
+                            Some(overflow) => (None, Some(overflow)),
+                            None => continue,
+                        },
+                    };
 
                     // Spawn a task to handle the connection
-                    let handler = ConnectionHandler::new(
-                        stream,
-                        addr,
-                        self.config.clone(),
-                        self.stats.clone(),
-                    );
-
-                    let stats_clone = self.stats.clone();
+                    let handler = ConnectionHandler::new(stream, addr, self.state.clone());
+
+                    let state = self.state.clone();
+                    let queue_timeout = Duration::from_secs(self.config.accept_queue_timeout);
                     tokio::spawn(async move {
+                        let permit = match slot {
+                            Some(permit) => permit,
+                            None => match timeout(queue_timeout, slots.acquire_owned()).await {
+                                Ok(Ok(permit)) => permit,
+                                _ => {
+                                    warn!(
+                                        "Connection limit reached, rejecting connection from {}",
+                                        addr
+                                    );
+                                    if let Err(e) = handler.reject("the proxy is busy").await {
+                                        debug!("Failed to reject connection from {}: {}", addr, e);
+                                    }
+                                    return;
+                                }
+                            },
+                        };
+                        drop(overflow);
+
+                        // Update connection stats
+                        state.counters.add(Counter::ConnectionsOpened, 1);
                         let start_time = Instant::now();
 
                         if let Err(e) = handler.handle().await {
@@ -127,15 +252,15 @@ impl ProxyServer {
                         }
 
                         // Update stats when connection is closed
-                        {
-                            let mut stats = stats_clone.write().await;
-                            stats.active_connections -= 1;
-                            stats.connections_closed += 1;
-                            stats.total_connection_time += start_time.elapsed();
-                        }
+                        state.counters.add(Counter::ConnectionsClosed, 1);
+                        state.counters.add(
+                            Counter::ConnectionTimeMicros,
+                            start_time.elapsed().as_micros() as u64,
+                        );
 
-                        // Release the connection permit
+                        // Release the connection permits
                         drop(permit);
+                        drop(client_permit);
                     });
                 }
                 Err(e) => {
@@ -147,13 +272,27 @@ impl ProxyServer {
         }
     }
 
+    /// A permit to wait for a slot or reject the connection from `addr`
+    /// in a task of its own, or None after closing the connection if too
+    /// many do already.
+    fn overflow_permit(&self, addr: SocketAddr) -> Option<OwnedSemaphorePermit> {
+        let permit = self.state.overflow_slots.clone().try_acquire_owned().ok();
+        if permit.is_none() {
+            warn!(
+                "Too many connections waiting, closing connection from {}",
+                addr
+            );
+            self.state.counters.add(Counter::RequestsDenied, 1);
+        }
+        permit
+    }
+
     pub async fn shutdown(&self) {
         info!("Initiating server shutdown...");
         let _ = self.shutdown_tx.send(()).await;
     }
 
     pub async fn get_stats(&self) -> Stats {
-        let stats = self.stats.read().await;
-        stats.clone()
+        self.state.stats_snapshot().await
     }
 }
@@ -0,0 +1,271 @@
+use crate::acl::IpList;
+use crate::config::Config;
+use crate::connector::{
+    Connector, DirectConnector, GuardedConnector, HttpTunnelConnector, SocketOptions, SocketTuning,
+};
+use crate::dns::DnsCache;
+use crate::utils::wildcard_match;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Largest TLS record, plus its header.
+pub const MAX_CLIENT_HELLO: usize = 5 + 16384;
+
+/// What the start of a tunnel tells about the TLS server name.
+#[derive(Debug, PartialEq, Eq)]
+pub enum ClientHello {
+    /// More data is needed to tell.
+    Incomplete,
+    /// A ClientHello naming this server.
+    ServerName(String),
+    /// Not a ClientHello, or one without a server name.
+    Unnamed,
+}
+
+/// Read the server name (SNI) from the first TLS record of a connection.
+pub fn parse_client_hello(data: &[u8]) -> ClientHello {
+    if data.is_empty() {
+        return ClientHello::Incomplete;
+    }
+    // Handshake record
+    if data[0] != 0x16 {
+        return ClientHello::Unnamed;
+    }
+    if data.len() < 5 {
+        return ClientHello::Incomplete;
+    }
+    let length = u16::from_be_bytes([data[3], data[4]]) as usize;
+    if data.len() < 5 + length {
+        return ClientHello::Incomplete;
+    }
+
+    match server_name(&data[5..5 + length]) {
+        Some(name) => ClientHello::ServerName(name),
+        None => ClientHello::Unnamed,
+    }
+}
+
+/// Server name from a record holding (the start of) a ClientHello.
+fn server_name(record: &[u8]) -> Option<String> {
+    let mut reader = Reader(record);
+    // ClientHello, its length, version and random
+    if reader.u8()? != 1 {
+        return None;
+    }
+    reader.skip(3 + 2 + 32)?;
+    let session_id = reader.u8()? as usize;
+    reader.skip(session_id)?;
+    let cipher_suites = reader.u16()? as usize;
+    reader.skip(cipher_suites)?;
+    let compression = reader.u8()? as usize;
+    reader.skip(compression)?;
+
+    let mut extensions = Reader(reader.block()?);
+    while let Some(kind) = extensions.u16() {
+        let mut data = Reader(extensions.block()?);
+        if kind != 0 {
+            continue;
+        }
+        let mut names = Reader(data.block()?);
+        while let Some(name_type) = names.u8() {
+            let name = names.block()?;
+            if name_type == 0 {
+                return std::str::from_utf8(name)
+                    .ok()
+                    .filter(|name| !name.is_empty())
+                    .map(|name| name.to_lowercase());
+            }
+        }
+    }
+    None
+}
+
+/// Reads big-endian fields, returning `None` past the end.
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, count: usize) -> Option<&'a [u8]> {
+        if self.0.len() < count {
+            return None;
+        }
+        let (taken, rest) = self.0.split_at(count);
+        self.0 = rest;
+        Some(taken)
+    }
+
+    fn skip(&mut self, count: usize) -> Option<()> {
+        self.take(count).map(|_| ())
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.take(1).map(|bytes| bytes[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.take(2)
+            .map(|bytes| u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    /// A field preceded by its 16-bit length.
+    fn block(&mut self) -> Option<&'a [u8]> {
+        let length = self.u16()? as usize;
+        self.take(length)
+    }
+}
+
+/// Egress chosen for CONNECT tunnels by the TLS server name the client
+/// asks for (SniRoute), such as another interface or an upstream proxy.
+/// The first matching route wins; other tunnels use the normal connector.
+pub struct SniRouter {
+    routes: Vec<(String, Arc<dyn Connector>)>,
+}
+
+impl SniRouter {
+    pub fn new(config: &Config) -> Self {
+        if config.sni_routes.is_empty() {
+            return Self { routes: vec![] };
+        }
+        let denied = Arc::new(IpList::new(
+            &config.deny_target_networks,
+            "DenyTargetNetworks",
+        ));
+        let routes = config
+            .sni_routes
+            .iter()
+            .map(|route| {
+                let direct: Arc<dyn Connector> = Arc::new(
+                    DirectConnector::new(Duration::from_secs(config.connect_timeout)).with_options(
+                        SocketOptions {
+                            interface: route
+                                .interface
+                                .clone()
+                                .or_else(|| config.outgoing_interface.clone()),
+                            mark: route.mark.or(config.outgoing_mark),
+                            tuning: SocketTuning::new(config),
+                        },
+                    ),
+                );
+                let connector = match &route.upstream {
+                    Some((host, port)) => {
+                        // Credentials come from a matching Upstream line
+                        let authorization = config
+                            .upstream
+                            .iter()
+                            .find(|upstream| upstream.host == *host && upstream.port == *port)
+                            .and_then(|upstream| upstream.proxy_authorization());
+                        Arc::new(
+                            HttpTunnelConnector::new(direct, host, *port)
+                                .with_authorization(authorization),
+                        )
+                    }
+                    None if denied.is_empty() => direct,
+                    None => Arc::new(GuardedConnector::new(
+                        direct,
+                        denied.clone(),
+                        Arc::new(DnsCache::default()),
+                    )),
+                };
+                (route.pattern.clone(), connector)
+            })
+            .collect();
+        Self { routes }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.routes.is_empty()
+    }
+
+    /// Connector for tunnels to `server_name`, if a route matches.
+    pub fn route(&self, server_name: &str) -> Option<Arc<dyn Connector>> {
+        let server_name = server_name.to_lowercase();
+        self.routes
+            .iter()
+            .find(|(pattern, _)| wildcard_match(pattern, &server_name))
+            .map(|(_, connector)| connector.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A TLS 1.2 ClientHello record with the given SNI extension data.
+    fn client_hello(extensions: &[&[u8]]) -> Vec<u8> {
+        let extensions: Vec<u8> = extensions.concat();
+        let mut body = vec![3, 3];
+        body.extend_from_slice(&[0; 32]);
+        body.push(0); // session id
+        body.extend_from_slice(&[0, 2, 0x13, 0x01]); // one cipher suite
+        body.extend_from_slice(&[1, 0]); // null compression
+        body.extend_from_slice(&(extensions.len() as u16).to_be_bytes());
+        body.extend_from_slice(&extensions);
+
+        let mut handshake = vec![1];
+        handshake.extend_from_slice(&(body.len() as u32).to_be_bytes()[1..]);
+        handshake.extend_from_slice(&body);
+
+        let mut record = vec![0x16, 3, 1];
+        record.extend_from_slice(&(handshake.len() as u16).to_be_bytes());
+        record.extend_from_slice(&handshake);
+        record
+    }
+
+    fn sni(name: &str) -> Vec<u8> {
+        let mut entry = vec![0];
+        entry.extend_from_slice(&(name.len() as u16).to_be_bytes());
+        entry.extend_from_slice(name.as_bytes());
+        let mut list = (entry.len() as u16).to_be_bytes().to_vec();
+        list.extend_from_slice(&entry);
+        let mut extension = vec![0, 0];
+        extension.extend_from_slice(&(list.len() as u16).to_be_bytes());
+        extension.extend_from_slice(&list);
+        extension
+    }
+
+    #[test]
+    fn test_parse_client_hello() {
+        // An ALPN extension before the server name
+        let alpn: &[u8] = &[0, 16, 0, 5, 0, 3, 2, b'h', b'2'];
+        let record = client_hello(&[alpn, &sni("WWW.Example.com")]);
+        assert_eq!(
+            parse_client_hello(&record),
+            ClientHello::ServerName("www.example.com".to_string())
+        );
+        assert_eq!(
+            parse_client_hello(&record[..record.len() - 1]),
+            ClientHello::Incomplete
+        );
+        assert_eq!(parse_client_hello(&record[..3]), ClientHello::Incomplete);
+
+        assert_eq!(
+            parse_client_hello(&client_hello(&[alpn])),
+            ClientHello::Unnamed
+        );
+        assert_eq!(
+            parse_client_hello(b"SSH-2.0-OpenSSH_9.6\r\n"),
+            ClientHello::Unnamed
+        );
+        // Lengths pointing past the record
+        let mut truncated = client_hello(&[&sni("example.com")]);
+        let end = truncated.len();
+        truncated[end - 12] = 0xff;
+        assert_eq!(parse_client_hello(&truncated), ClientHello::Unnamed);
+    }
+
+    #[test]
+    fn test_route() {
+        let config = Config::parse_config(
+            "Upstream http:parent.test:3128 bob:hunter2\n\
+             SniRoute *.bank.example upstream=parent.test:3128\n\
+             SniRoute *.example mark=0x10",
+        )
+        .unwrap();
+        let router = SniRouter::new(&config);
+        assert!(router.is_enabled());
+        let bank = router.route("www.Bank.example").unwrap();
+        let video = router.route("video.example").unwrap();
+        assert!(!Arc::ptr_eq(&bank, &video));
+        assert!(router.route("example.org").is_none());
+        assert!(!SniRouter::new(&Config::default()).is_enabled());
+    }
+}
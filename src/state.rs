@@ -0,0 +1,180 @@
+use crate::access_log::AccessLog;
+use crate::acl::{AccessControl, IpList};
+use crate::acl_sync::RemoteAccessList;
+use crate::auth::Authenticator;
+use crate::circuit::CircuitBreakers;
+use crate::config::{Config, RecordingMode};
+use crate::connector::{Connector, DirectConnector, GuardedConnector, SocketOptions, SocketTuning};
+use crate::denial::DenialLog;
+use crate::dns::DnsCache;
+use crate::error_pages::ErrorPages;
+use crate::filter::Filter;
+use crate::interceptor::Interceptors;
+use crate::mirror::Mirrors;
+#[cfg(feature = "rustls")]
+use crate::mitm::TlsInterception;
+use crate::pool::ConnectionPool;
+use crate::proxy::ProxyLogic;
+use crate::ratelimit::{
+    ClientConnectionLimits, DestinationConnectionLimits, DestinationRateLimits,
+};
+use crate::record::{RecordingConnector, ReplayConnector};
+use crate::registry::ConnectionRegistry;
+use crate::reverse::ReverseProxy;
+use crate::sni::SniRouter;
+use crate::stats::{ProcessStats, ShardedCounters, Stats};
+use crate::tarpit::Tarpit;
+use crate::throttle::ClientBandwidth;
+use std::sync::{Arc, RwLock as SyncRwLock};
+use std::time::Duration;
+use tokio::sync::{RwLock, Semaphore};
+
+/// Connections that may wait for a client slot or be sent a busy page at
+/// once; further ones are closed right away.
+const OVERFLOW_SLOTS: usize = 64;
+
+/// State shared by all client connections and the admin API.
+pub struct ServerState {
+    pub config: Arc<Config>,
+    pub stats: Arc<RwLock<Stats>>,
+    /// Per-request counters, kept outside `stats` to avoid its lock.
+    pub counters: ShardedCounters,
+    pub acl: SyncRwLock<AccessControl>,
+    pub remote_access_list: RemoteAccessList,
+    pub filter: SyncRwLock<Filter>,
+    pub client_bandwidth: ClientBandwidth,
+    pub client_limits: ClientConnectionLimits,
+    pub destination_limits: DestinationRateLimits,
+    pub connection_limits: DestinationConnectionLimits,
+    pub circuit_breakers: CircuitBreakers,
+    pub mirrors: Mirrors,
+    pub reverse_proxy: ReverseProxy,
+    pub sni_router: SniRouter,
+    #[cfg(feature = "rustls")]
+    pub tls_interception: TlsInterception,
+    pub tarpit: Tarpit,
+    pub denial_log: DenialLog,
+    pub authenticator: Authenticator,
+    pub access_log: AccessLog,
+    pub error_pages: ErrorPages,
+    pub connections: Arc<ConnectionRegistry>,
+    /// One permit per client connection, up to MaxClients.
+    pub connection_slots: Arc<Semaphore>,
+    /// One permit per connection waiting for a slot or being rejected.
+    pub overflow_slots: Arc<Semaphore>,
+    pub interceptors: Interceptors,
+    /// Upstream proxy selection and the other policy shared with the
+    /// built-in interceptors.
+    pub proxy: Arc<ProxyLogic>,
+    pub dns_cache: Arc<DnsCache>,
+    /// Networks clients may not reach through the proxy (DenyTargetNetworks).
+    pub denied_targets: Arc<IpList>,
+    /// Idle origin connections for reuse.
+    pub origin_pool: ConnectionPool,
+    pub connector: Arc<dyn Connector>,
+}
+
+impl ServerState {
+    pub fn new(config: Arc<Config>) -> Self {
+        Self::with_interceptors(config, Interceptors::new())
+    }
+
+    /// State whose interceptor chains run `custom` after the built-in
+    /// stages.
+    pub fn with_interceptors(config: Arc<Config>, custom: Interceptors) -> Self {
+        let proxy = Arc::new(ProxyLogic::new(config.clone()));
+        let mut interceptors = Interceptors::builtin(&config, &proxy);
+        interceptors.extend(custom);
+        let dns_cache = Arc::new(DnsCache::new(&config));
+        #[cfg(not(feature = "rustls"))]
+        if config.tls_intercept {
+            log::warn!("TLS interception disabled: built without the rustls feature");
+        }
+
+        Self {
+            stats: Arc::new(RwLock::new(Stats::new())),
+            counters: ShardedCounters::new(),
+            acl: SyncRwLock::new(AccessControl::new(&config)),
+            remote_access_list: RemoteAccessList::new(&config),
+            filter: SyncRwLock::new(Filter::new(&config)),
+            client_bandwidth: ClientBandwidth::new(&config),
+            client_limits: ClientConnectionLimits::new(&config),
+            destination_limits: DestinationRateLimits::new(&config),
+            connection_limits: DestinationConnectionLimits::new(&config),
+            circuit_breakers: CircuitBreakers::new(&config),
+            mirrors: Mirrors::new(&config),
+            reverse_proxy: ReverseProxy::new(&config),
+            sni_router: SniRouter::new(&config),
+            #[cfg(feature = "rustls")]
+            tls_interception: TlsInterception::new(&config),
+            tarpit: Tarpit::new(&config),
+            denial_log: DenialLog::new(&config),
+            authenticator: Authenticator::new(&config),
+            access_log: AccessLog::new(&config),
+            error_pages: ErrorPages::new(&config),
+            connections: Arc::new(ConnectionRegistry::new()),
+            connection_slots: Arc::new(Semaphore::new(config.max_clients)),
+            overflow_slots: Arc::new(Semaphore::new(OVERFLOW_SLOTS)),
+            interceptors,
+            proxy,
+            connector: default_connector(&config, dns_cache.clone()),
+            dns_cache,
+            denied_targets: Arc::new(IpList::new(
+                &config.deny_target_networks,
+                "DenyTargetNetworks",
+            )),
+            origin_pool: ConnectionPool::new(&config),
+            config,
+        }
+    }
+
+    /// Connector for targets chosen by clients: `connector`, refusing
+    /// DenyTargetNetworks addresses if any are configured.
+    pub fn target_connector(&self, connector: Arc<dyn Connector>) -> Arc<dyn Connector> {
+        if self.denied_targets.is_empty() {
+            return connector;
+        }
+        Arc::new(GuardedConnector::new(
+            connector,
+            self.denied_targets.clone(),
+            self.dns_cache.clone(),
+        ))
+    }
+
+    /// Current statistics, including the sharded counters.
+    pub async fn stats_snapshot(&self) -> Stats {
+        let mut stats = self.stats.read().await.clone();
+        self.counters.apply_to(&mut stats);
+        stats.update_uptime();
+        stats.process = ProcessStats {
+            client_slots_in_use: self.config.max_clients
+                - self.connection_slots.available_permits(),
+            max_clients: self.config.max_clients,
+            tarpit_slots_in_use: self.tarpit.slots_in_use(),
+            ..ProcessStats::collect()
+        };
+        stats
+    }
+}
+
+/// Direct connections with the configured socket options, recorded or
+/// replaced by recordings if configured.
+fn default_connector(config: &Config, dns_cache: Arc<DnsCache>) -> Arc<dyn Connector> {
+    let direct = Arc::new(
+        DirectConnector::new(Duration::from_secs(config.connect_timeout))
+            .with_options(SocketOptions {
+                interface: config.outgoing_interface.clone(),
+                mark: config.outgoing_mark,
+                tuning: SocketTuning::new(config),
+            })
+            .with_dns_cache(dns_cache),
+    );
+
+    match &config.recording {
+        Some(recording) if recording.mode == RecordingMode::Record => {
+            Arc::new(RecordingConnector::new(direct, &recording.dir))
+        }
+        Some(recording) => Arc::new(ReplayConnector::new(&recording.dir)),
+        None => direct,
+    }
+}
@@ -1,7 +1,216 @@
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Mutex;
 use std::time::Duration;
 
+/// Number of destinations listed on the statistics page.
+const TOP_DESTINATIONS: usize = 20;
+
+/// Client software families listed on the statistics page.
+const TOP_USER_AGENTS: usize = 20;
+
+/// Client software families counted separately; later ones count as
+/// [`OTHER_USER_AGENTS`].
+const MAX_USER_AGENTS: usize = 200;
+
+const OTHER_USER_AGENTS: &str = "Other";
+
+/// Upper bounds of the duration histogram buckets, in milliseconds; the
+/// last bucket holds everything longer.
+const DURATION_BOUNDS: [u64; 8] = [
+    100,
+    1_000,
+    10_000,
+    60_000,
+    600_000,
+    3_600_000,
+    6 * 3_600_000,
+    24 * 3_600_000,
+];
+
+const DURATION_LABELS: [&str; 9] = [
+    "&lt; 100 ms",
+    "&lt; 1 s",
+    "&lt; 10 s",
+    "&lt; 1 min",
+    "&lt; 10 min",
+    "&lt; 1 h",
+    "&lt; 6 h",
+    "&lt; 24 h",
+    "24 h or more",
+];
+
+/// Counters updated for every connection or request.
+#[derive(Debug, Clone, Copy)]
+pub enum Counter {
+    ConnectionsOpened,
+    ConnectionsClosed,
+    ConnectionTimeMicros,
+    RequestsProcessed,
+    RequestsDenied,
+    BytesTransferred,
+    /// Bytes read from clients: request heads and bodies, tunnel uploads.
+    BytesReceived,
+    /// Bytes written to clients: response heads and bodies, tunnel downloads.
+    BytesSent,
+    /// Connections closed for sending a request head too slowly.
+    SlowClients,
+}
+
+const COUNTERS: usize = 9;
+
+/// Buckets of a duration histogram.
+const DURATION_BUCKETS: usize = DURATION_BOUNDS.len() + 1;
+
+/// How long completed exchanges of a kind lasted.
+#[derive(Debug, Clone, Copy)]
+pub enum Histogram {
+    RequestDurations,
+    TunnelDurations,
+}
+
+const HISTOGRAMS: usize = 2;
+
+static NEXT_SHARD: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    static SHARD_INDEX: usize = NEXT_SHARD.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Hot counters sharded by thread. Every worker thread increments its own
+/// cache line, so busy threads never contend; readers sum the shards.
+/// Duration histograms are bucket counters in the shards too. Requests
+/// per client software family are kept in a map per shard, merged when
+/// read.
+pub struct ShardedCounters {
+    shards: Box<[Shard]>,
+}
+
+#[repr(align(128))]
+#[derive(Default)]
+struct Shard {
+    counters: [AtomicU64; COUNTERS],
+    histograms: [[AtomicU64; DURATION_BUCKETS]; HISTOGRAMS],
+    user_agents: Mutex<HashMap<String, AgentUsage>>,
+}
+
+impl ShardedCounters {
+    pub fn new() -> Self {
+        let threads = std::thread::available_parallelism()
+            .map(|threads| threads.get())
+            .unwrap_or(4);
+        Self::with_shards(threads * 2)
+    }
+
+    fn with_shards(shards: usize) -> Self {
+        Self {
+            shards: (0..shards.max(1)).map(|_| Shard::default()).collect(),
+        }
+    }
+
+    fn shard(&self) -> &Shard {
+        let index = SHARD_INDEX.with(|index| *index) % self.shards.len();
+        &self.shards[index]
+    }
+
+    pub fn add(&self, counter: Counter, value: u64) {
+        self.shard().counters[counter as usize].fetch_add(value, Ordering::Relaxed);
+    }
+
+    pub fn get(&self, counter: Counter) -> u64 {
+        self.shards
+            .iter()
+            .map(|shard| shard.counters[counter as usize].load(Ordering::Relaxed))
+            .sum()
+    }
+
+    /// Count an exchange that took `duration` in its histogram bucket.
+    pub fn record_duration(&self, histogram: Histogram, duration: Duration) {
+        let millis = duration.as_millis();
+        let bucket = DURATION_BOUNDS
+            .iter()
+            .position(|&bound| millis < bound as u128)
+            .unwrap_or(DURATION_BOUNDS.len());
+        self.shard().histograms[histogram as usize][bucket].fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn histogram(&self, histogram: Histogram) -> DurationHistogram {
+        let mut counts = [0; DURATION_BUCKETS];
+        for shard in self.shards.iter() {
+            for (count, bucket) in counts.iter_mut().zip(&shard.histograms[histogram as usize]) {
+                *count += bucket.load(Ordering::Relaxed);
+            }
+        }
+        DurationHistogram { counts }
+    }
+
+    /// Count a request from client software `family` that moved `bytes`.
+    pub fn record_user_agent(&self, family: &str, bytes: u64) {
+        let usage = AgentUsage { requests: 1, bytes };
+        let mut user_agents = self.shard().user_agents.lock().unwrap();
+        add_user_agent(&mut user_agents, family, &usage);
+    }
+
+    /// Fill in the counter-backed fields of a statistics snapshot.
+    pub fn apply_to(&self, stats: &mut Stats) {
+        stats.connections_opened = self.get(Counter::ConnectionsOpened);
+        stats.connections_closed = self.get(Counter::ConnectionsClosed);
+        stats.active_connections = stats
+            .connections_opened
+            .saturating_sub(stats.connections_closed);
+        stats.total_connection_time =
+            Duration::from_micros(self.get(Counter::ConnectionTimeMicros));
+        stats.requests_processed = self.get(Counter::RequestsProcessed);
+        stats.requests_denied = self.get(Counter::RequestsDenied);
+        stats.bytes_transferred = self.get(Counter::BytesTransferred);
+        stats.bytes_received = self.get(Counter::BytesReceived);
+        stats.bytes_sent = self.get(Counter::BytesSent);
+        stats.slow_clients = self.get(Counter::SlowClients);
+        stats.request_durations = self.histogram(Histogram::RequestDurations);
+        stats.tunnel_durations = self.histogram(Histogram::TunnelDurations);
+
+        // The busiest families keep their place when the shards together
+        // saw more than the table holds
+        let mut user_agents: HashMap<String, AgentUsage> = HashMap::new();
+        for shard in self.shards.iter() {
+            for (family, usage) in shard.user_agents.lock().unwrap().iter() {
+                add_usage(user_agents.entry(family.clone()).or_default(), usage);
+            }
+        }
+        let mut families: Vec<_> = user_agents.into_iter().collect();
+        families.sort_by(|a, b| b.1.requests.cmp(&a.1.requests).then(a.0.cmp(&b.0)));
+        stats.user_agents.clear();
+        for (family, usage) in families {
+            add_user_agent(&mut stats.user_agents, &family, &usage);
+        }
+    }
+}
+
+/// Add `usage` to the entry for `family`, or to [`OTHER_USER_AGENTS`] once
+/// the table holds [`MAX_USER_AGENTS`] other families.
+fn add_user_agent(agents: &mut HashMap<String, AgentUsage>, family: &str, usage: &AgentUsage) {
+    let families = agents.len() - usize::from(agents.contains_key(OTHER_USER_AGENTS));
+    let family = if families >= MAX_USER_AGENTS && !agents.contains_key(family) {
+        OTHER_USER_AGENTS
+    } else {
+        family
+    };
+    add_usage(agents.entry(family.to_string()).or_default(), usage);
+}
+
+fn add_usage(total: &mut AgentUsage, usage: &AgentUsage) {
+    total.requests += usage.requests;
+    total.bytes += usage.bytes;
+}
+
+impl Default for ShardedCounters {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Stats {
     // Connection statistics
@@ -9,6 +218,8 @@ pub struct Stats {
     pub connections_closed: u64,
     pub active_connections: u64,
     pub total_connection_time: Duration,
+    /// Connections closed for sending request heads too slowly.
+    pub slow_clients: u64,
 
     // Request statistics
     pub requests_processed: u64,
@@ -24,6 +235,16 @@ pub struct Stats {
     pub average_request_time: Duration,
     pub peak_connections: u64,
 
+    // Currently open upstream connections per destination host
+    pub upstream_connections: HashMap<String, u64>,
+
+    // Requests and bytes per client software family
+    pub user_agents: HashMap<String, AgentUsage>,
+
+    // How long completed HTTP requests and CONNECT tunnels lasted
+    pub request_durations: DurationHistogram,
+    pub tunnel_durations: DurationHistogram,
+
     // Filter statistics
     pub requests_filtered: u64,
 
@@ -34,6 +255,107 @@ pub struct Stats {
     // Server statistics
     pub start_time: DateTime<Utc>,
     pub uptime: Duration,
+    pub process: ProcessStats,
+}
+
+/// Requests made by one client software family and the bytes they moved.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AgentUsage {
+    pub requests: u64,
+    pub bytes: u64,
+}
+
+/// Counts of completed exchanges by how long they lasted, in buckets from
+/// under 100 ms to a day or more.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DurationHistogram {
+    /// One count per bucket, shortest first.
+    pub counts: [u64; DURATION_BUCKETS],
+}
+
+/// Resource usage of the proxy process, to spot capacity problems before
+/// limits are hit. Figures the platform does not provide are `None`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ProcessStats {
+    /// Resident memory in bytes.
+    pub resident_memory: Option<u64>,
+    pub open_files: Option<u64>,
+    /// Soft limit on open files.
+    pub max_open_files: Option<u64>,
+    /// Tasks alive on the async runtime.
+    pub tasks: Option<usize>,
+    pub client_slots_in_use: usize,
+    pub max_clients: usize,
+    pub tarpit_slots_in_use: usize,
+}
+
+impl ProcessStats {
+    /// Read the figures kept by the operating system and the runtime. Slot
+    /// usage is left for the caller to fill in.
+    pub fn collect() -> Self {
+        Self {
+            resident_memory: resident_memory(),
+            open_files: std::fs::read_dir("/proc/self/fd")
+                .ok()
+                // Reading the directory takes a descriptor of its own
+                .map(|entries| (entries.count() as u64).saturating_sub(1)),
+            max_open_files: max_open_files(),
+            tasks: tokio::runtime::Handle::try_current()
+                .ok()
+                .map(|runtime| runtime.metrics().num_alive_tasks()),
+            ..Self::default()
+        }
+    }
+
+    fn to_html(&self) -> String {
+        let known = |value: Option<String>| value.unwrap_or_else(|| "n/a".to_string());
+        let open_files = match (self.open_files, self.max_open_files) {
+            (Some(open), Some(max)) => Some(format!("{} / {}", open, max)),
+            (open, _) => open.map(|open| open.to_string()),
+        };
+        format!(
+            r#"    <div class="section">
+        <h2>Process Resources</h2>
+        <table>
+            <tr><th>Metric</th><th>Value</th></tr>
+            <tr><td>Resident Memory</td><td class="value">{}</td></tr>
+            <tr><td>Open Files</td><td class="value">{}</td></tr>
+            <tr><td>Runtime Tasks</td><td class="value">{}</td></tr>
+            <tr><td>Client Slots In Use</td><td class="value">{} / {}</td></tr>
+            <tr><td>Tarpit Slots In Use</td><td class="value">{}</td></tr>
+        </table>
+    </div>
+"#,
+            known(self.resident_memory.map(format_bytes)),
+            known(open_files),
+            known(self.tasks.map(|tasks| tasks.to_string())),
+            self.client_slots_in_use,
+            self.max_clients,
+            self.tarpit_slots_in_use
+        )
+    }
+}
+
+/// Resident set size, from /proc on Linux.
+fn resident_memory() -> Option<u64> {
+    let statm = std::fs::read_to_string("/proc/self/statm").ok()?;
+    let pages: u64 = statm.split_whitespace().nth(1)?.parse().ok()?;
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+    u64::try_from(page_size).ok().map(|size| pages * size)
+}
+
+fn max_open_files() -> Option<u64> {
+    let mut limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) } != 0
+        || limit.rlim_cur == libc::RLIM_INFINITY
+    {
+        return None;
+    }
+    #[allow(clippy::unnecessary_cast)] // rlim_t is not u64 everywhere
+    Some(limit.rlim_cur as u64)
 }
 
 impl Stats {
@@ -45,6 +367,7 @@ impl Stats {
             total_connection_time: Duration::new(0, 0),
 
             requests_processed: 0,
+            slow_clients: 0,
             requests_denied: 0,
             requests_failed: 0,
 
@@ -55,6 +378,11 @@ impl Stats {
             average_request_time: Duration::new(0, 0),
             peak_connections: 0,
 
+            upstream_connections: HashMap::new(),
+            user_agents: HashMap::new(),
+            request_durations: DurationHistogram::default(),
+            tunnel_durations: DurationHistogram::default(),
+
             requests_filtered: 0,
 
             auth_attempts: 0,
@@ -62,6 +390,7 @@ impl Stats {
 
             start_time: Utc::now(),
             uptime: Duration::new(0, 0),
+            process: ProcessStats::default(),
         }
     }
 
@@ -78,6 +407,47 @@ impl Stats {
         }
     }
 
+    pub fn upstream_connection_opened(&mut self, host: &str) {
+        *self
+            .upstream_connections
+            .entry(host.to_lowercase())
+            .or_insert(0) += 1;
+    }
+
+    pub fn upstream_connection_closed(&mut self, host: &str) {
+        let host = host.to_lowercase();
+        if let Some(count) = self.upstream_connections.get_mut(&host) {
+            *count = count.saturating_sub(1);
+            if *count == 0 {
+                self.upstream_connections.remove(&host);
+            }
+        }
+    }
+
+    /// Destinations with the most open upstream connections, busiest first.
+    pub fn top_upstream_connections(&self, limit: usize) -> Vec<(&str, u64)> {
+        let mut destinations: Vec<(&str, u64)> = self
+            .upstream_connections
+            .iter()
+            .map(|(host, count)| (host.as_str(), *count))
+            .collect();
+        destinations.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+        destinations.truncate(limit);
+        destinations
+    }
+
+    /// Client software families with the most requests, busiest first.
+    pub fn top_user_agents(&self, limit: usize) -> Vec<(&str, &AgentUsage)> {
+        let mut agents: Vec<(&str, &AgentUsage)> = self
+            .user_agents
+            .iter()
+            .map(|(family, usage)| (family.as_str(), usage))
+            .collect();
+        agents.sort_by(|a, b| b.1.requests.cmp(&a.1.requests).then(a.0.cmp(b.0)));
+        agents.truncate(limit);
+        agents
+    }
+
     pub fn calculate_average_request_time(&mut self) {
         if self.requests_processed > 0 {
             let total_nanos = self.total_connection_time.as_nanos();
@@ -105,6 +475,48 @@ impl Stats {
     }
 
     pub fn to_html(&self) -> String {
+        self.to_html_with_sections("")
+    }
+
+    /// Render the statistics page with additional HTML sections appended
+    /// after the built-in ones.
+    pub fn to_html_with_sections(&self, sections: &str) -> String {
+        let upstream_rows: String = self
+            .top_upstream_connections(TOP_DESTINATIONS)
+            .iter()
+            .map(|(host, count)| {
+                format!(
+                    "            <tr><td>{}</td><td class=\"value\">{}</td></tr>\n",
+                    host, count
+                )
+            })
+            .collect();
+        let agent_rows: String = self
+            .top_user_agents(TOP_USER_AGENTS)
+            .iter()
+            .map(|(family, usage)| {
+                format!(
+                    "            <tr><td>{}</td><td class=\"value\">{}</td><td class=\"value\">{}</td></tr>\n",
+                    family,
+                    usage.requests,
+                    format_bytes(usage.bytes)
+                )
+            })
+            .collect();
+
+        let duration_rows: String = DURATION_LABELS
+            .iter()
+            .enumerate()
+            .map(|(bucket, label)| {
+                format!(
+                    "            <tr><td>{}</td><td class=\"value\">{}</td><td class=\"value\">{}</td></tr>\n",
+                    label,
+                    self.request_durations.counts[bucket],
+                    self.tunnel_durations.counts[bucket]
+                )
+            })
+            .collect();
+
         format!(
             r#"<!DOCTYPE html>
 <html>
@@ -129,6 +541,7 @@ impl Stats {
         <div class="metric">Uptime: <span class="value">{}</span></div>
     </div>
 
+{}
     <div class="section">
         <h2>Connection Statistics</h2>
         <table>
@@ -138,6 +551,7 @@ impl Stats {
             <tr><td>Total Connections Closed</td><td class="value">{}</td></tr>
             <tr><td>Peak Connections</td><td class="value">{}</td></tr>
             <tr><td>Average Connection Time</td><td class="value">{:.2}s</td></tr>
+            <tr><td>Slow Clients Closed</td><td class="value">{}</td></tr>
         </table>
     </div>
 
@@ -153,16 +567,37 @@ impl Stats {
         </table>
     </div>
 
+    <div class="section">
+        <h2>Durations</h2>
+        <table>
+            <tr><th>Duration</th><th>HTTP Requests</th><th>CONNECT Tunnels</th></tr>
+{}        </table>
+    </div>
+
     <div class="section">
         <h2>Data Transfer</h2>
         <table>
             <tr><th>Metric</th><th>Value</th></tr>
             <tr><td>Total Bytes Transferred</td><td class="value">{}</td></tr>
-            <tr><td>Bytes Sent</td><td class="value">{}</td></tr>
-            <tr><td>Bytes Received</td><td class="value">{}</td></tr>
+            <tr><td>Bytes Sent to Clients</td><td class="value">{}</td></tr>
+            <tr><td>Bytes Received from Clients</td><td class="value">{}</td></tr>
         </table>
     </div>
 
+    <div class="section">
+        <h2>Open Upstream Connections</h2>
+        <table>
+            <tr><th>Destination</th><th>Connections</th></tr>
+{}        </table>
+    </div>
+
+    <div class="section">
+        <h2>Client Software</h2>
+        <table>
+            <tr><th>Agent</th><th>Requests</th><th>Bytes</th></tr>
+{}        </table>
+    </div>
+
     <div class="section">
         <h2>Authentication Statistics</h2>
         <table>
@@ -173,27 +608,34 @@ impl Stats {
         </table>
     </div>
 
+{}
     <p><em>Generated at: {}</em></p>
 </body>
 </html>"#,
             self.start_time.format("%Y-%m-%d %H:%M:%S UTC"),
             format_duration(&self.uptime),
+            self.process.to_html(),
             self.active_connections,
             self.connections_opened,
             self.connections_closed,
             self.peak_connections,
             self.average_request_time.as_secs_f64(),
+            self.slow_clients,
             self.requests_processed,
             self.requests_denied,
             self.requests_failed,
             self.requests_filtered,
             self.get_success_rate(),
+            duration_rows,
             format_bytes(self.bytes_transferred),
             format_bytes(self.bytes_sent),
             format_bytes(self.bytes_received),
+            upstream_rows,
+            agent_rows,
             self.auth_attempts,
             self.auth_failures,
             self.get_auth_success_rate(),
+            sections,
             Utc::now().format("%Y-%m-%d %H:%M:%S UTC")
         )
     }
@@ -209,6 +651,57 @@ impl Default for Stats {
     }
 }
 
+/// Client software family of a User-Agent header, such as "Chrome 120" or
+/// "curl 8": the browser, or else the first product, with its major
+/// version. Only name characters are kept, so the result is safe to show.
+pub fn user_agent_family(user_agent: Option<&str>) -> String {
+    // Products are name/version tokens; parenthesized comments are skipped
+    let mut products = Vec::new();
+    let mut depth = 0usize;
+    for token in user_agent.unwrap_or("").split_whitespace() {
+        if depth == 0 && !token.starts_with('(') {
+            products.push(token.split_once('/').unwrap_or((token, "")));
+        }
+        depth += token.matches('(').count();
+        depth = depth.saturating_sub(token.matches(')').count());
+    }
+
+    let version_of = |name: &str| {
+        products
+            .iter()
+            .find(|(product, _)| *product == name)
+            .map(|(_, version)| *version)
+    };
+    const BROWSERS: [(&str, &str); 6] = [
+        ("Edg", "Edge"),
+        ("OPR", "Opera"),
+        ("Firefox", "Firefox"),
+        ("CriOS", "Chrome"),
+        ("Chrome", "Chrome"),
+        ("Version", "Safari"),
+    ];
+    let (name, version) = BROWSERS
+        .iter()
+        .find_map(|(product, family)| Some((*family, version_of(product)?)))
+        .or_else(|| products.first().copied())
+        .unwrap_or(("", ""));
+
+    let name: String = name
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+        .take(32)
+        .collect();
+    if name.is_empty() {
+        return "Unknown".to_string();
+    }
+    let major = version.split('.').next().unwrap_or("");
+    if !major.is_empty() && major.len() <= 6 && major.chars().all(|c| c.is_ascii_digit()) {
+        format!("{} {}", name, major)
+    } else {
+        name
+    }
+}
+
 fn format_duration(duration: &Duration) -> String {
     let total_seconds = duration.as_secs();
     let days = total_seconds / 86400;
@@ -256,6 +749,40 @@ mod tests {
         assert_eq!(stats.bytes_transferred, 0);
     }
 
+    #[test]
+    fn test_sharded_counters() {
+        let counters = std::sync::Arc::new(ShardedCounters::with_shards(2));
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                let counters = counters.clone();
+                std::thread::spawn(move || {
+                    for _ in 0..1000 {
+                        counters.add(Counter::RequestsProcessed, 1);
+                    }
+                    counters.add(Counter::ConnectionsOpened, 2);
+                    counters.add(Counter::BytesTransferred, 512);
+                    counters.add(Counter::BytesReceived, 128);
+                    counters.add(Counter::BytesSent, 384);
+                    counters.add(Counter::SlowClients, 1);
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        counters.add(Counter::ConnectionsClosed, 3);
+
+        let mut stats = Stats::new();
+        counters.apply_to(&mut stats);
+        assert_eq!(stats.requests_processed, 4000);
+        assert_eq!(stats.connections_opened, 8);
+        assert_eq!(stats.active_connections, 5);
+        assert_eq!(stats.bytes_transferred, 2048);
+        assert_eq!(stats.bytes_received, 512);
+        assert_eq!(stats.bytes_sent, 1536);
+        assert_eq!(stats.slow_clients, 4);
+    }
+
     #[test]
     fn test_success_rate_calculation() {
         let mut stats = Stats::new();
@@ -274,6 +801,127 @@ mod tests {
         assert_eq!(stats.get_auth_success_rate(), 90.0);
     }
 
+    #[test]
+    fn test_upstream_connection_gauges() {
+        let mut stats = Stats::new();
+        stats.upstream_connection_opened("a.example.com");
+        stats.upstream_connection_opened("A.example.com");
+        stats.upstream_connection_opened("b.example.com");
+
+        assert_eq!(
+            stats.top_upstream_connections(10),
+            vec![("a.example.com", 2), ("b.example.com", 1)]
+        );
+
+        stats.upstream_connection_closed("b.example.com");
+        stats.upstream_connection_closed("a.example.com");
+        assert_eq!(
+            stats.top_upstream_connections(10),
+            vec![("a.example.com", 1)]
+        );
+        assert!(stats.to_html().contains("a.example.com"));
+    }
+
+    #[tokio::test]
+    async fn test_process_stats() {
+        let process = ProcessStats::collect();
+        assert!(process.tasks.is_some());
+        if cfg!(target_os = "linux") {
+            assert!(process.resident_memory.unwrap() > 0);
+            assert!(process.open_files.unwrap() > 0);
+        }
+
+        let stats = Stats {
+            process: ProcessStats {
+                client_slots_in_use: 3,
+                max_clients: 100,
+                ..process
+            },
+            ..Stats::new()
+        };
+        assert!(stats
+            .to_html()
+            .contains("<td>Client Slots In Use</td><td class=\"value\">3 / 100</td>"));
+    }
+
+    #[test]
+    fn test_user_agent_family() {
+        let family = |agent| user_agent_family(Some(agent));
+        assert_eq!(
+            family(
+                "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 \
+                 (KHTML, like Gecko) Chrome/120.0.0.0 Safari/537.36 Edg/120.0.2210.91"
+            ),
+            "Edge 120"
+        );
+        assert_eq!(
+            family("Mozilla/5.0 (X11; Linux x86_64; rv:121.0) Gecko/20100101 Firefox/121.0"),
+            "Firefox 121"
+        );
+        assert_eq!(
+            family(
+                "Mozilla/5.0 (Macintosh; Intel Mac OS X 10_15_7) AppleWebKit/605.1.15 \
+                 (KHTML, like Gecko) Version/17.1 Safari/605.1.15"
+            ),
+            "Safari 17"
+        );
+        assert_eq!(family("curl/8.4.0"), "curl 8");
+        assert_eq!(family("python-requests/2.31.0"), "python-requests 2");
+        assert_eq!(family("Go-http-client/1.1"), "Go-http-client 1");
+        assert_eq!(family("<script>/x"), "script");
+        assert_eq!(family("(comment only)"), "Unknown");
+        assert_eq!(user_agent_family(None), "Unknown");
+    }
+
+    #[test]
+    fn test_user_agents() {
+        let counters = std::sync::Arc::new(ShardedCounters::with_shards(2));
+        counters.record_user_agent("curl 8", 100);
+        let other = counters.clone();
+        std::thread::spawn(move || {
+            other.record_user_agent("curl 8", 50);
+            other.record_user_agent("Firefox 121", 10);
+        })
+        .join()
+        .unwrap();
+        let mut stats = Stats::new();
+        counters.apply_to(&mut stats);
+        assert_eq!(
+            stats.top_user_agents(1),
+            vec![(
+                "curl 8",
+                &AgentUsage {
+                    requests: 2,
+                    bytes: 150
+                }
+            )]
+        );
+
+        // The table is bounded
+        for i in 0..MAX_USER_AGENTS {
+            counters.record_user_agent(&format!("agent {}", i), 1);
+        }
+        counters.apply_to(&mut stats);
+        assert_eq!(stats.user_agents.len(), MAX_USER_AGENTS + 1);
+        assert_eq!(stats.user_agents[OTHER_USER_AGENTS].requests, 2);
+        assert!(stats.to_html().contains("<td>curl 8</td>"));
+    }
+
+    #[test]
+    fn test_duration_histogram() {
+        let counters = ShardedCounters::with_shards(2);
+        for millis in [5, 99, 100, 2_500, 7_200_000, 90_000_000] {
+            counters.record_duration(Histogram::TunnelDurations, Duration::from_millis(millis));
+        }
+        let mut stats = Stats::new();
+        counters.apply_to(&mut stats);
+        assert_eq!(stats.tunnel_durations.counts, [2, 1, 1, 0, 0, 0, 1, 0, 1]);
+        assert_eq!(stats.request_durations.counts, [0; 9]);
+        assert!(stats.to_html().contains(
+            "<tr><td>&lt; 6 h</td><td class=\"value\">0</td><td class=\"value\">1</td></tr>"
+        ));
+    }
+
     #[test]
     fn test_format_duration() {
         assert_eq!(format_duration(&Duration::from_secs(30)), "30s");
@@ -0,0 +1,163 @@
+use crate::config::Config;
+use log::{debug, info};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// Failed authentications are counted over this period.
+const AUTH_FAILURE_WINDOW: Duration = Duration::from_secs(600);
+
+/// Upper bound on tracked clients before expired ones are pruned.
+const MAX_TRACKED_CLIENTS: usize = 10_000;
+
+/// Holds abusive clients on a response dripped out a byte at a time
+/// instead of refusing them right away, which slows down scanners and
+/// password guessing.
+///
+/// Clients are tarpitted when Allow/Deny rules refuse them, and once they
+/// failed authentication `auth_failures` times within ten minutes. Only
+/// `max_clients` are held at once; beyond that they are refused normally,
+/// so a flood of bad clients cannot use up all connection slots.
+pub struct Tarpit {
+    duration: Duration,
+    auth_failures: u32,
+    max_clients: usize,
+    slots: Arc<Semaphore>,
+    failures: Mutex<HashMap<IpAddr, AuthFailures>>,
+}
+
+struct AuthFailures {
+    count: u32,
+    since: Instant,
+}
+
+impl Tarpit {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            duration: Duration::from_secs(config.tarpit_duration),
+            auth_failures: config.tarpit_auth_failures,
+            max_clients: config.tarpit_max_clients,
+            slots: Arc::new(Semaphore::new(config.tarpit_max_clients)),
+            failures: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.duration.is_zero()
+    }
+
+    /// Account a failed authentication by `ip`. Returns whether the client
+    /// has failed often enough to be tarpitted.
+    pub fn record_auth_failure(&self, ip: IpAddr) -> bool {
+        self.record_auth_failure_at(ip, Instant::now())
+    }
+
+    fn record_auth_failure_at(&self, ip: IpAddr, now: Instant) -> bool {
+        if !self.is_enabled() || self.auth_failures == 0 {
+            return false;
+        }
+
+        let mut failures = self.failures.lock().unwrap();
+        if failures.len() >= MAX_TRACKED_CLIENTS && !failures.contains_key(&ip) {
+            failures.retain(|_, entry| now.duration_since(entry.since) < AUTH_FAILURE_WINDOW);
+        }
+
+        let entry = failures.entry(ip).or_insert(AuthFailures {
+            count: 0,
+            since: now,
+        });
+        if now.duration_since(entry.since) >= AUTH_FAILURE_WINDOW {
+            entry.count = 0;
+            entry.since = now;
+        }
+        entry.count += 1;
+
+        if entry.count == self.auth_failures {
+            info!(
+                "{} failed authentication {} times, tarpitting it",
+                ip, entry.count
+            );
+        }
+        entry.count >= self.auth_failures
+    }
+
+    /// Take one of the tarpit slots, if any are free.
+    pub fn try_hold(&self) -> Option<OwnedSemaphorePermit> {
+        let slot = self.slots.clone().try_acquire_owned().ok();
+        if slot.is_none() {
+            debug!("All tarpit slots in use");
+        }
+        slot
+    }
+
+    /// Number of clients currently held.
+    pub fn slots_in_use(&self) -> usize {
+        self.max_clients - self.slots.available_permits()
+    }
+
+    /// Write `data` a byte at a time, spread over the tarpit duration.
+    pub async fn drip<W: AsyncWrite + Unpin>(
+        &self,
+        writer: &mut W,
+        data: &[u8],
+    ) -> std::io::Result<()> {
+        let interval = self.duration / data.len().max(1) as u32;
+        for byte in data {
+            tokio::time::sleep(interval).await;
+            writer.write_all(std::slice::from_ref(byte)).await?;
+            writer.flush().await?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_auth_failures() {
+        let config =
+            Config::parse_config("Tarpit 30\nTarpitAuthFailures 3\nTarpitMaxClients 1").unwrap();
+        let tarpit = Tarpit::new(&config);
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        let other: IpAddr = "192.0.2.2".parse().unwrap();
+        let start = Instant::now();
+
+        assert!(!tarpit.record_auth_failure_at(ip, start));
+        assert!(!tarpit.record_auth_failure_at(ip, start));
+        assert!(!tarpit.record_auth_failure_at(other, start));
+        assert!(tarpit.record_auth_failure_at(ip, start));
+        assert!(tarpit.record_auth_failure_at(ip, start + Duration::from_secs(60)));
+
+        // Failures are forgotten after the window
+        let later = start + AUTH_FAILURE_WINDOW;
+        assert!(!tarpit.record_auth_failure_at(ip, later));
+
+        let slot = tarpit.try_hold();
+        assert!(slot.is_some());
+        assert!(tarpit.try_hold().is_none());
+        assert_eq!(tarpit.slots_in_use(), 1);
+        drop(slot);
+        assert!(tarpit.try_hold().is_some());
+
+        let disabled = Tarpit::new(&Config::parse_config("TarpitAuthFailures 1").unwrap());
+        assert!(!disabled.is_enabled());
+        assert!(!disabled.record_auth_failure_at(ip, start));
+    }
+
+    #[tokio::test]
+    async fn test_drip() {
+        let mut tarpit = Tarpit::new(&Config::default());
+        tarpit.duration = Duration::from_millis(120);
+        let mut sink = Vec::new();
+        let start = Instant::now();
+
+        tarpit.drip(&mut sink, b"HTTP/1.1 403").await.unwrap();
+        assert_eq!(sink, b"HTTP/1.1 403");
+        assert!(start.elapsed() >= Duration::from_millis(120));
+    }
+}
@@ -0,0 +1,276 @@
+use crate::acl::IpList;
+use crate::config::Config;
+use std::collections::HashMap;
+use std::future::Future;
+use std::io;
+use std::net::IpAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, Weak};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, ReadBuf};
+use tokio::time::Sleep;
+
+/// Token bucket limiting a byte stream to `rate` bytes per second, with a
+/// burst allowance of one second worth of data. A limiter may be shared by
+/// several streams, which then split the rate between them.
+#[derive(Debug)]
+pub struct RateLimiter {
+    rate: u64,
+    state: Mutex<BucketState>,
+}
+
+#[derive(Debug)]
+struct BucketState {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(rate: u64) -> Arc<Self> {
+        Arc::new(Self {
+            rate,
+            state: Mutex::new(BucketState {
+                tokens: rate as f64,
+                last_refill: Instant::now(),
+            }),
+        })
+    }
+
+    pub fn rate(&self) -> u64 {
+        self.rate
+    }
+
+    /// Number of bytes that may be transferred right now, or how long to
+    /// wait until the bucket is out of debt.
+    fn available(&self, now: Instant) -> Result<usize, Duration> {
+        let mut state = self.state.lock().unwrap();
+        self.refill(&mut state, now);
+
+        if state.tokens >= 1.0 {
+            Ok(state.tokens as usize)
+        } else {
+            let missing = 1.0 - state.tokens;
+            Err(Duration::from_secs_f64(missing / self.rate as f64))
+        }
+    }
+
+    /// Record `bytes` as transferred. The bucket may go into debt when
+    /// several streams share it; later reads wait the debt off.
+    fn consume(&self, bytes: usize, now: Instant) {
+        let mut state = self.state.lock().unwrap();
+        self.refill(&mut state, now);
+        state.tokens -= bytes as f64;
+    }
+
+    fn refill(&self, state: &mut BucketState, now: Instant) {
+        let elapsed = now.saturating_duration_since(state.last_refill);
+        state.tokens =
+            (state.tokens + elapsed.as_secs_f64() * self.rate as f64).min(self.rate as f64);
+        state.last_refill = now;
+    }
+}
+
+/// Bandwidth caps per client address (RateLimit). Each client gets one
+/// limiter shared by all of its connections and both directions, so
+/// opening more connections does not raise its share of the uplink.
+/// Lines naming a network are tried in order before the catch-all one.
+pub struct ClientBandwidth {
+    rules: Vec<(IpList, u64)>,
+    default_rate: Option<u64>,
+    limiters: Mutex<HashMap<IpAddr, Weak<RateLimiter>>>,
+}
+
+impl ClientBandwidth {
+    pub fn new(config: &Config) -> Self {
+        let mut rules = Vec::new();
+        let mut default_rate = None;
+        for limit in &config.client_rate_limits {
+            match &limit.network {
+                Some(network) => {
+                    let list = IpList::new(std::slice::from_ref(network), "RateLimit");
+                    if !list.is_empty() {
+                        rules.push((list, limit.rate));
+                    }
+                }
+                None => default_rate = default_rate.or(Some(limit.rate)),
+            }
+        }
+
+        Self {
+            rules,
+            default_rate,
+            limiters: Mutex::default(),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.rules.is_empty() || self.default_rate.is_some()
+    }
+
+    /// The limiter shared by `client`'s connections, if any applies. It
+    /// lives as long as one of them holds it.
+    pub fn limiter(&self, client: IpAddr) -> Option<Arc<RateLimiter>> {
+        let rate = self
+            .rules
+            .iter()
+            .find(|(list, _)| list.contains(&client))
+            .map(|(_, rate)| *rate)
+            .or(self.default_rate)
+            .filter(|rate| *rate > 0)?;
+
+        let mut limiters = self.limiters.lock().unwrap();
+        if let Some(limiter) = limiters.get(&client).and_then(Weak::upgrade) {
+            return Some(limiter);
+        }
+        limiters.retain(|_, limiter| limiter.strong_count() > 0);
+        let limiter = RateLimiter::new(rate);
+        limiters.insert(client, Arc::downgrade(&limiter));
+        Some(limiter)
+    }
+}
+
+/// Reader that applies zero or more rate limiters to everything read
+/// through it.
+pub struct Throttled<R> {
+    inner: R,
+    limiters: Vec<Arc<RateLimiter>>,
+    sleep: Option<Pin<Box<Sleep>>>,
+}
+
+impl<R> Throttled<R> {
+    pub fn new(inner: R, limiters: Vec<Arc<RateLimiter>>) -> Self {
+        Self {
+            inner,
+            limiters,
+            sleep: None,
+        }
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for Throttled<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        if self.limiters.is_empty() {
+            return Pin::new(&mut self.inner).poll_read(cx, buf);
+        }
+
+        loop {
+            if let Some(sleep) = self.sleep.as_mut() {
+                if sleep.as_mut().poll(cx).is_pending() {
+                    return Poll::Pending;
+                }
+                self.sleep = None;
+            }
+
+            let now = Instant::now();
+            let mut allowance = buf.remaining();
+            let mut wait = Duration::ZERO;
+            for limiter in &self.limiters {
+                match limiter.available(now) {
+                    Ok(bytes) => allowance = allowance.min(bytes),
+                    Err(delay) => wait = wait.max(delay),
+                }
+            }
+
+            if wait > Duration::ZERO {
+                self.sleep = Some(Box::pin(tokio::time::sleep(wait)));
+                continue;
+            }
+
+            let this = &mut *self;
+            let mut limited = buf.take(allowance);
+            match Pin::new(&mut this.inner).poll_read(cx, &mut limited) {
+                Poll::Ready(Ok(())) => {
+                    let n = limited.filled().len();
+                    for limiter in &this.limiters {
+                        limiter.consume(n, Instant::now());
+                    }
+                    // SAFETY: the inner reader initialized and filled `n`
+                    // bytes of the unfilled region shared with `buf`.
+                    unsafe { buf.assume_init(n) };
+                    buf.advance(n);
+                    return Poll::Ready(Ok(()));
+                }
+                other => return other,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncReadExt;
+
+    #[test]
+    fn test_token_bucket() {
+        let limiter = RateLimiter::new(1000);
+        let start = Instant::now();
+
+        assert_eq!(limiter.available(start), Ok(1000));
+
+        limiter.consume(1500, start);
+        let wait = limiter.available(start).unwrap_err();
+        assert!(wait > Duration::from_millis(490) && wait < Duration::from_millis(510));
+
+        // Half a second later the debt is paid off
+        let later = start + Duration::from_millis(600);
+        assert!(limiter.available(later).unwrap() >= 99);
+
+        // Refill never exceeds one second of burst
+        let much_later = start + Duration::from_secs(10);
+        assert_eq!(limiter.available(much_later), Ok(1000));
+    }
+
+    #[tokio::test]
+    async fn test_throttled_reader() {
+        let data = vec![7u8; 4096];
+
+        // Without limiters the reader is a plain passthrough
+        let mut reader = Throttled::new(&data[..], vec![]);
+        let mut out = Vec::new();
+        reader.read_to_end(&mut out).await.unwrap();
+        assert_eq!(out, data);
+
+        // A single read never exceeds the current allowance
+        let limiter = RateLimiter::new(1024);
+        let mut reader = Throttled::new(&data[..], vec![limiter]);
+        let mut buf = vec![0u8; 4096];
+        let n = reader.read(&mut buf).await.unwrap();
+        assert_eq!(n, 1024);
+    }
+
+    #[test]
+    fn test_client_bandwidth() {
+        let config = Config::parse_config(
+            "RateLimit 192.168.1.0/24 2MBps\nRateLimit 10.0.0.1 0\nRateLimit 100K",
+        )
+        .unwrap();
+        let bandwidth = ClientBandwidth::new(&config);
+        assert!(bandwidth.is_enabled());
+
+        let lan: IpAddr = "192.168.1.20".parse().unwrap();
+        let other: IpAddr = "172.16.0.5".parse().unwrap();
+        let exempt: IpAddr = "10.0.0.1".parse().unwrap();
+
+        // Connections from one client share a limiter
+        let first = bandwidth.limiter(lan).unwrap();
+        assert_eq!(first.rate(), 2 * 1024 * 1024);
+        assert!(Arc::ptr_eq(&first, &bandwidth.limiter(lan).unwrap()));
+        assert_eq!(bandwidth.limiter(other).unwrap().rate(), 100 * 1024);
+        assert!(bandwidth.limiter(exempt).is_none());
+
+        // Once no connection holds it, the client starts afresh
+        drop(first);
+        let again = bandwidth.limiter(lan).unwrap();
+        assert_eq!(Arc::strong_count(&again), 1);
+
+        let disabled = ClientBandwidth::new(&Config::default());
+        assert!(!disabled.is_enabled());
+        assert!(disabled.limiter(lan).is_none());
+    }
+}
@@ -0,0 +1,145 @@
+use crate::connector::{BoxedStream, ConnectFailure, Connector, FailureKind};
+use crate::error::{ProxyError, ProxyResult};
+use crate::utils::authority;
+use async_trait::async_trait;
+use log::debug;
+use std::sync::Arc;
+
+/// Connector wrapping the streams of another connector in client-side TLS,
+/// verifying the target's certificate against the target host name.
+pub struct TlsConnector {
+    inner: Arc<dyn Connector>,
+    backend: backend::Backend,
+}
+
+impl TlsConnector {
+    pub fn new(inner: Arc<dyn Connector>) -> ProxyResult<Self> {
+        Ok(Self {
+            inner,
+            backend: backend::Backend::new().map_err(ProxyError::Tls)?,
+        })
+    }
+}
+
+#[async_trait]
+impl Connector for TlsConnector {
+    async fn connect(&self, host: &str, port: u16) -> Result<BoxedStream, ConnectFailure> {
+        let stream = self.inner.connect(host, port).await?;
+        let target = authority(host, port);
+
+        match self.backend.handshake(host, stream).await {
+            Ok(stream) => {
+                debug!("TLS established with {} ({})", target, backend::NAME);
+                Ok(stream)
+            }
+            Err(e) => Err(ConnectFailure::new(&target, FailureKind::Tls, e)),
+        }
+    }
+}
+
+#[cfg(feature = "rustls")]
+mod backend {
+    use crate::connector::BoxedStream;
+    use std::sync::{Arc, OnceLock};
+    use tokio_rustls::rustls::crypto::ring;
+    use tokio_rustls::rustls::pki_types::ServerName;
+    use tokio_rustls::rustls::{ClientConfig, RootCertStore};
+
+    pub const NAME: &str = "rustls";
+
+    pub struct Backend(tokio_rustls::TlsConnector);
+
+    impl Backend {
+        pub fn new() -> Result<Self, String> {
+            // Connectors are made per request, the settings only once
+            static CONFIG: OnceLock<Arc<ClientConfig>> = OnceLock::new();
+            if let Some(config) = CONFIG.get() {
+                return Ok(Self(tokio_rustls::TlsConnector::from(config.clone())));
+            }
+
+            let mut roots = RootCertStore::empty();
+            roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+
+            let config = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+                .with_safe_default_protocol_versions()
+                .map_err(|e| e.to_string())?
+                .with_root_certificates(roots)
+                .with_no_client_auth();
+            let config = CONFIG.get_or_init(|| Arc::new(config));
+            Ok(Self(tokio_rustls::TlsConnector::from(config.clone())))
+        }
+
+        pub async fn handshake(
+            &self,
+            host: &str,
+            stream: BoxedStream,
+        ) -> Result<BoxedStream, String> {
+            let name = ServerName::try_from(host.to_string()).map_err(|e| e.to_string())?;
+            let stream = self
+                .0
+                .connect(name, stream)
+                .await
+                .map_err(|e| e.to_string())?;
+            Ok(Box::new(stream))
+        }
+    }
+}
+
+#[cfg(all(feature = "native-tls", not(feature = "rustls")))]
+mod backend {
+    use crate::connector::BoxedStream;
+
+    pub const NAME: &str = "native-tls";
+
+    pub struct Backend(tokio_native_tls::TlsConnector);
+
+    impl Backend {
+        pub fn new() -> Result<Self, String> {
+            let connector = native_tls::TlsConnector::new().map_err(|e| e.to_string())?;
+            Ok(Self(connector.into()))
+        }
+
+        pub async fn handshake(
+            &self,
+            host: &str,
+            stream: BoxedStream,
+        ) -> Result<BoxedStream, String> {
+            let stream = self
+                .0
+                .connect(host, stream)
+                .await
+                .map_err(|e| e.to_string())?;
+            Ok(Box::new(stream))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::connector::DirectConnector;
+    use std::time::Duration;
+    use tokio::io::AsyncWriteExt;
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn test_handshake_failure() {
+        // A plain HTTP server cannot complete a TLS handshake
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let _ = stream.write_all(b"HTTP/1.1 400 Bad Request\r\n\r\n").await;
+        });
+
+        let direct = Arc::new(DirectConnector::new(Duration::from_secs(5)));
+        let connector = TlsConnector::new(direct).unwrap();
+        let failure = match connector.connect("localhost", port).await {
+            Ok(_) => panic!("handshake with a plain HTTP server succeeded"),
+            Err(failure) => failure,
+        };
+
+        assert_eq!(failure.kind, FailureKind::Tls);
+        assert!(matches!(failure.to_error(), ProxyError::Tls(_)));
+    }
+}
@@ -1,14 +1,61 @@
 use crate::error::{ProxyError, ProxyResult};
+use crate::headers::Headers;
 use log::debug;
-use std::collections::HashMap;
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::time::Instant;
 
 #[derive(Debug, Clone)]
 pub struct HttpRequest {
     pub method: String,
     pub uri: String,
     pub version: String,
-    pub headers: HashMap<String, String>,
+    pub headers: Headers,
+}
+
+impl HttpRequest {
+    /// HTTP version of the status lines the proxy writes itself. HTTP/1.0
+    /// clients may not understand a 1.1 response, so they get 1.0.
+    pub fn response_version(&self) -> &'static str {
+        if self.version == "1.0" {
+            "1.0"
+        } else {
+            "1.1"
+        }
+    }
+
+    /// Whether the client expects its connection to stay open after the
+    /// response: HTTP/1.0 only does with an explicit keep-alive, later
+    /// versions unless they ask to close. Proxy-Connection, which some
+    /// clients send to proxies instead, counts as well.
+    pub fn keep_alive(&self) -> bool {
+        let has_token = |token: &str| {
+            ["connection", "proxy-connection"].iter().any(|name| {
+                self.headers
+                    .get_all(name)
+                    .flat_map(|value| value.split(','))
+                    .any(|option| option.trim().eq_ignore_ascii_case(token))
+            })
+        };
+        if self.version == "1.0" {
+            has_token("keep-alive")
+        } else {
+            !has_token("close")
+        }
+    }
+
+    /// Whether the client asks to switch protocols, e.g. to WebSocket: it
+    /// sends Upgrade and names it in Connection (RFC 9110, 7.8).
+    pub fn is_upgrade(&self) -> bool {
+        self.headers.contains_key("upgrade")
+            && self
+                .headers
+                .get_all("connection")
+                .flat_map(|value| value.split(','))
+                .any(|option| option.trim().eq_ignore_ascii_case("upgrade"))
+    }
 }
 
 pub fn parse_http_request(data: &[u8]) -> ProxyResult<HttpRequest> {
@@ -35,16 +82,16 @@ pub fn parse_http_request(data: &[u8]) -> ProxyResult<HttpRequest> {
         .to_string();
 
     // Parse headers
-    let mut headers = HashMap::new();
+    let mut headers = Headers::new();
     for line in &lines[1..] {
         if line.is_empty() {
             break;
         }
 
         if let Some(colon_pos) = line.find(':') {
-            let name = line[..colon_pos].trim().to_lowercase();
-            let value = line[colon_pos + 1..].trim().to_string();
-            headers.insert(name, value);
+            let name = line[..colon_pos].trim();
+            let value = line[colon_pos + 1..].trim();
+            headers.append(name, value);
         }
     }
 
@@ -56,12 +103,245 @@ pub fn parse_http_request(data: &[u8]) -> ProxyResult<HttpRequest> {
     })
 }
 
+/// Response head from an origin server. Duplicate header fields (e.g.
+/// several Set-Cookie lines) are kept.
+#[derive(Debug, Clone)]
+pub struct HttpResponse {
+    pub version: String,
+    pub status: u16,
+    pub reason: String,
+    pub headers: Headers,
+}
+
+impl HttpResponse {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        format!(
+            "HTTP/{} {} {}\r\n{}\r\n",
+            self.version,
+            self.status,
+            self.reason,
+            self.headers.to_lines()
+        )
+        .into_bytes()
+    }
+}
+
+pub fn parse_http_response(data: &[u8]) -> ProxyResult<HttpResponse> {
+    let response_str = String::from_utf8_lossy(data);
+    let mut lines = response_str.lines();
+
+    let status_line = lines
+        .next()
+        .ok_or_else(|| ProxyError::Upstream("Empty response".to_string()))?;
+    let mut parts = status_line.splitn(3, ' ');
+    let version = parts
+        .next()
+        .and_then(|version| version.strip_prefix("HTTP/"))
+        .ok_or_else(|| ProxyError::Upstream(format!("Invalid status line: {}", status_line)))?
+        .to_string();
+    let status = parts
+        .next()
+        .and_then(|status| status.parse::<u16>().ok())
+        .ok_or_else(|| ProxyError::Upstream(format!("Invalid status line: {}", status_line)))?;
+    let reason = parts.next().unwrap_or("").to_string();
+
+    let mut headers = Headers::new();
+    for line in lines {
+        if line.is_empty() {
+            break;
+        }
+
+        if let Some((name, value)) = line.split_once(':') {
+            headers.append(name.trim(), value.trim());
+        }
+    }
+
+    Ok(HttpResponse {
+        version,
+        status,
+        reason,
+        headers,
+    })
+}
+
+/// Path and query of a request target, as sent to origin servers. Targets
+/// that are not absolute URIs are returned unchanged.
+pub fn origin_form(uri: &str) -> String {
+    let after_scheme = match uri.find("://") {
+        Some(position) if !uri.starts_with('/') => &uri[position + 3..],
+        _ => return uri.to_string(),
+    };
+    match after_scheme.find(['/', '?']) {
+        // The path may not be empty, even when a query follows
+        Some(position) if after_scheme[position..].starts_with('?') => {
+            format!("/{}", &after_scheme[position..])
+        }
+        Some(position) => after_scheme[position..].to_string(),
+        None => "/".to_string(),
+    }
+}
+
+/// Position of the blank line ending an HTTP message head.
+pub fn find_end_of_headers(buffer: &[u8]) -> Option<usize> {
+    buffer.windows(4).position(|window| window == b"\r\n\r\n")
+}
+
+/// Finds the end of a message head in a buffer that grows between calls,
+/// without rescanning the bytes already checked.
+#[derive(Debug, Default)]
+pub struct HeadScanner {
+    scanned: usize,
+}
+
+impl HeadScanner {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Position of the blank line ending the head, once it has arrived.
+    pub fn find(&mut self, buffer: &[u8]) -> Option<usize> {
+        // Back up so a terminator split across reads is still found
+        let start = self.scanned.saturating_sub(3).min(buffer.len());
+        match find_end_of_headers(&buffer[start..]) {
+            Some(position) => Some(start + position),
+            None => {
+                self.scanned = buffer.len();
+                None
+            }
+        }
+    }
+
+    /// Start over after data was removed from the front of the buffer.
+    pub fn reset(&mut self) {
+        self.scanned = 0;
+    }
+}
+
+/// Size of the buffer each direction of a relay copies through.
+const RELAY_BUFFER_SIZE: usize = 16 * 1024;
+
+/// Relay both ways until both sides have closed. When one side finishes
+/// sending, the other is told by shutting down the writer, so half-closed
+/// connections keep working in the other direction and nothing in flight
+/// is lost. An error in either direction ends both, as does nothing being
+/// copied either way for `idle_timeout`. Returns the bytes copied from
+/// reader1 to writer1 and from reader2 to writer2.
 pub async fn copy_bidirectional<R1, W1, R2, W2>(
+    reader1: R1,
+    writer1: W1,
+    reader2: R2,
+    writer2: W2,
+    idle_timeout: Option<Duration>,
+) -> ProxyResult<(u64, u64)>
+where
+    R1: AsyncRead + Unpin,
+    W1: AsyncWrite + Unpin,
+    R2: AsyncRead + Unpin,
+    W2: AsyncWrite + Unpin,
+{
+    let mut bytes1 = 0u64;
+    let mut bytes2 = 0u64;
+    let activity = Activity::new();
+
+    {
+        let copy = async {
+            tokio::try_join!(
+                copy_until_eof(reader1, writer1, &mut bytes1, &activity),
+                copy_until_eof(reader2, writer2, &mut bytes2, &activity)
+            )
+        };
+        tokio::pin!(copy);
+        loop {
+            let deadline = idle_timeout.map(|idle| activity.last() + idle);
+            tokio::select! {
+                result = &mut copy => {
+                    if let Err(e) = result {
+                        debug!("Bidirectional copy ended early: {}", e);
+                    }
+                    break;
+                }
+                _ = tokio::time::sleep_until(deadline.unwrap_or_else(Instant::now)), if deadline.is_some() => {
+                    // Data may have moved since the deadline was set
+                    if idle_timeout.is_some_and(|idle| activity.last().elapsed() >= idle) {
+                        debug!("Bidirectional copy idle, closing");
+                        break;
+                    }
+                }
+            }
+        }
+    }
+
+    debug!(
+        "Bidirectional copy completed, {} and {} bytes",
+        bytes1, bytes2
+    );
+    Ok((bytes1, bytes2))
+}
+
+/// When data last moved in a relay, in either direction.
+struct Activity {
+    start: Instant,
+    /// Milliseconds after `start`.
+    last: AtomicU64,
+}
+
+impl Activity {
+    fn new() -> Self {
+        Self {
+            start: Instant::now(),
+            last: AtomicU64::new(0),
+        }
+    }
+
+    fn touch(&self) {
+        let elapsed = self.start.elapsed().as_millis() as u64;
+        self.last.store(elapsed, Ordering::Relaxed);
+    }
+
+    fn last(&self) -> Instant {
+        self.start + Duration::from_millis(self.last.load(Ordering::Relaxed))
+    }
+}
+
+/// Copy from `reader` to `writer` until EOF, counting into `copied` and
+/// noting every transfer in `activity`, then shut `writer` down to pass
+/// the EOF on.
+async fn copy_until_eof<R, W>(
+    mut reader: R,
+    mut writer: W,
+    copied: &mut u64,
+    activity: &Activity,
+) -> io::Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut buf = vec![0u8; RELAY_BUFFER_SIZE];
+    loop {
+        let n = reader.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        activity.touch();
+        writer.write_all(&buf[..n]).await?;
+        writer.flush().await?;
+        *copied += n as u64;
+        activity.touch();
+    }
+    writer.shutdown().await
+}
+
+/// Relay one HTTP exchange: the request body from reader1 to writer1 while
+/// the response goes from reader2 to writer2. Unlike
+/// [`copy_bidirectional`], the end of the request body does not end the
+/// relay; it is over once the response is. Returns the bytes copied each
+/// way.
+pub async fn relay_exchange<R1, W1, R2, W2>(
     mut reader1: R1,
     mut writer1: W1,
     mut reader2: R2,
     mut writer2: W2,
-) -> ProxyResult<u64>
+) -> ProxyResult<(u64, u64)>
 where
     R1: AsyncRead + Unpin,
     W1: AsyncWrite + Unpin,
@@ -70,42 +350,41 @@ where
 {
     let mut buf1 = vec![0u8; 8192];
     let mut buf2 = vec![0u8; 8192];
-    let mut total_bytes = 0u64;
+    let mut bytes1 = 0u64;
+    let mut bytes2 = 0u64;
+    let mut uploading = true;
 
     loop {
         tokio::select! {
-            result1 = reader1.read(&mut buf1) => {
+            result1 = reader1.read(&mut buf1), if uploading => {
                 match result1 {
-                    Ok(0) => {
-                        debug!("Reader1 EOF reached");
-                        break;
-                    }
+                    Ok(0) => uploading = false,
                     Ok(n) => {
-                        writer1.write_all(&buf1[..n]).await.map_err(ProxyError::Io)?;
-                        writer1.flush().await.map_err(ProxyError::Io)?;
-                        total_bytes += n as u64;
-                        debug!("Copied {} bytes from reader1 to writer1", n);
-                    }
-                    Err(e) => {
-                        debug!("Reader1 error: {}", e);
-                        break;
+                        // An origin may answer before the whole body is sent
+                        let sent = match writer1.write_all(&buf1[..n]).await {
+                            Ok(()) => writer1.flush().await,
+                            Err(e) => Err(e),
+                        };
+                        if let Err(e) = sent {
+                            debug!("Request body not sent completely: {}", e);
+                            uploading = false;
+                        }
+                        bytes1 += n as u64;
                     }
+                    // The origin would wait for the rest of the body
+                    Err(e) => return Err(ProxyError::Io(e)),
                 }
             }
             result2 = reader2.read(&mut buf2) => {
                 match result2 {
-                    Ok(0) => {
-                        debug!("Reader2 EOF reached");
-                        break;
-                    }
+                    Ok(0) => break,
                     Ok(n) => {
                         writer2.write_all(&buf2[..n]).await.map_err(ProxyError::Io)?;
                         writer2.flush().await.map_err(ProxyError::Io)?;
-                        total_bytes += n as u64;
-                        debug!("Copied {} bytes from reader2 to writer2", n);
+                        bytes2 += n as u64;
                     }
                     Err(e) => {
-                        debug!("Reader2 error: {}", e);
+                        debug!("Response read error: {}", e);
                         break;
                     }
                 }
@@ -113,8 +392,8 @@ where
         }
     }
 
-    debug!("Bidirectional copy completed, total bytes: {}", total_bytes);
-    Ok(total_bytes)
+    debug!("Exchange relayed, {} and {} bytes", bytes1, bytes2);
+    Ok((bytes1, bytes2))
 }
 
 pub fn format_bytes(bytes: u64) -> String {
@@ -163,6 +442,46 @@ pub fn sanitize_header_value(value: &str) -> String {
         .collect()
 }
 
+/// Whether `name` matches `pattern`, where `*` stands for any run of
+/// characters. Both are lowercase.
+pub fn wildcard_match(pattern: &str, name: &str) -> bool {
+    match pattern.split_once('*') {
+        None => pattern == name,
+        Some((prefix, rest)) => {
+            let Some(name) = name.strip_prefix(prefix) else {
+                return false;
+            };
+            (0..=name.len())
+                .filter(|&start| name.is_char_boundary(start))
+                .any(|start| wildcard_match(rest, &name[start..]))
+        }
+    }
+}
+
+/// `host:port`, with IPv6 literals in brackets.
+pub fn authority(host: &str, port: u16) -> String {
+    if host.contains(':') {
+        format!("[{}]:{}", host, port)
+    } else {
+        format!("{}:{}", host, port)
+    }
+}
+
+pub fn html_escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -175,11 +494,109 @@ mod tests {
         assert_eq!(request.method, "GET");
         assert_eq!(request.uri, "http://example.com/path");
         assert_eq!(request.version, "1.1");
+        assert_eq!(request.headers.get("host"), Some("example.com"));
+        assert_eq!(request.headers.get("user-agent"), Some("test"));
+        assert_eq!(request.headers.iter().next(), Some(("Host", "example.com")));
+    }
+
+    #[test]
+    fn test_parse_repeated_request_headers() {
+        let data = b"GET / HTTP/1.1\r\nHost: example.com\r\nCookie: a=1\r\nAccept: */*\r\nCookie: b=2\r\n\r\n";
+        let request = parse_http_request(data).unwrap();
+
+        assert_eq!(
+            request.headers.get_all("cookie").collect::<Vec<_>>(),
+            ["a=1", "b=2"]
+        );
         assert_eq!(
-            request.headers.get("host"),
-            Some(&"example.com".to_string())
+            request.headers.to_lines(),
+            "Host: example.com\r\nCookie: a=1\r\nAccept: */*\r\nCookie: b=2\r\n"
+        );
+    }
+
+    #[test]
+    fn test_http_1_0_request() {
+        let request = parse_http_request(b"GET http://example.com/ HTTP/1.0\r\n\r\n").unwrap();
+        assert_eq!(request.response_version(), "1.0");
+        assert!(!request.keep_alive());
+
+        let request = parse_http_request(
+            b"GET http://example.com/ HTTP/1.0\r\nConnection: Keep-Alive\r\n\r\n",
+        )
+        .unwrap();
+        assert!(request.keep_alive());
+
+        let request =
+            parse_http_request(b"GET / HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n").unwrap();
+        assert_eq!(request.response_version(), "1.1");
+        assert!(!request.keep_alive());
+
+        let request = parse_http_request(
+            b"GET http://example.com/ HTTP/1.0\r\nProxy-Connection: keep-alive\r\n\r\n",
+        )
+        .unwrap();
+        assert!(request.keep_alive());
+    }
+
+    #[test]
+    fn test_upgrade_request() {
+        let request = parse_http_request(
+            b"GET ws://chat.test/ HTTP/1.1\r\nUpgrade: websocket\r\nConnection: keep-alive, Upgrade\r\n\r\n",
+        )
+        .unwrap();
+        assert!(request.is_upgrade());
+
+        // Upgrade means nothing unless Connection names it
+        let request =
+            parse_http_request(b"GET / HTTP/1.1\r\nHost: a\r\nUpgrade: websocket\r\n\r\n").unwrap();
+        assert!(!request.is_upgrade());
+    }
+
+    #[test]
+    fn test_parse_http_response() {
+        let data = b"HTTP/1.1 404 Not Found\r\nSet-Cookie: a=1\r\nSet-Cookie: b=2\r\n\r\n";
+        let mut response = parse_http_response(data).unwrap();
+
+        assert_eq!(response.status, 404);
+        assert_eq!(response.reason, "Not Found");
+        assert_eq!(response.headers.get("set-cookie"), Some("a=1"));
+        assert_eq!(response.to_bytes(), data);
+
+        response.headers.remove("set-cookie");
+        response.headers.insert("X-Cache", "MISS");
+        assert_eq!(
+            response.to_bytes(),
+            b"HTTP/1.1 404 Not Found\r\nX-Cache: MISS\r\n\r\n"
+        );
+        assert_eq!(find_end_of_headers(data), Some(data.len() - 4));
+    }
+
+    #[test]
+    fn test_head_scanner() {
+        let mut scanner = HeadScanner::new();
+        let mut buffer = b"GET / HTTP/1.1\r\nHost: a\r".to_vec();
+        assert_eq!(scanner.find(&buffer), None);
+
+        buffer.extend_from_slice(b"\n\r");
+        assert_eq!(scanner.find(&buffer), None);
+
+        // The terminator straddles three reads
+        buffer.extend_from_slice(b"\nbody");
+        assert_eq!(scanner.find(&buffer), Some(23));
+
+        scanner.reset();
+        assert_eq!(scanner.find(&buffer[25..]), None);
+    }
+
+    #[test]
+    fn test_origin_form() {
+        assert_eq!(origin_form("http://example.com/a/b?c=d"), "/a/b?c=d");
+        assert_eq!(origin_form("http://example.com:8080"), "/");
+        assert_eq!(origin_form("http://example.com?q"), "/?q");
+        assert_eq!(
+            origin_form("/next?to=http://example.com/"),
+            "/next?to=http://example.com/"
         );
-        assert_eq!(request.headers.get("user-agent"), Some(&"test".to_string()));
     }
 
     #[test]
@@ -201,4 +618,80 @@ mod tests {
         assert!(!is_valid_hostname("example-.com"));
         assert!(!is_valid_hostname("example..com"));
     }
+
+    #[test]
+    fn test_wildcard_match() {
+        assert!(wildcard_match("example.com", "example.com"));
+        assert!(!wildcard_match("example.com", "www.example.com"));
+        assert!(wildcard_match("*.example.com", "www.example.com"));
+        assert!(wildcard_match("*.example.com", "a.b.example.com"));
+        assert!(!wildcard_match("*.example.com", "example.com"));
+        assert!(wildcard_match("api-*.example.*", "api-eu.example.net"));
+        assert!(wildcard_match("*", "anything"));
+    }
+
+    #[test]
+    fn test_html_escape() {
+        assert_eq!(
+            html_escape("<a href=\"x\">&'</a>"),
+            "&lt;a href=&quot;x&quot;&gt;&amp;&#39;&lt;/a&gt;"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_copy_bidirectional_half_close() {
+        let (client, mut client_peer) = tokio::io::duplex(4096);
+        let (target, mut target_peer) = tokio::io::duplex(4096);
+        let (client_read, client_write) = tokio::io::split(client);
+        let (target_read, target_write) = tokio::io::split(target);
+        let relay = tokio::spawn(copy_bidirectional(
+            client_read,
+            target_write,
+            target_read,
+            client_write,
+            None,
+        ));
+
+        // The client finishes sending; the target still answers afterwards
+        client_peer.write_all(b"request").await.unwrap();
+        client_peer.shutdown().await.unwrap();
+        let mut request = Vec::new();
+        target_peer.read_to_end(&mut request).await.unwrap();
+        assert_eq!(request, b"request");
+
+        let response = vec![b'x'; 1000];
+        target_peer.write_all(&response).await.unwrap();
+        target_peer.shutdown().await.unwrap();
+        let mut received = Vec::new();
+        client_peer.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, response);
+
+        assert_eq!(relay.await.unwrap().unwrap(), (7, 1000));
+    }
+
+    #[tokio::test]
+    async fn test_copy_bidirectional_idle_timeout() {
+        let (client, mut client_peer) = tokio::io::duplex(4096);
+        let (target, mut target_peer) = tokio::io::duplex(4096);
+        let (client_read, client_write) = tokio::io::split(client);
+        let (target_read, target_write) = tokio::io::split(target);
+        let relay = tokio::spawn(copy_bidirectional(
+            client_read,
+            target_write,
+            target_read,
+            client_write,
+            Some(Duration::from_millis(300)),
+        ));
+
+        // Traffic every few seconds keeps the relay open
+        for _ in 0..3 {
+            tokio::time::sleep(Duration::from_millis(150)).await;
+            client_peer.write_all(b"ping").await.unwrap();
+            let mut ping = [0u8; 4];
+            target_peer.read_exact(&mut ping).await.unwrap();
+        }
+        assert!(!relay.is_finished());
+
+        assert_eq!(relay.await.unwrap().unwrap(), (12, 0));
+    }
 }